@@ -3,11 +3,12 @@ use std::fmt::format;
 use schnorr_nizk;
 use chrono::prelude::*;
 use std::time::{Instant};
+use std::sync::Arc;
 
 fn main() {
     // ID's of A and B
-    let AID: u32 = 10000;
-    let BID: u32 = 20000;
+    let AID = schnorr_nizk::DeviceId::numeric(10000);
+    let BID = schnorr_nizk::DeviceId::numeric(20000);
     let iterations = 5000;
 
     /*
@@ -17,30 +18,23 @@ fn main() {
     */
     // Run this one time to have a key pair for A and for B
 
+    // Keys are saved in the Linux user keyring
+    let store: Arc<dyn schnorr_nizk::KeyStore> = Arc::new(schnorr_nizk::KeyUtilsStore::new().unwrap());
+
     // Generate A's Key Pair
     // Update keys in case old keys already exists
     let (pub_kA, priv_kA) = schnorr_nizk::gen_random_key_pair();
     println!("my pub key = {:?}\nmy priv key = {:?}\n", pub_kA, priv_kA);
 
-    let desc = format!("PublicKey:{}", AID);
-    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32,Some(Vec::from(pub_kA))).unwrap();
-    my_key.update_key_in_ring(Vec::from(pub_kA));
-
-    let desc = format!("PrivateKey:{}", AID);
-    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32,Some(Vec::from(priv_kA))).unwrap();
-    my_key.update_key_in_ring(Vec::from(priv_kA));
+    store.put(&format!("PublicKey:{}", AID), &pub_kA).unwrap();
+    store.put(&format!("PrivateKey:{}", AID), &priv_kA).unwrap();
 
     // Generate B's Key Pair
     let (pub_kB, priv_kB) = schnorr_nizk::gen_random_key_pair();
     println!("Server pub key = {:?}\nServer priv key = {:?}\n", pub_kB, priv_kB);
 
-    let desc = format!("PublicKey:{}", BID);
-    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32, Some(Vec::from(pub_kB))).unwrap();
-    my_key.update_key_in_ring(Vec::from(pub_kB));
-
-    let desc = format!("PrivateKey:{}", BID);
-    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32, Some(Vec::from(priv_kB))).unwrap();
-    my_key.update_key_in_ring(Vec::from(priv_kB));
+    store.put(&format!("PublicKey:{}", BID), &pub_kB).unwrap();
+    store.put(&format!("PrivateKey:{}", BID), &priv_kB).unwrap();
 
     /*
     ************************************************************************************************
@@ -65,34 +59,27 @@ fn main() {
         // Measure duration of each one
         let start = Instant::now();
 
-        // Init A instance and get values to send
-        let mut a_int_auth = schnorr_nizk::get_int_mut_auth_instance(AID,BID, schnorr_nizk::CONST_INITIATOR_ROLE);
-        let (Acommitment, _, Areq_type) = a_int_auth.gen_next_values();
+        // Init A instance and get commitment to send
+        let (a_int_auth, a_commitment) = schnorr_nizk::int_mut_auth::Initiator::start(store.clone(), AID.clone(), BID.clone());
 
-        // Init B's instance, add received values, and generate values to send
-        let mut b_int_auth = schnorr_nizk::get_int_mut_auth_instance(BID,AID, schnorr_nizk::CONST_RECEIVER_ROLE);
-        let _ = b_int_auth.add_recipient_values(Areq_type, Acommitment, None);
-        let (Bcommitment, Bchallenge, Breq_type) = b_int_auth.gen_next_values();
+        // Init B's instance with A's commitment, and generate commitment and challenge to send
+        let (b_int_auth, b_commitment) = schnorr_nizk::int_mut_auth::Responder::start(store.clone(), BID.clone(), AID.clone(), a_commitment).unwrap();
 
-        // Add received values and generate next values to send
-        let _ = a_int_auth.add_recipient_values(Breq_type, Bcommitment, Bchallenge);
-        let (Achallenge, Aresponse, Areq_type) = a_int_auth.gen_next_values();
+        // Add received values and generate challenge and response to send
+        let (a_int_auth, a_response) = a_int_auth.receive_commitment(b_commitment).unwrap();
 
-        // Add A's response and Generate own response to send
-        let _ = b_int_auth.add_recipient_values(Areq_type, Achallenge, Aresponse);
-        let (Bresponse, _, Breq_type) = b_int_auth.gen_next_values();
+        // Verify A's response and generate own response to send
+        let (b_verified, b_response) = b_int_auth.receive_challenge_response(a_response).unwrap();
 
-        // Add received values and verify proof
-        let _ = a_int_auth.add_recipient_values(Breq_type, Bresponse, None);
-        let a_accepted = a_int_auth.verify_proof();
+        // Add received response and verify proof
+        let a_accepted = a_int_auth.receive_response(b_response).is_ok();
 
         // Calculate Duration
         let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
         all_measurements.push(duration);
 
-        // Verify proof of A
-        // This is not included in the duration, since it's supposed to be done simultaneously
-        let b_accepted = b_int_auth.verify_proof();
+        // Proof of A was already verified by B before sending its response
+        let b_accepted = b_verified.recipient_ID == AID;
 
         if i == iterations - 1 {
             accepted_1 = a_accepted;
@@ -153,7 +140,7 @@ fn main() {
         let start = Instant::now();
 
         // Generate proof
-        let proof = schnorr_nizk::gen_nizk_proof(AID, BID, m, true);
+        let proof = schnorr_nizk::gen_nizk_proof(&*store, &AID, &BID, m, true).unwrap();
 
         // Calculate Duration
         let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
@@ -166,7 +153,7 @@ fn main() {
         let start = Instant::now();
 
         // Verify NIZK proof
-        let result = schnorr_nizk::verify_nizk_proof(BID, AID, m, proof, true);
+        let result = schnorr_nizk::verify_nizk_proof(&*store, &BID, &AID, m, proof, true).unwrap();
         let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
         all_measurements_ver.push(duration);
 
@@ -215,23 +202,23 @@ fn main() {
         let start = Instant::now();
 
         // Generate proof of A
-        let (mut nizk_a, proof_a) = schnorr_nizk::NIZKMutAuth::new(AID, BID, None);
+        let (mut nizk_a, proof_a) = schnorr_nizk::NIZKMutAuth::new(store.clone(), AID.clone(), BID.clone(), None).unwrap();
 
         // Add proof of A and Generate proof of B
-        let (mut nizk_b, proof_b) = schnorr_nizk::NIZKMutAuth::new(BID, AID, Some(proof_a));
+        let (mut nizk_b, proof_b) = schnorr_nizk::NIZKMutAuth::new(store.clone(), BID.clone(), AID.clone(), Some(proof_a)).unwrap();
 
         // Add proof of B to A's Data and verify B's proof and generate session key
         nizk_a.add_recipient_values(proof_b);
-        let verify_b = nizk_a.verify_proof();
-        let ska = nizk_a.calculate_session_key();
+        let verify_b = nizk_a.verify_proof().unwrap();
+        let ska = nizk_a.calculate_session_keys().unwrap();
 
         // Calculate duration
         let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
         all_measurements.push(duration);
 
         // This is not part of the duration because it's supposed to run simultaneously
-        let verify_a = nizk_b.verify_proof();
-        let skb = nizk_b.calculate_session_key();
+        let verify_a = nizk_b.verify_proof().unwrap();
+        let skb = nizk_b.calculate_session_keys().unwrap();
 
         if i == iterations - 1 {
             same_skey = (ska == skb);
@@ -263,12 +250,12 @@ fn main() {
     // Test intrusion detection system
     println!("Start intrusion test:");
     let m = format!("NIZK AUTH message of {:?}", AID);
-    let result = schnorr_nizk::verify_nizk_proof(BID, AID, m, ([0u8; 32], [1u8; 32], [2u8; 32]), true);
+    let result = schnorr_nizk::verify_nizk_proof(&*store, &BID, &AID, m, ([0u8; 32], [1u8; 32], [2u8; 32]), true);
 
     println!("Check if a key is compromised:");
 
     // Get Intrusion Values
-    let (asym, sym, dos) = schnorr_nizk::check_intrusion(AID);
+    let (asym, sym, dos) = schnorr_nizk::check_intrusion(&AID).unwrap();
     println!("asym key is compromised ?: {:?}", asym);
     println!("sym key is compromised ?: {:?}", sym);
     println!("Dos attack being conducted ?: {:?}\n", dos);
@@ -278,12 +265,12 @@ fn main() {
     let resource_id: u32 = 12345;
     println!("Creating a resource with ID {:?}", resource_id);
     let resp = schnorr_nizk::access_control::add_resource(resource_id, None);
-    println!("received response code {}\n", resp);
+    println!("received response {:?}\n", resp);
 
     // Delete a resource
     println!("Deleting resource with ID {:?}", resource_id);
     let resp = schnorr_nizk::access_control::remove_resource(resource_id);
-    println!("received response code {}\n", resp);
+    println!("received response {:?}\n", resp);
 
     // Create resource with actions
     println!("Recreating resource with ID {:?}", resource_id);
@@ -292,44 +279,115 @@ fn main() {
     actions.push(String::from("GET").into_bytes());
     actions.push(String::from("SET").into_bytes());
     let resp = schnorr_nizk::access_control::add_resource(resource_id, Some(actions));
-    println!("received response code {}\n", resp);
+    println!("received response {:?}\n", resp);
 
     // Add a new action
     println!("Adding a new action to resource with ID {:?}", resource_id);
     let resp = schnorr_nizk::access_control::add_action_to_resource(resource_id, String::from("DEL").into_bytes());
-    println!("received response code {}\n", resp);
+    println!("received response {:?}\n", resp);
 
     // Add a new action
     println!("Deleting an action to resource with ID {:?}", resource_id);
     let resp = schnorr_nizk::access_control::remove_action_from_resource(resource_id, String::from("POST").into_bytes());
-    println!("received response code {}\n", resp);
+    println!("received response {:?}\n", resp);
 
     // Add device ID to all actions
     println!("Adding device {} to all actions of resource with ID {:?}", AID, resource_id);
-    let resp = schnorr_nizk::access_control::add_device_to_all_actions(resource_id, AID);
-    println!("received response code {}\n", resp);
+    let resp = schnorr_nizk::access_control::add_device_to_all_actions(resource_id, &AID);
+    println!("received response {:?}\n", resp);
 
     println!("Adding device {} to all actions of resource with ID {:?}", BID, resource_id);
-    let resp = schnorr_nizk::access_control::add_device_to_all_actions(resource_id, BID);
-    println!("received response code {}\n", resp);
+    let resp = schnorr_nizk::access_control::add_device_to_all_actions(resource_id, &BID);
+    println!("received response {:?}\n", resp);
 
     // Removing device form actions
     println!("Removing device {} from all actions of resource with ID {:?}", AID, resource_id);
-    let resp = schnorr_nizk::access_control::remove_device_from_all_actions(resource_id, AID);
-    println!("received response code {}\n", resp);
+    let resp = schnorr_nizk::access_control::remove_device_from_all_actions(resource_id, &AID);
+    println!("received response {:?}\n", resp);
 
     println!("Removing device {} from last action of resource with ID {:?}", BID, resource_id);
-    let resp = schnorr_nizk::access_control::remove_device_from_resource_action(resource_id, String::from("DEL").into_bytes(), BID);
-    println!("received response code {}\n", resp);
+    let resp = schnorr_nizk::access_control::remove_device_from_resource_action(resource_id, String::from("DEL").into_bytes(), &BID);
+    println!("received response {:?}\n", resp);
 
     // Check access of a device to a resource
     println!("Check if device {} is allowed to access an action of resource with ID {:?}.\nexpected response: false.", AID, resource_id);
-    let resp = schnorr_nizk::access_control::check_access(resource_id, String::from("DEL").into_bytes(), AID);
-    println!("received response: {}\n", resp);
+    let resp = schnorr_nizk::access_control::check_access(resource_id, String::from("DEL").into_bytes(), &AID);
+    println!("received response: {:?}\n", resp);
 
     println!("Check if device {} is allowed to access an action of resource with ID {:?}.\nexpected response: true.", BID, resource_id);
-    let resp = schnorr_nizk::access_control::check_access(resource_id, String::from("GET").into_bytes(), BID);
-    println!("received response {}\n", resp);
+    let resp = schnorr_nizk::access_control::check_access(resource_id, String::from("GET").into_bytes(), &BID);
+    println!("received response {:?}\n", resp);
+
+    // Grant access through a role instead of adding the device to every action
+    println!("Creating role \"reader\" with GET of resource {:?} and group \"sensors\" with device {}", resource_id, AID);
+    let resp = schnorr_nizk::roles::add_role("reader", vec![schnorr_nizk::roles::Permission::new(resource_id, b"GET")])
+        .and_then(|_| schnorr_nizk::roles::add_group("sensors"))
+        .and_then(|_| schnorr_nizk::roles::add_device_to_group("sensors", &AID))
+        .and_then(|_| schnorr_nizk::roles::assign_role_to_group("sensors", "reader"));
+    println!("received response {:?}\n", resp);
+
+    println!("Explain why device {} is allowed to access GET of resource with ID {:?}.", AID, resource_id);
+    let resp = schnorr_nizk::access_control::explain_access(resource_id, b"GET", &AID);
+    println!("received response: {:?}\n", resp);
+
+    println!("Effective permissions of device {}:", AID);
+    let resp = schnorr_nizk::access_control::effective_permissions(&AID);
+    println!("received response: {:?}\n", resp);
+
+    // Limit the role to three uses of requests up to 1 KiB, the fourth request is refused as expired
+    println!("Limiting role \"reader\" to 3 uses of requests up to 1024 bytes");
+    let conditions = schnorr_nizk::conditions::Conditions::default().with_max_uses(3).with_max_message_size(1024);
+    let resp = schnorr_nizk::conditions::set_conditions(schnorr_nizk::conditions::GrantSubject::Role(String::from("reader")),
+                                                        schnorr_nizk::roles::Permission::new(resource_id, b"GET"),
+                                                        conditions);
+    println!("received response {:?}\n", resp);
+
+    let context = schnorr_nizk::conditions::AccessContext::default().with_message_size(512);
+    for _ in 0..4 {
+        let resp = schnorr_nizk::access_control::check_access_with(resource_id, String::from("GET").into_bytes(), &AID, &context);
+        println!("Check access of device {} with a 512 byte request, received response: {:?}", AID, resp);
+    }
+    let resp = schnorr_nizk::access_control::explain_access_with(resource_id, b"GET", &AID, &context);
+    println!("received response: {:?}\n", resp);
+
+    // Deny the group "sensors" every action of a child resource, the deny overrides the role
+    let child_id = resource_id + 1;
+    println!("Creating resource {:?} as child of resource {:?} with action GET:/sensors/*, denied to group \"sensors\"", child_id, resource_id);
+    let resp = schnorr_nizk::access_control::add_resource(child_id, Some(vec![String::from("GET:/sensors/*").into_bytes()]))
+        .and_then(|_| schnorr_nizk::access_control::set_resource_parent(child_id, Some(resource_id)))
+        .and_then(|_| schnorr_nizk::access_control::add_device_to_resource_action(child_id, String::from("GET:/sensors/*").into_bytes(), &BID))
+        .and_then(|_| schnorr_nizk::access_control::deny_group_on_resource_action(child_id, String::from("GET:/sensors/*").into_bytes(), "sensors"));
+    println!("received response {:?}\n", resp);
+
+    println!("Explain access of device {} to GET:/sensors/temperature of resource with ID {:?}.", AID, child_id);
+    let resp = schnorr_nizk::access_control::explain_access(child_id, b"GET:/sensors/temperature", &AID);
+    println!("received response: {:?}\n", resp);
+
+    println!("Check if device {} is allowed to access GET:/sensors/temperature of resource with ID {:?}.\nexpected response: true.", BID, child_id);
+    let resp = schnorr_nizk::access_control::check_access(child_id, String::from("GET:/sensors/temperature").into_bytes(), &BID);
+    println!("received response {:?}\n", resp);
+
+    println!("Deleting resource with ID {:?}", child_id);
+    let resp = schnorr_nizk::access_control::remove_resource(child_id);
+    println!("received response {:?}\n", resp);
+
+    // Export the whole access control as a policy and compare it with a changed copy
+    println!("Exporting the access control policy as TOML");
+    match schnorr_nizk::access_control::export_policy().and_then(|policy| policy.to_toml().map(|text| (policy, text))) {
+        Ok((policy, text)) => {
+            println!("{}", text);
+            let mut changed = policy.clone();
+            changed.resources.retain(|resource| resource.id != resource_id);
+            println!("Dry run of an import without resource {:?}:", resource_id);
+            let resp = schnorr_nizk::access_control::import_policy(&changed, true);
+            match resp {
+                Ok(changes) => changes.iter().for_each(|change| println!("  {}", change)),
+                Err(e) => println!("received error {:?}", e),
+            }
+            println!();
+        },
+        Err(e) => println!("received error {:?}\n", e),
+    }
 
     /*
     ************************************************************************************************
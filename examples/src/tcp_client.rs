@@ -1,140 +1,82 @@
-use std::io::{BufRead, BufReader, Write};
 use std::net::TcpStream;
 use std::io::prelude::*;
 use schnorr_nizk;
-use serde::{Deserialize, Serialize};
+use schnorr_nizk::wire::{Body, Message};
+use schnorr_nizk::{DeviceId, Engine, Event};
 use std::env;
 use chrono::prelude::*;
 use std::time::{Instant};
+use std::sync::Arc;
 
 
 // ID's of client and server
-const MY_ID: u32 = 100000;
-const SERVER_ID: u32 = 200000;
+const MY_ID: DeviceId = DeviceId::numeric(100000);
+const SERVER_ID: DeviceId = DeviceId::numeric(200000);
 const SERVER_ADDRESS: &str = "000.000.0.00:8000";
 
-// Data to send and receive
-#[derive(Debug, Serialize, Deserialize)]
-struct DataExchange {
-    auth_type: u8,
-    request_type: u8,
-    message: Option<String>,
-    value_1: [u8; 32],
-    value_2: Option<[u8; 32]>,
-    value_3: Option<[u8; 32]>,
-}
-
-fn shared_key_agreement() {
-    // Prepare data to be send
-    // Create an instance of Mutual auth as an initiator role and get the values to send
-    let mut int_mut_auth = schnorr_nizk::get_int_mut_auth_instance(MY_ID,SERVER_ID, schnorr_nizk::CONST_INITIATOR_ROLE);
-    let (my_commitment, val_2, my_req_type) = int_mut_auth.gen_next_values();
-
-    // Create a data struct with all info
-    let data_struct = DataExchange {
-        auth_type: 0,
-        request_type: my_req_type,
-        message: None,
-        value_1: my_commitment,
-        value_2: val_2,
-        value_3: None,
-    };
-
-    // Convert data to String
-    let json_string = serde_json::to_string(&data_struct).unwrap();
-
-    // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
-    let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
-    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
+// Context of announcements, proofs that only need the public key to be verified
+const ANNOUNCEMENT_CONTEXT: &[u8] = b"announcement";
 
-    // Send message
-    println!("Sending first message:");
-    stream.write_all(json_string.as_bytes()).expect("write failed");
-    stream.write_all(b"\n").expect("Failed to write to server");
-    println!("Message sent! Reading server response...");
+// Key store used by the example. Keys are saved in the Linux user keyring
+fn key_store() -> Arc<dyn schnorr_nizk::KeyStore> {
+    schnorr_nizk::config::current().open_key_store(None).expect("Failed to open the key store")
+}
 
-    // Define server response reader and enter a loop for sending and receiving messages
-    let mut reader = BufReader::new(stream_copy);
+// Drive a protocol engine over the TCP stream until the protocol is finished and return the session keys, if any
+fn run_engine(engine: &mut Engine, stream: &mut TcpStream, mut events: Vec<Event>) -> Result<Option<schnorr_nizk::SessionKeys>, schnorr_nizk::NizkError> {
     loop {
-        // Read server response
-        let mut response = String::new();
-        reader.read_line(&mut response).expect("Read server response failed!\n");
-        let response_str = response.trim();
-        print!("Got response from server: {}\n", response_str);
-
-        // Convert response into a DataExchange struct
-        let mut data: DataExchange = serde_json::from_str(response_str).unwrap();
-
-        // Enter received values to our int_mut_auth instance
-        let response_state = int_mut_auth.add_recipient_values(data.request_type, data.value_1, data.value_2);
-
-        // Check if we still need to send data or not
-        if (response_state == schnorr_nizk::CONST_RESPONSE_CANNOT_BE_VERIFIED) ||
-            (response_state == schnorr_nizk::CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE) {
-
-            // Generate data to send
-            let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
-            let data_to_send = DataExchange {
-                auth_type: 0,
-                request_type: my_req_type,
-                message: None,
-                value_1: val1,
-                value_2: val2,
-                value_3: None,
-            };
-            let json_string = serde_json::to_string(&data_to_send).unwrap();
-
-            // Send data
-            println!("Sending data:");
-            stream.write_all(json_string.as_bytes()).expect("write failed");
-            stream.write_all(b"\n").expect("Failed to write to server");
-            println!("Message sent!");
+        for event in events {
+            match event {
+                Event::NeedSend(message) => {
+                    println!("Sending message: {:?}", message);
+                    message.write_to(stream)?;
+                },
+                Event::Established { peer_ID, session_key } => {
+                    println!("Protocol with device {} established\n", peer_ID);
+                    return Ok(session_key);
+                },
+                Event::Verified { .. } => return Ok(None),
+                Event::Rejected { reason } => return Err(reason),
+            }
         }
 
-        // Check if we need to verify the proof
-        if (response_state == schnorr_nizk::CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE) ||
-            (response_state == schnorr_nizk::CONST_RESPONSE_CAN_BE_VERIFIED) {
+        // Give the next message of the server to the engine
+        let message = Message::read_from(stream)?;
+        println!("Got response from server: {:?}", message);
+        events = engine.handle_message(message);
+    }
+}
 
-            // Verify the proof
-            let accepted = int_mut_auth.verify_proof();
-            println!("Client {} verified proof of Server {}, result: {}\n", MY_ID, SERVER_ID, accepted);
+fn shared_key_agreement(resync: bool) {
+    // Start the mutual auth as the initiator.
+    // A resync uses the same handshake, but requires that the public key of the server is already known
+    let (mut engine, events) = Engine::key_agreement(key_store(), MY_ID, SERVER_ID, resync).expect("Server is not paired yet, run exchange_keys first");
 
-            // Exist the loop since verification is complete
-            break;
-        }
-    }
+    // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
+    let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
+
+    // Run the handshake and verify the proof of the server
+    let accepted = run_engine(&mut engine, &mut stream, events);
+    println!("Client {} verified proof of Server {}, result: {:?}\n", MY_ID, SERVER_ID, accepted.map(|_| true));
 }
 
 // NIZK Auth
 fn test_nizk_auth_speed(m: String, m_copy: String) {
     // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
     let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
-    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
 
     // Generate NIZK Proof
-    let (commitment, challenge, mut response) = schnorr_nizk::gen_nizk_proof(MY_ID, SERVER_ID, m, true);
+    let proof = schnorr_nizk::gen_nizk_proof(&*key_store(), &MY_ID, &SERVER_ID, m, true).expect("Failed to generate NIZK proof");
 
     // Prepare data to send
-    let data = DataExchange {
-        auth_type: 123,
-        request_type: 0,
-        message: Some(m_copy),
-        value_1: commitment,
-        value_2: Some(challenge),
-        value_3: Some(response),
-    };
-
-    // Convert data to String
-    let json_string = serde_json::to_string(&data).unwrap();
+    let message = Message::new(MY_ID, SERVER_ID, Body::NizkProof { proof, message: m_copy.into_bytes() });
 
     // Send message
-    stream.write_all(json_string.as_bytes()).expect("write failed");
-    stream.write_all(b"\n").expect("Failed to write to server");
+    message.write_to(&mut stream).expect("write failed");
 
-    // Define server response reader and enter a loop for sending and receiving messages
-    let mut reader = BufReader::new(stream_copy);
-    let mut response = String::new();
-    reader.read_line(&mut response).expect("Read server response failed!\n");
+    // Wait until the server verified the proof and closed the connection
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).expect("Read server response failed!\n");
 }
 
 
@@ -151,7 +93,7 @@ fn nizk_auth(m: String, m_copy: String, fake_schnorr: bool, fake_mac: bool) {
         update = false;
     }
 
-    let (commitment, challenge, mut response) = schnorr_nizk::gen_nizk_proof(MY_ID, SERVER_ID, m, update);
+    let (commitment, challenge, mut response) = schnorr_nizk::gen_nizk_proof(&*key_store(), &MY_ID, &SERVER_ID, m, update).expect("Failed to generate NIZK proof");
 
     // Fake schnorr proof
     if fake_schnorr {
@@ -171,22 +113,29 @@ fn nizk_auth(m: String, m_copy: String, fake_schnorr: bool, fake_mac: bool) {
     }
     println!("message: {:?}", my_message);
     // Prepare data to send
-    let data = DataExchange {
-        auth_type: 1,
-        request_type: 0,
-        message: Some(my_message),
-        value_1: commitment,
-        value_2: Some(challenge),
-        value_3: Some(response),
-    };
-
-    // Convert data to String
-    let json_string = serde_json::to_string(&data).unwrap();
+    let message = Message::new(MY_ID, SERVER_ID, Body::NizkProof {
+        proof: (commitment, challenge, response),
+        message: my_message.into_bytes(),
+    });
 
     // Send message
     println!("Sending NIZK message:");
-    stream.write_all(json_string.as_bytes()).expect("write failed");
-    stream.write_all(b"\n").expect("Failed to write to server");
+    message.write_to(&mut stream).expect("write failed");
+    println!("Message sent!\n");
+}
+
+// Announcement, the server only needs the public key of the client to verify it
+fn announce(m: String) {
+    // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
+    let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
+
+    // Generate NIZK Proof in public key mode
+    let mode = schnorr_nizk::NizkMode::PublicKey { context: ANNOUNCEMENT_CONTEXT };
+    let proof = schnorr_nizk::gen_proof(&*key_store(), mode, &MY_ID, &SERVER_ID, m.as_bytes()).expect("Failed to generate NIZK proof");
+
+    // Send message
+    println!("Sending announcement:");
+    Message::new(MY_ID, SERVER_ID, Body::PublicNizkProof { proof, message: m.into_bytes() }).write_to(&mut stream).expect("write failed");
     println!("Message sent!\n");
 }
 
@@ -194,49 +143,27 @@ fn nizk_auth(m: String, m_copy: String, fake_schnorr: bool, fake_mac: bool) {
 fn session_key() {
     // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
     let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
-    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
 
-    // Generate NIZK Proof
+    // Generate NIZK Proof, the keys are confirmed by both devices before they are used
     println!("Generating NIZK Mutual Auth Proof");
-    let (mut nizk_ins, (commitment, challenge, response)) = schnorr_nizk::NIZKMutAuth::new(MY_ID, SERVER_ID, None);
-
-    // Prepare data to send
-    let data = DataExchange {
-        auth_type: 2,
-        request_type: 0,
-        message: None,
-        value_1: commitment,
-        value_2: Some(challenge),
-        value_3: Some(response),
+    let (mut engine, events) = Engine::mut_auth(key_store(), MY_ID, SERVER_ID, true).expect("Failed to generate NIZK proof");
+
+    // Verify the proof of the server and calculate session key
+    let s_keys = match run_engine(&mut engine, &mut stream, events) {
+        Ok(Some(s_keys)) => s_keys,
+        Ok(None) => return,
+        Err(e) => {
+            println!("Client could not calculate session keys: {}\n", e);
+            return;
+        }
     };
+    println!("Client calculated session keys, initiator to responder key: {:?}\n", s_keys.initiator_to_responder);
 
-    // Convert data to String
-    let json_string = serde_json::to_string(&data).unwrap();
-
-    // Send message
-    println!("Sending NIZK Mutual Auth request:");
-    stream.write_all(json_string.as_bytes()).expect("write failed");
-    stream.write_all(b"\n").expect("Failed to write to server");
-    println!("Message sent!\n");
-
-    // Read server response
-    let mut reader = BufReader::new(stream_copy);
-    let mut response = String::new();
-    reader.read_line(&mut response).expect("Read server response failed!\n");
-    let response_str = response.trim();
-    print!("Got response from server: {}\n", response_str);
-
-    // Convert response into a DataExchange struct
-    let mut data: DataExchange = serde_json::from_str(response_str).unwrap();
-
-    // Add data to NIZK Auth and verify proof
-    nizk_ins.add_recipient_values((data.value_1, data.value_2.unwrap(), data.value_3.unwrap()));
-    let verify = nizk_ins.verify_proof();
-    println!("Client verified server's proof, result = {:?}\n\n", verify);
-
-    // Calculate session key
-    let s_key = nizk_ins.calculate_session_key();
-    println!("Client calculated session key as: {:?}\n", s_key);
+    // Continue with encrypted records on the same connection
+    let mut channel = schnorr_nizk::SecureChannel::new(stream, &s_keys, true);
+    channel.send(b"Hello server, this message is encrypted").expect("Failed to send record");
+    let reply = channel.receive().expect("Failed to receive record");
+    println!("Got encrypted reply from server: {}\n", String::from_utf8_lossy(&reply));
 }
 
 fn fake_nizk_auth(dos_attack: bool) {
@@ -251,25 +178,17 @@ fn fake_nizk_auth(dos_attack: bool) {
         let (commitment, _) = schnorr_nizk::gen_random_key_pair();
         let (response, _) = schnorr_nizk::gen_random_key_pair();
         let challenge = schnorr_nizk::generate_random_32bytes();
-        let message = format!("fake test proof {:?}", schnorr_nizk::generate_random_32bytes());
+        let fake_message = format!("fake test proof {:?}", schnorr_nizk::generate_random_32bytes());
 
         // Prepare data to send
-        let data = DataExchange {
-            auth_type: 1,
-            request_type: 0,
-            message: Some(message),
-            value_1: commitment,
-            value_2: Some(challenge),
-            value_3: Some(response),
-        };
-
-        // Convert data to String
-        let json_string = serde_json::to_string(&data).unwrap();
+        let message = Message::new(MY_ID, SERVER_ID, Body::NizkProof {
+            proof: (commitment, challenge, response),
+            message: fake_message.into_bytes(),
+        });
 
         // Send message
         println!("Sending NIZK message:");
-        stream.write_all(json_string.as_bytes()).expect("write failed");
-        stream.write_all(b"\n").expect("Failed to write to server");
+        message.write_to(&mut stream).expect("write failed");
         println!("Message sent!\n");
 
         // Increment counter for dos attack
@@ -288,20 +207,22 @@ fn fake_nizk_auth(dos_attack: bool) {
 fn print_help() {
     println!("\n----------------------------------------------------------------------------\n");
     println!("Usage: ./tcp_client <auth_type> [message]");
-    println!("auth_type can be one of: exchange_keys, sharedsecretkey, nizk, sessionkey, fake, semi_fake_asymmetric, semi_fake_symmetric, dos_attack, testnizkspeed\n");
+    println!("auth_type can be one of: exchange_keys, sharedsecretkey, resync, nizk, announce, sessionkey, fake, semi_fake_asymmetric, semi_fake_symmetric, dos_attack, testnizkspeed\n");
     println!("exchange_keys: Init and Exchange Asymmetric keys between client and server for test purposes.");
     println!("After running this, sharedsecretkey command has to be executed for a new shared secret key compatible with the current key.");
     println!("Note: This has to be replaced by a real trusted authority in future.\n");
     println!("sharedsecretkey: will generate a secret shared key between client and server, to use for NIZK Authentication!");
+    println!("resync: will renew the shared secret key and counter with the long-term keys, if the server reports that they are out of sync.");
     println!("nizk: will send a Non-Interactive Authentication proof to the Server.");
+    println!("announce: will send a NIZK proof that only needs the public key of the client, no shared secret key.");
     println!("sessionkey: will calculate a session secret key that can be used for end-to-end secure communication.");
     println!("fake: will generate a random fake NIZK proof.");
     println!("semi_fake_asymmetric: will generate a semi random fake NIZK proof, where the asymmetric key is correct and the Schnorr Proof is valid, but with a valid Mac Tag.");
     println!("semi_fake_symmetric: will generate a semi random fake NIZK proof, where the symmetric shared key is correct and the MAC Tag is valid, but with a valid Schnorr proof.");
     println!("dos_attack: will send 1000 fake NIZK proofs quickly to mimic a DoS attack.\n");
-    println!("If auth_type is nizk, testnizkspeed, semi_fake_asymmetric, or semi_fake_symmetric, a message must be provided.\n");
+    println!("If auth_type is nizk, announce, testnizkspeed, semi_fake_asymmetric, or semi_fake_symmetric, a message must be provided.\n");
     println!("PLEASE NOTE: For speed testing you can modify iteration number in tcp_client.rs, you have to change IP Adress to a valid one of Server.");
-    println!("PLEASE NOTE: For testing NIZK Auth speed, a seperate command, testnizkspeed, is provided, since it requires an additional response compared to the normal NIZK.");
+    println!("PLEASE NOTE: For testing NIZK Auth speed, a seperate command, testnizkspeed, is provided, since it waits until the server closed the connection after verifying.");
     println!("PLEASE NOTE: For accurate speed results, please comment all the prints of the console!\n");
     println!("----------------------------------------------------------------------------\n");
 }
@@ -311,61 +232,47 @@ fn exchange_keys() {
     // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
     // 192.168.0.21 for inside wlan and 127.0.0.1 for local computer
     let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
-    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
 
     // Generate random key pair
     let (public_key, private_key) = schnorr_nizk::gen_random_key_pair();
     println!("Generated Pub key: {:?}\n\nPrivKey: {:?}\n", public_key, private_key);
+    let store = key_store();
     let desc_priv = format!("PrivateKey:{}", MY_ID);
     let desc_pub = format!("PublicKey:{}", MY_ID);
-    let mut my_key_priv = schnorr_nizk::get_key_instance(&desc_priv, 32, Some(private_key.to_vec())).unwrap();
-    let mut my_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(public_key.to_vec())).unwrap();
 
-    // Update keys in Ring. This is important because if the key already exists
-    // then it will not be changed without this step
-    my_key_priv.update_key_in_ring(private_key.to_vec());
-    my_key_pub.update_key_in_ring(public_key.to_vec());
+    // Save keys in the key store. Old keys with the same description are replaced
+    store.put(&desc_priv, &private_key).expect("Failed to save private key");
+    store.put(&desc_pub, &public_key).expect("Failed to save public key");
 
     // Send public key to Server
     // Prepare data to send
-    let data = DataExchange {
-        auth_type: 11,
-        request_type: 0,
-        message: None,
-        value_1: public_key,
-        value_2: None,
-        value_3: None,
-    };
-
-    // Convert data to String
-    let json_string = serde_json::to_string(&data).unwrap();
+    let message = Message::new(MY_ID, SERVER_ID, Body::PublicKey(public_key));
 
     // Send message
-    println!("Sending NIZK Mutual Auth request:");
-    stream.write_all(json_string.as_bytes()).expect("write failed");
-    stream.write_all(b"\n").expect("Failed to write to server");
+    println!("Sending public key:");
+    message.write_to(&mut stream).expect("write failed");
     println!("Message sent!\n");
 
     // Read server response
-    let mut reader = BufReader::new(stream_copy);
-    let mut response = String::new();
-    reader.read_line(&mut response).expect("Read server response failed!\n");
-    let response_str = response.trim();
-    print!("Got response from server: {}\n", response_str);
-
-    // Convert response into a DataExchange struct
-    let mut data: DataExchange = serde_json::from_str(response_str).unwrap();
+    let response = Message::read_from(&mut stream).expect("Read server response failed!\n");
+    print!("Got response from server: {:?}\n", response);
+    let server_key = match response.body {
+        Body::PublicKey(key) => key,
+        _ => panic!("Server did not send a public key"),
+    };
     let desc_pub = format!("PublicKey:{}", SERVER_ID);
-    let mut server_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(data.value_1.to_vec())).unwrap();
 
-    // Make sure to update key in Ring, in case an old key with same ID exists
-    server_key_pub.update_key_in_ring(data.value_1.to_vec());
+    // Save key in the key store, in case an old key with same ID exists it is replaced
+    store.put(&desc_pub, &server_key).expect("Failed to save server public key");
 }
 
 // Main function
 fn main() {
     let iterations = 1;
 
+    // Use the default state directory and key store, the directory must not be writable by other users
+    schnorr_nizk::config::install(schnorr_nizk::NizkConfig::default()).expect("Unsafe state directory");
+
     // Read arguments
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
@@ -373,6 +280,9 @@ fn main() {
         return;
     }
 
+    // Restore shared states that were half-written when the client stopped
+    schnorr_nizk::shared_state::recover(&*key_store(), &MY_ID).expect("Failed to recover shared states");
+
     // Check requested auth type
     let auth_type = &args[1];
     match auth_type.as_str() {
@@ -390,7 +300,7 @@ fn main() {
                 let start = Instant::now();
 
                 // Interactive mutual auth for shared secret key agreement
-                shared_key_agreement();
+                shared_key_agreement(false);
 
                 // Calculate Duration
                 let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
@@ -400,6 +310,10 @@ fn main() {
             println!("All Measurements for Int Mut Auth:\n{:?}\n", all_measurements);
 
         }
+        "resync" => {
+            println!("\nResynchronising the shared secret key with the server:");
+            shared_key_agreement(true);
+        }
         "nizk" => {
             if args.len() < 3 {
                 println!("Error: No message provided for NIZK authentication");
@@ -411,6 +325,14 @@ fn main() {
             nizk_auth(m, m_copy, false, false);
 
         }
+        "announce" => {
+            if args.len() < 3 {
+                println!("Error: No message provided for the announcement");
+                return;
+            }
+            println!("\nAuthentication type: Announcement\n");
+            announce(args[2].to_owned());
+        }
         "testnizkspeed" => {
             if args.len() < 3 {
                 println!("Error: No message provided for NIZK authentication");
@@ -2,183 +2,145 @@ use std::net::{TcpListener, TcpStream};
 use std::io::prelude::*;
 use std::{io, thread};
 use schnorr_nizk;
-use serde::{Deserialize, Serialize};
+use schnorr_nizk::wire::{Body, Message};
+use schnorr_nizk::{DeviceId, Engine, Event};
 use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use std::sync::Arc;
+use schnorr_nizk::{Decision, RateLimiter, Subject};
 
 // ID's of client and server
-const MY_ID: u32 = 200000;
-const CLIENT_ID: u32 = 100000;
+const MY_ID: DeviceId = DeviceId::numeric(200000);
+const CLIENT_ID: DeviceId = DeviceId::numeric(100000);
 const SERVER_ADDRESS: &str = "000.000.0.00:8000";
 
-// Block duration if a DoS attack is detected
-const BLOCK_DURATION: Duration = Duration::from_secs(10);
+// Context of announcements, proofs that only need the public key to be verified
+const ANNOUNCEMENT_CONTEXT: &[u8] = b"announcement";
 
-// Data to send and receive
-#[derive(Debug, Serialize, Deserialize)]
-struct DataExchange {
-    auth_type: u8,
-    request_type: u8,
-    message: Option<String>,
-    value_1: [u8; 32],
-    value_2: Option<[u8; 32]>,
-    value_3: Option<[u8; 32]>,
+// Key store used by the example. Keys are saved in the Linux user keyring
+fn key_store() -> Arc<dyn schnorr_nizk::KeyStore> {
+    schnorr_nizk::config::current().open_key_store(None).expect("Failed to open the key store")
 }
 
-// Callback function to handle an incoming connection
-fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, SystemTime>>>) {
-    // Read received message
-    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
-    let mut stream_copy_2 = stream.try_clone().expect("Failed to clone stream\n");
-    let mut reader = io::BufReader::new(stream);
-    let mut message = String::new();
-    reader.read_line(&mut message).expect("Read server response failed!\n");
-    let message_str = message.trim();
-    println!("Got response from client: {}\n", message_str);
-
-    // Convert message into a DataExchange struct
-    let mut data: DataExchange = serde_json::from_str(message_str).unwrap();
-
-    match data.auth_type {
-        // Interactive Mutual Auth for key Agreement
-        0 => {
-            // Get instance of IntMutAuth and add received values
-            let mut int_mut_auth = schnorr_nizk::get_int_mut_auth_instance(MY_ID,CLIENT_ID, schnorr_nizk::CONST_RECEIVER_ROLE);
-            int_mut_auth.add_recipient_values(data.request_type, data.value_1, data.value_2);
-
-            // Generate data to send
-            let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
-            let data_to_send = DataExchange {
-                auth_type: 0,
-                request_type: my_req_type,
-                message: None,
-                value_1: val1,
-                value_2: val2,
-                value_3: None,
-            };
-            let json_string = serde_json::to_string(&data_to_send).unwrap();
-
-            // Send data
-            println!("Sending data:");
-            stream_copy.write_all(json_string.as_bytes()).expect("write failed");
-            stream_copy.write_all(b"\n").expect("Failed to write to server");
-            println!("Message sent!");
-
-            // Read last data
-            message.clear();
-            reader.read_line(&mut message).expect("Read server response failed!\n");
-            let message_str = message.trim();
-            print!("Got response from client: {}\n", message_str);
-
-            // Convert message into a DataExchange struct and add recipient values
-            let mut last_data: DataExchange = serde_json::from_str(message_str).unwrap();
-            int_mut_auth.add_recipient_values(last_data.request_type, last_data.value_1, last_data.value_2);
+// Drive a protocol engine over the TCP stream until the protocol is finished and return the session keys, if any
+fn run_engine(engine: &mut Engine, stream: &mut TcpStream, mut events: Vec<Event>) -> Result<Option<schnorr_nizk::SessionKeys>, schnorr_nizk::NizkError> {
+    loop {
+        for event in events {
+            match event {
+                Event::NeedSend(message) => {
+                    println!("Sending message: {:?}", message);
+                    message.write_to(stream)?;
+                },
+                Event::Established { session_key, .. } => return Ok(session_key),
+                Event::Verified { .. } => return Ok(None),
+                Event::Rejected { reason } => return Err(reason),
+            }
+        }
 
-            // Generate next values
-            let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
-            let data_to_send = DataExchange {
-                auth_type: 0,
-                request_type: my_req_type,
-                message: None,
-                value_1: val1,
-                value_2: val2,
-                value_3: None,
-            };
-            let json_string = serde_json::to_string(&data_to_send).unwrap();
+        // Give the next message of the client to the engine
+        let message = Message::read_from(stream)?;
+        println!("Got response from client: {:?}", message);
+        events = engine.handle_message(message);
+    }
+}
 
-            // Send data
-            println!("Sending data:");
-            stream_copy.write_all(json_string.as_bytes()).expect("write failed");
-            stream_copy.write_all(b"\n").expect("Failed to write to server");
-            println!("Message sent!");
+// Callback function to handle an incoming connection
+fn handle_connection(mut stream: TcpStream, limiter: Arc<RateLimiter>) {
+    // Read received message. Invalid messages are dropped before any key is used
+    let data = match Message::read_from(&mut stream) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Received an invalid message: {}\n", e);
+            return;
+        }
+    };
+    println!("Got message from client: {:?}\n", data);
 
-            // Verify proof
-            let accepted = int_mut_auth.verify_proof();
-            println!("Server {} verified proof of Client {}, result: {}\n", MY_ID, CLIENT_ID, accepted);
+    // Senders blocked for too many rejected proofs are refused, also from other addresses
+    if let Ok(Decision::Block(until)) = schnorr_nizk::check_sender(&data.sender_ID) {
+        println!("Device {} is blocked until {:?}\n", data.sender_ID, until);
+        return;
+    }
+    let store = key_store();
+
+    match data.body {
+        // Interactive Mutual Auth for key Agreement (also used to resync diverged shared values) and
+        // NIZK Mut Auth for session keys, both run by the protocol engine
+        Body::IntCommitment(_) | Body::ResyncRequest(_) | Body::NizkMutAuthProof(_) => {
+            let mut engine = Engine::responder(store.clone(), MY_ID).with_key_confirmation(true);
+            let events = engine.handle_message(data);
+            match run_engine(&mut engine, &mut stream, events) {
+                // Shared secret key was saved
+                Ok(None) => println!("Server {} verified proof of Client {}, result: true\n", MY_ID, CLIENT_ID),
+                // Continue with encrypted records on the same connection
+                Ok(Some(s_keys)) => {
+                    println!("Server calculated session keys, initiator to responder key: {:?}\n", s_keys.initiator_to_responder);
+                    let mut channel = schnorr_nizk::SecureChannel::new(stream, &s_keys, false);
+                    match channel.receive() {
+                        Ok(record) => {
+                            println!("Got encrypted message from client: {}\n", String::from_utf8_lossy(&record));
+                            channel.send(b"Hello client, message received").expect("Failed to send record");
+                        },
+                        Err(e) => println!("Failed to receive record: {}\n", e),
+                    }
+                },
+                Err(e) => println!("Server {} verified proof of Client {}, result: {}\n", MY_ID, CLIENT_ID, e),
+            }
         },
 
         // NIZK Authentication
-        1 => {
+        Body::NizkProof { proof, message } => {
             // Verify proof
             println!("\nVerifying NIZK Proof of client: {}", CLIENT_ID);
-            let result = schnorr_nizk::verify_nizk_proof(MY_ID,
-                                                         CLIENT_ID,
-                                                         data.message.unwrap(),
-                                                         (data.value_1, data.value_2.unwrap(), data.value_3.unwrap()),
+            let message = String::from_utf8_lossy(&message).into_owned();
+            let result = schnorr_nizk::verify_nizk_proof(&*store,
+                                                         &MY_ID,
+                                                         &CLIENT_ID,
+                                                         message,
+                                                         proof,
                                                          true);
 
+            // Diverged shared values are no attack, the client has to resync
+            let result = match result {
+                Ok(result) => result,
+                Err(schnorr_nizk::NizkError::Desynchronized(id)) => {
+                    println!("\nShared secret key with client {} is out of sync, the client has to run resync\n", id);
+                    return;
+                },
+                Err(e) => {
+                    println!("\nFailed to verify proof: {}\n", e);
+                    false
+                }
+            };
+
             if !result {
                 println!("\nProof not accepted, Checking intrusion...");
-                let (asym, sym, dos) = schnorr_nizk::check_intrusion(CLIENT_ID);
+                let (asym, sym, dos) = schnorr_nizk::check_intrusion(&CLIENT_ID).expect("Failed to read intrusion data");
                 println!("Asymmetric keypair are compromised ?: {:?}", asym);
                 println!("Shared symmetric secret key is compromised ?: {:?}", sym);
                 println!("Dos attack is being conducted ?: {:?}\n", dos);
 
-                if dos {
-                    println!("Dos attack detected! Blocking client...");
-
-                    // Get client IP Address
-                    let client_ip = stream_copy_2.peer_addr().unwrap().ip();
-
-                    // Add client to Block map
-                    let mut block_map_guard = block_map.lock().unwrap();
-                    block_map_guard.entry(client_ip).or_insert(SystemTime::now());
-                    drop(block_map_guard);
-
-                    // Sleep thread to unblock client later
-                    println!("Client blocked!");
-                    thread::sleep(BLOCK_DURATION);
-                    println!("Block duration end... Unblocking client...");
-
-                    // Remove expired block record
-                    block_map_guard = block_map.lock().unwrap();
-                    block_map_guard.remove(&client_ip);
-                    println!("Client unblocked!\n");
+                // Rejections are also counted for the address, the accept loop refuses it while it is blocked
+                let client_ip = stream.peer_addr().unwrap().ip();
+                if let Decision::Block(until) = limiter.record_rejection(&Subject::Address(client_ip)) {
+                    println!("Dos attack detected! Client IP is blocked until {:?}", until);
                 }
-
             }
 
+            // The connection is closed when returning, which lets the speed test of the client stop its timer
             println!("Result of NIZK Proof of client {} is: {}\n", CLIENT_ID ,result);
         },
 
-        // Session Key (NIZK Mut Auth)
-        2 => {
-            // Verify proof and send own proof
-            println!("\nVerifying NIZK Mutual Auth of client: {}", CLIENT_ID);
-            let (mut nizk_ins, (commitment, challenge, response)) = schnorr_nizk::NIZKMutAuth::new(MY_ID, CLIENT_ID, Some((data.value_1, data.value_2.unwrap(), data.value_3.unwrap())));
-            let verify = nizk_ins.verify_proof();
-            println!("Server verified NIZK mutual auth proof of client, result = {:?}\n", verify);
-
-            // Send proof
-            let data_to_send = DataExchange {
-                auth_type: 2,
-                request_type: 0,
-                message: None,
-                value_1: commitment,
-                value_2: Some(challenge),
-                value_3: Some(response),
-            };
-            let json_string = serde_json::to_string(&data_to_send).unwrap();
-
-            // Send data
-            println!("Sending proof of server:");
-            stream_copy.write_all(json_string.as_bytes()).expect("write failed");
-            stream_copy.write_all(b"\n").expect("Failed to write to server");
-            println!("Proof sent!");
-
-            // Calculate session key
-            let s_key = nizk_ins.calculate_session_key();
-            println!("Server calculated session key as: {:?}\n", s_key);
-        }
+        // Announcement, verified with the public key of the client only
+        Body::PublicNizkProof { proof, message } => {
+            let mode = schnorr_nizk::NizkMode::PublicKey { context: ANNOUNCEMENT_CONTEXT };
+            let result = schnorr_nizk::verify_proof(&*store, mode, &MY_ID, &data.sender_ID, &message, proof).unwrap_or(false);
+            println!("Announcement of device {}: {:?}, accepted: {}\n", data.sender_ID, String::from_utf8_lossy(&message), result);
+        },
 
         // Generate new keys and Exchange Public Keys
-        11 => {
+        Body::PublicKey(client_key) => {
             let desc_pub = format!("PublicKey:{}", CLIENT_ID);
-            let mut client_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(data.value_1.to_vec())).unwrap();
-            client_key_pub.update_key_in_ring(data.value_1.to_vec());
+            store.put(&desc_pub, &client_key).expect("Failed to save client public key");
 
             // Generate own public private key pairs
             let (public_key, private_key) = schnorr_nizk::gen_random_key_pair();
@@ -186,67 +148,51 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
 
             let desc_priv = format!("PrivateKey:{}", MY_ID);
             let desc_pub = format!("PublicKey:{}", MY_ID);
-            let mut my_key_priv = schnorr_nizk::get_key_instance(&desc_priv, 32, Some(private_key.to_vec())).unwrap();
-            let mut my_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(public_key.to_vec())).unwrap();
-
-            // Update keys in Ring. This is important because if the key already exists
-            // then it will not be changed without this step
-            my_key_priv.update_key_in_ring(private_key.to_vec());
-            my_key_pub.update_key_in_ring(public_key.to_vec());
-
-            // Send public key to Server
-            // Prepare data to send
-            let data = DataExchange {
-                auth_type: 11,
-                request_type: 0,
-                message: None,
-                value_1: public_key,
-                value_2: None,
-                value_3: None,
-            };
 
-            // Convert data to String
-            let json_string = serde_json::to_string(&data).unwrap();
+            // Save keys in the key store. Old keys with the same description are replaced
+            store.put(&desc_priv, &private_key).expect("Failed to save private key");
+            store.put(&desc_pub, &public_key).expect("Failed to save public key");
 
-            // Send data
-            stream_copy.write_all(json_string.as_bytes()).expect("write failed");
-            stream_copy.write_all(b"\n").expect("Failed to write to server");
+            // Send public key to the client
+            Message::new(MY_ID, CLIENT_ID, Body::PublicKey(public_key)).write_to(&mut stream).expect("write failed");
             println!("Public key sent!");
         }
 
-        // For testing the speed of nizk proof. It has less checks and no prints and extra response
-        123 => {
-            // Verify proof
-            let result = schnorr_nizk::verify_nizk_proof(MY_ID,
-                                                         CLIENT_ID,
-                                                         data.message.unwrap(),
-                                                         (data.value_1, data.value_2.unwrap(), data.value_3.unwrap()),
-                                                         true);
-
-            // Send response
-            stream_copy.write_all("verified".as_bytes()).expect("write failed");
-            stream_copy.write_all(b"\n").expect("Failed to write to server");
-        },
-
         _ => {
-
+            println!("Unexpected first message from client\n");
         },
     }
 }
 
 // Main function of the TCP Server
 fn main() {
+    // Use the default state directory and key store, the directory must not be writable by other users
+    schnorr_nizk::config::install(schnorr_nizk::NizkConfig::default()).expect("Unsafe state directory");
+
+    // Record verifications, key changes and access decisions in a hash-chained log, authenticated with a key of the server
+    let audit_key = schnorr_nizk::audit::device_key(&*key_store(), &MY_ID).expect("Failed to load the audit key");
+    schnorr_nizk::audit::install(schnorr_nizk::AuditLog::new("audit.log").with_mac_key(audit_key));
+
+    // Revoke compromised keys of clients and log all intrusion events as JSON lines in the state directory
+    schnorr_nizk::intrusion::add_handler(Arc::new(schnorr_nizk::intrusion::RevokeSharedKey));
+    schnorr_nizk::intrusion::add_handler(Arc::new(schnorr_nizk::intrusion::QuarantinePublicKey));
+    schnorr_nizk::intrusion::add_handler(Arc::new(schnorr_nizk::intrusion::EventLog::new("intrusion_events.jsonl")));
+
     // Init intrusion data
-    schnorr_nizk::init_intrusion_counters(CLIENT_ID);
+    schnorr_nizk::init_intrusion_counters(&CLIENT_ID).expect("Failed to init intrusion data");
     println!("\nReset intrusion values since server is restarted!\n");
 
+    // Restore shared states that were half-written when the server stopped
+    let recovered = schnorr_nizk::shared_state::recover(&*key_store(), &MY_ID).expect("Failed to recover shared states");
+    println!("Shared states checked: {:?}\n", recovered);
+
     // Random port, just for the example
     // 192.168.0.21 for inside wlan and 127.0.0.1 for local computer
     // 192.168.0.196 My RP
     let listener = TcpListener::bind(SERVER_ADDRESS).expect("could not start server");
 
-    // Maintain a map of blocked client IP addresses and their block start times
-    let block_map: Arc<Mutex<HashMap<IpAddr, SystemTime>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Requests and rejected proofs of each client address
+    let limiter = Arc::new(RateLimiter::new(schnorr_nizk::config::current().rate_limit.clone()));
 
     // Accept incoming connections and get a TcpStream
     for connection in listener.incoming() {
@@ -254,28 +200,24 @@ fn main() {
             Ok(stream) => {
                 println!("Received a connection from Client IP: {:?}", stream.peer_addr().unwrap());
 
-                // Check if client is blocked from Doing and ignore them if true
-                let block_map_clone = block_map.clone();
-                let block_map_clone_2 = block_map.clone();
-                let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
-                let client_ip = stream_copy.peer_addr().unwrap().ip();
-
-                let mut block_map_guard = block_map_clone_2.lock().unwrap();
-                if let Some(block_start_time) = block_map_guard.get(&client_ip) {
-                    if block_start_time.elapsed().unwrap() < BLOCK_DURATION {
-                        println!("Client IP is blocked from making new connections, waiting until unlocked...");
-
-                        // Continue to next iteration of loop to ignore the current request
+                // Blocked and throttled clients are disconnected without reading their request
+                let client_ip = stream.peer_addr().unwrap().ip();
+                match limiter.check(&Subject::Address(client_ip)) {
+                    Decision::Allow => (),
+                    Decision::Throttle(retry_after) => {
+                        println!("Too many requests of client IP, retry after {:?}", retry_after);
                         continue;
-                    } else {
-                        // Remove expired block record
-                        block_map_guard.remove(&client_ip);
-                    }
+                    },
+                    Decision::Block(until) => {
+                        println!("Client IP is blocked until {:?}", until);
+                        continue;
+                    },
                 }
 
                 // Handle connexion
-                thread::spawn(|| {
-                    handle_connection(stream, block_map_clone);
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    handle_connection(stream, limiter);
                 });
             }
             Err(e) => {
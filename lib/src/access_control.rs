@@ -1,329 +1,766 @@
-use std::fs::{File, Permissions};
-use std::io::{BufReader, Write};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
+use crate::audit::{self, AccessChange, AuditEvent};
+use crate::config;
+use crate::conditions::{self, AccessContext, ConditionFailure, Conditions, GrantConditions, GrantSubject};
+use crate::roles::{self, Group, Permission, Roles};
+use crate::policy::{self, ActionPolicy, ConditionPolicy, GroupPolicy, PermissionPolicy, Policy, PolicyChange, ResourcePolicy};
+use crate::file_lock::{self, FileLock};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+
+// Access control of the resources.
+//
+// Each resource has a list of actions, each with the devices allowed on it and the devices and groups
+// denied on it. An action name is either an exact name, "*" for every action, or a prefix pattern ending
+// in "*", like "GET:/sensors/*" for every action starting with "GET:/sensors/". A resource can have a
+// parent resource, a rule of a parent also applies to all its children.
+//
+// A request of a device for an action of a resource is evaluated in this order:
+// 1. The resource and its parents are read, nearest first. A removed parent ends the chain.
+// 2. The actions of all these resources whose name matches the request are collected. Without one the
+//    resource has no such action and the access is refused.
+// 3. If one of the matching actions denies the device or one of its groups, the access is refused. A deny
+//    overrides every allow, however specific the allow is and on whichever level it is.
+// 4. The device is granted by each matching action that allows it, and by each permission of its roles
+//    whose resource is in the chain and whose action matches the request. Without a grant the access
+//    is refused.
+// 5. The access is allowed if the conditions of at least one grant are met, see conditions.
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ActionsControl {
     actionName: Vec<u8>,
-    allowedDevices: Vec<u32>,
+    allowedDevices: Vec<DeviceId>,
+    #[serde(default)]
+    deniedDevices: Vec<DeviceId>,
+    #[serde(default)]
+    deniedGroups: Vec<String>,
+}
+
+impl ActionsControl {
+    fn new(actionName: Vec<u8>) -> ActionsControl {
+        ActionsControl {
+            actionName,
+            allowedDevices: Vec::new(),
+            deniedDevices: Vec::new(),
+            deniedGroups: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AccessControl {
     resourceID: u32,
+    // Resource whose rules also apply to this one
+    #[serde(default)]
+    parent: Option<u32>,
     actions: Vec<ActionsControl>,
 }
 
-// File path of the data control detection data
-fn get_json_file_path(resourceID: u32) -> String {
-    format!(".nizk-auth/access_control/resource_{}.json", resourceID)
+// Whether an action name or pattern matches the requested action. "*" matches every action and a
+// pattern ending in "*" every action starting with the rest of the pattern
+pub fn action_matches(pattern: &[u8], actionName: &[u8]) -> bool {
+    match pattern.strip_suffix(b"*") {
+        Some(prefix) => actionName.starts_with(prefix),
+        None => pattern == actionName,
+    }
 }
 
-// Set the file permissions to 0o600, so that only the user can write to it
-fn shrink_file_permissions(path: String) {
-    let mut perms = Permissions::from_mode(0o600);
-    std::fs::set_permissions(&path, perms).expect("Failed to set file permissions");
+// File path of the data control detection data
+fn get_json_file_path(resourceID: u32) -> Result<PathBuf, NizkError> {
+    config::state_path(&format!("access_control/resource_{}.json", resourceID))
 }
 
 // Create all parent directories for the file
-fn create_parent_dirs(file_path: String) {
-    let path = Path::new(&file_path);
-
+fn create_parent_dirs(path: &Path) -> Result<(), NizkError> {
     // Create parent directories if they don't already exist
     if let Some(parent) = path.parent() {
         if !parent.exists() {
-            if let Err(err) = std::fs::create_dir_all(parent) {
-                eprintln!("Failed to create parent directories: {}", err);
-            }
+            std::fs::create_dir_all(parent)?;
         }
     }
+    Ok(())
 }
 
-// Save json data into a json file
-fn update_resource_data(resourceID: u32, data: String) {
-    // Create File with json content
-    let file_path = get_json_file_path(resourceID);
-    let path = Path::new(&file_path);
-    let mut file = File::create(&path).unwrap();
-    file.write_all(data.as_bytes());
+// Lock the data of a resource, so changes of other threads and processes are not lost
+fn lock_resource(resourceID: u32) -> Result<FileLock, NizkError> {
+    let file_path = get_json_file_path(resourceID)?;
+    create_parent_dirs(&file_path)?;
+    Ok(FileLock::acquire(&file_path)?)
+}
+
+// Save access control data into a json file. The caller holds the lock of the resource
+fn update_resource_data(resourceID: u32, access: &AccessControl) -> Result<(), NizkError> {
+    // Convert struct to a JSON
+    let json_string = serde_json::to_string(access)?;
+
+    // Replace the file with the json content, only the user can write to it
+    let file_path = get_json_file_path(resourceID)?;
+    file_lock::write_atomic(&file_path, json_string.as_bytes())?;
+    Ok(())
+}
+
+// Record a saved change in the audit log
+fn record_change(resourceID: u32, change: AccessChange) -> Result<(), NizkError> {
+    audit::record(AuditEvent::AccessControlChanged { resource: resourceID, change });
+    Ok(())
 }
 
 // Create a new Resource
-pub fn add_resource(resourceID: u32, actions: Option<Vec<Vec<u8>>>) -> u8 {
+pub fn add_resource(resourceID: u32, actions: Option<Vec<Vec<u8>>>) -> Result<(), NizkError> {
     // Get resource file path
-    let file_path = get_json_file_path(resourceID);
+    let _lock = lock_resource(resourceID)?;
+    let file_path = get_json_file_path(resourceID)?;
     let path = Path::new(&file_path);
 
     // Check if resource already exists
     if path.exists() {
         println!("ResourceID: {:?} already exists, try to delete it first!\n", resourceID);
-        return 1;
+        return Err(NizkError::ResourceAlreadyExists(resourceID));
     }
 
-    // Save Data in AccessControl struct
-    let access = match actions {
-        // Check if user included actions or not
-        Some(all_actions) => {
-            // User added actions list, convert it into ActionsStruct
-            let mut actions_vec: Vec<ActionsControl> = Vec::new();
-            for actionName in all_actions {
-                let action = ActionsControl {
-                    actionName,
-                    allowedDevices: Vec::new(),
-                };
-                actions_vec.push(action);
-            }
-
-            // Generate AccessControl Struct
-            AccessControl {
-                resourceID,
-                actions: actions_vec,
-            }
-        },
-
-        // User did not include actions -> Create empty AccessControl struct
-        None => {
-            AccessControl {
-                resourceID,
-                actions: Vec::new(),
-            }
-        }
+    // Convert the actions list of the user into ActionsControl structs.
+    // If the user did not include actions, create an empty AccessControl struct
+    let actions_vec: Vec<ActionsControl> = actions
+        .unwrap_or_default()
+        .into_iter()
+        .map(ActionsControl::new)
+        .collect();
+
+    // Generate AccessControl Struct
+    let access = AccessControl {
+        resourceID,
+        parent: None,
+        actions: actions_vec,
     };
 
-    // Convert struct to a JSON
-    let json_string = serde_json::to_string(&access).unwrap();
-
-    // Create parent directories if they does not exist
-    let file_path = get_json_file_path(resourceID);
-    create_parent_dirs(file_path);
-
     // Create File with json content
-    update_resource_data(resourceID, json_string);
-
-    // Shrink file permissions
-    let file_path = get_json_file_path(resourceID);
-    shrink_file_permissions(file_path);
-
-    // Return success
-    return 0;
+    update_resource_data(resourceID, &access)?;
+    let actions = access.actions.iter().map(|action| audit::action_name(&action.actionName)).collect();
+    record_change(resourceID, AccessChange::AddResource { actions })
 }
 
 // Delete a resource from resources list
-pub fn remove_resource(resourceID: u32) -> u8 {
+pub fn remove_resource(resourceID: u32) -> Result<(), NizkError> {
     // Check if resource already exists
-    let file_path = get_json_file_path(resourceID);
+    let _lock = lock_resource(resourceID)?;
+    let file_path = get_json_file_path(resourceID)?;
     let path = Path::new(&file_path);
-    if path.exists() {
-        // Path exists, delete path
-        let file_path = get_json_file_path(resourceID);
-        match fs::remove_file(file_path) {
-            Ok(_) => {
-                return 0;
-            },
-            Err(e) => {
-                // Return exit code 2 since file could not be deleted
-                println!("Error deleting resource: {}", e);
-                return 2;
-            },
-        }
-    }else {
-        // Return 1 because resource does not exist
-        return 1;
+    if !path.exists() {
+        return Err(NizkError::ResourceNotFound(resourceID));
     }
+
+    // Path exists, delete path
+    fs::remove_file(path)?;
+    record_change(resourceID, AccessChange::RemoveResource)
 }
 
 // Read data from a saved json file
-fn read_access_data(resourceID: u32) -> AccessControl {
+fn read_access_data(resourceID: u32) -> Result<AccessControl, NizkError> {
     // Open file and read content as AccessControl struct
-    let file_path = get_json_file_path(resourceID);
-    let path = Path::new(&file_path);
-    let file = File::open(&path).unwrap();
+    let file_path = get_json_file_path(resourceID)?;
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(NizkError::ResourceNotFound(resourceID));
+        },
+        Err(e) => {
+            return Err(NizkError::Io(e));
+        }
+    };
     let reader = BufReader::new(file);
-    let mut access: AccessControl = serde_json::from_reader(reader).unwrap();
-    access
+    let access: AccessControl = serde_json::from_reader(reader)?;
+    Ok(access)
 }
 
 // Add a new allowed action to a certain resource
-pub fn add_action_to_resource(resourceID: u32, actionName: Vec<u8>) -> u8 {
+pub fn add_action_to_resource(resourceID: u32, actionName: Vec<u8>) -> Result<(), NizkError> {
     // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
 
     // Go through all action in the access control data and find if action already exists
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Action exists
-            println!("Action already exists for resource {:?}. Please delete it first to continue.\n", resourceID);
-            return 1;
-        }
+    if accessData.actions.iter().any(|action| action.actionName == actionName) {
+        println!("Action already exists for resource {:?}. Please delete it first to continue.\n", resourceID);
+        return Err(NizkError::ActionAlreadyExists);
     }
 
     // Create a struct for the action name
-    let action = ActionsControl {
-        actionName,
-        allowedDevices: Vec::new(),
-    };
+    let change = AccessChange::AddAction { action: audit::action_name(&actionName) };
+    let action = ActionsControl::new(actionName);
 
-    // Append the new action to the actions list
+    // Append the new action to the actions list and write it to file
     accessData.actions.push(action);
-
-    // Convert to String and write it to file
-    let json_string = serde_json::to_string(&accessData).unwrap();
-    update_resource_data(resourceID, json_string);
-
-    return 0;
+    update_resource_data(resourceID, &accessData)?;
+    record_change(resourceID, change)
 }
 
 // Remove an action from a resource
-pub fn remove_action_from_resource(resourceID: u32, actionName: Vec<u8>) -> u8 {
+pub fn remove_action_from_resource(resourceID: u32, actionName: Vec<u8>) -> Result<(), NizkError> {
     // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
 
     // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Remove action and break from loop
+    match accessData.actions.iter().position(|action| action.actionName == actionName) {
+        Some(index) => {
+            // Remove action and write it to file
             accessData.actions.remove(index);
-
-            // Convert to String and write it to file
-            let json_string = serde_json::to_string(&accessData).unwrap();
-            update_resource_data(resourceID, json_string);
-
-            // Return success
-            return 0;
-        }
+            update_resource_data(resourceID, &accessData)?;
+            record_change(resourceID, AccessChange::RemoveAction { action: audit::action_name(&actionName) })
+        },
+        // Action not removed because it does not exist
+        None => Err(NizkError::ActionNotFound),
     }
-
-    // Action not removed because it does not exist
-    return 1;
 }
 
 // Add a device to an action of a resource
-pub fn add_device_to_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: u32) -> u8 {
+pub fn add_device_to_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: &DeviceId) -> Result<(), NizkError> {
     // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
 
     // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
+    match accessData.actions.iter_mut().find(|action| action.actionName == actionName) {
+        Some(action) => {
             // Add user to the allowed users for this action
-            accessData.actions[index].allowedDevices.push(deviceID);
+            if !action.allowedDevices.contains(deviceID) {
+                action.allowedDevices.push(deviceID.clone());
+            }
+
+            // Write it to file
+            update_resource_data(resourceID, &accessData)?;
+            record_change(resourceID, AccessChange::AddDevice { action: audit::action_name(&actionName), device: deviceID.clone() })
+        },
+        // Action not found
+        None => Err(NizkError::ActionNotFound),
+    }
+}
 
-            // Convert to String and write it to file
-            let json_string = serde_json::to_string(&accessData).unwrap();
-            update_resource_data(resourceID, json_string);
+// Add a device to all actions of a resource ID
+pub fn add_device_to_all_actions(resourceID: u32, deviceID: &DeviceId) -> Result<(), NizkError> {
+    // Read access control data for the provided resource ID
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
 
-            // Return success
-            return 0;
+    // Go through all action in the access control data and add the device if missing
+    for action in accessData.actions.iter_mut() {
+        if !action.allowedDevices.contains(deviceID) {
+            action.allowedDevices.push(deviceID.clone());
         }
     }
 
-    // Action not found, return 1.
-    return 1;
+    // Write it to file
+    update_resource_data(resourceID, &accessData)?;
+    record_change(resourceID, AccessChange::AddDeviceToAllActions { device: deviceID.clone() })
 }
 
-// Add a device to all actions of a resource ID
-pub fn add_device_to_all_actions(resourceID: u32, deviceID: u32) -> u8 {
+pub fn remove_device_from_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: &DeviceId) -> Result<(), NizkError> {
     // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-    let mut accessDataCopy = read_access_data(resourceID);
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
 
     // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        let mut id_exists = false;
-        let mut data_copy = &action.allowedDevices;
-        for (user_index, userID) in data_copy.iter().enumerate() {
-            if *userID == deviceID {
-                id_exists = true;
-            }
+    let action = match accessData.actions.iter_mut().find(|action| action.actionName == actionName) {
+        Some(action) => action,
+        None => return Err(NizkError::ActionNotFound),
+    };
+
+    // Remove user from the allowed users for this action
+    match action.allowedDevices.iter().position(|userID| userID == deviceID) {
+        Some(user_index) => {
+            action.allowedDevices.remove(user_index);
+            update_resource_data(resourceID, &accessData)?;
+            record_change(resourceID, AccessChange::RemoveDevice { action: audit::action_name(&actionName), device: deviceID.clone() })
+        },
+        // User already not allowed to use that resource
+        None => Err(NizkError::DeviceNotFound(deviceID.clone())),
+    }
+}
+
+pub fn remove_device_from_all_actions(resourceID: u32, deviceID: &DeviceId) -> Result<(), NizkError> {
+    // Read access control data for the provided resource ID
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
+
+    // Remove user from the allowed users of every action
+    for action in accessData.actions.iter_mut() {
+        action.allowedDevices.retain(|userID| userID != deviceID);
+    }
+
+    // Write it to file
+    update_resource_data(resourceID, &accessData)?;
+    record_change(resourceID, AccessChange::RemoveDeviceFromAllActions { device: deviceID.clone() })
+}
+
+// Change an action of a resource while holding the lock of the resource, then record the change
+fn modify_action(resourceID: u32, actionName: &[u8], change: AccessChange, f: impl FnOnce(&mut ActionsControl) -> Result<(), NizkError>) -> Result<(), NizkError> {
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
+    let action = accessData.actions.iter_mut()
+        .find(|action| action.actionName == actionName)
+        .ok_or(NizkError::ActionNotFound)?;
+    f(action)?;
+    update_resource_data(resourceID, &accessData)?;
+    record_change(resourceID, change)
+}
+
+// Deny a device an action of a resource, also if it is allowed directly, through a role or on a parent
+pub fn deny_device_on_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: &DeviceId) -> Result<(), NizkError> {
+    let change = AccessChange::DenyDevice { action: audit::action_name(&actionName), device: deviceID.clone() };
+    modify_action(resourceID, &actionName, change, |action| {
+        if !action.deniedDevices.contains(deviceID) {
+            action.deniedDevices.push(deviceID.clone());
         }
-        if !id_exists {
-            accessDataCopy.actions[index].allowedDevices.push(deviceID);
+        Ok(())
+    })
+}
+
+pub fn remove_device_deny_from_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: &DeviceId) -> Result<(), NizkError> {
+    let change = AccessChange::RemoveDeviceDeny { action: audit::action_name(&actionName), device: deviceID.clone() };
+    modify_action(resourceID, &actionName, change, |action| {
+        match action.deniedDevices.iter().position(|device| device == deviceID) {
+            Some(index) => {
+                action.deniedDevices.remove(index);
+                Ok(())
+            },
+            None => Err(NizkError::DeviceNotFound(deviceID.clone())),
+        }
+    })
+}
+
+// Deny all devices of a group an action of a resource. The group does not need to exist yet
+pub fn deny_group_on_resource_action(resourceID: u32, actionName: Vec<u8>, group: &str) -> Result<(), NizkError> {
+    let change = AccessChange::DenyGroup { action: audit::action_name(&actionName), group: group.to_string() };
+    modify_action(resourceID, &actionName, change, |action| {
+        if !action.deniedGroups.iter().any(|name| name == group) {
+            action.deniedGroups.push(group.to_string());
+        }
+        Ok(())
+    })
+}
+
+pub fn remove_group_deny_from_resource_action(resourceID: u32, actionName: Vec<u8>, group: &str) -> Result<(), NizkError> {
+    let change = AccessChange::RemoveGroupDeny { action: audit::action_name(&actionName), group: group.to_string() };
+    modify_action(resourceID, &actionName, change, |action| {
+        match action.deniedGroups.iter().position(|name| name == group) {
+            Some(index) => {
+                action.deniedGroups.remove(index);
+                Ok(())
+            },
+            None => Err(NizkError::GroupNotFound(group.to_string())),
+        }
+    })
+}
+
+// Make a resource the child of another one, or a root resource with None. The parent must exist and
+// must not be the resource itself or one of its children
+pub fn set_resource_parent(resourceID: u32, parent: Option<u32>) -> Result<(), NizkError> {
+    let _lock = lock_resource(resourceID)?;
+    let mut accessData = read_access_data(resourceID)?;
+    if let Some(parentID) = parent {
+        let chain = resource_chain(parentID)?;
+        if chain.iter().any(|resource| resource.resourceID == resourceID) {
+            return Err(NizkError::InvalidResourceParent(parentID));
         }
     }
 
-    // Convert to String and write it to file
-    let json_string = serde_json::to_string(&accessDataCopy).unwrap();
-    update_resource_data(resourceID, json_string);
+    accessData.parent = parent;
+    update_resource_data(resourceID, &accessData)?;
+    record_change(resourceID, AccessChange::SetParent { parent })
+}
 
-    // Return 0 for success
-    return 0;
+pub fn get_resource_parent(resourceID: u32) -> Result<Option<u32>, NizkError> {
+    Ok(read_access_data(resourceID)?.parent)
 }
 
-pub fn remove_device_from_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: u32) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+// The resource and its parents, nearest first. The chain ends at a removed parent, and at a parent that
+// is already in it if the files were changed by hand
+fn resource_chain(resourceID: u32) -> Result<Vec<AccessControl>, NizkError> {
+    let mut chain = vec![read_access_data(resourceID)?];
+    while let Some(parentID) = chain.last().and_then(|resource| resource.parent) {
+        if chain.iter().any(|resource| resource.resourceID == parentID) {
+            break;
+        }
+        match read_access_data(parentID) {
+            Ok(parent) => chain.push(parent),
+            Err(NizkError::ResourceNotFound(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(chain)
+}
 
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Remove user from the allowed users for this action
-            for (user_index, userID) in action.allowedDevices.iter().enumerate() {
-                if *userID == deviceID {
-                    accessData.actions[index].allowedDevices.remove(user_index);
-
-                    // Convert to String and write it to file
-                    let json_string = serde_json::to_string(&accessData).unwrap();
-                    update_resource_data(resourceID, json_string);
-
-                    // Return success
-                    return 0;
-                }
-            }
-            // User already not allowed to use that resource
-            return 2;
+// Why a device may use an action. The permission is the action, or action pattern, of the resource or
+// parent resource the grant was given on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Grant {
+    // The device is allowed on the action
+    Direct { permission: Permission },
+    // A role of a group of the device has the permission
+    Role { group: String, role: String, permission: Permission },
+}
+
+impl Grant {
+    pub fn permission(&self) -> &Permission {
+        match self {
+            Grant::Direct { permission } | Grant::Role { permission, .. } => permission,
         }
     }
+}
 
-    // Action not found, return 1
-    return 1;
+// Why a device may not use an action. The permission is the action, or action pattern, of the resource
+// or parent resource the deny was given on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Deny {
+    // The device is denied on the action
+    Device { permission: Permission },
+    // A group of the device is denied on the action
+    Group { group: String, permission: Permission },
 }
 
-pub fn remove_device_from_all_actions(resourceID: u32, deviceID: u32) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-    let mut accessDataCopy = read_access_data(resourceID);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessExplanation {
+    // All grants of the device for the action whose conditions are met
+    Granted(Vec<Grant>),
+    // The device has grants for the action, but the request does not meet their conditions
+    Refused(Vec<(Grant, ConditionFailure)>),
+    // The device or one of its groups is denied, whatever it is granted
+    Denied(Vec<Deny>),
+    // The resource has no such action
+    ActionNotFound,
+    // Neither the device nor one of its roles is allowed
+    NoGrant,
+}
 
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        // Remove user from the allowed users for this action
-        for (user_index, userID) in action.allowedDevices.iter().enumerate() {
-            if *userID == deviceID {
-                accessDataCopy.actions[index].allowedDevices.remove(user_index);
-                break;
+impl AccessExplanation {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, AccessExplanation::Granted(_))
+    }
+
+    // The device was refused only because its grants expired or were used up
+    pub fn is_expired(&self) -> bool {
+        match self {
+            AccessExplanation::Refused(refusals) => refusals.iter().all(|(_, failure)| failure.is_expired()),
+            _ => false,
+        }
+    }
+}
+
+// Action of a resource that a device may use. Grants are listed with their conditions, None for
+// unconditional ones, whether the conditions are met depends on the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectivePermission {
+    pub permission: Permission,
+    pub grants: Vec<(Grant, Option<Conditions>)>,
+}
+
+// Rules of the resource chain for a requested action, before conditions are checked
+enum Rules {
+    ActionNotFound,
+    Denied(Vec<Deny>),
+    Granted(Vec<Grant>),
+}
+
+// Apply the deny and allow rules of the resource and its parents to the request of the device
+fn match_rules(chain: &[AccessControl], actionName: &[u8], deviceID: &DeviceId, roles: &Roles) -> Rules {
+    let actions: Vec<(u32, &ActionsControl)> = chain.iter()
+        .flat_map(|resource| resource.actions.iter().map(move |action| (resource.resourceID, action)))
+        .filter(|(_, action)| action_matches(&action.actionName, actionName))
+        .collect();
+    if actions.is_empty() {
+        return Rules::ActionNotFound;
+    }
+
+    // A deny of any matching action overrides all grants
+    let groups: Vec<&str> = roles.groups.iter()
+        .filter(|(_, group)| group.devices.contains(deviceID))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let mut denies = Vec::new();
+    for (resourceID, action) in &actions {
+        let permission = Permission::new(*resourceID, &action.actionName);
+        if action.deniedDevices.contains(deviceID) {
+            denies.push(Deny::Device { permission: permission.clone() });
+        }
+        for group in action.deniedGroups.iter().filter(|group| groups.contains(&group.as_str())) {
+            denies.push(Deny::Group { group: group.clone(), permission: permission.clone() });
+        }
+    }
+    if !denies.is_empty() {
+        return Rules::Denied(denies);
+    }
+
+    let mut grants: Vec<Grant> = actions.iter()
+        .filter(|(_, action)| action.allowedDevices.contains(deviceID))
+        .map(|(resourceID, action)| Grant::Direct { permission: Permission::new(*resourceID, &action.actionName) })
+        .collect();
+    for (group, role, permissions) in roles.roles_of(deviceID) {
+        let matching = permissions.iter().filter(|permission| {
+            chain.iter().any(|resource| resource.resourceID == permission.resource) && action_matches(&permission.action, actionName)
+        });
+        for permission in matching {
+            grants.push(Grant::Role { group: group.to_string(), role: role.to_string(), permission: permission.clone() });
+        }
+    }
+    Rules::Granted(grants)
+}
+
+// Explain whether a device may use an action of a resource now, for requests without attributes
+pub fn explain_access(resourceID: u32, actionName: &[u8], deviceID: &DeviceId) -> Result<AccessExplanation, NizkError> {
+    explain_access_with(resourceID, actionName, deviceID, &AccessContext::default())
+}
+
+// Explain whether a device may use an action of a resource for the request, through the devices of the
+// actions, through roles and through the parents of the resource, and whether the conditions of the
+// grants are met. The rules are evaluated in the order given at the top of this file
+pub fn explain_access_with(resourceID: u32, actionName: &[u8], deviceID: &DeviceId, context: &AccessContext) -> Result<AccessExplanation, NizkError> {
+    // Read access control data for the provided resource ID and its parents
+    let chain = resource_chain(resourceID)?;
+
+    // If no action matches, the device is not allowed
+    let grants = match match_rules(&chain, actionName, deviceID, &roles::load()?) {
+        Rules::ActionNotFound => return Ok(AccessExplanation::ActionNotFound),
+        Rules::Denied(denies) => return Ok(AccessExplanation::Denied(denies)),
+        Rules::Granted(grants) => grants,
+    };
+    if grants.is_empty() {
+        return Ok(AccessExplanation::NoGrant);
+    }
+
+    // Keep the grants whose conditions are met
+    let conditions = conditions::load()?;
+    let (valid, refused): (Vec<_>, Vec<_>) = grants.into_iter()
+        .map(|grant| {
+            let result = conditions.evaluate(&grant, deviceID, context);
+            (grant, result)
+        })
+        .partition(|(_, result)| result.is_ok());
+    if valid.is_empty() {
+        let refused = refused.into_iter().filter_map(|(grant, result)| result.err().map(|failure| (grant, failure))).collect();
+        Ok(AccessExplanation::Refused(refused))
+    } else {
+        Ok(AccessExplanation::Granted(valid.into_iter().map(|(grant, _)| grant).collect()))
+    }
+}
+
+// All actions of all resources the device may use, ordered by resource and action. Actions that are
+// patterns are listed as patterns, and the actions of all parents of a resource are listed for the
+// resource as well, also those the resource itself does not have. Denied actions are left out
+pub fn effective_permissions(deviceID: &DeviceId) -> Result<Vec<EffectivePermission>, NizkError> {
+    let roles = roles::load()?;
+    let conditions = conditions::load()?;
+    let mut permissions = Vec::new();
+    for resourceID in resource_ids()? {
+        let chain = match resource_chain(resourceID) {
+            Ok(chain) => chain,
+            // Removed in the meantime
+            Err(NizkError::ResourceNotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        // The actions of the parents apply to the resource as well, like in check_access_with
+        let mut actions: Vec<&[u8]> = Vec::new();
+        for action in chain.iter().flat_map(|resource| &resource.actions) {
+            if !actions.contains(&action.actionName.as_slice()) {
+                actions.push(&action.actionName);
+            }
+        }
+        for actionName in actions {
+            let permission = Permission::new(resourceID, actionName);
+            let Rules::Granted(grants) = match_rules(&chain, actionName, deviceID, &roles) else {
+                continue;
+            };
+            let grants: Vec<_> = grants.into_iter()
+                .map(|grant| {
+                    let grant_conditions = grant_conditions(&conditions, &grant, deviceID);
+                    (grant, grant_conditions)
+                })
+                .collect();
+            if !grants.is_empty() {
+                permissions.push(EffectivePermission { permission, grants });
             }
         }
     }
+    permissions.sort_by(|a, b| a.permission.cmp(&b.permission));
+    Ok(permissions)
+}
+
+fn grant_conditions(conditions: &GrantConditions, grant: &Grant, deviceID: &DeviceId) -> Option<Conditions> {
+    conditions.get(&GrantSubject::of(grant, deviceID), grant.permission())
+}
 
-    // Convert to String and write it to file
-    let json_string = serde_json::to_string(&accessDataCopy).unwrap();
-    update_resource_data(resourceID, json_string);
+// IDs of all resources, in ascending order
+pub fn resource_ids() -> Result<Vec<u32>, NizkError> {
+    let dir = config::state_path("access_control")?;
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(NizkError::Io(e)),
+    };
 
-    // Return 0 for success
-    return 0;
+    let mut ids = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let id = name.to_str()
+            .and_then(|name| name.strip_prefix("resource_"))
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|id| id.parse().ok());
+        if let Some(id) = id {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
 }
 
-// Check if a device has access to an action for a certain resource
-pub fn check_access(resourceID: u32, actionName: Vec<u8>, deviceID: u32) -> bool {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+// Check if a device has access to an action for a certain resource now, for a request without attributes
+pub fn check_access(resourceID: u32, actionName: Vec<u8>, deviceID: &DeviceId) -> Result<bool, NizkError> {
+    check_access_with(resourceID, actionName, deviceID, &AccessContext::default())
+}
 
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Action found. Check if user is allowed to use this action
-            for (user_index, userID) in action.allowedDevices.iter().enumerate(){
-                if *userID == deviceID {
-                    return true;
-                }
+// Check if a device has access to an action for a certain resource for the request. A use of a grant with
+// a use limit is counted
+pub fn check_access_with(resourceID: u32, actionName: Vec<u8>, deviceID: &DeviceId, context: &AccessContext) -> Result<bool, NizkError> {
+    let allowed = match explain_access_with(resourceID, &actionName, deviceID, context)? {
+        AccessExplanation::Granted(grants) => conditions::use_grant(&grants, deviceID, context)?,
+        _ => false,
+    };
+
+    audit::record(AuditEvent::AccessChecked {
+        resource: resourceID,
+        action: audit::action_name(&actionName),
+        device: deviceID.clone(),
+        allowed,
+    });
+    Ok(allowed)
+}
+
+// Names of the actions of a resource, in the order they were added
+pub fn list_actions(resourceID: u32) -> Result<Vec<Vec<u8>>, NizkError> {
+    Ok(read_access_data(resourceID)?.actions.into_iter().map(|action| action.actionName).collect())
+}
+
+// Devices allowed directly on an action of a resource
+pub fn list_allowed_devices(resourceID: u32, actionName: &[u8]) -> Result<Vec<DeviceId>, NizkError> {
+    read_access_data(resourceID)?.actions.into_iter()
+        .find(|action| action.actionName == actionName)
+        .map(|action| action.allowedDevices)
+        .ok_or(NizkError::ActionNotFound)
+}
+
+// All devices allowed or denied on any action of a resource, in ascending order
+pub fn list_devices(resourceID: u32) -> Result<Vec<DeviceId>, NizkError> {
+    let mut devices: Vec<DeviceId> = read_access_data(resourceID)?.actions.into_iter()
+        .flat_map(|action| action.allowedDevices.into_iter().chain(action.deniedDevices))
+        .collect();
+    devices.sort();
+    devices.dedup();
+    Ok(devices)
+}
+
+fn permission_policy(permission: &Permission) -> PermissionPolicy {
+    PermissionPolicy { resource: permission.resource, action: policy::action_name(&permission.action) }
+}
+
+// The whole access control as a policy document: all resources, roles, groups and conditions
+pub fn export_policy() -> Result<Policy, NizkError> {
+    let mut policy = Policy::default();
+    for resourceID in resource_ids()? {
+        let accessData = match read_access_data(resourceID) {
+            Ok(accessData) => accessData,
+            // Removed in the meantime
+            Err(NizkError::ResourceNotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let actions = accessData.actions.into_iter()
+            .map(|action| ActionPolicy {
+                name: policy::action_name(&action.actionName),
+                allow: action.allowedDevices,
+                deny_devices: action.deniedDevices,
+                deny_groups: action.deniedGroups,
+            })
+            .collect();
+        policy.resources.push(ResourcePolicy { id: resourceID, parent: accessData.parent, actions });
+    }
+
+    let roles = roles::load()?;
+    for (role, permissions) in &roles.roles {
+        let permissions = permissions.iter().map(permission_policy).collect();
+        policy.roles.insert(role.clone(), permissions);
+    }
+    for (name, group) in roles.groups {
+        policy.groups.insert(name, GroupPolicy { devices: group.devices, roles: group.roles });
+    }
+    for (subject, permission, conditions) in conditions::export()? {
+        let PermissionPolicy { resource, action } = permission_policy(&permission);
+        policy.conditions.push(ConditionPolicy { subject, resource, action, conditions });
+    }
+    Ok(policy)
+}
+
+// Replace the whole access control by a policy document and return the changes. The policy is
+// validated first, a dry run only returns the changes. Uses of grants whose conditions stay the same
+// are kept. Resources, roles and conditions are replaced one after the other, a concurrent check may
+// see a mix of the old and the new policy
+pub fn import_policy(policy: &Policy, dry_run: bool) -> Result<Vec<PolicyChange>, NizkError> {
+    policy.validate()?;
+    let changes = export_policy()?.diff(policy);
+    if dry_run || changes.is_empty() {
+        return Ok(changes);
+    }
+
+    for resource in &policy.resources {
+        let _lock = lock_resource(resource.id)?;
+        let access = AccessControl {
+            resourceID: resource.id,
+            parent: resource.parent,
+            actions: resource.actions.iter()
+                .map(|action| Ok(ActionsControl {
+                    actionName: policy::action_bytes(&action.name)?,
+                    allowedDevices: action.allow.clone(),
+                    deniedDevices: action.deny_devices.clone(),
+                    deniedGroups: action.deny_groups.clone(),
+                }))
+                .collect::<Result<_, NizkError>>()?,
+        };
+        update_resource_data(resource.id, &access)?;
+    }
+    for resourceID in resource_ids()? {
+        if !policy.resources.iter().any(|resource| resource.id == resourceID) {
+            let _lock = lock_resource(resourceID)?;
+            match fs::remove_file(get_json_file_path(resourceID)?) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(NizkError::Io(e)),
+                _ => {},
             }
-            // User not allowed to use that resource
-            return false;
         }
     }
 
-    // Action does not exist
-    return false;
+    let roles = Roles {
+        roles: policy.roles.iter()
+            .map(|(role, permissions)| {
+                let mut permissions: Vec<Permission> = permissions.iter()
+                    .map(|permission| Ok(Permission::new(permission.resource, &policy::action_bytes(&permission.action)?)))
+                    .collect::<Result<_, NizkError>>()?;
+                permissions.sort();
+                permissions.dedup();
+                Ok((role.clone(), permissions))
+            })
+            .collect::<Result<_, NizkError>>()?,
+        groups: policy.groups.iter()
+            .map(|(name, group)| (name.clone(), Group { devices: group.devices.clone(), roles: group.roles.clone() }))
+            .collect(),
+    };
+    roles::replace(&roles)?;
+    conditions::replace(policy.conditions.iter()
+        .map(|condition| {
+            let permission = Permission::new(condition.resource, &policy::action_bytes(&condition.action)?);
+            Ok((condition.subject.clone(), permission, condition.conditions.clone()))
+        })
+        .collect::<Result<_, NizkError>>()?)?;
+
+    audit::record(AuditEvent::PolicyImported { changes: changes.iter().map(|change| change.to_string()).collect() });
+    Ok(changes)
 }
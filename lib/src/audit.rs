@@ -0,0 +1,384 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::SystemTime;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use crate::{config, rate_limit};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::conditions::GrantSubject;
+use crate::intrusion::IntrusionKind;
+use crate::roles::RoleChange;
+use crate::file_lock::{self, FileLock};
+use crate::schnorr_identification::{self, KMAC_AUDIT_LOG};
+use crate::secret_management::{KeyStore, SecretKeyErrors};
+
+// Append-only audit log of proof verifications, key ratchets, established sessions, access control
+// changes, access decisions and failed intrusion handlers.
+//
+// Each entry is one JSON line
+//   {"seq":1,"time":...,"prev":"<hash of entry 0>","event":{...},"hash":"<hash>","mac":"<tag>"}
+// The hash is the SHA3-256 of the line up to the hash field, so every entry covers the hash of the one
+// before it. With a MAC key, each hash is also authenticated with KMAC256. The last entry is repeated in
+// "<log>.head" together with the length of the log, so a log whose last entries were cut off does not
+// verify either. Without a MAC key anyone with write access can rebuild the whole chain, the last hash
+// returned by verify has to be kept somewhere else then.
+//
+// Nothing is written until a log is installed. Writing an entry holds the file lock of the log, so
+// several processes can share one log. Bytes after the length in the head were left by an interrupted
+// append and are cut off by the next one. A log without its head is not appended to.
+
+// Protocol step that verified a proof
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProofKind {
+    // NIZK proof with the shared key, also in a batch
+    SharedKey,
+    // NIZK proof with the public key only
+    PublicKey,
+    // Mutual authentication for session keys
+    MutualAuth,
+    // Interactive Schnorr identification
+    Interactive,
+    // Interactive mutual authentication for a new shared key
+    KeyAgreement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProofOutcome {
+    Accepted,
+    Rejected,
+    // Valid Schnorr proof with an unknown MAC tag
+    Desynchronized,
+    // The proof could not be checked, for example because a key is missing
+    Failed,
+}
+
+impl ProofOutcome {
+    pub(crate) fn of(result: &Result<bool, NizkError>) -> ProofOutcome {
+        match result {
+            Ok(true) => ProofOutcome::Accepted,
+            Ok(false) => ProofOutcome::Rejected,
+            Err(e) => ProofOutcome::of_error(e),
+        }
+    }
+
+    pub(crate) fn of_error(error: &NizkError) -> ProofOutcome {
+        match error {
+            NizkError::ProofRejected | NizkError::ReplayedCommitment => ProofOutcome::Rejected,
+            NizkError::Desynchronized(_) => ProofOutcome::Desynchronized,
+            _ => ProofOutcome::Failed,
+        }
+    }
+}
+
+// Change of the access control data of a resource. Action names are shown as text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum AccessChange {
+    AddResource { actions: Vec<String> },
+    RemoveResource,
+    AddAction { action: String },
+    RemoveAction { action: String },
+    AddDevice { action: String, device: DeviceId },
+    AddDeviceToAllActions { device: DeviceId },
+    RemoveDevice { action: String, device: DeviceId },
+    RemoveDeviceFromAllActions { device: DeviceId },
+    SetConditions { action: String, subject: GrantSubject },
+    ClearConditions { action: String, subject: GrantSubject },
+    DenyDevice { action: String, device: DeviceId },
+    RemoveDeviceDeny { action: String, device: DeviceId },
+    DenyGroup { action: String, group: String },
+    RemoveGroupDeny { action: String, group: String },
+    SetParent { parent: Option<u32> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEvent {
+    // A proof of the peer was verified by the device
+    ProofVerified { kind: ProofKind, device: DeviceId, peer: DeviceId, outcome: ProofOutcome },
+    // A new shared key was agreed on
+    KeyAgreed { device: DeviceId, peer: DeviceId, epoch: u32 },
+    // The shared key was ratcheted forward to the counter
+    KeyRatcheted { device: DeviceId, peer: DeviceId, counter: u32, steps: u32 },
+    // Session keys were derived after a mutual authentication
+    SessionEstablished { device: DeviceId, peer: DeviceId },
+    AccessControlChanged { resource: u32, #[serde(flatten)] change: AccessChange },
+    RolesChanged { #[serde(flatten)] change: RoleChange },
+    AccessChecked { resource: u32, action: String, device: DeviceId, allowed: bool },
+    // A policy replaced the access control, with the changes it made
+    PolicyImported { changes: Vec<String> },
+    // An intrusion handler failed to act on an event
+    IntrusionHandlerFailed { kind: IntrusionKind, device: DeviceId, peer: DeviceId, error: String },
+}
+
+pub(crate) fn action_name(action: &[u8]) -> String {
+    String::from_utf8_lossy(action).into_owned()
+}
+
+// Audit log file and the key its entries are authenticated with
+#[derive(Clone)]
+pub struct AuditLog {
+    // A relative path is taken below the state directory
+    pub path: PathBuf,
+    mac_key: Option<[u8; 32]>,
+}
+
+// The key is not shown
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog").field("path", &self.path).field("mac", &self.mac_key.is_some()).finish()
+    }
+}
+
+// Result of a successful verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditSummary {
+    pub entries: u64,
+    // Hash of the last entry, all zero for an empty log
+    pub last_hash: [u8; 32],
+}
+
+#[derive(Serialize)]
+struct EntryBody<'a> {
+    seq: u64,
+    time: i64,
+    prev: String,
+    event: &'a AuditEvent,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    seq: u64,
+    prev: String,
+    // Only parsed to check that it is a known event
+    #[serde(rename = "event")]
+    _event: AuditEvent,
+    hash: String,
+    mac: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Head {
+    seq: u64,
+    hash: String,
+    // Length of the log up to the end of the entry
+    length: u64,
+    mac: Option<String>,
+}
+
+const HASH_FIELD: &str = ",\"hash\":\"";
+
+fn tampered(entry: u64, reason: &'static str) -> NizkError {
+    NizkError::AuditLogTampered { entry, reason }
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".head");
+    PathBuf::from(name)
+}
+
+fn entry_mac(key: &[u8; 32], hash: &[u8; 32]) -> String {
+    hex::encode(schnorr_identification::kmac_256(*key, KMAC_AUDIT_LOG, hash, None, None))
+}
+
+fn head_mac(key: &[u8; 32], hash: &[u8; 32], seq: u64, length: u64) -> String {
+    hex::encode(schnorr_identification::kmac_256(*key, KMAC_AUDIT_LOG, hash, Some(&seq.to_be_bytes()), Some(&length.to_be_bytes())))
+}
+
+fn read_head(path: &Path) -> Result<Option<Head>, NizkError> {
+    match fs::read(head_path(path)) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(NizkError::Io(e)),
+    }
+}
+
+fn decode_hash(hex_hash: &str, entry: u64) -> Result<[u8; 32], NizkError> {
+    hex::decode(hex_hash).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(tampered(entry, "hash is not valid"))
+}
+
+impl AuditLog {
+    pub fn new<P: Into<PathBuf>>(path: P) -> AuditLog {
+        AuditLog { path: path.into(), mac_key: None }
+    }
+
+    // Authenticate every entry with the key, see device_key
+    pub fn with_mac_key(mut self, key: [u8; 32]) -> AuditLog {
+        self.mac_key = Some(key);
+        self
+    }
+
+    fn resolved_path(&self) -> Result<PathBuf, NizkError> {
+        let config = config::current();
+        config.prepare_state_dir()?;
+        Ok(config.state_dir.join(&self.path))
+    }
+
+    // Append an entry for the event and wait until it is on the disk
+    pub fn append(&self, event: &AuditEvent) -> Result<(), NizkError> {
+        let path = self.resolved_path()?;
+        let _lock = FileLock::acquire(&path)?;
+        let head = read_head(&path)?;
+        let (seq, prev, length) = match &head {
+            Some(head) => (head.seq + 1, head.hash.clone(), head.length),
+            None => (1, hex::encode([0u8; 32]), 0),
+        };
+
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).mode(0o600).open(&path)?;
+        let file_length = file.metadata()?.len();
+        if file_length < length {
+            return Err(tampered(seq - 1, "audit log is shorter than its head"));
+        }
+        // Without a head only an empty log can be started, the entries of a log whose head was removed are kept
+        if head.is_none() && file_length > 0 {
+            return Err(tampered(0, "head of the log is missing"));
+        }
+        file.set_len(length)?;
+
+        // The hash covers the line up to the hash field
+        let body = serde_json::to_string(&EntryBody { seq, time: rate_limit::to_millis(SystemTime::now()), prev, event })?;
+        let mut line = body[..body.len() - 1].to_string();
+        let hash = schnorr_identification::sha3_256(line.as_bytes(), None, None, None);
+        line.push_str(&format!("{}{}\"", HASH_FIELD, hex::encode(hash)));
+        if let Some(key) = &self.mac_key {
+            line.push_str(&format!(",\"mac\":\"{}\"", entry_mac(key, &hash)));
+        }
+        line.push_str("}\n");
+
+        file.seek(SeekFrom::Start(length))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        let length = length + line.len() as u64;
+        let head = Head {
+            seq,
+            hash: hex::encode(hash),
+            length,
+            mac: self.mac_key.as_ref().map(|key| head_mac(key, &hash, seq, length)),
+        };
+        file_lock::write_atomic(&head_path(&path), serde_json::to_string(&head)?.as_bytes())?;
+        Ok(())
+    }
+
+    // Check the whole chain of the log, and the MAC tags if the log has a key
+    pub fn verify(&self) -> Result<AuditSummary, NizkError> {
+        let path = self.resolved_path()?;
+        let _lock = FileLock::acquire(&path)?;
+        verify_file(&path, self.mac_key.as_ref())
+    }
+}
+
+// Check the audit log at the path. Entries without a MAC tag are rejected if a key is given
+pub fn verify_file(path: &Path, mac_key: Option<&[u8; 32]>) -> Result<AuditSummary, NizkError> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(NizkError::Io(e)),
+    };
+    let head = read_head(path)?;
+
+    let mut summary = AuditSummary { entries: 0, last_hash: [0; 32] };
+    let mut length = 0;
+    for line in content.split_inclusive(|byte| *byte == b'\n') {
+        let seq = summary.entries + 1;
+        if head.as_ref().is_some_and(|head| length >= head.length) {
+            return Err(tampered(seq, "entries after the head of the log"));
+        }
+        let text = std::str::from_utf8(line).map_err(|_| tampered(seq, "entry is not text"))?;
+        let text = text.strip_suffix('\n').ok_or(tampered(seq, "entry is incomplete"))?;
+        let entry: Entry = serde_json::from_str(text).map_err(|_| tampered(seq, "entry is not valid"))?;
+
+        if entry.seq != seq {
+            return Err(tampered(seq, "entry is missing or out of order"));
+        }
+        if decode_hash(&entry.prev, seq)? != summary.last_hash {
+            return Err(tampered(seq, "entry does not follow the previous one"));
+        }
+        let position = text.rfind(HASH_FIELD).ok_or(tampered(seq, "entry has no hash"))?;
+        let hash = schnorr_identification::sha3_256(&text.as_bytes()[..position], None, None, None);
+        if decode_hash(&entry.hash, seq)? != hash {
+            return Err(tampered(seq, "entry was changed"));
+        }
+        if let Some(key) = mac_key {
+            if entry.mac.as_deref() != Some(entry_mac(key, &hash).as_str()) {
+                return Err(tampered(seq, "MAC tag is not valid"));
+            }
+        }
+
+        summary.entries = seq;
+        summary.last_hash = hash;
+        length += line.len() as u64;
+    }
+
+    // The head names the last entry, entries cut off at the end are found here
+    match head {
+        None if summary.entries > 0 => Err(tampered(summary.entries, "head of the log is missing")),
+        None => Ok(summary),
+        Some(head) => {
+            if head.seq != summary.entries || head.length != length || decode_hash(&head.hash, head.seq)? != summary.last_hash {
+                return Err(tampered(summary.entries, "log does not end with the entry of its head"));
+            }
+            if let Some(key) = mac_key {
+                if head.mac.as_deref() != Some(head_mac(key, &summary.last_hash, head.seq, head.length).as_str()) {
+                    return Err(tampered(head.seq, "MAC tag of the head is not valid"));
+                }
+            }
+            Ok(summary)
+        },
+    }
+}
+
+// Key of the device for the MAC tags of its audit log, created on first use as "AuditKey:<id>"
+pub fn device_key(store: &dyn KeyStore, my_ID: &DeviceId) -> Result<[u8; 32], NizkError> {
+    let description = format!("AuditKey:{}", my_ID);
+    match crate::get_32byte_key(store, description.clone()) {
+        Err(NizkError::KeyRing(SecretKeyErrors::KeyNotFound(_))) => {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            store.put(&description, &key)?;
+            Ok(key)
+        },
+        result => result,
+    }
+}
+
+// Installed audit log, None until one is installed
+static AUDIT_LOG: RwLock<Option<Arc<AuditLog>>> = RwLock::new(None);
+
+// Record all following events in the log
+pub fn install(log: AuditLog) {
+    *AUDIT_LOG.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(log));
+}
+
+pub fn uninstall() {
+    *AUDIT_LOG.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+// Record the event if a log is installed. Events are recorded once the change or decision is saved, which
+// can not be undone anymore, so an event that can not be recorded does not fail the operation
+pub(crate) fn record(event: AuditEvent) {
+    let log = AUDIT_LOG.read().unwrap_or_else(PoisonError::into_inner).clone();
+    if let Some(log) = log {
+        let _ = log.append(&event);
+    }
+}
+
+// Record the outcome of a proof verification and pass its result on
+pub(crate) fn record_proof(kind: ProofKind, device: &DeviceId, peer: &DeviceId, result: Result<bool, NizkError>) -> Result<bool, NizkError> {
+    record(AuditEvent::ProofVerified {
+        kind,
+        device: device.clone(),
+        peer: peer.clone(),
+        outcome: ProofOutcome::of(&result),
+    });
+    result
+}
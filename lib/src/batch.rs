@@ -0,0 +1,202 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::scalar::Scalar;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::file_lock::FileLock;
+use crate::schnorr_identification::{self, Transcript};
+use crate::secret_management::KeyStore;
+use crate::audit::{self, ProofKind};
+use crate::{config, intrusion, get_32byte_key, ratchet_shared_key, shared_state, update_used_values, NizkProof};
+
+// Batch verification of NIZK proofs in shared key mode.
+//
+// The Schnorr equations of all proofs are verified together with a single multiscalar multiplication.
+// If the batch fails, every proof is checked on its own to find the invalid ones. The KMAC challenges
+// are then checked in the order of the batch, so several proofs of the same sender can be verified at
+// once. Without update_keys every proof is checked against the saved shared values, so each result is
+// the same as the one of verify_nizk_proof. Rejected proofs are counted and reported to the intrusion
+// handlers like in verify_nizk_proof.
+
+// One proof to verify
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchItem {
+    pub sender_ID: DeviceId,
+    pub message: Vec<u8>,
+    pub proof: NizkProof,
+}
+
+// Shared values of a sender while going through the batch
+struct SenderState {
+    public_key: EdwardsPoint,
+    // Saved values, or the ones of the last accepted proof
+    key: [u8; 32],
+    counter: u32,
+    // Ratchet steps from the saved values to key and counter
+    steps: u32,
+    // A proof with key and counter was accepted, the next one has to use later values
+    used: bool,
+}
+
+// Decoded values of one proof of the batch
+struct Decoded {
+    index: usize,
+    commitment: EdwardsPoint,
+    challenge: Scalar,
+    response: Scalar,
+}
+
+// Verify many proofs and return the result of each one, in the same order as the items
+pub fn verify_nizk_proof_batch(store: &dyn KeyStore, my_ID: &DeviceId, items: &[BatchItem], update_keys: bool) -> Vec<Result<bool, NizkError>> {
+    let mut results: Vec<Result<bool, NizkError>> = items.iter().map(|_| Ok(false)).collect();
+    let mut senders: HashMap<DeviceId, SenderState> = HashMap::new();
+    let mut decoded: Vec<Decoded> = Vec::with_capacity(items.len());
+
+    // Lock the shared values of all senders until they are saved, in a fixed order so batches can not deadlock.
+    // A sender whose lock failed is tried again with each of its proofs, which then fail with the error
+    let mut locks: HashMap<DeviceId, FileLock> = HashMap::new();
+    if update_keys {
+        let sender_IDs: BTreeSet<&DeviceId> = items.iter().map(|item| &item.sender_ID).collect();
+        for sender_ID in sender_IDs {
+            if let Ok(lock) = shared_state::lock(my_ID, sender_ID) {
+                locks.insert(sender_ID.clone(), lock);
+            }
+        }
+    }
+
+    // Load keys of all senders and decode all proofs
+    for (index, item) in items.iter().enumerate() {
+        if update_keys && !locks.contains_key(&item.sender_ID) {
+            match shared_state::lock(my_ID, &item.sender_ID) {
+                Ok(lock) => locks.insert(item.sender_ID.clone(), lock),
+                Err(e) => {
+                    results[index] = Err(e);
+                    continue;
+                },
+            };
+        }
+        match decode(store, my_ID, item, &mut senders) {
+            Ok(commitment) => {
+                let (_, challenge, response) = item.proof;
+                decoded.push(Decoded {
+                    index,
+                    commitment,
+                    challenge: Scalar::from_bytes_mod_order(challenge),
+                    response: Scalar::from_bytes_mod_order(response),
+                });
+            },
+            Err(e) => results[index] = Err(e),
+        }
+    }
+
+    // Verify all Schnorr equations together. Points with a torsion component are always checked alone
+    let public_key = |proof: &Decoded| senders[&items[proof.index].sender_ID].public_key;
+    let torsion_free = |proof: &Decoded| public_key(proof).is_torsion_free() && proof.commitment.is_torsion_free();
+    let batch: Vec<(EdwardsPoint, EdwardsPoint, Scalar, Scalar)> = decoded.iter()
+        .filter(|proof| torsion_free(proof))
+        .map(|proof| (public_key(proof), proof.commitment, proof.challenge, proof.response))
+        .collect();
+    let batch_valid = schnorr_identification::verify_schnorr_batch(&batch);
+
+    // Fall back to the check of single proofs to find the invalid ones
+    let schnorr: Vec<bool> = decoded.iter()
+        .map(|proof| {
+            (batch_valid && torsion_free(proof))
+                || proof.response * ED25519_BASEPOINT_POINT == proof.commitment + proof.challenge * public_key(proof)
+        })
+        .collect();
+
+    // Check the MAC Tags in the order of the batch. Only accepted proofs move the shared counter forward,
+    // and only if the keys are updated
+    for (proof, schnorr) in decoded.iter().zip(schnorr) {
+        let item = &items[proof.index];
+        let sender = senders.get_mut(&item.sender_ID).expect("sender was loaded");
+        results[proof.index] = match find_challenge(my_ID, item, sender, schnorr && update_keys) {
+            Ok(true) if schnorr => Ok(true),
+            // Valid Schnorr proof with an unknown MAC Tag, counted like in verify_nizk_proof
+            Ok(false) if schnorr => intrusion::report_rejection(store, my_ID, &item.sender_ID, true, false)
+                .and(Err(NizkError::Desynchronized(item.sender_ID.clone()))),
+            Ok(mac) => intrusion::report_rejection(store, my_ID, &item.sender_ID, false, mac).map(|_| false),
+            Err(e) => Err(e),
+        };
+    }
+
+    // Save the shared values after the last accepted proof of each sender
+    if update_keys {
+        for (sender_ID, sender) in &senders {
+            if !sender.used {
+                continue;
+            }
+            // The values of the last accepted proof are used as well
+            if let Err(e) = update_used_values(store, my_ID, sender_ID, sender.steps + 1) {
+                // Report the error on the last accepted proof of the sender
+                let last = (0..items.len()).rev()
+                    .find(|index| &items[*index].sender_ID == sender_ID && matches!(results[*index], Ok(true)));
+                if let Some(index) = last {
+                    results[index] = Err(e);
+                }
+            }
+        }
+    }
+
+    // Record the outcome of every proof
+    results.into_iter()
+        .zip(items)
+        .map(|(result, item)| audit::record_proof(ProofKind::SharedKey, my_ID, &item.sender_ID, result))
+        .collect()
+}
+
+// Load public key and shared values of the sender once and decode the commitment
+fn decode(store: &dyn KeyStore, my_ID: &DeviceId, item: &BatchItem, senders: &mut HashMap<DeviceId, SenderState>) -> Result<EdwardsPoint, NizkError> {
+    if let Entry::Vacant(entry) = senders.entry(item.sender_ID.clone()) {
+        let public_key = get_32byte_key(store, format!("PublicKey:{}", item.sender_ID))?;
+        let public_key = schnorr_identification::bytes_to_edwards(&public_key)?;
+        let state = shared_state::load(store, my_ID, &item.sender_ID)?;
+        entry.insert(SenderState {
+            public_key,
+            key: state.key,
+            counter: state.counter,
+            steps: 0,
+            used: false,
+        });
+    }
+
+    let (commitment, _, _) = item.proof;
+    schnorr_identification::bytes_to_edwards(&commitment)
+}
+
+// Look for the MAC Tag from the first unused shared values of the sender up to the look-ahead window.
+// If advance is set, the next proof of the sender has to use values after the found ones.
+// The values are only ratcheted when the next ones are checked, so a proof with the last counter is found
+fn find_challenge(my_ID: &DeviceId, item: &BatchItem, sender: &mut SenderState, advance: bool) -> Result<bool, NizkError> {
+    let (commitment, challenge, _) = item.proof;
+    let mut transcript = Transcript {
+        customization: schnorr_identification::KMAC_NIZK_PROOF,
+        sender_ID: &item.sender_ID,
+        recipient_ID: my_ID,
+        shared_counter: sender.counter.to_be_bytes(),
+        message: Some(&item.message),
+    };
+
+    let (mut key, mut counter, mut steps) = (sender.key, sender.counter, sender.steps);
+    for step in 0..=config::current().lookahead_window {
+        if step > 0 || sender.used {
+            (key, counter) = ratchet_shared_key(key, counter)?;
+            steps += 1;
+        }
+        transcript.shared_counter = counter.to_be_bytes();
+        if schnorr_identification::verify_challenge(key, commitment, challenge, &transcript) {
+            if advance {
+                sender.key = key;
+                sender.counter = counter;
+                sender.steps = steps;
+                sender.used = true;
+            }
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
@@ -0,0 +1,171 @@
+use std::io::{Read, Write};
+use chacha20poly1305::{ChaCha20Poly1305, Key as AeadKey, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use crate::error::NizkError;
+use crate::schnorr_identification::{self, KMAC_CHANNEL_REKEY};
+use crate::session::SessionKeys;
+
+// Encrypted and authenticated channel over any stream, using the keys of a NIZK mutual authentication.
+//
+// Every record is encrypted with ChaCha20Poly1305. A record is
+//   version (1 byte) | key epoch (4 bytes) | sequence number (8 bytes) | length (2 bytes) | ciphertext
+// The header is the associated data and the nonce is the sequence number, so every direction has its
+// own key and nonces. Records have to arrive in order, replayed or reordered records are rejected.
+// After a number of records both devices ratchet the key of the direction to a new epoch.
+// After any error the channel should be closed, because the stream may be in the middle of a record.
+
+// Version of the record layout
+const RECORD_VERSION: u8 = 1;
+
+// Size of the record header in bytes
+const RECORD_HEADER_SIZE: usize = 15;
+
+// Size of the authentication tag
+const TAG_SIZE: usize = 16;
+
+// Biggest plaintext of one record
+pub const MAX_RECORD_SIZE: usize = u16::MAX as usize - TAG_SIZE;
+
+// Default number of records before the key of a direction is renewed
+pub const DEFAULT_REKEY_INTERVAL: u64 = 1 << 20;
+
+// Key and counters of one direction
+struct Direction {
+    key: [u8; 32],
+    epoch: u32,
+    sequence: u64,
+    records_with_key: u64,
+}
+
+impl Direction {
+    fn new(key: [u8; 32]) -> Direction {
+        Direction {
+            key,
+            epoch: 0,
+            sequence: 0,
+            records_with_key: 0,
+        }
+    }
+
+    // Count a record and ratchet the key when the interval is reached
+    fn next_record(&mut self, rekey_interval: u64) -> Result<(), NizkError> {
+        self.sequence = self.sequence.checked_add(1).ok_or(NizkError::CounterExhausted)?;
+        self.records_with_key += 1;
+        if self.records_with_key >= rekey_interval {
+            self.epoch = self.epoch.checked_add(1).ok_or(NizkError::CounterExhausted)?;
+            self.key = schnorr_identification::kmac_256(self.key, KMAC_CHANNEL_REKEY, &self.epoch.to_be_bytes(), None, None);
+            self.records_with_key = 0;
+        }
+        Ok(())
+    }
+
+    fn header(&self, length: usize) -> [u8; RECORD_HEADER_SIZE] {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[0] = RECORD_VERSION;
+        header[1..5].copy_from_slice(&self.epoch.to_be_bytes());
+        header[5..13].copy_from_slice(&self.sequence.to_be_bytes());
+        header[13..15].copy_from_slice(&(length as u16).to_be_bytes());
+        header
+    }
+
+    fn nonce(&self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.sequence.to_be_bytes());
+        Nonce::from(nonce)
+    }
+}
+
+// Secure channel, wrapping the stream to the other device
+pub struct SecureChannel<S: Read + Write> {
+    stream: S,
+    send: Direction,
+    receive: Direction,
+    rekey_interval: u64,
+}
+
+impl<S: Read + Write> SecureChannel<S> {
+    // Create the channel after the mutual authentication. The initiator sends with the initiator to responder key
+    pub fn new(stream: S, keys: &SessionKeys, initiator: bool) -> SecureChannel<S> {
+        let (send_key, receive_key) = if initiator {
+            (keys.initiator_to_responder, keys.responder_to_initiator)
+        } else {
+            (keys.responder_to_initiator, keys.initiator_to_responder)
+        };
+
+        SecureChannel {
+            stream,
+            send: Direction::new(send_key),
+            receive: Direction::new(receive_key),
+            rekey_interval: DEFAULT_REKEY_INTERVAL,
+        }
+    }
+
+    // Change the number of records before a key is renewed. Both devices have to use the same value
+    pub fn with_rekey_interval(mut self, records: u64) -> SecureChannel<S> {
+        self.rekey_interval = records.max(1);
+        self
+    }
+
+    // Encrypt and send one record
+    pub fn send(&mut self, data: &[u8]) -> Result<(), NizkError> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(NizkError::InvalidMessage("record is too large"));
+        }
+
+        // Encrypt the data with the header as associated data
+        let header = self.send.header(data.len() + TAG_SIZE);
+        let cipher = ChaCha20Poly1305::new(&AeadKey::from(self.send.key));
+        let ciphertext = cipher.encrypt(&self.send.nonce(), Payload { msg: data, aad: &header })
+            .map_err(|_| NizkError::InvalidMessage("record can not be encrypted"))?;
+
+        // Write header and ciphertext together
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + ciphertext.len());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(&ciphertext);
+        self.stream.write_all(&record)?;
+        self.stream.flush()?;
+
+        self.send.next_record(self.rekey_interval)
+    }
+
+    // Receive and decrypt the next record
+    pub fn receive(&mut self) -> Result<Vec<u8>, NizkError> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.stream.read_exact(&mut header)?;
+
+        // Check the header before reading the ciphertext
+        if header[0] != RECORD_VERSION {
+            return Err(NizkError::UnsupportedVersion(header[0]));
+        }
+        let sequence = u64::from_be_bytes(header[5..13].try_into().expect("header has a fixed size"));
+        if sequence != self.receive.sequence {
+            return Err(NizkError::RecordOutOfOrder { expected: self.receive.sequence, found: sequence });
+        }
+        let length = u16::from_be_bytes([header[13], header[14]]) as usize;
+        if length < TAG_SIZE {
+            return Err(NizkError::InvalidMessage("record is shorter than the authentication tag"));
+        }
+
+        let mut ciphertext = vec![0u8; length];
+        self.stream.read_exact(&mut ciphertext)?;
+
+        // The expected header is the associated data, so decryption fails for a wrong epoch or length as well
+        let expected_header = self.receive.header(length);
+        let cipher = ChaCha20Poly1305::new(&AeadKey::from(self.receive.key));
+        let plaintext = cipher.decrypt(&self.receive.nonce(), Payload { msg: &ciphertext, aad: &expected_header })
+            .map_err(|_| NizkError::RecordRejected)?;
+
+        self.receive.next_record(self.rekey_interval)?;
+        Ok(plaintext)
+    }
+
+    // Access the wrapped stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    // Return the wrapped stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use crate::config;
+use crate::file_lock::{self, FileLock};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+
+// Used commitments of the interactive authentication, to detect replay attacks.
+//
+// The commitments of each sender are kept in a hash set in memory, so a lookup does not depend on the
+// number of used commitments. The set is persisted as an append-only log in the state directory:
+//   magic (8 bytes) | record | record | ...
+// with the records
+//   0x01 | commitment (32 bytes)    commitment used in the current epoch
+//   0x02 | epoch (4 bytes)          a new shared key was agreed on, following commitments belong to this epoch
+// A half-written record at the end of the log is cut off when the log is loaded.
+//
+// If NizkConfig::commitment_epochs is set, commitments older than that number of epochs are dropped when
+// a new epoch starts. The epoch of the log only grows, also when the shared key was revoked and its epochs
+// start at 1 again, so the log does not grow forever. The log is rewritten without the dropped
+// commitments and epoch markers once it has more dead records than live commitments.
+// The text files "mut_comm_<id>.txt" of older versions are moved into the log when it is first opened.
+//
+// Every operation holds the lock of the sender in this process and the file lock of the log, so a
+// sender waiting for the disk does not hold up the others. Before the commitment is checked, records that other
+// processes appended are read, and the log is loaded again if another process compacted it.
+
+const LOG_MAGIC: &[u8; 8] = b"NIZKCL01";
+const RECORD_COMMITMENT: u8 = 0x01;
+const RECORD_EPOCH: u8 = 0x02;
+
+// Logs with less records are never compacted
+const COMPACTION_MIN_RECORDS: usize = 1024;
+
+// Used commitments of one sender
+struct CommitmentLog {
+    file: File,
+    path: PathBuf,
+    epoch: u32,
+    // Epoch in which each commitment was used
+    used: HashMap<[u8; 32], u32>,
+    // Number of records in the log file
+    records: usize,
+    // Bytes of the log file that were read
+    length: u64,
+}
+
+// Log of one sender with its own lock, None until it is opened
+type SenderLog = Arc<Mutex<Option<CommitmentLog>>>;
+
+// Logs by file path, so a changed state directory opens new logs
+static LOGS: LazyLock<Mutex<HashMap<PathBuf, SenderLog>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn get_log_file_path(senderID: &DeviceId) -> Result<PathBuf, NizkError> {
+    config::state_path(&format!("commitments_{}.log", senderID.file_name()))
+}
+
+// File of older versions with one hex encoded commitment per line
+fn get_legacy_file_path(senderID: &DeviceId) -> Result<PathBuf, NizkError> {
+    config::state_path(&format!("mut_comm_{}.txt", senderID.file_name()))
+}
+
+fn invalid_log(reason: &str) -> NizkError {
+    NizkError::Io(io::Error::new(ErrorKind::InvalidData, reason.to_string()))
+}
+
+// Run f with the log of the sender, opening it first if needed
+fn with_log<T>(senderID: &DeviceId, f: impl FnOnce(&mut CommitmentLog) -> Result<T, NizkError>) -> Result<T, NizkError> {
+    let path = get_log_file_path(senderID)?;
+
+    // The map is only locked to find the log, reading and writing happens under the lock of the sender
+    let sender_log = LOGS.lock().unwrap_or_else(PoisonError::into_inner).entry(path.clone()).or_default().clone();
+    let mut log = sender_log.lock().unwrap_or_else(PoisonError::into_inner);
+    let _lock = FileLock::acquire(&path)?;
+
+    match log.as_mut() {
+        Some(log) => log.refresh()?,
+        None => *log = Some(CommitmentLog::open(&path, &get_legacy_file_path(senderID)?)?),
+    }
+    f(log.as_mut().expect("log was opened"))
+}
+
+// Save the commitment as used. Returns false if it was already used before
+pub fn check_and_insert(senderID: &DeviceId, commitment: [u8; 32]) -> Result<bool, NizkError> {
+    with_log(senderID, |log| {
+        if log.used.contains_key(&commitment) {
+            return Ok(false);
+        }
+        log.append(RECORD_COMMITMENT, &commitment)?;
+        log.used.insert(commitment, log.epoch);
+        Ok(true)
+    })
+}
+
+// A new shared key was agreed on with the sender. Old commitments expire if configured.
+// An epoch that is not newer than the one of the log, e.g. after a revoke, starts the next epoch of the log
+pub fn start_epoch(senderID: &DeviceId, epoch: u32) -> Result<(), NizkError> {
+    with_log(senderID, |log| {
+        let epoch = epoch.max(log.epoch.saturating_add(1));
+        log.append(RECORD_EPOCH, &epoch.to_be_bytes())?;
+        log.epoch = epoch;
+
+        if let Some(epochs) = config::current().commitment_epochs {
+            log.used.retain(|_, used_epoch| epoch - *used_epoch < epochs.max(1));
+        }
+        if log.records >= COMPACTION_MIN_RECORDS && log.records > 2 * log.used.len() {
+            log.compact()?;
+        }
+        Ok(())
+    })
+}
+
+// Rewrite the log of the sender with only the live commitments
+pub fn compact(senderID: &DeviceId) -> Result<(), NizkError> {
+    with_log(senderID, |log| log.compact())
+}
+
+impl CommitmentLog {
+    fn open(path: &Path, legacy_path: &Path) -> Result<CommitmentLog, NizkError> {
+        let mut log = match OpenOptions::new().read(true).append(true).open(path) {
+            // An empty file is left by a crash while the log was created, it has no magic yet
+            Ok(file) if file.metadata()?.len() == 0 => CommitmentLog::create(path, 0, &HashMap::new())?,
+            Ok(file) => {
+                let mut log = CommitmentLog::empty(path, file);
+                log.read_new_records()?;
+                log
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => CommitmentLog::create(path, 0, &HashMap::new())?,
+            Err(e) => return Err(NizkError::Io(e)),
+        };
+
+        // Move the commitments of older versions into the log, the text file is only deleted once they are saved
+        if legacy_path.exists() {
+            for line in BufReader::new(File::open(legacy_path)?).lines() {
+                let commitment = hex::decode(line?.trim()).ok().and_then(|bytes| bytes.try_into().ok());
+                if let Some(commitment) = commitment {
+                    log.used.entry(commitment).or_insert(log.epoch);
+                }
+            }
+            log.compact()?;
+            fs::remove_file(legacy_path)?;
+        }
+        Ok(log)
+    }
+
+    fn empty(path: &Path, file: File) -> CommitmentLog {
+        CommitmentLog {
+            file,
+            path: path.to_path_buf(),
+            epoch: 0,
+            used: HashMap::new(),
+            records: 0,
+            length: 0,
+        }
+    }
+
+    // Catch up with changes of other processes. A compacted log is a new file and is read from the start
+    fn refresh(&mut self) -> Result<(), NizkError> {
+        if fs::metadata(&self.path)?.ino() != self.file.metadata()?.ino() {
+            let file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+            *self = CommitmentLog::empty(&self.path, file);
+        }
+        self.read_new_records()
+    }
+
+    // Read the records after the known length, a half-written record at the end is cut off
+    fn read_new_records(&mut self) -> Result<(), NizkError> {
+        let mut bytes = Vec::new();
+        self.file.seek(SeekFrom::Start(self.length))?;
+        self.file.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let mut position = 0;
+        if self.length == 0 {
+            if bytes.len() < LOG_MAGIC.len() || &bytes[..LOG_MAGIC.len()] != LOG_MAGIC {
+                return Err(invalid_log("commitment log has an unknown format"));
+            }
+            position = LOG_MAGIC.len();
+        }
+
+        while position < bytes.len() {
+            let length = match bytes[position] {
+                RECORD_COMMITMENT => 32,
+                RECORD_EPOCH => 4,
+                _ => return Err(invalid_log("commitment log contains an unknown record")),
+            };
+            let Some(value) = bytes.get(position + 1..position + 1 + length) else {
+                break;
+            };
+
+            if bytes[position] == RECORD_COMMITMENT {
+                self.used.insert(value.try_into().expect("record has a fixed size"), self.epoch);
+            } else {
+                self.epoch = u32::from_be_bytes(value.try_into().expect("record has a fixed size"));
+            }
+            self.records += 1;
+            position += 1 + length;
+        }
+
+        self.length += position as u64;
+        self.file.set_len(self.length)?;
+        Ok(())
+    }
+
+    // Write a new log with the given commitments and move it into place
+    fn create(path: &Path, epoch: u32, used: &HashMap<[u8; 32], u32>) -> Result<CommitmentLog, NizkError> {
+        let mut bytes = LOG_MAGIC.to_vec();
+        let mut records = 0;
+
+        // Commitments are written ordered by their epoch, so each one gets its epoch back when loaded
+        let mut entries: Vec<_> = used.iter().collect();
+        entries.sort_by_key(|(_, used_epoch)| **used_epoch);
+        let mut written_epoch = 0;
+        for (commitment, used_epoch) in entries {
+            if *used_epoch != written_epoch {
+                bytes.push(RECORD_EPOCH);
+                bytes.extend_from_slice(&used_epoch.to_be_bytes());
+                written_epoch = *used_epoch;
+                records += 1;
+            }
+            bytes.push(RECORD_COMMITMENT);
+            bytes.extend_from_slice(commitment);
+            records += 1;
+        }
+        if epoch != written_epoch {
+            bytes.push(RECORD_EPOCH);
+            bytes.extend_from_slice(&epoch.to_be_bytes());
+            records += 1;
+        }
+        file_lock::write_atomic(path, &bytes)?;
+
+        Ok(CommitmentLog {
+            file: OpenOptions::new().read(true).append(true).open(path)?,
+            path: path.to_path_buf(),
+            epoch,
+            used: used.clone(),
+            records,
+            length: bytes.len() as u64,
+        })
+    }
+
+    // Append a record and wait until it is on the disk, a used commitment must not be lost in a crash
+    fn append(&mut self, kind: u8, value: &[u8]) -> Result<(), NizkError> {
+        let mut record = Vec::with_capacity(1 + value.len());
+        record.push(kind);
+        record.extend_from_slice(value);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        self.length += record.len() as u64;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), NizkError> {
+        *self = CommitmentLog::create(&self.path, self.epoch, &self.used)?;
+        Ok(())
+    }
+}
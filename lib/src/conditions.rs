@@ -0,0 +1,493 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::access_control::Grant;
+use crate::audit::{self, AccessChange, AuditEvent};
+use crate::{config, rate_limit};
+use crate::file_lock::{self, FileLock};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::roles::Permission;
+
+// Conditions of grants of the access control.
+//
+// A grant, a device allowed on an action or a permission of a role, can be limited by conditions: a
+// validity period, time-of-day windows, a maximum number of uses, and conditions on the request that the
+// caller passes in an AccessContext. A grant without conditions is always valid.
+// A grant whose conditions are not met does not allow the access, access_control reports the failed
+// condition, so an expired grant is told apart from a missing one. Conditions belong to the action, or
+// action pattern, of the resource the grant was given on, so they also limit the grant on child
+// resources.
+//
+// The conditions and the uses of each grant are saved in "access_control/conditions.json".
+
+// Device or role a grant was given to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantSubject {
+    Device(DeviceId),
+    Role(String),
+}
+
+impl GrantSubject {
+    pub(crate) fn of(grant: &Grant, deviceID: &DeviceId) -> GrantSubject {
+        match grant {
+            Grant::Direct { .. } => GrantSubject::Device(deviceID.clone()),
+            Grant::Role { role, .. } => GrantSubject::Role(role.clone()),
+        }
+    }
+}
+
+// Minutes of a day, the largest start or end of a time window
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+// Daily time window, in minutes after midnight. A window whose end is before its start runs over midnight
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub start: u16,
+    pub end: u16,
+    // Days of the week the window applies to, 0 is Monday. Empty means every day
+    #[serde(default)]
+    pub weekdays: Vec<u8>,
+    // Offset of the local time of the window to UTC in minutes
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+impl TimeWindow {
+    pub fn new(start: u16, end: u16) -> TimeWindow {
+        TimeWindow { start, end, weekdays: Vec::new(), utc_offset: 0 }
+    }
+
+    fn validate(&self) -> Result<(), NizkError> {
+        if self.start > MINUTES_PER_DAY || self.end > MINUTES_PER_DAY {
+            return Err(NizkError::InvalidCondition("time window is longer than a day"));
+        }
+        if self.start == self.end {
+            return Err(NizkError::InvalidCondition("time window is empty"));
+        }
+        if self.weekdays.iter().any(|weekday| *weekday > 6) {
+            return Err(NizkError::InvalidCondition("weekday is not between 0 and 6"));
+        }
+        Ok(())
+    }
+
+    fn contains(&self, time: SystemTime) -> bool {
+        let minutes = rate_limit::to_millis(time) / 60_000 + self.utc_offset as i64;
+        let day = minutes.div_euclid(24 * 60);
+        let minute = minutes.rem_euclid(24 * 60) as u16;
+
+        // The window belongs to the day it started on
+        let (in_window, start_day) = if self.start <= self.end {
+            (self.start <= minute && minute < self.end, day)
+        } else if minute >= self.start {
+            (true, day)
+        } else {
+            (minute < self.end, day - 1)
+        };
+        // 1970-01-01 was a Thursday
+        let weekday = (start_day + 3).rem_euclid(7) as u8;
+        in_window && (self.weekdays.is_empty() || self.weekdays.contains(&weekday))
+    }
+}
+
+// IP network in CIDR notation like "10.0.0.0/8". The prefix is never longer than the address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn new(address: IpAddr, prefix: u8) -> Result<Network, NizkError> {
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        if prefix > max_prefix {
+            return Err(NizkError::InvalidCondition("network prefix is too long"));
+        }
+        Ok(Network { address, prefix })
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*address) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*address) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = NizkError;
+
+    fn from_str(text: &str) -> Result<Network, NizkError> {
+        let invalid = NizkError::InvalidCondition("network is not in CIDR notation");
+        let (address, prefix) = text.split_once('/').unwrap_or((text, ""));
+        let address: IpAddr = address.parse().map_err(|_| invalid)?;
+        let prefix = if prefix.is_empty() {
+            if address.is_ipv4() { 32 } else { 128 }
+        } else {
+            prefix.parse().map_err(|_| NizkError::InvalidCondition("network prefix is too long"))?
+        };
+        Network::new(address, prefix)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl Serialize for Network {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Network, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Conditions of a grant, unset fields do not limit it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Conditions {
+    // Validity period in milliseconds since the Unix epoch
+    pub valid_from: Option<i64>,
+    pub valid_until: Option<i64>,
+    // The grant is valid within any of the windows, no windows means at any time
+    pub time_windows: Vec<TimeWindow>,
+    // Uses of the grant. The uses of a grant to a role are counted for all devices with the role together
+    pub max_uses: Option<u32>,
+    pub max_message_size: Option<usize>,
+    // The source address of the request has to be in one of the networks, no networks means any source
+    pub source_networks: Vec<Network>,
+    // Attributes the request has to have with exactly these values
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Conditions {
+    pub fn with_validity(mut self, from: Option<SystemTime>, until: Option<SystemTime>) -> Conditions {
+        self.valid_from = from.map(rate_limit::to_millis);
+        self.valid_until = until.map(rate_limit::to_millis);
+        self
+    }
+
+    pub fn with_time_window(mut self, window: TimeWindow) -> Conditions {
+        self.time_windows.push(window);
+        self
+    }
+
+    pub fn with_max_uses(mut self, max_uses: u32) -> Conditions {
+        self.max_uses = Some(max_uses);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, size: usize) -> Conditions {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    pub fn with_source_network(mut self, network: Network) -> Conditions {
+        self.source_networks.push(network);
+        self
+    }
+
+    pub fn with_attribute(mut self, name: &str, value: &str) -> Conditions {
+        self.attributes.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    // Check that the conditions can be met, set_conditions refuses conditions that are not valid
+    pub fn validate(&self) -> Result<(), NizkError> {
+        if let (Some(from), Some(until)) = (self.valid_from, self.valid_until) {
+            if from >= until {
+                return Err(NizkError::InvalidCondition("validity period ends before it starts"));
+            }
+        }
+        self.time_windows.iter().try_for_each(TimeWindow::validate)
+    }
+
+    // Check the conditions for a request, uses is the number of times the grant was used before
+    pub fn evaluate(&self, uses: u32, context: &AccessContext) -> Result<(), ConditionFailure> {
+        let now = rate_limit::to_millis(context.time);
+        if let Some(from) = self.valid_from.filter(|from| now < *from) {
+            return Err(ConditionFailure::NotYetValid { valid_from: from });
+        }
+        if let Some(until) = self.valid_until.filter(|until| now >= *until) {
+            return Err(ConditionFailure::Expired { valid_until: until });
+        }
+        if !self.time_windows.is_empty() && !self.time_windows.iter().any(|window| window.contains(context.time)) {
+            return Err(ConditionFailure::OutsideTimeWindow);
+        }
+        if let Some(max_uses) = self.max_uses.filter(|max_uses| uses >= *max_uses) {
+            return Err(ConditionFailure::UsesExhausted { max_uses });
+        }
+        if let Some(max_size) = self.max_message_size {
+            match context.message_size {
+                None => return Err(ConditionFailure::MissingAttribute("message_size".to_string())),
+                Some(size) if size > max_size => return Err(ConditionFailure::MessageTooLarge { size, max_size }),
+                Some(_) => (),
+            }
+        }
+        if !self.source_networks.is_empty() {
+            match context.source {
+                None => return Err(ConditionFailure::MissingAttribute("source".to_string())),
+                Some(source) if !self.source_networks.iter().any(|network| network.contains(&source)) => {
+                    return Err(ConditionFailure::SourceNotAllowed(source));
+                },
+                Some(_) => (),
+            }
+        }
+        for (name, value) in &self.attributes {
+            match context.attributes.get(name) {
+                None => return Err(ConditionFailure::MissingAttribute(name.clone())),
+                Some(found) if found != value => return Err(ConditionFailure::AttributeMismatch(name.clone())),
+                Some(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Condition of a grant that a request does not meet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionFailure {
+    NotYetValid { valid_from: i64 },
+    Expired { valid_until: i64 },
+    OutsideTimeWindow,
+    UsesExhausted { max_uses: u32 },
+    MessageTooLarge { size: usize, max_size: usize },
+    SourceNotAllowed(IpAddr),
+    // The request does not have an attribute that a condition needs
+    MissingAttribute(String),
+    AttributeMismatch(String),
+}
+
+impl ConditionFailure {
+    // The grant was valid once but is not any more
+    pub fn is_expired(&self) -> bool {
+        matches!(self, ConditionFailure::Expired { .. } | ConditionFailure::UsesExhausted { .. })
+    }
+}
+
+// Attributes of a request, supplied by the caller of check_access_with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessContext {
+    pub time: SystemTime,
+    pub message_size: Option<usize>,
+    pub source: Option<IpAddr>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Default for AccessContext {
+    fn default() -> AccessContext {
+        AccessContext {
+            time: SystemTime::now(),
+            message_size: None,
+            source: None,
+            attributes: BTreeMap::new(),
+        }
+    }
+}
+
+impl AccessContext {
+    pub fn with_time(mut self, time: SystemTime) -> AccessContext {
+        self.time = time;
+        self
+    }
+
+    pub fn with_message_size(mut self, size: usize) -> AccessContext {
+        self.message_size = Some(size);
+        self
+    }
+
+    pub fn with_source(mut self, source: IpAddr) -> AccessContext {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_attribute(mut self, name: &str, value: &str) -> AccessContext {
+        self.attributes.insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+// Conditions of one grant and how often it was used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ConditionEntry {
+    subject: GrantSubject,
+    resource: u32,
+    action: Vec<u8>,
+    conditions: Conditions,
+    #[serde(default)]
+    uses: u32,
+}
+
+impl ConditionEntry {
+    fn matches(&self, subject: &GrantSubject, resource: u32, action: &[u8]) -> bool {
+        &self.subject == subject && self.resource == resource && self.action == action
+    }
+}
+
+// Conditions of all grants
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GrantConditions {
+    entries: Vec<ConditionEntry>,
+}
+
+impl GrantConditions {
+    fn find(&self, subject: &GrantSubject, resource: u32, action: &[u8]) -> Option<&ConditionEntry> {
+        self.entries.iter().find(|entry| entry.matches(subject, resource, action))
+    }
+
+    pub(crate) fn get(&self, subject: &GrantSubject, permission: &Permission) -> Option<Conditions> {
+        self.find(subject, permission.resource, &permission.action).map(|entry| entry.conditions.clone())
+    }
+
+    // Check the conditions of a grant of the device
+    pub(crate) fn evaluate(&self, grant: &Grant, deviceID: &DeviceId, context: &AccessContext) -> Result<(), ConditionFailure> {
+        let permission = grant.permission();
+        match self.find(&GrantSubject::of(grant, deviceID), permission.resource, &permission.action) {
+            Some(entry) => entry.conditions.evaluate(entry.uses, context),
+            None => Ok(()),
+        }
+    }
+}
+
+fn get_conditions_file_path() -> Result<PathBuf, NizkError> {
+    config::state_path("access_control/conditions.json")
+}
+
+pub(crate) fn load() -> Result<GrantConditions, NizkError> {
+    let file_path = get_conditions_file_path()?;
+    match fs::read(&file_path) {
+        Ok(bytes) => Ok(GrantConditions { entries: serde_json::from_slice(&bytes)? }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(GrantConditions::default()),
+        Err(e) => Err(NizkError::Io(e)),
+    }
+}
+
+// Change the conditions while holding the lock of their file
+fn modify<T>(f: impl FnOnce(&mut GrantConditions) -> Result<T, NizkError>) -> Result<T, NizkError> {
+    let file_path = get_conditions_file_path()?;
+    if let Some(parent) = Path::new(&file_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _lock = FileLock::acquire(&file_path)?;
+    let mut conditions = load()?;
+    let result = f(&mut conditions)?;
+    file_lock::write_atomic(&file_path, serde_json::to_string(&conditions.entries)?.as_bytes())?;
+    Ok(result)
+}
+
+// Limit a grant by conditions, replacing its old conditions. The uses of the grant start again at zero.
+// A maximum of uses on a grant to a role is shared by all devices with the role
+pub fn set_conditions(subject: GrantSubject, permission: Permission, conditions: Conditions) -> Result<(), NizkError> {
+    conditions.validate()?;
+    let change = AccessChange::SetConditions { action: audit::action_name(&permission.action), subject: subject.clone() };
+    modify(|grants| {
+        grants.entries.retain(|entry| !entry.matches(&subject, permission.resource, &permission.action));
+        grants.entries.push(ConditionEntry {
+            subject,
+            resource: permission.resource,
+            action: permission.action.clone(),
+            conditions,
+            uses: 0,
+        });
+        Ok(())
+    })?;
+    audit::record(AuditEvent::AccessControlChanged { resource: permission.resource, change });
+    Ok(())
+}
+
+// Remove the conditions of a grant, it is valid at any time again
+pub fn clear_conditions(subject: GrantSubject, permission: &Permission) -> Result<(), NizkError> {
+    let change = AccessChange::ClearConditions { action: audit::action_name(&permission.action), subject: subject.clone() };
+    modify(|grants| {
+        let count = grants.entries.len();
+        grants.entries.retain(|entry| !entry.matches(&subject, permission.resource, &permission.action));
+        if grants.entries.len() == count {
+            return Err(NizkError::ActionNotFound);
+        }
+        Ok(())
+    })?;
+    audit::record(AuditEvent::AccessControlChanged { resource: permission.resource, change });
+    Ok(())
+}
+
+// Conditions of all grants, for a policy export
+pub(crate) fn export() -> Result<Vec<(GrantSubject, Permission, Conditions)>, NizkError> {
+    Ok(load()?.entries.into_iter()
+        .map(|entry| (entry.subject, Permission { resource: entry.resource, action: entry.action }, entry.conditions))
+        .collect())
+}
+
+// Replace the conditions of all grants, for a policy import. The uses of grants whose conditions stay
+// the same are kept
+pub(crate) fn replace(grants: Vec<(GrantSubject, Permission, Conditions)>) -> Result<(), NizkError> {
+    modify(|current| {
+        current.entries = grants.into_iter()
+            .map(|(subject, permission, conditions)| {
+                let uses = current.find(&subject, permission.resource, &permission.action)
+                    .filter(|entry| entry.conditions == conditions)
+                    .map_or(0, |entry| entry.uses);
+                ConditionEntry { subject, resource: permission.resource, action: permission.action, conditions, uses }
+            })
+            .collect();
+        Ok(())
+    })
+}
+
+// Conditions of a grant, None if it has none
+pub fn get_conditions(subject: &GrantSubject, permission: &Permission) -> Result<Option<Conditions>, NizkError> {
+    Ok(load()?.get(subject, permission))
+}
+
+// Count a use of one of the valid grants. Grants without a use limit are preferred, so limited uses are
+// only spent if nothing else allows the access. Returns false if another process used up the last use
+pub(crate) fn use_grant(grants: &[Grant], deviceID: &DeviceId, context: &AccessContext) -> Result<bool, NizkError> {
+    let keys: Vec<(GrantSubject, &Permission)> = grants.iter().map(|grant| (GrantSubject::of(grant, deviceID), grant.permission())).collect();
+    let unlimited = |conditions: &GrantConditions| keys.iter().any(|(subject, permission)| {
+        conditions.find(subject, permission.resource, &permission.action).is_none_or(|entry| entry.conditions.max_uses.is_none())
+    });
+    if unlimited(&load()?) {
+        return Ok(true);
+    }
+
+    modify(|conditions| {
+        for (subject, permission) in &keys {
+            let entry = conditions.entries.iter_mut().find(|entry| entry.matches(subject, permission.resource, &permission.action));
+            if let Some(entry) = entry {
+                if entry.conditions.evaluate(entry.uses, context).is_ok() {
+                    entry.uses += 1;
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    })
+}
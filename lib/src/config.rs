@@ -0,0 +1,150 @@
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use crate::error::NizkError;
+use crate::rate_limit::RateLimitPolicy;
+use crate::secret_management::{EncryptedFileStore, KeyStore, KeyUtilsStore, MemoryStore};
+use crate::LOOKAHEAD_WINDOW;
+
+// Configuration of the library: where the state files are saved, the limits of the intrusion detection,
+// the lookahead window of the verifier and which key store is used.
+//
+// A configuration is installed once when the device starts and is used by all functions of the crate.
+// Without an installed configuration the default one is used. The default state directory is the first of
+//   $NIZK_STATE_DIR, $XDG_STATE_HOME/nizk-auth, $HOME/.local/state/nizk-auth, /var/lib/nizk-auth
+// Older versions saved the state in ".nizk-auth" below the working directory. Setting state_dir to that
+// directory keeps using the old files.
+
+// Name of the state directory below the XDG or system location
+pub const STATE_DIR_NAME: &str = "nizk-auth";
+
+// Storage of the keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStoreChoice {
+    // Linux keyring of the current user, keys are lost when the device reboots
+    KeyUtils,
+    // Keys are only kept in memory, for tests
+    Memory,
+    // Password encrypted file. A relative path is taken below the state directory
+    EncryptedFile(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NizkConfig {
+    // Directory of the used commitments, the intrusion data and the access control data
+    pub state_dir: PathBuf,
+    // Rejected proofs with a valid Schnorr part or MAC tag before a key counts as compromised
+    pub key_guess_threshold: u8,
+    // Time after which the key guess counters are decreased by one
+    pub counter_decay: Duration,
+    // Rejected proofs of a sender that count as a DoS attack and how long the sender is blocked then
+    pub rate_limit: RateLimitPolicy,
+    // Number of lost proofs the verifier skips to find the shared counter of the sender
+    pub lookahead_window: u32,
+    // Number of key epochs the used commitments of a device are kept, None keeps them forever
+    pub commitment_epochs: Option<u32>,
+    pub key_store: KeyStoreChoice,
+}
+
+impl Default for NizkConfig {
+    fn default() -> NizkConfig {
+        NizkConfig {
+            state_dir: default_state_dir(),
+            key_guess_threshold: 5,
+            counter_decay: Duration::from_secs(24 * 3600),
+            rate_limit: RateLimitPolicy::default(),
+            lookahead_window: LOOKAHEAD_WINDOW,
+            commitment_epochs: None,
+            key_store: KeyStoreChoice::KeyUtils,
+        }
+    }
+}
+
+// State directory used when none is configured
+pub fn default_state_dir() -> PathBuf {
+    let non_empty = |name: &str| env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+
+    if let Some(dir) = non_empty("NIZK_STATE_DIR") {
+        dir
+    } else if let Some(dir) = non_empty("XDG_STATE_HOME") {
+        dir.join(STATE_DIR_NAME)
+    } else if let Some(home) = non_empty("HOME") {
+        home.join(".local/state").join(STATE_DIR_NAME)
+    } else {
+        // Services without a home directory
+        PathBuf::from("/var/lib").join(STATE_DIR_NAME)
+    }
+}
+
+impl NizkConfig {
+    // Create the state directory if it is missing and check that no other user can change its files.
+    // It has to be owned by the user of the process or by root
+    pub fn prepare_state_dir(&self) -> Result<(), NizkError> {
+        if !self.state_dir.exists() {
+            DirBuilder::new().recursive(true).mode(0o700).create(&self.state_dir)?;
+        }
+
+        let unsafe_dir = |reason| Err(NizkError::UnsafeStateDir { path: self.state_dir.clone(), reason });
+        let metadata = fs::symlink_metadata(&self.state_dir)?;
+        if metadata.file_type().is_symlink() {
+            return unsafe_dir("state directory is a symbolic link");
+        }
+        if !metadata.is_dir() {
+            return unsafe_dir("state directory is not a directory");
+        }
+        if metadata.permissions().mode() & 0o022 != 0 {
+            return unsafe_dir("state directory is writable by other users");
+        }
+        // The owner can always change the permissions, so it has to be the user of the process or root.
+        // geteuid has no preconditions and can not fail
+        let uid = unsafe { libc::geteuid() };
+        if metadata.uid() != uid && metadata.uid() != 0 {
+            return unsafe_dir("owned by another user");
+        }
+        Ok(())
+    }
+
+    // Open the configured key store. The password is only needed for an encrypted file
+    pub fn open_key_store(&self, password: Option<&[u8]>) -> Result<Arc<dyn KeyStore>, NizkError> {
+        let store: Arc<dyn KeyStore> = match &self.key_store {
+            KeyStoreChoice::KeyUtils => Arc::new(KeyUtilsStore::new()?),
+            KeyStoreChoice::Memory => Arc::new(MemoryStore::new()),
+            KeyStoreChoice::EncryptedFile(path) => {
+                let password = password.ok_or(NizkError::WrongState("encrypted key file needs a password"))?;
+                self.prepare_state_dir()?;
+                Arc::new(EncryptedFileStore::open(self.state_dir.join(path), password)?)
+            },
+        };
+        Ok(store)
+    }
+}
+
+// Configuration used by the crate, None until it is installed or first used
+static CONFIG: RwLock<Option<Arc<NizkConfig>>> = RwLock::new(None);
+
+// Check the state directory of the configuration and use it for all following calls
+pub fn install(config: NizkConfig) -> Result<(), NizkError> {
+    config.prepare_state_dir()?;
+    *CONFIG.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(config));
+    Ok(())
+}
+
+// Installed configuration, or the default one if none was installed
+pub fn current() -> Arc<NizkConfig> {
+    if let Some(config) = CONFIG.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        return config.clone();
+    }
+    CONFIG.write().unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(|| Arc::new(NizkConfig::default()))
+        .clone()
+}
+
+// Path of a file in the state directory. The directory is created and checked first
+pub(crate) fn state_path(file_name: &str) -> Result<PathBuf, NizkError> {
+    let config = current();
+    config.prepare_state_dir()?;
+    Ok(config.state_dir.join(file_name))
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::error::NizkError;
+
+// Identifier of a device: a number, a 128-bit UUID or a hierarchical name like "plant-1/line-2/robot".
+//
+// Text form, used in key descriptions and access control data:
+//   numeric IDs are written as plain numbers, "uuid:<hyphenated hex>" and "name:<name>".
+// Numeric IDs have the same text and JSON form as the u32 IDs of older versions, so existing keys,
+// files and access control data keep working without migration.
+//
+// Wire form: tag (1 byte) | value, with
+//   0x01 | u32 (4 bytes), 0x02 | UUID (16 bytes), 0x03 | length (1 byte) | name
+
+// Tags of the wire form
+const TAG_NUMERIC: u8 = 0x01;
+const TAG_UUID: u8 = 0x02;
+const TAG_NAME: u8 = 0x03;
+
+// Longest allowed name in bytes
+pub const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Repr {
+    Numeric(u32),
+    Uuid([u8; 16]),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(Repr);
+
+impl DeviceId {
+    pub const fn numeric(id: u32) -> DeviceId {
+        DeviceId(Repr::Numeric(id))
+    }
+
+    pub const fn uuid(uuid: [u8; 16]) -> DeviceId {
+        DeviceId(Repr::Uuid(uuid))
+    }
+
+    // Names are made of segments separated by "/". Segments contain only ASCII letters, digits, "-", "_" and "."
+    pub fn name(name: &str) -> Result<DeviceId, NizkError> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(NizkError::InvalidDeviceId("name must have 1 to 64 bytes"));
+        }
+        let valid_segment = |segment: &str| {
+            !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        };
+        if !name.split('/').all(valid_segment) {
+            return Err(NizkError::InvalidDeviceId("name contains an empty segment or an invalid character"));
+        }
+        Ok(DeviceId(Repr::Name(name.to_string())))
+    }
+
+    // The number of a numeric ID, for state of older versions
+    pub fn as_numeric(&self) -> Option<u32> {
+        match self.0 {
+            Repr::Numeric(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    // Name of the ID in file names. Numeric IDs keep the file names of older versions
+    pub(crate) fn file_name(&self) -> String {
+        match &self.0 {
+            Repr::Numeric(id) => id.to_string(),
+            Repr::Uuid(uuid) => format!("uuid-{}", hex::encode(uuid)),
+            Repr::Name(name) => format!("name-{}", hex::encode(name)),
+        }
+    }
+
+    // Append the wire form
+    pub(crate) fn encode_into(&self, buffer: &mut Vec<u8>) {
+        match &self.0 {
+            Repr::Numeric(id) => {
+                buffer.push(TAG_NUMERIC);
+                buffer.extend_from_slice(&id.to_be_bytes());
+            },
+            Repr::Uuid(uuid) => {
+                buffer.push(TAG_UUID);
+                buffer.extend_from_slice(uuid);
+            },
+            Repr::Name(name) => {
+                buffer.push(TAG_NAME);
+                buffer.push(name.len() as u8);
+                buffer.extend_from_slice(name.as_bytes());
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.encode_into(&mut buffer);
+        buffer
+    }
+
+    // Read the wire form from the start of the bytes and return the ID with the number of used bytes
+    pub(crate) fn decode(bytes: &[u8]) -> Result<(DeviceId, usize), NizkError> {
+        let (tag, rest) = bytes.split_first().ok_or(NizkError::InvalidMessage("device ID is too short"))?;
+        let take = |start: usize, length: usize| {
+            rest.get(start..start + length).ok_or(NizkError::InvalidMessage("device ID is too short"))
+        };
+
+        match *tag {
+            TAG_NUMERIC => {
+                let id = take(0, 4)?.try_into().expect("slice has a fixed size");
+                Ok((DeviceId::numeric(u32::from_be_bytes(id)), 5))
+            },
+            TAG_UUID => {
+                let uuid = take(0, 16)?.try_into().expect("slice has a fixed size");
+                Ok((DeviceId::uuid(uuid), 17))
+            },
+            TAG_NAME => {
+                let length = take(0, 1)?[0] as usize;
+                let name = std::str::from_utf8(take(1, length)?)
+                    .map_err(|_| NizkError::InvalidDeviceId("name is not valid UTF-8"))?;
+                Ok((DeviceId::name(name)?, 2 + length))
+            },
+            _ => Err(NizkError::InvalidMessage("unknown device ID type")),
+        }
+    }
+}
+
+impl From<u32> for DeviceId {
+    fn from(id: u32) -> DeviceId {
+        DeviceId::numeric(id)
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Repr::Numeric(id) => write!(f, "{}", id),
+            Repr::Uuid(uuid) => {
+                let hex = hex::encode(uuid);
+                write!(f, "uuid:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+            },
+            Repr::Name(name) => write!(f, "name:{}", name),
+        }
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = NizkError;
+
+    fn from_str(text: &str) -> Result<DeviceId, NizkError> {
+        if let Some(uuid) = text.strip_prefix("uuid:") {
+            let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+            let bytes = hex::decode(hex).map_err(|_| NizkError::InvalidDeviceId("UUID is not hexadecimal"))?;
+            let uuid = bytes.try_into().map_err(|_| NizkError::InvalidDeviceId("UUID must have 16 bytes"))?;
+            return Ok(DeviceId::uuid(uuid));
+        }
+        if let Some(name) = text.strip_prefix("name:") {
+            return DeviceId::name(name);
+        }
+        if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+            return text.parse::<u32>().map(DeviceId::numeric)
+                .map_err(|_| NizkError::InvalidDeviceId("numeric ID is too large"));
+        }
+        Err(NizkError::InvalidDeviceId("expected a number, \"uuid:\" or \"name:\""))
+    }
+}
+
+// Numeric IDs are JSON numbers like the u32 IDs of older versions, the others are strings in text form
+impl Serialize for DeviceId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Repr::Numeric(id) => serializer.serialize_u32(id),
+            _ => serializer.serialize_str(&self.to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DeviceId, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Numeric(u32),
+            Text(String),
+        }
+
+        match Stored::deserialize(deserializer)? {
+            Stored::Numeric(id) => Ok(DeviceId::numeric(id)),
+            Stored::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
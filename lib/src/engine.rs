@@ -0,0 +1,276 @@
+use std::mem;
+use std::sync::Arc;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::int_mut_auth::{AwaitingChallengeResponse, AwaitingCommitment, AwaitingResponse, Initiator, Responder};
+use crate::secret_management::KeyStore;
+use crate::session::{KeyConfirmation, SessionKeys};
+use crate::wire::{Body, Message, MessageBuffer};
+use crate::{gen_proof, verify_proof, NIZKMutAuth, NizkMode};
+
+// Sans-IO engine for the three sub-protocols: interactive key agreement, one-shot NIZK proofs and
+// NIZK mutual authentication.
+//
+// The engine does not read or write anything itself. Received messages or raw bytes are given to
+// the engine, which returns events: messages that have to be sent to the other device and the result
+// of the protocol. So the same sequencing works over TCP, UDP, serial links or message queues.
+//
+// An initiator engine is started for one protocol and one peer. A responder engine accepts the first
+// message of any protocol and continues with the device that sent it. After Established, Verified or
+// Rejected the engine is finished and every further message is rejected.
+
+// Output of an engine
+#[derive(Debug)]
+pub enum Event {
+    // Message that has to be sent to the other device
+    NeedSend(Message),
+    // Handshake finished. The NIZK mutual authentication returns the session keys, the key agreement
+    // saved the shared secret key in the key store and has no session key
+    Established { peer_ID: DeviceId, session_key: Option<SessionKeys> },
+    // Proof of a one-shot NIZK message was accepted
+    Verified { peer_ID: DeviceId, message: Vec<u8> },
+    // Protocol was aborted, nothing more will be sent
+    Rejected { reason: NizkError },
+}
+
+// Protocol step the engine waits for
+enum State {
+    // Responder waits for the first message of any protocol
+    Listening,
+    KeyAgreementCommitment(Initiator<AwaitingCommitment>),
+    KeyAgreementResponse(Initiator<AwaitingResponse>),
+    KeyAgreementChallengeResponse(Responder<AwaitingChallengeResponse>),
+    MutAuthProof(NIZKMutAuth),
+    // Waits for the confirmation tag of the other device. The responder answers with its own tag
+    KeyConfirmation { confirmation: KeyConfirmation, send_tag: bool },
+    Finished,
+}
+
+// Protocol engine of one device
+pub struct Engine {
+    store: Arc<dyn KeyStore>,
+    my_ID: DeviceId,
+    peer_ID: Option<DeviceId>,
+    state: State,
+    buffer: MessageBuffer,
+    key_confirmation: bool,
+    public_context: Option<Vec<u8>>,
+}
+
+impl Engine {
+    fn with_state(store: Arc<dyn KeyStore>, my_ID: DeviceId, peer_ID: Option<DeviceId>, state: State) -> Engine {
+        Engine {
+            store,
+            my_ID,
+            peer_ID,
+            state,
+            buffer: MessageBuffer::new(),
+            key_confirmation: false,
+            public_context: None,
+        }
+    }
+
+    // Responder for all protocols, waiting for the first message of another device
+    pub fn responder(store: Arc<dyn KeyStore>, my_ID: DeviceId) -> Engine {
+        Engine::with_state(store, my_ID, None, State::Listening)
+    }
+
+    // Require the key confirmation round after a NIZK mutual authentication. Both devices have to use the same setting
+    pub fn with_key_confirmation(mut self, key_confirmation: bool) -> Engine {
+        self.key_confirmation = key_confirmation;
+        self
+    }
+
+    // Accept one-shot proofs in public key mode with the given context. Without a context they are rejected
+    pub fn with_public_context(mut self, context: &[u8]) -> Engine {
+        self.public_context = Some(context.to_vec());
+        self
+    }
+
+    // Start the interactive key agreement, or a resync of an already paired device
+    pub fn key_agreement(store: Arc<dyn KeyStore>, my_ID: DeviceId, peer_ID: DeviceId, resync: bool) -> Result<(Engine, Vec<Event>), NizkError> {
+        let (initiator, body) = if resync {
+            let (initiator, commitment) = Initiator::resync(store.clone(), my_ID.clone(), peer_ID.clone())?;
+            (initiator, Body::ResyncRequest(commitment))
+        } else {
+            let (initiator, commitment) = Initiator::start(store.clone(), my_ID.clone(), peer_ID.clone());
+            (initiator, Body::IntCommitment(commitment))
+        };
+
+        let events = vec![Event::NeedSend(Message::new(my_ID.clone(), peer_ID.clone(), body))];
+        let engine = Engine::with_state(store, my_ID, Some(peer_ID), State::KeyAgreementCommitment(initiator));
+        Ok((engine, events))
+    }
+
+    // Start the NIZK mutual authentication. With key_confirmation the keys are only returned after the confirmation round
+    pub fn mut_auth(store: Arc<dyn KeyStore>, my_ID: DeviceId, peer_ID: DeviceId, key_confirmation: bool) -> Result<(Engine, Vec<Event>), NizkError> {
+        let (nizk_mut_auth, proof) = NIZKMutAuth::new(store.clone(), my_ID.clone(), peer_ID.clone(), None)?;
+
+        let events = vec![Event::NeedSend(Message::new(my_ID.clone(), peer_ID.clone(), Body::NizkMutAuthProof(proof)))];
+        let engine = Engine::with_state(store, my_ID, Some(peer_ID), State::MutAuthProof(nizk_mut_auth))
+            .with_key_confirmation(key_confirmation);
+        Ok((engine, events))
+    }
+
+    // Handle a received message
+    pub fn handle_message(&mut self, message: Message) -> Vec<Event> {
+        self.step(Ok(message))
+    }
+
+    // Handle received bytes. Messages can be split or joined in any way, invalid bytes abort the protocol
+    pub fn handle_bytes(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.buffer.push(bytes);
+
+        let mut events = Vec::new();
+        while !self.is_finished() {
+            match self.buffer.next_message().transpose() {
+                Some(message) => events.extend(self.step(message)),
+                None => break,
+            }
+        }
+        events
+    }
+
+    // True after Established, Verified or Rejected
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::Finished)
+    }
+
+    // Device the engine runs the protocol with, None while a responder waits for the first message
+    pub fn peer_ID(&self) -> Option<&DeviceId> {
+        self.peer_ID.as_ref()
+    }
+
+    fn step(&mut self, message: Result<Message, NizkError>) -> Vec<Event> {
+        let state = mem::replace(&mut self.state, State::Finished);
+        let result = message.and_then(|message| {
+            // Check the addresses before any key is used
+            if message.recipient_ID != self.my_ID {
+                return Err(NizkError::InvalidMessage("message is addressed to another device"));
+            }
+            if self.peer_ID.as_ref().is_some_and(|peer_ID| *peer_ID != message.sender_ID) {
+                return Err(NizkError::InvalidMessage("message is from an unexpected device"));
+            }
+            self.advance(state, message)
+        });
+
+        match result {
+            Ok(events) => events,
+            Err(reason) => {
+                self.state = State::Finished;
+                vec![Event::Rejected { reason }]
+            }
+        }
+    }
+
+    // Handle the message in the current state. The next state is set by the step itself
+    fn advance(&mut self, state: State, message: Message) -> Result<Vec<Event>, NizkError> {
+        let peer_ID = message.sender_ID;
+        let my_ID = self.my_ID.clone();
+        let reply = |body: Body| Event::NeedSend(Message::new(my_ID.clone(), peer_ID.clone(), body));
+
+        match (state, message.body) {
+            // Interactive key agreement, initiator
+            (State::KeyAgreementCommitment(initiator), Body::IntCommitmentAndChallenge(values)) => {
+                let (initiator, response) = initiator.receive_commitment(values)?;
+                self.state = State::KeyAgreementResponse(initiator);
+                Ok(vec![reply(Body::IntChallengeAndResponse(response))])
+            },
+            (State::KeyAgreementResponse(initiator), Body::IntResponse(values)) => {
+                initiator.receive_response(values)?;
+                Ok(vec![Event::Established { peer_ID, session_key: None }])
+            },
+
+            // Interactive key agreement, responder
+            (State::Listening, Body::IntCommitment(values) | Body::ResyncRequest(values)) => {
+                let (responder, commitment) = Responder::start(self.store.clone(), self.my_ID.clone(), peer_ID.clone(), values)?;
+                self.peer_ID = Some(peer_ID.clone());
+                self.state = State::KeyAgreementChallengeResponse(responder);
+                Ok(vec![reply(Body::IntCommitmentAndChallenge(commitment))])
+            },
+            (State::KeyAgreementChallengeResponse(responder), Body::IntChallengeAndResponse(values)) => {
+                let (_, response) = responder.receive_challenge_response(values)?;
+                Ok(vec![reply(Body::IntResponse(response)), Event::Established { peer_ID, session_key: None }])
+            },
+
+            // One-shot NIZK proofs, responder
+            (State::Listening, Body::NizkProof { proof, message }) => {
+                self.peer_ID = Some(peer_ID.clone());
+                let mode = NizkMode::SharedKey { update_keys: true };
+                if !verify_proof(&*self.store, mode, &self.my_ID, &peer_ID, &message, proof)? {
+                    return Err(NizkError::ProofRejected);
+                }
+                Ok(vec![Event::Verified { peer_ID, message }])
+            },
+            (State::Listening, Body::PublicNizkProof { proof, message }) => {
+                self.peer_ID = Some(peer_ID.clone());
+                let context = self.public_context.as_deref()
+                    .ok_or(NizkError::WrongState("proofs in public key mode are not accepted"))?;
+                if !verify_proof(&*self.store, NizkMode::PublicKey { context }, &self.my_ID, &peer_ID, &message, proof)? {
+                    return Err(NizkError::ProofRejected);
+                }
+                Ok(vec![Event::Verified { peer_ID, message }])
+            },
+
+            // NIZK mutual authentication, initiator
+            (State::MutAuthProof(mut nizk_mut_auth), Body::NizkMutAuthProof(proof)) => {
+                nizk_mut_auth.add_recipient_values(proof);
+                if !nizk_mut_auth.verify_proof()? {
+                    return Err(NizkError::ProofRejected);
+                }
+
+                if !self.key_confirmation {
+                    let session_key = nizk_mut_auth.calculate_session_keys()?;
+                    return Ok(vec![Event::Established { peer_ID, session_key: Some(session_key) }]);
+                }
+                let confirmation = nizk_mut_auth.confirm_session_keys()?;
+                let tag = confirmation.tag();
+                self.state = State::KeyConfirmation { confirmation, send_tag: false };
+                Ok(vec![reply(Body::KeyConfirmation(tag))])
+            },
+
+            // NIZK mutual authentication, responder. The own proof is only sent if the proof of the initiator is accepted
+            (State::Listening, Body::NizkMutAuthProof(proof)) => {
+                self.peer_ID = Some(peer_ID.clone());
+                let (mut nizk_mut_auth, my_proof) = NIZKMutAuth::new(self.store.clone(), self.my_ID.clone(), peer_ID.clone(), Some(proof))?;
+                if !nizk_mut_auth.verify_proof()? {
+                    return Err(NizkError::ProofRejected);
+                }
+
+                let mut events = vec![reply(Body::NizkMutAuthProof(my_proof))];
+                if self.key_confirmation {
+                    let confirmation = nizk_mut_auth.confirm_session_keys()?;
+                    self.state = State::KeyConfirmation { confirmation, send_tag: true };
+                } else {
+                    let session_key = nizk_mut_auth.calculate_session_keys()?;
+                    events.push(Event::Established { peer_ID, session_key: Some(session_key) });
+                }
+                Ok(events)
+            },
+
+            // Key confirmation round of both roles
+            (State::KeyConfirmation { mut confirmation, send_tag }, Body::KeyConfirmation(tag)) => {
+                confirmation.verify(tag)?;
+                let mut events = Vec::new();
+                if send_tag {
+                    events.push(reply(Body::KeyConfirmation(confirmation.tag())));
+                }
+                events.push(Event::Established { peer_ID, session_key: Some(confirmation.session_keys()?) });
+                Ok(events)
+            },
+
+            (State::Finished, _) => Err(NizkError::WrongState("protocol is already finished")),
+            (_, _) => Err(NizkError::WrongState("unexpected message for the current protocol step")),
+        }
+    }
+}
+
+// Build the message of a one-shot NIZK proof. The sender does not wait for an answer, so no engine is needed
+pub fn nizk_message(store: &dyn KeyStore, mode: NizkMode, my_ID: &DeviceId, peer_ID: &DeviceId, message: &[u8]) -> Result<Message, NizkError> {
+    let proof = gen_proof(store, mode, my_ID, peer_ID, message)?;
+    let body = match mode {
+        NizkMode::SharedKey { .. } => Body::NizkProof { proof, message: message.to_vec() },
+        NizkMode::PublicKey { .. } => Body::PublicNizkProof { proof, message: message.to_vec() },
+    };
+    Ok(Message::new(my_ID.clone(), peer_ID.clone(), body))
+}
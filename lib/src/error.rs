@@ -0,0 +1,139 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use crate::device_id::DeviceId;
+use crate::rate_limit::Decision;
+use crate::secret_management::SecretKeyErrors;
+
+// Errors returned by the public API of the crate
+#[derive(Debug)]
+pub enum NizkError {
+    // Reading or writing a key in the key storage failed
+    KeyRing(SecretKeyErrors),
+    // A stored key does not have the expected size
+    InvalidKeyLength { description: String, expected: usize, found: usize },
+    // Received bytes are not a valid point on the elliptic curve
+    InvalidCurvePoint,
+    // A protocol step was called in the wrong order or with missing values
+    WrongState(&'static str),
+    // The shared counter can not be incremented anymore, keys have to be renewed
+    CounterExhausted,
+    // The commitment of the other device was already used, risk of a replay attack
+    ReplayedCommitment,
+    // The proof of the other device was not accepted
+    ProofRejected,
+    // The shared secret key with the device is out of sync and has to be renewed by a resync handshake
+    Desynchronized(DeviceId),
+    // The saved shared secret key and counter of the device are broken and can not be restored
+    CorruptedSharedState(DeviceId),
+    // The other device derived different session keys
+    KeyConfirmationFailed(DeviceId),
+    // Text or bytes are not a valid device ID
+    InvalidDeviceId(&'static str),
+    // Received bytes are not a valid protocol message
+    InvalidMessage(&'static str),
+    // Received message uses a version of the wire format that is not supported
+    UnsupportedVersion(u8),
+    // Record of the secure channel was replayed, reordered or lost
+    RecordOutOfOrder { expected: u64, found: u64 },
+    // Record of the secure channel could not be decrypted or was modified
+    RecordRejected,
+    // Access control errors
+    ResourceAlreadyExists(u32),
+    ResourceNotFound(u32),
+    // The resource cannot be a child of the parent, it would be its own parent
+    InvalidResourceParent(u32),
+    ActionAlreadyExists,
+    ActionNotFound,
+    DeviceNotFound(DeviceId),
+    RoleAlreadyExists(String),
+    RoleNotFound(String),
+    GroupAlreadyExists(String),
+    GroupNotFound(String),
+    // A condition of a grant is not valid
+    InvalidCondition(&'static str),
+    // A policy document cannot be read or is not consistent
+    InvalidPolicy(String),
+    // The other device did not answer in time
+    Timeout,
+    // The rate limiter refused the request of the device or address
+    RateLimited(Decision),
+    // The state directory could be changed by other users
+    UnsafeStateDir { path: PathBuf, reason: &'static str },
+    // An entry of the audit log was changed, removed or added afterwards
+    AuditLogTampered { entry: u64, reason: &'static str },
+    // Reading or writing a file failed
+    Io(io::Error),
+    // Serializing or deserializing stored data failed
+    Serde(serde_json::Error),
+}
+
+impl fmt::Display for NizkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NizkError::KeyRing(e) => write!(f, "key storage error: {:?}", e),
+            NizkError::InvalidKeyLength { description, expected, found } => {
+                write!(f, "key {} has length {}, expected {}", description, found, expected)
+            },
+            NizkError::InvalidCurvePoint => write!(f, "bytes are not a valid point on the curve"),
+            NizkError::WrongState(reason) => write!(f, "wrong protocol state: {}", reason),
+            NizkError::CounterExhausted => write!(f, "shared counter exhausted, keys have to be renewed"),
+            NizkError::ReplayedCommitment => write!(f, "commitment was already used, risk of replay attack"),
+            NizkError::ProofRejected => write!(f, "proof was not accepted"),
+            NizkError::Desynchronized(id) => write!(f, "shared secret key with device {} is out of sync, resync needed", id),
+            NizkError::CorruptedSharedState(id) => write!(f, "shared state with device {} is corrupted, resync needed", id),
+            NizkError::KeyConfirmationFailed(id) => write!(f, "device {} derived different session keys", id),
+            NizkError::InvalidDeviceId(reason) => write!(f, "invalid device ID: {}", reason),
+            NizkError::InvalidMessage(reason) => write!(f, "invalid message: {}", reason),
+            NizkError::UnsupportedVersion(version) => write!(f, "unsupported wire format version {}", version),
+            NizkError::RecordOutOfOrder { expected, found } => write!(f, "expected record {}, received record {}", expected, found),
+            NizkError::RecordRejected => write!(f, "record could not be authenticated"),
+            NizkError::ResourceAlreadyExists(id) => write!(f, "resource {} already exists", id),
+            NizkError::ResourceNotFound(id) => write!(f, "resource {} does not exist", id),
+            NizkError::InvalidResourceParent(id) => write!(f, "resource {} cannot be the parent, the resources would form a cycle", id),
+            NizkError::ActionAlreadyExists => write!(f, "action already exists for this resource"),
+            NizkError::ActionNotFound => write!(f, "action does not exist for this resource"),
+            NizkError::DeviceNotFound(id) => write!(f, "device {} is not allowed for this action", id),
+            NizkError::RoleAlreadyExists(name) => write!(f, "role {} already exists", name),
+            NizkError::RoleNotFound(name) => write!(f, "role {} does not exist", name),
+            NizkError::GroupAlreadyExists(name) => write!(f, "group {} already exists", name),
+            NizkError::GroupNotFound(name) => write!(f, "group {} does not exist", name),
+            NizkError::InvalidCondition(reason) => write!(f, "invalid condition: {}", reason),
+            NizkError::InvalidPolicy(reason) => write!(f, "invalid policy: {}", reason),
+            NizkError::Timeout => write!(f, "other device did not answer in time"),
+            NizkError::RateLimited(decision) => write!(f, "request refused by the rate limiter: {:?}", decision),
+            NizkError::UnsafeStateDir { path, reason } => write!(f, "unsafe state directory {}: {}", path.display(), reason),
+            NizkError::AuditLogTampered { entry, reason } => write!(f, "audit log tampered at entry {}: {}", entry, reason),
+            NizkError::Io(e) => write!(f, "file error: {}", e),
+            NizkError::Serde(e) => write!(f, "serialization error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NizkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NizkError::Io(e) => Some(e),
+            NizkError::Serde(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SecretKeyErrors> for NizkError {
+    fn from(e: SecretKeyErrors) -> Self {
+        NizkError::KeyRing(e)
+    }
+}
+
+impl From<io::Error> for NizkError {
+    fn from(e: io::Error) -> Self {
+        NizkError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for NizkError {
+    fn from(e: serde_json::Error) -> Self {
+        NizkError::Serde(e)
+    }
+}
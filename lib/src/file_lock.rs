@@ -0,0 +1,60 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+// Advisory locks and atomic replacement of files with persistent state.
+//
+// The lock of a file is taken on a separate "<file>.lock", so the file itself can be replaced by a rename
+// while it is locked. Locks are flock locks on an own open file, so they exclude other threads of the
+// process as well as other processes. Files are replaced by writing and syncing "<file>.tmp" and renaming
+// it, then syncing the directory. Readers without the lock see either the old or the new content, never a
+// half-written file.
+
+// Exclusive lock of a file, released when dropped
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    // Wait until no other thread or process holds the lock of the file
+    pub(crate) fn acquire(path: &Path) -> io::Result<FileLock> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(with_suffix(path, ".lock"))?;
+        file.lock()?;
+        Ok(FileLock { _file: file })
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Replace the content of the file, only the user can read the new file.
+// The caller has to hold the lock of the file, the temporary file is shared
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    // Sync the directory as well, so the rename is not lost on a crash
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
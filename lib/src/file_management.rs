@@ -1,232 +1,164 @@
-use std::fs::{File, OpenOptions, Permissions};
-use std::io::{BufReader, BufWriter, Write};
-use std::io::prelude::*;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
-use serde_json::{Result, Value};
-use hex;
-use chrono::Utc;
-
-// Threshold for max key guesses
-const CONST_KEY_GUESS_THRESHOLD: u8 = 5;
-const CONST_MAX_AUTH_RATE: f64 = 0.01;      // 20 requests per 1000 ms
-const CONST_MIN_AUTH_RATE: f64 = 0.005;
-
-// File path of the used commitments list
-fn get_commitments_file_path(senderID: u32) -> String {
-    format!(".nizk-auth/mut_comm_{}.txt", senderID)
-}
+use std::time::{Duration, SystemTime};
+use crate::{commitment_store, config, rate_limit};
+use crate::rate_limit::{Decision, RejectionWindow};
+use crate::file_lock::{self, FileLock};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
 
 // File path of the intrusion detection data
-fn get_intrusion_file_path(senderID: u32) -> String {
-    format!(".nizk-auth/intrusion_data_{}.json", senderID)
+fn get_intrusion_file_path(senderID: &DeviceId) -> Result<PathBuf, NizkError> {
+    config::state_path(&format!("intrusion_data_{}.json", senderID.file_name()))
 }
 
-// Set the file permissions to 0o600, so that only the user can write to it
-fn shrink_file_permissions(path: String) {
-    let mut perms = Permissions::from_mode(0o600);
-    std::fs::set_permissions(&path, perms).expect("Failed to set file permissions");
-}
-
-fn create_parent_dirs(file_path: String) {
-    let path = Path::new(&file_path);
-
+// Create all parent directories for the file
+fn create_parent_dirs(path: &Path) -> Result<(), NizkError> {
     // Create parent directories if they don't already exist
     if let Some(parent) = path.parent() {
         if !parent.exists() {
-            if let Err(err) = std::fs::create_dir_all(parent) {
-                eprintln!("Failed to create parent directories: {}", err);
-            }
+            std::fs::create_dir_all(parent)?;
         }
     }
+    Ok(())
 }
 
 // Check if an old commitment is being reused again
-pub fn check_commitment(senderID: u32, commitment: [u8; 32]) -> bool {
-    // Get path instance
-    let file_path = get_commitments_file_path(senderID);
-    let file_path_copy = get_commitments_file_path(senderID);
-    let path = Path::new(&file_path);
-
-    // Convert bytes into string for comparision
-    let mut commitement_str = hex::encode(&commitment);
-
-    // Check if path already exists
-    if path.exists() {
-        // Open the file again for appending, in read mode as well
-        let file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .expect("Failed to open file!\n");
-
-        // Wrap the file in a buffered reader to read its contents
-        let mut commitment_exists = false;
-        let reader = BufReader::new(file);
-
-        // Compare lines
-        for line in reader.lines() {
-            let line_str = line.unwrap();
-            if line_str.eq(&commitement_str) {
-                println!("Commitment already exists in commitments list, Risk of Replay attack!\n");
-                commitment_exists = true;
-                break;
-            }
-        }
-
-        if !commitment_exists {
-            // Open the file again for appending, and get write buffer
-            let file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .open(path)
-                .expect("Failed to open file!\n");
-
-            let mut writer = BufWriter::new(file);
-
-            // Write Commitment into the file followed by a new line
-            writer.write_all(commitement_str.as_bytes()).expect("Failed to append commitment to the old file\n");
-            writer.write_all(b"\n").expect("Failed to write to file\n");
-
-            // Flush the writer to ensure all data is written to the file
-            writer.flush().expect("Failed to flush file\n");
-        }
-
-        return !commitment_exists;
-    }else {
-        // Create parent directories if they don't already exist
-        create_parent_dirs(get_commitments_file_path(senderID));
-
-        // Create file and set the file permissions so that only the user can write to it
-        let mut file = File::create(file_path).unwrap();
-        shrink_file_permissions(file_path_copy);
-
-        // Wrap the file in a buffered writer to improve performance
-        let mut writer = BufWriter::new(file);
-
-        // Write Commitment into the file followed by a new line
-        let comm_str: String = hex::encode(&commitment);
-        writer.write_all(comm_str.as_bytes()).expect("Failed to write commitment to the new file");
-        writer.write_all(b"\n").expect("Failed to write to file");
-        writer.flush().expect("Failed to flush file");
-
-        true
+pub fn check_commitment(senderID: &DeviceId, commitment: [u8; 32]) -> Result<bool, NizkError> {
+    let unused = commitment_store::check_and_insert(senderID, commitment)?;
+    if !unused {
+        println!("Commitment already exists in commitments list, Risk of Replay attack!\n");
     }
+    Ok(unused)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Intrusion data of a sender. Files of older versions with a rejection rate are read with empty windows
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct Intrusion {
     asym_counter: u8,
     sym_counter: u8,
-    start_timestamp: i64,
-    rejections: u16,
-    dos_attack: bool,
+    // Time the counters were last decreased
+    #[serde(default, alias = "start_timestamp")]
+    last_decay: i64,
+    // Rejected proofs of the sender in the sliding window of the rate limit policy
+    #[serde(default)]
+    window: RejectionWindow,
 }
 
-// Update the last intrusion system values
-pub fn manage_intrusion(senderID: u32, schnorr_proof: bool, mac_tag: bool) {
-    // Check if file exists and create file if it does not exist
-    let file_path = get_intrusion_file_path(senderID);
-    let path = Path::new(&file_path);
-    if !path.exists() {
-        println!("Path does not exist\n");
-        // Define Data
-        let (asym, sym) = get_counter_values(schnorr_proof, mac_tag);
-        let timestamp = Utc::now().timestamp_millis();
-        let intrusion = Intrusion {
-            asym_counter: asym,
-            sym_counter: sym,
-            start_timestamp: timestamp,
-            rejections: 1,
-            dos_attack: false,
-        };
-
-        // Convert Data to a JSON
-        let json_string = serde_json::to_string(&intrusion).unwrap();
-
-        // Create parent directories if they does not exist
-        let file_path = get_intrusion_file_path(senderID);
-        create_parent_dirs(file_path);
-
-        // Create File with json content
-        let file_path = get_intrusion_file_path(senderID);
-        let path = Path::new(&file_path);
-        let mut file = File::create(&path).unwrap();
-        file.write_all(json_string.as_bytes());
-
-        // Shrink file permissions
-        let file_path = get_intrusion_file_path(senderID);
-        shrink_file_permissions(file_path);
+impl Intrusion {
+    // Decrease both counters by one for every decay period since the last decrease
+    fn decay(&mut self, counter_decay: Duration, now: i64) {
+        let period = (counter_decay.as_millis() as i64).max(1);
+        let periods = (now - self.last_decay).max(0) / period;
+        if periods > 0 {
+            let steps = u8::try_from(periods).unwrap_or(u8::MAX);
+            self.asym_counter = self.asym_counter.saturating_sub(steps);
+            self.sym_counter = self.sym_counter.saturating_sub(steps);
+            self.last_decay += periods * period;
+        }
     }
-    // File exists! Read content and modify data
-    else {
-        // Get current timestamp
-        let timestamp = Utc::now().timestamp_millis();
-
-        // Open file and read content as Intrusion struct
-        let mut intrusion = read_intrusion_data(senderID);
-
-        // Calculate rejection rate
-        let rejection_rate: f64 = intrusion.rejections as f64 / (timestamp - intrusion.start_timestamp) as f64;
-        println!("Current rejection rate = {:?}\n", rejection_rate);
-        // println!("Rejection rate = {:?}", rejection_rate);
-        // println!("Max rate = {:?}", CONST_MAX_AUTH_RATE);
-        // println!("Min rate = {:?}", CONST_MIN_AUTH_RATE);
+}
 
-        // Dos
-        if (rejection_rate > CONST_MAX_AUTH_RATE) && (intrusion.rejections > 10) {
-            intrusion.dos_attack = true;
-            println!("Auth rejection rate is too high. Risk of DoS Attack!\n")
-        } else if rejection_rate < CONST_MIN_AUTH_RATE {
-            intrusion.rejections = 0;
-            intrusion.dos_attack = false;
-            intrusion.start_timestamp = timestamp;
-        }
+// Intrusion data of a sender after a rejected proof was counted
+pub(crate) struct Rejection {
+    pub(crate) asym_counter: u8,
+    pub(crate) sym_counter: u8,
+    pub(crate) decision: Decision,
+    // The rejection started a new block of the sender
+    pub(crate) new_block: bool,
+}
 
-        // Modify data
-        let (asym, sym) = get_counter_values(schnorr_proof, mac_tag);
-        intrusion.asym_counter = intrusion.asym_counter + asym;
-        intrusion.sym_counter = intrusion.sym_counter + sym;
-        intrusion.rejections = intrusion.rejections + 1;
+// Update the last intrusion system values. Returns the decision for the following requests of the sender
+pub fn manage_intrusion(senderID: &DeviceId, schnorr_proof: bool, mac_tag: bool) -> Result<Decision, NizkError> {
+    Ok(record_rejection(senderID, schnorr_proof, mac_tag)?.decision)
+}
 
-        // Convert to String and write it to file
-        let json_string = serde_json::to_string(&intrusion).unwrap();
-        let file_path = get_intrusion_file_path(senderID);
-        let path = Path::new(&file_path);
-        let mut file = File::create(&path).unwrap();
-        file.write_all(json_string.as_bytes());
+pub(crate) fn record_rejection(senderID: &DeviceId, schnorr_proof: bool, mac_tag: bool) -> Result<Rejection, NizkError> {
+    let file_path = get_intrusion_file_path(senderID)?;
+    let path = Path::new(&file_path);
+    create_parent_dirs(path)?;
+
+    // Other threads and processes may reject proofs of the same sender at the same time
+    let _lock = FileLock::acquire(path)?;
+    let now = rate_limit::to_millis(SystemTime::now());
+    let config = config::current();
+    let mut intrusion = if path.exists() {
+        read_intrusion_data(senderID)?
+    } else {
+        Intrusion { last_decay: now, ..Default::default() }
+    };
+    intrusion.decay(config.counter_decay, now);
+
+    // Count the rejection for the key guess counters and in the sliding window
+    let (asym, sym) = get_counter_values(schnorr_proof, mac_tag);
+    intrusion.asym_counter = intrusion.asym_counter.saturating_add(asym);
+    intrusion.sym_counter = intrusion.sym_counter.saturating_add(sym);
+    let was_blocked = !intrusion.window.decision(now).is_allowed();
+    let decision = intrusion.window.reject(&config.rate_limit, now);
+    if let Decision::Block(_) = decision {
+        println!("Auth rejection rate is too high. Risk of DoS Attack!\n")
     }
+
+    // Convert to String and write it to file
+    write_intrusion_data(senderID, &intrusion)?;
+    Ok(Rejection {
+        asym_counter: intrusion.asym_counter,
+        sym_counter: intrusion.sym_counter,
+        decision,
+        new_block: !was_blocked && !decision.is_allowed(),
+    })
 }
 
 // Check if a key is compromised or if a brute force attack is being conducted
-pub fn check_intrusion(senderID: u32) -> (bool, bool, bool) {
-    // Open file and read content as Intrusion struct
-    let mut intrusion = read_intrusion_data(senderID);
+pub fn check_intrusion(senderID: &DeviceId) -> Result<(bool, bool, bool), NizkError> {
+    let Some(intrusion) = current_intrusion(senderID)? else {
+        return Ok((false, false, false));
+    };
 
     // Check if a key is compromised
-    let mut asym_comp: bool = false;
-    let mut sym_comp: bool = false;
-    if intrusion.asym_counter > CONST_KEY_GUESS_THRESHOLD {
-        asym_comp = true;
-    }
-    if intrusion.sym_counter > CONST_KEY_GUESS_THRESHOLD {
-        sym_comp = true;
-    }
+    let threshold = config::current().key_guess_threshold;
+    let asym_comp = intrusion.asym_counter > threshold;
+    let sym_comp = intrusion.sym_counter > threshold;
+    let dos = !intrusion.window.decision(rate_limit::to_millis(SystemTime::now())).is_allowed();
 
     // Return verification result
-    (asym_comp, sym_comp, intrusion.dos_attack)
+    Ok((asym_comp, sym_comp, dos))
 }
 
-fn read_intrusion_data(senderID: u32) -> Intrusion {
+// Decision for the next request of the sender, Block while the sender is blocked for too many rejections
+pub fn sender_decision(senderID: &DeviceId) -> Result<Decision, NizkError> {
+    let now = rate_limit::to_millis(SystemTime::now());
+    Ok(current_intrusion(senderID)?.map_or(Decision::Allow, |intrusion| intrusion.window.decision(now)))
+}
+
+// Intrusion data of the sender with decayed counters, None if no proof of the sender was ever rejected
+fn current_intrusion(senderID: &DeviceId) -> Result<Option<Intrusion>, NizkError> {
+    let file_path = get_intrusion_file_path(senderID)?;
+    if !Path::new(&file_path).exists() {
+        return Ok(None);
+    }
+    let mut intrusion = read_intrusion_data(senderID)?;
+    intrusion.decay(config::current().counter_decay, rate_limit::to_millis(SystemTime::now()));
+    Ok(Some(intrusion))
+}
+
+fn read_intrusion_data(senderID: &DeviceId) -> Result<Intrusion, NizkError> {
     // Open file and read content as Intrusion struct
-    let file_path = get_intrusion_file_path(senderID);
-    let path = Path::new(&file_path);
-    let file = File::open(&path).unwrap();
+    let file_path = get_intrusion_file_path(senderID)?;
+    let file = File::open(file_path)?;
     let reader = BufReader::new(file);
-    let mut intrusion: Intrusion = serde_json::from_reader(reader).unwrap();
-    intrusion
+    let intrusion: Intrusion = serde_json::from_reader(reader)?;
+    Ok(intrusion)
+}
+
+// Save intrusion data as json. The caller holds the lock of the file
+fn write_intrusion_data(senderID: &DeviceId, intrusion: &Intrusion) -> Result<(), NizkError> {
+    let json_string = serde_json::to_string(intrusion)?;
+    let file_path = get_intrusion_file_path(senderID)?;
+    file_lock::write_atomic(&file_path, json_string.as_bytes())?;
+    Ok(())
 }
 
 // Return which counters to increment
@@ -246,27 +178,23 @@ fn get_counter_values(schnorr_proof: bool, mac_tag: bool) -> (u8, u8) {
 }
 
 // Init intrusion data
-pub fn init_data(senderID: u32) {
+pub fn init_data(senderID: &DeviceId) -> Result<(), NizkError> {
     // Check if file exists and create file if it does not exist
-    let file_path = get_intrusion_file_path(senderID);
+    let file_path = get_intrusion_file_path(senderID)?;
     let path = Path::new(&file_path);
+    let _lock = FileLock::acquire(path)?;
     if path.exists() {
         // Open file and read content as Intrusion struct
-        let mut intrusion = read_intrusion_data(senderID);
+        let mut intrusion = read_intrusion_data(senderID)?;
 
-        // Get current timestamp
-        let timestamp = Utc::now().timestamp_millis();
-        intrusion.start_timestamp = timestamp;
+        // Reset the counters and the window
+        intrusion.last_decay = rate_limit::to_millis(SystemTime::now());
         intrusion.asym_counter = 0;
         intrusion.sym_counter = 0;
-        intrusion.rejections = 0;
-        intrusion.dos_attack = false;
+        intrusion.window.reset();
 
         // Convert to String and write it to file
-        let json_string = serde_json::to_string(&intrusion).unwrap();
-        let file_path = get_intrusion_file_path(senderID);
-        let path = Path::new(&file_path);
-        let mut file = File::create(&path).unwrap();
-        file.write_all(json_string.as_bytes());
+        write_intrusion_data(senderID, &intrusion)?;
     }
-}
\ No newline at end of file
+    Ok(())
+}
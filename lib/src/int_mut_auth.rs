@@ -0,0 +1,245 @@
+use std::sync::Arc;
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::scalar::Scalar;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::secret_management::KeyStore;
+use crate::audit::{self, AuditEvent, ProofKind, ProofOutcome};
+use crate::{commitment_store, file_management, get_32byte_key, schnorr_identification, shared_state};
+
+// Interactive mutual authentication for secret key sharing.
+//
+// The protocol runs in four messages between an initiator A and a responder B:
+//   A -> B: Commitment
+//   B -> A: CommitmentAndChallenge
+//   A -> B: ChallengeAndResponse     (B verifies A)
+//   B -> A: Response                 (A verifies B)
+// Every state is its own type and each step consumes the previous state, so a step can not
+// be skipped or replayed. After a successful verification both sides save the same
+// shared secret key and shared counter ("SharedState:<a>:<b>") in their key store.
+//
+// The same handshake resynchronises two paired devices whose shared values diverged
+// (NizkError::Desynchronized). It only needs the long-term keys, so no new pairing is necessary.
+
+// First message, sent by the initiator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commitment {
+    pub commitment: [u8; 32],
+}
+
+// Second message, sent by the responder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitmentAndChallenge {
+    pub commitment: [u8; 32],
+    pub challenge: [u8; 32],
+}
+
+// Third message, sent by the initiator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChallengeAndResponse {
+    pub challenge: [u8; 32],
+    pub response: [u8; 32],
+}
+
+// Last message, sent by the responder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response {
+    pub response: [u8; 32],
+}
+
+// Initiator sent its commitment and waits for the commitment and challenge of the responder
+pub struct AwaitingCommitment;
+
+// Initiator sent its challenge and response and waits for the response of the responder
+pub struct AwaitingResponse {
+    recipient_commitment: [u8; 32],
+    my_challenge: Scalar,
+}
+
+// Responder sent its commitment and challenge and waits for the challenge and response of the initiator
+pub struct AwaitingChallengeResponse {
+    recipient_commitment: [u8; 32],
+    my_challenge: Scalar,
+}
+
+// Final state of both roles. The shared secret key and the shared counter are saved in the key store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
+}
+
+// Initiator of the interactive mutual authentication
+pub struct Initiator<S> {
+    store: Arc<dyn KeyStore>,
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
+    my_random_int: Scalar,
+    state: S,
+}
+
+// Responder of the interactive mutual authentication
+pub struct Responder<S> {
+    store: Arc<dyn KeyStore>,
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
+    my_random_int: Scalar,
+    state: S,
+}
+
+impl Initiator<AwaitingCommitment> {
+    // Start the protocol and return the commitment to send to the responder
+    pub fn start(store: Arc<dyn KeyStore>, sender_ID: DeviceId, recipient_ID: DeviceId) -> (Initiator<AwaitingCommitment>, Commitment) {
+        // Generate random secret scalar and Commitment
+        let my_random_int = schnorr_identification::generate_random_scalar();
+        let commitment = (my_random_int * ED25519_BASEPOINT_POINT).compress().to_bytes();
+
+        let initiator = Initiator {
+            store,
+            sender_ID,
+            recipient_ID,
+            my_random_int,
+            state: AwaitingCommitment,
+        };
+        (initiator, Commitment { commitment })
+    }
+
+    // Start a resync with an already paired device. Fails if the public key of the recipient is unknown
+    pub fn resync(store: Arc<dyn KeyStore>, sender_ID: DeviceId, recipient_ID: DeviceId) -> Result<(Initiator<AwaitingCommitment>, Commitment), NizkError> {
+        get_32byte_key(&*store, format!("PublicKey:{}", recipient_ID))?;
+        Ok(Initiator::start(store, sender_ID, recipient_ID))
+    }
+
+    // Add commitment and challenge of the responder and return own challenge and response
+    pub fn receive_commitment(self, message: CommitmentAndChallenge) -> Result<(Initiator<AwaitingResponse>, ChallengeAndResponse), NizkError> {
+        // Reject commitments that are not on the curve before doing anything else
+        schnorr_identification::bytes_to_edwards(&message.commitment)?;
+
+        // Generate own challenge and the response to the challenge of the responder
+        let challenge = schnorr_identification::generate_random_32bytes();
+        let recipient_challenge = Scalar::from_bytes_mod_order(message.challenge);
+        let response = gen_proof(&*self.store, &self.sender_ID, self.my_random_int, recipient_challenge)?;
+
+        let initiator = Initiator {
+            store: self.store,
+            sender_ID: self.sender_ID,
+            recipient_ID: self.recipient_ID,
+            my_random_int: self.my_random_int,
+            state: AwaitingResponse {
+                recipient_commitment: message.commitment,
+                my_challenge: Scalar::from_bytes_mod_order(challenge),
+            },
+        };
+        Ok((initiator, ChallengeAndResponse { challenge, response }))
+    }
+}
+
+impl Initiator<AwaitingResponse> {
+    // Verify the response of the responder and save the shared secret key
+    pub fn receive_response(self, message: Response) -> Result<Verified, NizkError> {
+        verify_and_share_key(&*self.store,
+                             &self.sender_ID,
+                             &self.recipient_ID,
+                             self.my_random_int,
+                             self.state.recipient_commitment,
+                             self.state.my_challenge,
+                             message.response)
+    }
+}
+
+impl Responder<AwaitingChallengeResponse> {
+    // Start the protocol with the commitment of the initiator and return own commitment and challenge
+    pub fn start(store: Arc<dyn KeyStore>, sender_ID: DeviceId, recipient_ID: DeviceId, message: Commitment) -> Result<(Responder<AwaitingChallengeResponse>, CommitmentAndChallenge), NizkError> {
+        // Reject commitments that are not on the curve
+        schnorr_identification::bytes_to_edwards(&message.commitment)?;
+
+        // Generate random secret scalar, Commitment and Challenge
+        let my_random_int = schnorr_identification::generate_random_scalar();
+        let commitment = (my_random_int * ED25519_BASEPOINT_POINT).compress().to_bytes();
+        let challenge = schnorr_identification::generate_random_32bytes();
+
+        let responder = Responder {
+            store,
+            sender_ID,
+            recipient_ID,
+            my_random_int,
+            state: AwaitingChallengeResponse {
+                recipient_commitment: message.commitment,
+                my_challenge: Scalar::from_bytes_mod_order(challenge),
+            },
+        };
+        Ok((responder, CommitmentAndChallenge { commitment, challenge }))
+    }
+
+    // Verify the proof of the initiator and, only if it is accepted, return own response
+    pub fn receive_challenge_response(self, message: ChallengeAndResponse) -> Result<(Verified, Response), NizkError> {
+        let verified = verify_and_share_key(&*self.store,
+                                            &self.sender_ID,
+                                            &self.recipient_ID,
+                                            self.my_random_int,
+                                            self.state.recipient_commitment,
+                                            self.state.my_challenge,
+                                            message.response)?;
+
+        // Generate own response to the challenge of the initiator
+        let recipient_challenge = Scalar::from_bytes_mod_order(message.challenge);
+        let response = gen_proof(&*self.store, &self.sender_ID, self.my_random_int, recipient_challenge)?;
+
+        Ok((verified, Response { response }))
+    }
+}
+
+// Generate the response to a challenge with the private key of the device
+fn gen_proof(store: &dyn KeyStore, my_ID: &DeviceId, my_random_int: Scalar, challenge: Scalar) -> Result<[u8; 32], NizkError> {
+    // Fetch secret key, necessary for the proof and convert it to Scalar type
+    let secret_key_bytes = get_32byte_key(store, format!("PrivateKey:{}", my_ID))?;
+    let secret_key_sc = Scalar::from_bytes_mod_order(secret_key_bytes);
+
+    // Generate Proof
+    Ok(schnorr_identification::generate_proof_response(my_random_int, secret_key_sc, challenge))
+}
+
+// Verify the proof of the other device and save the shared secret key if it is accepted
+fn verify_and_share_key(store: &dyn KeyStore, my_ID: &DeviceId, recipient_ID: &DeviceId, my_random_int: Scalar,
+                        recipient_commitment: [u8; 32], my_challenge: Scalar, recipient_response: [u8; 32]) -> Result<Verified, NizkError> {
+    let result = share_key(store, my_ID, recipient_ID, my_random_int, recipient_commitment, my_challenge, recipient_response);
+    audit::record(AuditEvent::ProofVerified {
+        kind: ProofKind::KeyAgreement,
+        device: my_ID.clone(),
+        peer: recipient_ID.clone(),
+        outcome: result.as_ref().map_or_else(ProofOutcome::of_error, |_| ProofOutcome::Accepted),
+    });
+    result
+}
+
+fn share_key(store: &dyn KeyStore, my_ID: &DeviceId, recipient_ID: &DeviceId, my_random_int: Scalar,
+             recipient_commitment: [u8; 32], my_challenge: Scalar, recipient_response: [u8; 32]) -> Result<Verified, NizkError> {
+    // Check if commitment is never used to protect against replay attacks
+    if !file_management::check_commitment(recipient_ID, recipient_commitment)? {
+        return Err(NizkError::ReplayedCommitment);
+    }
+
+    // Fetch Public Key of the recipient and verify proof
+    let key_bytes = get_32byte_key(store, format!("PublicKey:{}", recipient_ID))?;
+    let proof = (recipient_commitment, my_challenge, recipient_response);
+    if !schnorr_identification::verify_int_proof(key_bytes, proof)? {
+        return Err(NizkError::ProofRejected);
+    }
+
+    // Calculate shared secret key
+    let commitment = schnorr_identification::bytes_to_edwards(&recipient_commitment)?;
+    let shared_secret_key = (my_random_int * commitment).compress().to_bytes();
+
+    // Hash the shared secret key and save it with a new shared counter in the key store
+    let hashed_shared_secret = schnorr_identification::sha3_256(&shared_secret_key, None, None, None);
+    let state = shared_state::establish(store, my_ID, recipient_ID, hashed_shared_secret)?;
+    audit::record(AuditEvent::KeyAgreed { device: my_ID.clone(), peer: recipient_ID.clone(), epoch: state.epoch });
+
+    // Commitments of older keys may expire now
+    commitment_store::start_epoch(recipient_ID, state.epoch)?;
+
+    Ok(Verified {
+        sender_ID: my_ID.clone(),
+        recipient_ID: recipient_ID.clone(),
+    })
+}
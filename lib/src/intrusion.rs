@@ -0,0 +1,243 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::{audit, config, file_management, rate_limit, shared_state};
+use crate::audit::AuditEvent;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::file_lock::FileLock;
+use crate::rate_limit::Decision;
+use crate::secret_management::{KeyStore, SecretKeyErrors};
+
+// Events of the intrusion detection and the handlers that act on them.
+//
+// Every rejected proof is counted by file_management and reported as an IntrusionEvent to all installed
+// handlers. A proof with a valid Schnorr part but an unknown MAC tag means the private key of the peer
+// may be known to someone else, a valid MAC tag with an invalid Schnorr part means the same for the shared
+// secret key. When a counter passes NizkConfig::key_guess_threshold, the event names the compromised key.
+// A MAC tag that is not found within the lookahead window can also be a desynchronization of an honest
+// peer, it is counted all the same: the counters decay over time, and an honest peer resyncs after the
+// first Desynchronized error, so only a peer that keeps sending such proofs passes the threshold.
+// A rejection that blocks the peer is reported as a second event of kind Dos.
+//
+// Without installed handlers nothing happens besides the counting. The built-in handlers revoke the shared
+// key, quarantine the public key of the peer, force a new pairing or write the events as JSON lines.
+// A handler that fails is recorded in the audit log, the first failure is returned to the verifier of the proof.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntrusionKind {
+    // Neither the Schnorr proof nor the MAC tag were valid
+    InvalidProof,
+    // Valid Schnorr proof with a MAC tag that is not found within the lookahead window
+    MacOnlyFailure,
+    // Valid MAC tag with an invalid Schnorr proof
+    SchnorrOnlyFailure,
+    // Too many rejected proofs, the peer is blocked
+    Dos,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompromisedKey {
+    // Private key of the peer
+    Asymmetric,
+    // Shared secret key with the peer
+    Symmetric,
+}
+
+// Record of an event, serialized as JSON for log collectors
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IntrusionEvent {
+    // Milliseconds since the Unix epoch
+    pub time: i64,
+    pub kind: IntrusionKind,
+    // Device that rejected the proof
+    pub device: DeviceId,
+    // Device whose proof was rejected
+    pub peer: DeviceId,
+    pub asym_counter: u8,
+    pub sym_counter: u8,
+    // Key that counts as compromised from this event on
+    pub compromised: Option<CompromisedKey>,
+    // End of the block of the peer in milliseconds since the Unix epoch, for Dos events
+    pub blocked_until: Option<i64>,
+}
+
+impl IntrusionEvent {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("event can always be serialized")
+    }
+}
+
+// Called for every event. The key store is the one that verified the rejected proof
+pub trait IntrusionHandler: Send + Sync {
+    fn on_event(&self, store: &dyn KeyStore, event: &IntrusionEvent) -> Result<(), NizkError>;
+}
+
+// Installed handlers, called in the order they were added
+static HANDLERS: RwLock<Vec<Arc<dyn IntrusionHandler>>> = RwLock::new(Vec::new());
+
+pub fn add_handler(handler: Arc<dyn IntrusionHandler>) {
+    HANDLERS.write().unwrap_or_else(PoisonError::into_inner).push(handler);
+}
+
+pub fn clear_handlers() {
+    HANDLERS.write().unwrap_or_else(PoisonError::into_inner).clear();
+}
+
+// Count a rejected proof and report it. All handlers are called, the first error is returned and all of
+// them are recorded in the audit log
+pub(crate) fn report_rejection(store: &dyn KeyStore, my_ID: &DeviceId, sender_ID: &DeviceId, schnorr: bool, mac: bool) -> Result<(), NizkError> {
+    let rejection = file_management::record_rejection(sender_ID, schnorr, mac)?;
+
+    // A key is reported once, by the event that moves its counter past the threshold
+    let passed = config::current().key_guess_threshold.saturating_add(1);
+    let (kind, compromised) = match (schnorr, mac) {
+        (false, false) => (IntrusionKind::InvalidProof, None),
+        (false, true) => (IntrusionKind::SchnorrOnlyFailure, (rejection.sym_counter == passed).then_some(CompromisedKey::Symmetric)),
+        (true, _) => (IntrusionKind::MacOnlyFailure, (rejection.asym_counter == passed).then_some(CompromisedKey::Asymmetric)),
+    };
+
+    let event = IntrusionEvent {
+        time: rate_limit::to_millis(SystemTime::now()),
+        kind,
+        device: my_ID.clone(),
+        peer: sender_ID.clone(),
+        asym_counter: rejection.asym_counter,
+        sym_counter: rejection.sym_counter,
+        compromised,
+        blocked_until: None,
+    };
+    let mut events = vec![event.clone()];
+    if let (true, Decision::Block(until)) = (rejection.new_block, rejection.decision) {
+        events.push(IntrusionEvent {
+            kind: IntrusionKind::Dos,
+            compromised: None,
+            blocked_until: Some(rate_limit::to_millis(until)),
+            ..event
+        });
+    }
+    dispatch(store, &events)
+}
+
+fn dispatch(store: &dyn KeyStore, events: &[IntrusionEvent]) -> Result<(), NizkError> {
+    // Handlers may install other handlers, so they are called without holding the lock
+    let handlers = HANDLERS.read().unwrap_or_else(PoisonError::into_inner).clone();
+    let mut result = Ok(());
+    for event in events {
+        for handler in &handlers {
+            if let Err(e) = handler.on_event(store, event) {
+                audit::record(AuditEvent::IntrusionHandlerFailed {
+                    kind: event.kind,
+                    device: event.device.clone(),
+                    peer: event.peer.clone(),
+                    error: e.to_string(),
+                });
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    result
+}
+
+fn quarantine_description(peer: &DeviceId) -> String {
+    format!("Quarantine:PublicKey:{}", peer)
+}
+
+// Move the public key of the peer out of use, proofs of the peer are not accepted until it is released.
+// The key is kept as "Quarantine:PublicKey:<id>" for the analysis
+pub fn quarantine_public_key(store: &dyn KeyStore, peer: &DeviceId) -> Result<(), NizkError> {
+    let description = format!("PublicKey:{}", peer);
+    let key = match store.get(&description) {
+        Ok(key) => key,
+        Err(SecretKeyErrors::KeyNotFound(_)) => return Ok(()),
+        Err(e) => return Err(NizkError::KeyRing(e)),
+    };
+    store.put(&quarantine_description(peer), &key)?;
+    store.delete(&description)?;
+    Ok(())
+}
+
+// Use the quarantined public key of the peer again
+pub fn release_public_key(store: &dyn KeyStore, peer: &DeviceId) -> Result<(), NizkError> {
+    let description = quarantine_description(peer);
+    let key = store.get(&description)?;
+    store.put(&format!("PublicKey:{}", peer), &key)?;
+    store.delete(&description)?;
+    Ok(())
+}
+
+// Delete the shared secret key with the peer once it is compromised
+#[derive(Debug, Clone, Default)]
+pub struct RevokeSharedKey;
+
+impl IntrusionHandler for RevokeSharedKey {
+    fn on_event(&self, store: &dyn KeyStore, event: &IntrusionEvent) -> Result<(), NizkError> {
+        if event.compromised == Some(CompromisedKey::Symmetric) {
+            shared_state::revoke(store, &event.device, &event.peer)?;
+        }
+        Ok(())
+    }
+}
+
+// Quarantine the public key of the peer once its private key is compromised
+#[derive(Debug, Clone, Default)]
+pub struct QuarantinePublicKey;
+
+impl IntrusionHandler for QuarantinePublicKey {
+    fn on_event(&self, store: &dyn KeyStore, event: &IntrusionEvent) -> Result<(), NizkError> {
+        if event.compromised == Some(CompromisedKey::Asymmetric) {
+            quarantine_public_key(store, &event.peer)?;
+        }
+        Ok(())
+    }
+}
+
+// Once any key is compromised, remove all keys of the peer so it has to be paired again with new public
+// keys and a new shared key. The counters of the peer start again at zero
+#[derive(Debug, Clone, Default)]
+pub struct ForceRepairing;
+
+impl IntrusionHandler for ForceRepairing {
+    fn on_event(&self, store: &dyn KeyStore, event: &IntrusionEvent) -> Result<(), NizkError> {
+        if event.compromised.is_some() {
+            shared_state::revoke(store, &event.device, &event.peer)?;
+            quarantine_public_key(store, &event.peer)?;
+            file_management::init_data(&event.peer)?;
+        }
+        Ok(())
+    }
+}
+
+// Append every event as one JSON line to a file. A relative path is taken below the state directory
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    pub path: PathBuf,
+}
+
+impl EventLog {
+    pub fn new<P: Into<PathBuf>>(path: P) -> EventLog {
+        EventLog { path: path.into() }
+    }
+}
+
+impl IntrusionHandler for EventLog {
+    fn on_event(&self, _store: &dyn KeyStore, event: &IntrusionEvent) -> Result<(), NizkError> {
+        let config = config::current();
+        config.prepare_state_dir()?;
+        let path = config.state_dir.join(&self.path);
+
+        // Lines of other processes are not interleaved
+        let _lock = FileLock::acquire(&path)?;
+        let mut file = OpenOptions::new().append(true).create(true).mode(0o600).open(&path)?;
+        file.write_all(format!("{}\n", event.to_json()).as_bytes())?;
+        Ok(())
+    }
+}
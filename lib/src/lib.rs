@@ -1,53 +1,75 @@
-mod secret_management;
+#![allow(non_snake_case)]
 mod schnorr_identification;
 use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
-use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::scalar::Scalar;
-use std::thread;
+use std::sync::Arc;
 use crate::secret_management::MyKey;
+use crate::schnorr_identification::Transcript;
+use crate::session::SessionTranscript;
+use crate::audit::ProofKind;
+pub mod error;
+pub mod config;
+mod file_lock;
+pub mod device_id;
+pub mod secret_management;
+pub mod int_mut_auth;
 pub mod file_management;
+pub mod commitment_store;
 pub mod access_control;
-
-// Constants for defining a role of a protocol initiator or a receiver.
-pub const CONST_INITIATOR_ROLE: u8 = 0;
-pub const CONST_RECEIVER_ROLE: u8 = 1;
-
-// Const for defining protocol stages
-const CONST_NEXT_STEP_REQUIRED: u8 = 0;
-const CONST_COMMITMENT: u8 = 1;
-const CONST_COMMITMENT_AND_CHALLENGE: u8 = 2;
-const CONST_CHALLENGE_AND_RESPONSE: u8 = 3;
-const CONST_RESPONSE: u8 = 4;
-
-// For knowing that the response can be verified or not
-pub const CONST_RESPONSE_CANNOT_BE_VERIFIED: u8 = 0;
-pub const CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE: u8 = 1;
-pub const CONST_RESPONSE_CAN_BE_VERIFIED: u8 = 2;
-pub const CONST_RECEIVED_WRONG_REQUEST_ID: u8 = 3;
-
-// For checking if no other values needs to be generated
-pub const CONST_NO_OTHER_VALUES_TO_GENERATE: u8 = 0;
-pub const CONST_NEXT_VALUES_HAS_TO_BE_GENERATED: u8 = 1;
-
-// Return an instance of MyKey of the key corresponding to the key description
-pub fn get_key_instance(key_description: &str, key_size: usize, key: Option<Vec<u8>>) -> Result<secret_management::MyKey, secret_management::SecretKeyErrors> {
-    let my_key = secret_management::MyKey::new(key_description, 32, key);
-    my_key
+pub mod roles;
+pub mod conditions;
+pub mod policy;
+pub mod rate_limit;
+pub mod intrusion;
+pub mod audit;
+pub mod wire;
+pub mod shared_state;
+pub mod batch;
+pub mod session;
+pub mod channel;
+pub mod engine;
+#[cfg(feature = "tokio")]
+pub mod transport;
+pub use crate::error::NizkError;
+pub use crate::device_id::DeviceId;
+pub use crate::config::{KeyStoreChoice, NizkConfig};
+pub use crate::rate_limit::{Decision, RateLimitPolicy, RateLimiter, Subject};
+pub use crate::intrusion::{IntrusionEvent, IntrusionHandler, IntrusionKind};
+pub use crate::audit::{AuditEvent, AuditLog};
+pub use crate::secret_management::{SecretKeyErrors, KeyStore, KeyUtilsStore, MemoryStore, EncryptedFileStore};
+pub use crate::batch::{verify_nizk_proof_batch, BatchItem};
+pub use crate::session::{KeyConfirmation, SessionKeys, SessionState};
+pub use crate::channel::SecureChannel;
+pub use crate::engine::{Engine, Event};
+
+// NIZK proof values as (commitment, challenge, response)
+pub type NizkProof = ([u8; 32], [u8; 32], [u8; 32]);
+
+// Default number of lost proofs the verifier skips to find the shared counter of the sender, see NizkConfig
+pub const LOOKAHEAD_WINDOW: u32 = 16;
+
+// How the challenge of a NIZK proof is generated. Both modes use the same proof values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NizkMode<'a> {
+    // KMAC Tag with the shared secret key of two paired devices. Shared key and counter are ratcheted if update_keys is set
+    SharedKey { update_keys: bool },
+    // Fiat-Shamir hash of public key, commitment, message and context. Only the public key of the sender
+    // is needed to verify, so it can be used for broadcasts and unpaired devices. The recipient ID is not used
+    PublicKey { context: &'a [u8] },
 }
 
-pub fn get_int_mut_auth_instance(sender_ID: u32, recipient_ID: u32, role: u8) -> IntMutAuth {
-    let mut ins = IntMutAuth::new(sender_ID, recipient_ID, role);
-    ins
+// Return an instance of MyKey of the key corresponding to the key description
+pub fn get_key_instance(key_description: &str, key_size: usize, key: Option<Vec<u8>>) -> Result<MyKey, NizkError> {
+    let my_key = MyKey::new(key_description, key_size, key)?;
+    Ok(my_key)
 }
 
-pub fn get_int_schnorr_prover_instance(my_ID: u32, recipient_ID: u32) -> IntSchnorrProver {
-    let mut ins = IntSchnorrProver::new(my_ID, recipient_ID);
-    ins
+pub fn get_int_schnorr_prover_instance(store: Arc<dyn KeyStore>, my_ID: DeviceId, recipient_ID: DeviceId) -> IntSchnorrProver {
+    IntSchnorrProver::new(store, my_ID, recipient_ID)
 }
 
-pub fn get_int_schnorr_verifier_instance(my_ID: u32, sender_ID: u32, commitment: [u8; 32]) -> IntSchnorrVerifier {
-    let mut ins = IntSchnorrVerifier::new(my_ID, sender_ID, commitment);
-    ins
+pub fn get_int_schnorr_verifier_instance(store: Arc<dyn KeyStore>, my_ID: DeviceId, sender_ID: DeviceId, commitment: [u8; 32]) -> IntSchnorrVerifier {
+    IntSchnorrVerifier::new(store, my_ID, sender_ID, commitment)
 }
 
 // Generate a random 32-byte value
@@ -57,222 +79,14 @@ pub fn generate_random_32bytes() -> [u8; 32] {
 
 // Return Public and private key
 pub fn gen_random_key_pair() -> ([u8; 32], [u8; 32]) {
-    // Generate private key as a 32-byte randon value and as type Scalar
-    let random_bytes = generate_random_32bytes();
-    let private_key = Scalar::from_bytes_mod_order(random_bytes);
-    // let private_key = Scalar::from_bytes_mod_order(<[u8; 32]>::try_from(random_bytes).unwrap());
-
-    // Calculate public key using ED25519_BASEPOINT_POINT
-    let public_key = (private_key * &ED25519_BASEPOINT_POINT).compress().to_bytes();
-
-    // Return Public and Private Key pair
-    (public_key, private_key.to_bytes())
-}
-
-// Struct for interactive mutual authentication for secret key sharing
-pub struct IntMutAuth {
-    pub sender_ID: u32,
-    pub recipient_ID: u32,
-    pub role: u8,
-    stage: u8,
-    my_random_int: Scalar,
-    my_commitment: [u8; 32],
-    my_challenge: Scalar,
-    my_response: [u8; 32],
-    recipient_commitment: [u8; 32],
-    recipient_challenge: Scalar,
-    recipient_response: [u8; 32],
-}
-
-impl IntMutAuth {
-    // Create a new instance of Int_mut_auth
-    pub fn new(sender_ID: u32, recipient_ID: u32, role: u8) -> IntMutAuth {
-        // Generate random secret scalar and Commitment
-        let my_random_int = schnorr_identification::generate_random_scalar();
-        let my_commitment = (my_random_int * &ED25519_BASEPOINT_POINT).compress().to_bytes();
-
-        // Init protocol variables
-        let my_challenge = Scalar::from_bytes_mod_order([0u8; 32]);
-        let my_response = [0u8; 32];
-        let recipient_commitment = [0u8; 32];
-        let recipient_challenge = Scalar::from_bytes_mod_order([0u8; 32]);
-        let recipient_response = [0u8; 32];
-
-        // Define the request type
-        let mut stage =  CONST_COMMITMENT;
-        if role == CONST_RECEIVER_ROLE {
-            stage = CONST_COMMITMENT_AND_CHALLENGE;
-        }
-
-        // Genrate Instance of interactive mutual authentication struct
-        let mut int_mut_auth = IntMutAuth {
-            sender_ID,
-            recipient_ID,
-            role,
-            stage,
-            my_random_int,
-            my_commitment,
-            my_challenge,
-            my_response,
-            recipient_commitment,
-            recipient_challenge,
-            recipient_response,
-        };
-
-        // Return
-        int_mut_auth
-    }
-
-    // Add Recipient Commitment
-    pub fn add_recipient_values(&mut self, request_type: u8, val1: [u8; 32], val2: Option<[u8; 32]>) -> u8 {
-        match request_type {
-            CONST_NEXT_STEP_REQUIRED => {
-                CONST_RESPONSE_CANNOT_BE_VERIFIED
-            },
-            CONST_COMMITMENT => {
-                self.recipient_commitment = val1;
-                CONST_RESPONSE_CANNOT_BE_VERIFIED
-            },
-            CONST_COMMITMENT_AND_CHALLENGE => {
-                self.recipient_commitment = val1;
-                self.recipient_challenge = Scalar::from_bytes_mod_order(val2.unwrap());
-                CONST_RESPONSE_CANNOT_BE_VERIFIED
-            },
-            CONST_CHALLENGE_AND_RESPONSE => {
-                self.recipient_challenge = Scalar::from_bytes_mod_order(val1);
-                self.recipient_response = val2.unwrap();
-                CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE
-            },
-            CONST_RESPONSE => {
-                self.recipient_response = val1;
-                CONST_RESPONSE_CAN_BE_VERIFIED
-            },
-            _ => {
-                CONST_RECEIVED_WRONG_REQUEST_ID
-            },
-        }
-    }
-
-    // Generate challenge
-    pub fn gen_next_values(&mut self) -> ([u8; 32], Option<[u8; 32]>, u8) {
-        match self.stage {
-            CONST_COMMITMENT => {
-                // Define next stage and return Commitment
-                self.stage = CONST_CHALLENGE_AND_RESPONSE;
-                (self.my_commitment, None, CONST_COMMITMENT)
-            },
-            CONST_COMMITMENT_AND_CHALLENGE => {
-                // Generate Challenge
-                let challenge = schnorr_identification::generate_random_32bytes();
-                self.my_challenge = Scalar::from_bytes_mod_order(challenge);
-
-                // Define next stage and return commitment and challenge
-                self.stage = CONST_RESPONSE;
-                (self.my_commitment, Some(challenge), CONST_COMMITMENT_AND_CHALLENGE)
-            },
-            CONST_CHALLENGE_AND_RESPONSE => {
-                // Generate Challenge
-                let challenge = schnorr_identification::generate_random_32bytes();
-                self.my_challenge = Scalar::from_bytes_mod_order(challenge);
-
-                // Calculate response
-                let response = self.gen_proof();
-                self.my_response = response;
-                (challenge, Some(response), CONST_CHALLENGE_AND_RESPONSE)
-            },
-            CONST_RESPONSE => {
-                let response = self.gen_proof();
-                self.my_response = response;
-                (response, None, CONST_RESPONSE)
-            },
-            _ => {
-                (self.my_commitment, None, CONST_NEXT_STEP_REQUIRED)
-            },
-        }
-    }
-
-    // Generate Proof
-    fn gen_proof(&self) -> [u8; 32] {
-        // Fetch secret key, necessary for the proof and convert it to Scalar type
-        let desciption = format!("PrivateKey:{}", &self.sender_ID);
-        let my_secret_key = get_key_instance(&desciption, 32, None).unwrap();
-        let key: &[u8] = my_secret_key.get_key();
-        let secret_key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
-        let secret_key_sc = Scalar::from_bytes_mod_order(secret_key_bytes);
-
-        // Generate Proof
-        let proof = schnorr_identification::generate_proof_response(self.my_random_int,
-                                                                    secret_key_sc,
-                                                                    self.recipient_challenge);
-        // Return Proof
-        proof
-    }
-
-    // Verify proof
-    pub fn verify_proof(&self) -> bool {
-        // Check if commitment is never used to protect against replay attacks
-        if !file_management::check_commitment(self.recipient_ID, self.recipient_commitment) {
-            return false;
-        }
-
-        // Fetch Public Key of the recipient
-        let desciption = format!("PublicKey:{}", &self.recipient_ID);
-        let recipient_pubkey = get_key_instance(&desciption, 32, None).unwrap();
-
-        // Convert Key into [u8; 32] format
-        let key: &[u8] = recipient_pubkey.get_key();
-        let key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
-
-        // Verify proof
-        let proof = (self.recipient_commitment, self.my_challenge, self.recipient_response);
-        let accepted = schnorr_identification::verify_int_proof(key_bytes, proof);
-
-        // Calculate the shared secret key
-        if accepted == true {
-            self.calculate_shared_secret_key();
-        }
-
-        // Return verification results
-        accepted
-    }
-
-    fn calculate_shared_secret_key(&self) {
-        // Calculate shared secret key
-        let commitment = schnorr_identification::bytes_to_edwards(&self.recipient_commitment);
-        let shared_secret_key = (self.my_random_int * commitment).compress().to_bytes();
-
-        // Hash the shared secret key
-        let hashed_shared_Secret = schnorr_identification::sha3_256(&shared_secret_key, None, None, None);
-        let key_vec = Vec::from(hashed_shared_Secret);
-        let key_vec_copy = Vec::from(hashed_shared_Secret);
-
-        // Save the shared key in the OS
-        let desciption = format!("SharedSecretKey:{}:{}",&self.sender_ID, &self.recipient_ID);
-        let mut mykey = get_key_instance(&desciption, 32, Some(key_vec)).unwrap();
-
-        // Check if key value was changed during initiation of Mykey instance or not. Change it if not
-        if mykey.get_key() != &key_vec_copy {
-            mykey.update_key_in_ring(key_vec_copy).unwrap();
-        }
-
-        // Initiate the shared counter and save it in the OS
-        let mut shared_counter: u32 = 1;
-        let shared_counter_vec = Vec::from(shared_counter.to_be_bytes());
-        let shared_counter_vec_copy = Vec::from(shared_counter.to_be_bytes());
-        let desciption = format!("SharedCounter:{}:{}",&self.sender_ID, &self.recipient_ID);
-        let mut mykey = get_key_instance(&desciption, 4, Some(shared_counter_vec)).unwrap();
-
-        // Check if key value was changed during initiation of Mykey instance or not. Change it if not
-        if mykey.get_key() != &shared_counter_vec_copy {
-            mykey.update_key_in_ring(shared_counter_vec_copy).unwrap();
-        }
-    }
+    schnorr_identification::key_gen()
 }
 
 // Struct for mutual authentication using the NIZKP
 pub struct NIZKMutAuth {
-    pub sender_ID: u32,
-    pub recipient_ID: u32,
+    store: Arc<dyn KeyStore>,
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
     initiator: bool,
     my_random_int: Scalar,
     my_commitment: [u8; 32],
@@ -281,34 +95,22 @@ pub struct NIZKMutAuth {
     recipient_commitment: [u8; 32],
     recipient_challenge: [u8; 32],
     recipient_response: [u8; 32],
+    shared_counter: u32,
     proof_accepted: bool,
 }
 
 impl NIZKMutAuth {
     // Create a new instance of Int_mut_auth
-    pub fn new(sender_ID: u32, recipient_ID: u32, sender_proof: Option<([u8; 32], [u8; 32], [u8; 32])>) -> (NIZKMutAuth, ([u8; 32], [u8; 32], [u8; 32])) {
+    pub fn new(store: Arc<dyn KeyStore>, sender_ID: DeviceId, recipient_ID: DeviceId, sender_proof: Option<NizkProof>) -> Result<(NIZKMutAuth, NizkProof), NizkError> {
         // Generate random secret scalar and Commitment
         let my_random_int = schnorr_identification::generate_random_scalar();
-        let my_commitment = (my_random_int * &ED25519_BASEPOINT_POINT).compress().to_bytes();
-
-        // Init variables
-        let mut initiator = true;
-        let mut recipient_commitment = [0u8; 32];
-        let mut recipient_challenge = [0u8; 32];
-        let mut recipient_response = [0u8; 32];
+        let my_commitment = (my_random_int * ED25519_BASEPOINT_POINT).compress().to_bytes();
 
         // Check if we have recipient proof or if we need to init the values
-        match sender_proof {
-            Some((commitment, challenge, response)) => {
-                initiator = false;
-                recipient_commitment = commitment;
-                recipient_challenge = challenge;
-                recipient_response = response;
-            },
-            None => {
-
-            }
-        }
+        let (initiator, (recipient_commitment, recipient_challenge, recipient_response)) = match sender_proof {
+            Some(proof) => (false, proof),
+            None => (true, ([0u8; 32], [0u8; 32], [0u8; 32])),
+        };
 
         // Init protocol variables
         let my_challenge = [0u8; 32];
@@ -317,6 +119,7 @@ impl NIZKMutAuth {
 
         // Genrate Instance of interactive mutual authentication struct
         let mut nizk_mut_auth = NIZKMutAuth {
+            store,
             sender_ID,
             recipient_ID,
             initiator,
@@ -327,41 +130,46 @@ impl NIZKMutAuth {
             recipient_commitment,
             recipient_challenge,
             recipient_response,
+            shared_counter: 0,
             proof_accepted,
         };
 
         // Generate NIZK proof
-        let (commitment, challenge, response) = nizk_mut_auth.nizk_proof();
+        let (commitment, challenge, response) = nizk_mut_auth.nizk_proof()?;
 
         // Return
-        (nizk_mut_auth, (commitment, challenge, response))
+        Ok((nizk_mut_auth, (commitment, challenge, response)))
     }
 
-    fn nizk_proof(&mut self) -> ([u8; 32], [u8; 32], [u8; 32]){
+    fn nizk_proof(&mut self) -> Result<NizkProof, NizkError> {
         // Fetch secret key and shared secret key
-        let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", self.sender_ID));
-        let (sharedkey, mut sk) = get_32byte_key(format!("SharedSecretKey:{}:{}", self.sender_ID, self.recipient_ID));
+        let privkey = get_32byte_key(&*self.store, format!("PrivateKey:{}", self.sender_ID))?;
 
-        // Fetch shared counter value
-        let (shared_counter, mut sc) = get_shared_counter(self.sender_ID, self.recipient_ID);
+        // Fetch shared secret key and shared counter value
+        let state = shared_state::load(&*self.store, &self.sender_ID, &self.recipient_ID)?;
 
         // Calculate proof
-        let (r, commitment, challenge, response) = schnorr_identification::nizk_proof(privkey,
-                                                                                      sharedkey,
-                                                                                      shared_counter,
-                                                                                      None);
+        let transcript = Transcript {
+            customization: schnorr_identification::KMAC_MUT_AUTH_PROOF,
+            sender_ID: &self.sender_ID,
+            recipient_ID: &self.recipient_ID,
+            shared_counter: state.counter.to_be_bytes(),
+            message: None,
+        };
+        let (r, commitment, challenge, response) = schnorr_identification::nizk_proof(privkey, state.key, &transcript);
 
         // Save values
+        self.shared_counter = state.counter;
         self.my_random_int = r;
         self.my_commitment = commitment;
         self.my_challenge = challenge;
         self.my_response = response;
 
-        (commitment, challenge, response)
+        Ok((commitment, challenge, response))
     }
 
     // Add proof values of recipient. This function should be called only by the initiator
-    pub fn add_recipient_values(&mut self, proof: ([u8; 32], [u8; 32], [u8; 32])) {
+    pub fn add_recipient_values(&mut self, proof: NizkProof) {
         let (commitment, challenge, response) = proof;
         self.recipient_commitment = commitment;
         self.recipient_challenge = challenge;
@@ -369,174 +177,273 @@ impl NIZKMutAuth {
     }
 
     // Verif recipient's proof
-    pub fn verify_proof(&mut self) -> bool {
+    pub fn verify_proof(&mut self) -> Result<bool, NizkError> {
+        let result = self.check_proof();
+        audit::record_proof(ProofKind::MutualAuth, &self.sender_ID, &self.recipient_ID, result)
+    }
+
+    fn check_proof(&mut self) -> Result<bool, NizkError> {
         // Fetch Public key of the sender, shared secret key, and shared counter
-        let (pubkey, _) = get_32byte_key(format!("PublicKey:{}", self.recipient_ID));
-        let (sharedkey, _) = get_32byte_key(format!("SharedSecretKey:{}:{}", self.sender_ID, self.recipient_ID));
-        let (shared_counter, _) = get_shared_counter(self.sender_ID, self.recipient_ID);
+        let pubkey = get_32byte_key(&*self.store, format!("PublicKey:{}", self.recipient_ID))?;
+        let state = shared_state::load(&*self.store, &self.sender_ID, &self.recipient_ID)?;
 
         // Verify proof
+        // The recipient generated the proof, so it is the sender of the transcript
+        let transcript = Transcript {
+            customization: schnorr_identification::KMAC_MUT_AUTH_PROOF,
+            sender_ID: &self.recipient_ID,
+            recipient_ID: &self.sender_ID,
+            shared_counter: state.counter.to_be_bytes(),
+            message: None,
+        };
         let (schnorr, mac) = schnorr_identification::verify_nizk_proof(pubkey,
-                                                                       sharedkey,
-                                                                       shared_counter,
-                                                                       None,
+                                                                       state.key,
+                                                                       &transcript,
                                                                        (self.recipient_commitment,
                                                                         self.recipient_challenge,
-                                                                        self.recipient_response));
+                                                                        self.recipient_response))?;
         // Save verification result
         let accepted = schnorr && mac;
         self.proof_accepted = accepted;
 
-        // Check intrusion
+        // Check intrusion. A valid Schnorr proof with a wrong MAC Tag is counted as well, the shared values
+        // diverged or the private key of the other device is used by someone else
         if !accepted {
-            file_management::manage_intrusion(self.recipient_ID, schnorr, mac);
+            intrusion::report_rejection(&*self.store, &self.sender_ID, &self.recipient_ID, schnorr, mac)?;
+        }
+        if schnorr && !mac {
+            return Err(NizkError::Desynchronized(self.recipient_ID.clone()));
         }
 
-        accepted
+        Ok(accepted)
     }
 
-    // Return session key
-    pub fn calculate_session_key(&self) -> [u8; 32] {
-        // Check if proof was accepted
-        if self.proof_accepted {
-            // Calculate shared session key
-            let commitment = schnorr_identification::bytes_to_edwards(&self.recipient_commitment);
-            let session_key = (self.my_random_int * commitment).compress().to_bytes();
-
-            // Hash the shared secret key
-            let hashed_session_key = schnorr_identification::sha3_256(&session_key, None, None, None);
-
-            // Update used values
-            if self.initiator {
-                update_used_values(self.sender_ID,
-                                   self.recipient_ID,
-                                   self.my_response,
-                                   Some(&self.recipient_response));
-            } else {
-                update_used_values(self.sender_ID,
-                                   self.recipient_ID,
-                                   self.recipient_response,
-                                   Some(&self.my_response));
-            }
+    // Derive the session keys. Consumes the instance, since the shared values are ratcheted afterwards
+    pub fn calculate_session_keys(self) -> Result<SessionKeys, NizkError> {
+        let (session_keys, _) = self.derive_session()?;
+        Ok(session_keys)
+    }
 
-            hashed_session_key
-        } else {
-            [0u8; 32]
+    // Derive the session keys and start the key confirmation round. The keys are only returned
+    // after the confirmation tag of the other device was verified
+    pub fn confirm_session_keys(self) -> Result<KeyConfirmation, NizkError> {
+        let initiator = self.initiator;
+        let (session_keys, transcript) = self.derive_session()?;
+        Ok(KeyConfirmation::new(session_keys, &transcript, initiator))
+    }
+
+    fn derive_session(self) -> Result<(SessionKeys, SessionTranscript), NizkError> {
+        // Check if proof was verified and accepted
+        if !self.proof_accepted {
+            return Err(NizkError::ProofRejected);
         }
+
+        // Calculate the ECDH point with the commitment of the recipient
+        let commitment = schnorr_identification::bytes_to_edwards(&self.recipient_commitment)?;
+        let ecdh_point = (self.my_random_int * commitment).compress().to_bytes();
+
+        // Derive the session keys, bound to the whole transcript
+        let transcript = if self.initiator {
+            SessionTranscript {
+                initiator_ID: self.sender_ID.clone(),
+                responder_ID: self.recipient_ID.clone(),
+                initiator_commitment: self.my_commitment,
+                responder_commitment: self.recipient_commitment,
+                shared_counter: self.shared_counter,
+            }
+        } else {
+            SessionTranscript {
+                initiator_ID: self.recipient_ID.clone(),
+                responder_ID: self.sender_ID.clone(),
+                initiator_commitment: self.recipient_commitment,
+                responder_commitment: self.my_commitment,
+                shared_counter: self.shared_counter,
+            }
+        };
+        let session_keys = SessionKeys::derive(ecdh_point, &transcript);
+
+        // Update used values
+        let _lock = shared_state::lock(&self.sender_ID, &self.recipient_ID)?;
+        update_used_values(&*self.store, &self.sender_ID, &self.recipient_ID, 1)?;
+        audit::record(AuditEvent::SessionEstablished { device: self.sender_ID.clone(), peer: self.recipient_ID.clone() });
+
+        Ok((session_keys, transcript))
     }
 }
 
-// Function to read shared counter from OS
-fn get_shared_counter(my_ID: u32, receiver_ID: u32) -> ([u8; 4], MyKey) {
-    // Fetch Counter from OS
-    let desciption = format!("SharedCounter:{}:{}", my_ID, receiver_ID);
-    let mut counter_instance = get_key_instance(&desciption, 4, None).unwrap();
-    let shared_counter: &[u8] = counter_instance.get_key();
-    let shared_counter_bytes: [u8; 4] = <[u8; 4]>::try_from(shared_counter).unwrap();
+// Fetch any 32 byte key from the key store
+fn get_32byte_key(store: &dyn KeyStore, description: String) -> Result<[u8; 32], NizkError> {
+    let key_vec = store.get(&description)?;
+    let key: [u8; 32] = <[u8; 32]>::try_from(key_vec.as_slice())
+        .map_err(|_| NizkError::InvalidKeyLength { description, expected: 32, found: key_vec.len() })?;
+
+    Ok(key)
+}
+
+// Generate a NIZK proof of a message in the given mode
+pub fn gen_proof(store: &dyn KeyStore, mode: NizkMode, my_ID: &DeviceId, receiver_ID: &DeviceId, message: &[u8]) -> Result<NizkProof, NizkError> {
+    match mode {
+        NizkMode::SharedKey { update_keys } => gen_shared_key_proof(store, my_ID, receiver_ID, message, update_keys),
+        NizkMode::PublicKey { context } => {
+            let privkey = get_32byte_key(store, format!("PrivateKey:{}", my_ID))?;
+            Ok(schnorr_identification::fs_proof(privkey, message, context))
+        },
+    }
+}
 
-    // Return counter
-    (shared_counter_bytes, counter_instance)
+// Verify a NIZK proof of a message in the given mode
+pub fn verify_proof(store: &dyn KeyStore, mode: NizkMode, my_ID: &DeviceId, sender_ID: &DeviceId, message: &[u8], proof: NizkProof) -> Result<bool, NizkError> {
+    match mode {
+        NizkMode::SharedKey { update_keys } => verify_shared_key_proof(store, my_ID, sender_ID, message, proof, update_keys),
+        NizkMode::PublicKey { context } => {
+            // Anyone can send a proof in the name of the sender, so rejections are not counted as intrusion
+            let result = get_32byte_key(store, format!("PublicKey:{}", sender_ID))
+                .and_then(|pubkey| schnorr_identification::verify_fs_proof(pubkey, message, context, proof));
+            audit::record_proof(ProofKind::PublicKey, my_ID, sender_ID, result)
+        },
+    }
 }
 
-// Fetch any 32 byte key from OS
-fn get_32byte_key(description: String) -> ([u8; 32], MyKey) {
-    let mut mykey = get_key_instance(&description, 32, None).unwrap();
-    let key_ins: &[u8] = mykey.get_key();
-    let key: [u8; 32] = <[u8; 32]>::try_from(key_ins).unwrap();
+pub fn gen_nizk_proof(store: &dyn KeyStore, my_ID: &DeviceId, receiver_ID: &DeviceId, message: String, update_keys: bool) -> Result<NizkProof, NizkError> {
+    gen_shared_key_proof(store, my_ID, receiver_ID, message.as_bytes(), update_keys)
+}
 
-    (key, mykey)
+pub fn verify_nizk_proof(store: &dyn KeyStore, my_ID: &DeviceId, sender_ID: &DeviceId, message: String, proof: NizkProof, update_keys: bool) -> Result<bool, NizkError> {
+    verify_shared_key_proof(store, my_ID, sender_ID, message.as_bytes(), proof, update_keys)
 }
 
-pub fn gen_nizk_proof(my_ID: u32, receiver_ID: u32, message: String, update_keys: bool) -> ([u8; 32], [u8; 32], [u8; 32]) {
+fn gen_shared_key_proof(store: &dyn KeyStore, my_ID: &DeviceId, receiver_ID: &DeviceId, message: &[u8], update_keys: bool) -> Result<NizkProof, NizkError> {
     // Fetch secret key and shared secret key
-    let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", my_ID));
-    let (sharedkey, mut sk) = get_32byte_key(format!("SharedSecretKey:{}:{}", my_ID, receiver_ID));
+    let privkey = get_32byte_key(store, format!("PrivateKey:{}", my_ID))?;
 
-    // Fetch shared counter value
-    let (shared_counter, mut sc) = get_shared_counter(my_ID, receiver_ID);
+    // Fetch shared secret key and shared counter value. Locked until the update, so no counter is used twice
+    let _lock = update_keys.then(|| shared_state::lock(my_ID, receiver_ID)).transpose()?;
+    let state = shared_state::load(store, my_ID, receiver_ID)?;
 
     // Generate proof
-    let (_, commitment, challenge, response) = schnorr_identification::nizk_proof(privkey,
-                                                                                  sharedkey,
-                                                                                  shared_counter,
-                                                                                  Some(message.as_bytes()));
+    let transcript = Transcript {
+        customization: schnorr_identification::KMAC_NIZK_PROOF,
+        sender_ID: my_ID,
+        recipient_ID: receiver_ID,
+        shared_counter: state.counter.to_be_bytes(),
+        message: Some(message),
+    };
+    let (_, commitment, challenge, response) = schnorr_identification::nizk_proof(privkey, state.key, &transcript);
+
     // Update shared counter and shared secret key
     if update_keys {
-        update_used_values(my_ID, receiver_ID, response, None);
+        update_used_values(store, my_ID, receiver_ID, 1)?;
     }
 
     // Return NIZK Proof
-    (commitment, challenge, response)
+    Ok((commitment, challenge, response))
 }
 
-pub fn verify_nizk_proof(my_ID: u32, sender_ID: u32, message: String, proof: ([u8; 32], [u8; 32], [u8; 32]), update_keys: bool) -> bool {
-    // Fetch Public key of the sender, shared secret key, and shared counter
-    let (pubkey, _) = get_32byte_key(format!("PublicKey:{}", sender_ID));
-    let (sharedkey, _) = get_32byte_key(format!("SharedSecretKey:{}:{}", my_ID, sender_ID));
-    let (shared_counter, _) = get_shared_counter(my_ID, sender_ID);
-
-    // Get the commitment and the challenge response
-    let (commitment, challenge, response) = proof;
-    let (schnorr, mac) = schnorr_identification::verify_nizk_proof(pubkey,
-                                                                   sharedkey,
-                                                                   shared_counter,
-                                                                   Some(message.as_bytes()),
-                                                                   proof);
-    // Update shared values if proof was accepted
-    let accepted = schnorr && mac;
-    if accepted == true {
-        if update_keys {
-            update_used_values(my_ID, sender_ID, response, None);
-        }
-    } else {
-        // Check intrusion
-        file_management::manage_intrusion(sender_ID, schnorr, mac);
+fn verify_shared_key_proof(store: &dyn KeyStore, my_ID: &DeviceId, sender_ID: &DeviceId, message: &[u8], proof: NizkProof, update_keys: bool) -> Result<bool, NizkError> {
+    let result = check_shared_key_proof(store, my_ID, sender_ID, message, proof, update_keys);
+    audit::record_proof(ProofKind::SharedKey, my_ID, sender_ID, result)
+}
+
+fn check_shared_key_proof(store: &dyn KeyStore, my_ID: &DeviceId, sender_ID: &DeviceId, message: &[u8], proof: NizkProof, update_keys: bool) -> Result<bool, NizkError> {
+    // Fetch Public key of the sender, shared secret key, and shared counter.
+    // Locked until the update, so a proof is not accepted twice by concurrent verifications
+    let pubkey = get_32byte_key(store, format!("PublicKey:{}", sender_ID))?;
+    let _lock = update_keys.then(|| shared_state::lock(my_ID, sender_ID)).transpose()?;
+    let state = shared_state::load(store, my_ID, sender_ID)?;
+    let (mut sharedkey, mut counter_value) = (state.key, state.counter);
+
+    let mut transcript = Transcript {
+        customization: schnorr_identification::KMAC_NIZK_PROOF,
+        sender_ID,
+        recipient_ID: my_ID,
+        shared_counter: counter_value.to_be_bytes(),
+        message: Some(message),
+    };
+    let (schnorr, mac) = schnorr_identification::verify_nizk_proof(pubkey, sharedkey, &transcript, proof)?;
+
+    // Check intrusion if the Schnorr proof is not valid
+    if !schnorr {
+        intrusion::report_rejection(store, my_ID, sender_ID, schnorr, mac)?;
+        return Ok(false);
+    }
+
+    // The Schnorr proof is valid but the MAC Tag may use a newer counter, if proofs of the sender were lost.
+    // Look ahead a bounded number of ratchet steps to find it
+    let (commitment, challenge, _) = proof;
+    let mut steps = 1;
+    let mut found = mac;
+    let lookahead_window = config::current().lookahead_window;
+    while !found && steps <= lookahead_window {
+        (sharedkey, counter_value) = ratchet_shared_key(sharedkey, counter_value)?;
+        transcript.shared_counter = counter_value.to_be_bytes();
+        found = schnorr_identification::verify_challenge(sharedkey, commitment, challenge, &transcript);
+        steps += 1;
+    }
+
+    // The shared values of both devices diverged, or someone else uses the private key of the sender.
+    // Counted towards the private key, so repeated proofs of this kind are detected and rate limited
+    if !found {
+        intrusion::report_rejection(store, my_ID, sender_ID, true, false)?;
+        return Err(NizkError::Desynchronized(sender_ID.clone()));
+    }
+
+    // Update shared values after all skipped steps and the used one
+    if update_keys {
+        update_used_values(store, my_ID, sender_ID, steps)?;
     }
 
     // Return verification result
-    accepted
+    Ok(true)
 }
 
-// Update counter and secret key after each use
-fn update_used_values(my_ID: u32, other_ID: u32, response: [u8; 32], additional_data: Option<&[u8]>) {
-    // Fetch shared secret key and shared counter value
-    let (sharedkey, mut sharedkey_ins) = get_32byte_key(format!("SharedSecretKey:{}:{}", my_ID, other_ID));
-    let (shared_counter, mut shared_counter_ins) = get_shared_counter(my_ID, other_ID);
+// Ratchet the shared secret key one step forward, the new key only depends on the old key and the counter
+fn ratchet_shared_key(sharedkey: [u8; 32], counter_value: u32) -> Result<([u8; 32], u32), NizkError> {
+    let counter_value = counter_value.checked_add(1).ok_or(NizkError::CounterExhausted)?;
+    let new_key = schnorr_identification::kmac_256(sharedkey,
+                                                   schnorr_identification::KMAC_KEY_UPDATE,
+                                                   &counter_value.to_be_bytes(),
+                                                   None,
+                                                   None);
+    Ok((new_key, counter_value))
+}
 
-    // Convert counter into u32 and increment it
-    let mut counter_value: u32 = u32::from_be_bytes(shared_counter);
-    counter_value = counter_value + 1;
+// Update counter and secret key after each use. The caller holds the lock of the shared state.
+// Both devices ratchet the same way, so a verifier can catch up with a sender that is some steps ahead
+fn update_used_values(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId, steps: u32) -> Result<(), NizkError> {
+    // Fetch shared secret key and shared counter value
+    let mut state = shared_state::load(store, my_ID, other_ID)?;
 
     // Calculate the new shared secret key
-    let new_key = schnorr_identification::sha3_256(&sharedkey,
-                                                   Some(counter_value.to_be_bytes().as_ref()),
-                                                   Some(&response),
-                                                   additional_data);
-
-    // Update new key in OS
-    // let mut sharedkey_ins = get_key_instance(&format!("SharedSecretKey:{}:{}", my_ID, other_ID), None).unwrap();
-    // let mut sharedkey_ins = get_key_instance(&format!("SharedSecretKey:{}:{}", my_ID, other_ID), 32, Some(Vec::from(new_key))).unwrap();
-    sharedkey_ins.update_key_in_ring(Vec::from(new_key)).unwrap();
-
-    // Update Counter in OS
-    counter_value = counter_value + 1;
-    shared_counter_ins.update_key_in_ring(Vec::from(counter_value.to_be_bytes())).unwrap();
+    for _ in 0..steps {
+        (state.key, state.counter) = ratchet_shared_key(state.key, state.counter)?;
+    }
+
+    // Save new key and counter in one record
+    shared_state::save(store, my_ID, other_ID, &state)?;
+    audit::record(AuditEvent::KeyRatcheted { device: my_ID.clone(), peer: other_ID.clone(), counter: state.counter, steps });
+    Ok(())
 }
 
 // Check if there is a compromised key
-pub fn check_intrusion(senderID: u32) -> (bool, bool, bool) {
+pub fn check_intrusion(senderID: &DeviceId) -> Result<(bool, bool, bool), NizkError> {
     file_management::check_intrusion(senderID)
 }
 
+// Check if the sender is blocked for too many rejected proofs
+pub fn check_sender(senderID: &DeviceId) -> Result<Decision, NizkError> {
+    file_management::sender_decision(senderID)
+}
+
 // Init Data
-pub fn init_intrusion_counters(senderID: u32) {
-    file_management::init_data(senderID);
+pub fn init_intrusion_counters(senderID: &DeviceId) -> Result<(), NizkError> {
+    file_management::init_data(senderID)
 }
 
 // Struct for interactive SIS proof
 pub struct IntSchnorrProver {
-    pub my_ID: u32,
-    pub recipient_ID: u32,
+    store: Arc<dyn KeyStore>,
+    pub my_ID: DeviceId,
+    pub recipient_ID: DeviceId,
     my_random_int: Scalar,
     pub my_commitment: [u8; 32],
     pub my_challenge: Scalar,
@@ -546,48 +453,43 @@ pub struct IntSchnorrProver {
 // Prover for interactive Schnorr identification scheme over elliptic curves
 impl IntSchnorrProver {
     // Create a new instance of Int_mut_auth
-    pub fn new(my_ID: u32, recipient_ID: u32) -> IntSchnorrProver {
+    pub fn new(store: Arc<dyn KeyStore>, my_ID: DeviceId, recipient_ID: DeviceId) -> IntSchnorrProver {
         // Generate random secret scalar and Commitment
         let my_random_int = schnorr_identification::generate_random_scalar();
-        let my_commitment = (my_random_int * &ED25519_BASEPOINT_POINT).compress().to_bytes();
+        let my_commitment = (my_random_int * ED25519_BASEPOINT_POINT).compress().to_bytes();
 
         // Init protocol variables
         let my_challenge = Scalar::from_bytes_mod_order([0u8; 32]);
         let my_response = [0u8; 32];
 
         // Genrate Instance of interactive mutual authentication struct
-        let mut int_schnorr_prover = IntSchnorrProver {
+        IntSchnorrProver {
+            store,
             my_ID,
             recipient_ID,
             my_random_int,
             my_commitment,
             my_challenge,
             my_response,
-        };
-
-        // Return
-        int_schnorr_prover
+        }
     }
 
     // Add Recipient Commitment
-    pub fn add_challenge(&mut self, challenge: Scalar) -> [u8; 32] {
+    pub fn add_challenge(&mut self, challenge: Scalar) -> Result<[u8; 32], NizkError> {
         // Save challenge and generate response
         self.my_challenge = challenge;
 
         // Calculate response
-        let response = self.gen_proof();
+        let response = self.gen_proof()?;
         self.my_response = response;
 
-        response
+        Ok(response)
     }
 
     // Generate Proof
-    fn gen_proof(&self) -> [u8; 32] {
+    fn gen_proof(&self) -> Result<[u8; 32], NizkError> {
         // Fetch secret key, necessary for the proof and convert it to Scalar type
-        let desciption = format!("PrivateKey:{}", &self.my_ID);
-        let my_secret_key = get_key_instance(&desciption, 32, None).unwrap();
-        let key: &[u8] = my_secret_key.get_key();
-        let secret_key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
+        let secret_key_bytes = get_32byte_key(&*self.store, format!("PrivateKey:{}", &self.my_ID))?;
         let secret_key_sc = Scalar::from_bytes_mod_order(secret_key_bytes);
 
         // Generate Proof
@@ -595,14 +497,15 @@ impl IntSchnorrProver {
                                                                     secret_key_sc,
                                                                     self.my_challenge);
         // Return Proof
-        proof
+        Ok(proof)
     }
 }
 
 // Struct for interactive mutual authentication for secret key sharing
 pub struct IntSchnorrVerifier {
-    pub my_ID: u32,
-    pub sender_ID: u32,
+    store: Arc<dyn KeyStore>,
+    pub my_ID: DeviceId,
+    pub sender_ID: DeviceId,
     pub commitment: [u8; 32],
     pub challenge: Scalar,
     pub response: [u8; 32],
@@ -611,7 +514,7 @@ pub struct IntSchnorrVerifier {
 // Prover for interactive Schnorr identification scheme over elliptic curves
 impl IntSchnorrVerifier {
     // Create a new instance of Int_mut_auth
-    pub fn new(my_ID: u32, sender_ID: u32, commitment: [u8; 32]) -> IntSchnorrVerifier {
+    pub fn new(store: Arc<dyn KeyStore>, my_ID: DeviceId, sender_ID: DeviceId, commitment: [u8; 32]) -> IntSchnorrVerifier {
         // Generate random secret scalar and Commitment
         let challenge = schnorr_identification::generate_random_scalar();
 
@@ -619,41 +522,38 @@ impl IntSchnorrVerifier {
         let response = [0u8; 32];
 
         // Genrate Instance of interactive mutual authentication struct
-        let mut int_schnorr_verifier = IntSchnorrVerifier {
+        IntSchnorrVerifier {
+            store,
             my_ID,
             sender_ID,
             commitment,
             challenge,
             response,
-        };
-
-        // Return
-        int_schnorr_verifier
+        }
     }
 
     // Verify proof
-    pub fn verify_proof(&mut self, response: [u8; 32]) -> bool {
+    pub fn verify_proof(&mut self, response: [u8; 32]) -> Result<bool, NizkError> {
         self.response = response;
+        let result = self.check_proof(response);
+        audit::record_proof(ProofKind::Interactive, &self.my_ID, &self.sender_ID, result)
+    }
+
+    fn check_proof(&mut self, response: [u8; 32]) -> Result<bool, NizkError> {
 
         // Check if commitment is never used to protect against replay attacks
-        if !file_management::check_commitment(self.sender_ID, self.commitment) {
-            return false;
+        if !file_management::check_commitment(&self.sender_ID, self.commitment)? {
+            return Ok(false);
         }
 
         // Fetch Public Key of the sender
-        let desciption = format!("PublicKey:{}", &self.sender_ID);
-        let sender_pubkey = get_key_instance(&desciption, 32, None).unwrap();
-
-        // Convert Key into [u8; 32] format
-        let key: &[u8] = sender_pubkey.get_key();
-        let key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
+        let key_bytes = get_32byte_key(&*self.store, format!("PublicKey:{}", &self.sender_ID))?;
 
         // Verify proof
         let proof = (self.commitment, self.challenge, response);
-        let accepted = schnorr_identification::verify_int_proof(key_bytes, proof);
+        let accepted = schnorr_identification::verify_int_proof(key_bytes, proof)?;
 
         // Return verification results
-        accepted
+        Ok(accepted)
     }
 }
-
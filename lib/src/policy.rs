@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::conditions::{Conditions, GrantSubject};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+
+// Policy document of the access control.
+//
+// A policy holds all resources with their actions, the roles and groups, and the conditions of the
+// grants, so the access control can be backed up, compared and provisioned as a whole. It is written as
+// JSON or TOML. Action names are written as text, actions that are not UTF-8 as "hex:" followed by their
+// bytes in hex, see action_name. The uses counted for use limits are state and not part of the policy.
+//
+// access_control::export_policy returns the current policy. access_control::import_policy validates a
+// policy, returns the changes to the current one and, unless it is a dry run, replaces the current one.
+
+// Version of the policy document, documents of other versions are refused
+pub const POLICY_VERSION: u32 = 1;
+
+// Prefix of action names that are written in hex
+pub const HEX_ACTION_PREFIX: &str = "hex:";
+
+// Name of an action in a policy. Text that starts with the hex prefix is written in hex as well, so every
+// name is read back as the same action
+pub fn action_name(action: &[u8]) -> String {
+    match std::str::from_utf8(action) {
+        Ok(text) if !text.starts_with(HEX_ACTION_PREFIX) => text.to_string(),
+        _ => format!("{}{}", HEX_ACTION_PREFIX, hex::encode(action)),
+    }
+}
+
+// Action of a name in a policy
+pub fn action_bytes(name: &str) -> Result<Vec<u8>, NizkError> {
+    match name.strip_prefix(HEX_ACTION_PREFIX) {
+        Some(hex_name) => hex::decode(hex_name).map_err(|_| invalid(format!("action {} is not valid hex", name))),
+        None => Ok(name.as_bytes().to_vec()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyFormat {
+    Json,
+    Toml,
+}
+
+impl PolicyFormat {
+    // Format of a file by its extension, ".json" or ".toml"
+    pub fn of_path(path: &Path) -> Result<PolicyFormat, NizkError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(PolicyFormat::Json),
+            Some("toml") => Ok(PolicyFormat::Toml),
+            _ => Err(NizkError::InvalidPolicy(format!("unknown policy format of {}", path.display()))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub version: u32,
+    #[serde(default)]
+    pub resources: Vec<ResourcePolicy>,
+    // Permissions of each role
+    #[serde(default)]
+    pub roles: BTreeMap<String, Vec<PermissionPolicy>>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, GroupPolicy>,
+    #[serde(default)]
+    pub conditions: Vec<ConditionPolicy>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            version: POLICY_VERSION,
+            resources: Vec::new(),
+            roles: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            conditions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourcePolicy {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u32>,
+    #[serde(default)]
+    pub actions: Vec<ActionPolicy>,
+}
+
+// Action, or action pattern, of a resource with the devices allowed and the devices and groups denied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActionPolicy {
+    pub name: String,
+    #[serde(default)]
+    pub allow: Vec<DeviceId>,
+    #[serde(default)]
+    pub deny_devices: Vec<DeviceId>,
+    #[serde(default)]
+    pub deny_groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PermissionPolicy {
+    pub resource: u32,
+    pub action: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupPolicy {
+    #[serde(default)]
+    pub devices: Vec<DeviceId>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+// Conditions of the grant of an action of a resource to a device or role
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConditionPolicy {
+    pub subject: GrantSubject,
+    pub resource: u32,
+    pub action: String,
+    pub conditions: Conditions,
+}
+
+// Change of the current policy by an import
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyChange {
+    AddResource(u32),
+    RemoveResource(u32),
+    SetParent { resource: u32, parent: Option<u32> },
+    AddAction { resource: u32, action: String },
+    RemoveAction { resource: u32, action: String },
+    // The allowed or denied devices or groups of the action change
+    ChangeAction { resource: u32, action: String },
+    AddRole(String),
+    RemoveRole(String),
+    ChangeRole(String),
+    AddGroup(String),
+    RemoveGroup(String),
+    ChangeGroup(String),
+    SetConditions { subject: GrantSubject, resource: u32, action: String },
+    ClearConditions { subject: GrantSubject, resource: u32, action: String },
+}
+
+fn subject_name(subject: &GrantSubject) -> String {
+    match subject {
+        GrantSubject::Device(device) => format!("device {}", device),
+        GrantSubject::Role(role) => format!("role {}", role),
+    }
+}
+
+impl fmt::Display for PolicyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyChange::AddResource(id) => write!(f, "add resource {}", id),
+            PolicyChange::RemoveResource(id) => write!(f, "remove resource {}", id),
+            PolicyChange::SetParent { resource, parent: Some(parent) } => write!(f, "set parent of resource {} to {}", resource, parent),
+            PolicyChange::SetParent { resource, parent: None } => write!(f, "remove parent of resource {}", resource),
+            PolicyChange::AddAction { resource, action } => write!(f, "add action {} to resource {}", action, resource),
+            PolicyChange::RemoveAction { resource, action } => write!(f, "remove action {} from resource {}", action, resource),
+            PolicyChange::ChangeAction { resource, action } => write!(f, "change devices of action {} of resource {}", action, resource),
+            PolicyChange::AddRole(role) => write!(f, "add role {}", role),
+            PolicyChange::RemoveRole(role) => write!(f, "remove role {}", role),
+            PolicyChange::ChangeRole(role) => write!(f, "change permissions of role {}", role),
+            PolicyChange::AddGroup(group) => write!(f, "add group {}", group),
+            PolicyChange::RemoveGroup(group) => write!(f, "remove group {}", group),
+            PolicyChange::ChangeGroup(group) => write!(f, "change devices or roles of group {}", group),
+            PolicyChange::SetConditions { subject, resource, action } => {
+                write!(f, "set conditions of {} on action {} of resource {}", subject_name(subject), action, resource)
+            },
+            PolicyChange::ClearConditions { subject, resource, action } => {
+                write!(f, "clear conditions of {} on action {} of resource {}", subject_name(subject), action, resource)
+            },
+        }
+    }
+}
+
+// Lists whose order does not matter are compared as sets
+fn same_set<T: Ord>(a: &[T], b: &[T]) -> bool {
+    a.iter().collect::<BTreeSet<_>>() == b.iter().collect::<BTreeSet<_>>()
+}
+
+fn same_action(a: &ActionPolicy, b: &ActionPolicy) -> bool {
+    same_set(&a.allow, &b.allow) && same_set(&a.deny_devices, &b.deny_devices) && same_set(&a.deny_groups, &b.deny_groups)
+}
+
+fn same_group(a: &GroupPolicy, b: &GroupPolicy) -> bool {
+    same_set(&a.devices, &b.devices) && same_set(&a.roles, &b.roles)
+}
+
+fn invalid(reason: String) -> NizkError {
+    NizkError::InvalidPolicy(reason)
+}
+
+impl Policy {
+    pub fn from_json(text: &str) -> Result<Policy, NizkError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Policy, NizkError> {
+        toml::from_str(text).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn to_json(&self) -> Result<String, NizkError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, NizkError> {
+        toml::to_string_pretty(self).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn decode(text: &str, format: PolicyFormat) -> Result<Policy, NizkError> {
+        match format {
+            PolicyFormat::Json => Policy::from_json(text),
+            PolicyFormat::Toml => Policy::from_toml(text),
+        }
+    }
+
+    pub fn encode(&self, format: PolicyFormat) -> Result<String, NizkError> {
+        match format {
+            PolicyFormat::Json => self.to_json(),
+            PolicyFormat::Toml => self.to_toml(),
+        }
+    }
+
+    // Read a policy file, the format is taken from the extension
+    pub fn read_file(path: &Path) -> Result<Policy, NizkError> {
+        let format = PolicyFormat::of_path(path)?;
+        Policy::decode(&fs::read_to_string(path)?, format)
+    }
+
+    // Write a policy file, the format is taken from the extension
+    pub fn write_file(&self, path: &Path) -> Result<(), NizkError> {
+        let text = self.encode(PolicyFormat::of_path(path)?)?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    fn resource(&self, id: u32) -> Option<&ResourcePolicy> {
+        self.resources.iter().find(|resource| resource.id == id)
+    }
+
+    // Check that the policy is consistent, so importing it cannot leave the access control half changed
+    // or with rules that can never apply
+    pub fn validate(&self) -> Result<(), NizkError> {
+        if self.version != POLICY_VERSION {
+            return Err(invalid(format!("version {} is not supported, expected {}", self.version, POLICY_VERSION)));
+        }
+
+        let mut ids = BTreeSet::new();
+        for resource in &self.resources {
+            if !ids.insert(resource.id) {
+                return Err(invalid(format!("resource {} is listed twice", resource.id)));
+            }
+            let mut names = BTreeSet::new();
+            for action in &resource.actions {
+                let bytes = action_bytes(&action.name)?;
+                if bytes.is_empty() {
+                    return Err(invalid(format!("resource {} has an action without a name", resource.id)));
+                }
+                if !names.insert(bytes) {
+                    return Err(invalid(format!("action {} of resource {} is listed twice", action.name, resource.id)));
+                }
+            }
+        }
+
+        // Parents exist and never lead back to the resource
+        for resource in &self.resources {
+            let mut visited = BTreeSet::from([resource.id]);
+            let mut parent = resource.parent;
+            while let Some(id) = parent {
+                if !visited.insert(id) {
+                    return Err(invalid(format!("parents of resource {} form a cycle", resource.id)));
+                }
+                parent = match self.resource(id) {
+                    Some(parent) => parent.parent,
+                    None => return Err(invalid(format!("parent {} of resource {} does not exist", id, resource.id))),
+                };
+            }
+        }
+
+        for permission in self.roles.values().flatten() {
+            action_bytes(&permission.action)?;
+        }
+        for (group, group_policy) in &self.groups {
+            if let Some(role) = group_policy.roles.iter().find(|role| !self.roles.contains_key(*role)) {
+                return Err(invalid(format!("role {} of group {} does not exist", role, group)));
+            }
+        }
+
+        let mut grants = BTreeSet::new();
+        for condition in &self.conditions {
+            if self.resource(condition.resource).is_none() {
+                return Err(invalid(format!("resource {} of conditions does not exist", condition.resource)));
+            }
+            if let GrantSubject::Role(role) = &condition.subject {
+                if !self.roles.contains_key(role) {
+                    return Err(invalid(format!("role {} of conditions does not exist", role)));
+                }
+            }
+            action_bytes(&condition.action)?;
+            if let Err(e) = condition.conditions.validate() {
+                return Err(invalid(format!("conditions of {} on action {} of resource {}: {}",
+                                           subject_name(&condition.subject), condition.action, condition.resource, e)));
+            }
+            if !grants.insert((subject_name(&condition.subject), condition.resource, &condition.action)) {
+                return Err(invalid(format!("conditions of {} on action {} of resource {} are listed twice",
+                                           subject_name(&condition.subject), condition.action, condition.resource)));
+            }
+        }
+        Ok(())
+    }
+
+    // Changes that turn this policy into the target policy. The order of resources, actions and
+    // devices does not matter
+    pub fn diff(&self, target: &Policy) -> Vec<PolicyChange> {
+        let mut changes = Vec::new();
+
+        for resource in &self.resources {
+            if target.resource(resource.id).is_none() {
+                changes.push(PolicyChange::RemoveResource(resource.id));
+            }
+        }
+        for resource in &target.resources {
+            let current = self.resource(resource.id);
+            let current_actions = current.map(|current| current.actions.as_slice()).unwrap_or_default();
+            if current.is_none() {
+                changes.push(PolicyChange::AddResource(resource.id));
+            }
+            if current.map(|current| current.parent).unwrap_or_default() != resource.parent {
+                changes.push(PolicyChange::SetParent { resource: resource.id, parent: resource.parent });
+            }
+
+            for action in current_actions {
+                if !resource.actions.iter().any(|target_action| target_action.name == action.name) {
+                    changes.push(PolicyChange::RemoveAction { resource: resource.id, action: action.name.clone() });
+                }
+            }
+            for action in &resource.actions {
+                match current_actions.iter().find(|current_action| current_action.name == action.name) {
+                    None => changes.push(PolicyChange::AddAction { resource: resource.id, action: action.name.clone() }),
+                    Some(current_action) if !same_action(current_action, action) => {
+                        changes.push(PolicyChange::ChangeAction { resource: resource.id, action: action.name.clone() });
+                    },
+                    Some(_) => {},
+                }
+            }
+        }
+
+        for role in self.roles.keys().filter(|role| !target.roles.contains_key(*role)) {
+            changes.push(PolicyChange::RemoveRole(role.clone()));
+        }
+        for (role, permissions) in &target.roles {
+            match self.roles.get(role) {
+                None => changes.push(PolicyChange::AddRole(role.clone())),
+                Some(current) if !same_set(current, permissions) => changes.push(PolicyChange::ChangeRole(role.clone())),
+                Some(_) => {},
+            }
+        }
+
+        for group in self.groups.keys().filter(|group| !target.groups.contains_key(*group)) {
+            changes.push(PolicyChange::RemoveGroup(group.clone()));
+        }
+        for (group, group_policy) in &target.groups {
+            match self.groups.get(group) {
+                None => changes.push(PolicyChange::AddGroup(group.clone())),
+                Some(current) if !same_group(current, group_policy) => changes.push(PolicyChange::ChangeGroup(group.clone())),
+                Some(_) => {},
+            }
+        }
+
+        let same_grant = |a: &ConditionPolicy, b: &ConditionPolicy| a.subject == b.subject && a.resource == b.resource && a.action == b.action;
+        for condition in &self.conditions {
+            if !target.conditions.iter().any(|target_condition| same_grant(condition, target_condition)) {
+                changes.push(PolicyChange::ClearConditions {
+                    subject: condition.subject.clone(),
+                    resource: condition.resource,
+                    action: condition.action.clone(),
+                });
+            }
+        }
+        for condition in &target.conditions {
+            let current = self.conditions.iter().find(|current| same_grant(current, condition));
+            if current.is_none_or(|current| current.conditions != condition.conditions) {
+                changes.push(PolicyChange::SetConditions {
+                    subject: condition.subject.clone(),
+                    resource: condition.resource,
+                    action: condition.action.clone(),
+                });
+            }
+        }
+        changes
+    }
+}
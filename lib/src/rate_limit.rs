@@ -0,0 +1,278 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::device_id::DeviceId;
+
+// Rate limiting of authentication requests and blocking of senders with too many rejected proofs.
+//
+// Every subject, a device or a source address, has a token bucket for its requests and a sliding window
+// of its rejected proofs. A request without a token is throttled. When more rejected proofs than allowed
+// fall into the window, the subject is blocked. Each block following within the cooldown of the last one
+// lasts twice as long, up to the maximum block duration.
+//
+// Servers ask the limiter before they handle a request and enforce the returned Decision themselves, by
+// closing the connection or by not accepting new ones until the block ends.
+// The intrusion data of file_management uses the same sliding window, so blocks of a sender are also
+// known to other processes and after a restart.
+
+// What a server does with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    // Handle the request
+    Allow,
+    // Too many requests, refuse this one. The subject may try again after the duration
+    Throttle(Duration),
+    // Too many rejected proofs, refuse all requests of the subject until the time
+    Block(SystemTime),
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        *self == Decision::Allow
+    }
+}
+
+// Who a request is counted for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    Device(DeviceId),
+    Address(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitPolicy {
+    // Requests per second a subject may make on average. Zero, negative or NaN lets no more requests through after the burst
+    pub requests_per_second: f64,
+    // Requests a subject may make at once after being idle
+    pub burst: u32,
+    // Rejected proofs within the rejection window that block the subject
+    pub max_rejections: u32,
+    pub rejection_window: Duration,
+    // Duration of the first block, doubled for each following block up to max_block_duration
+    pub block_duration: Duration,
+    pub max_block_duration: Duration,
+    // Time after the end of a block after which the next block is a first block again
+    pub cooldown: Duration,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> RateLimitPolicy {
+        RateLimitPolicy {
+            requests_per_second: 10.0,
+            burst: 20,
+            max_rejections: 10,
+            rejection_window: Duration::from_secs(10),
+            block_duration: Duration::from_secs(10),
+            max_block_duration: Duration::from_secs(3600),
+            cooldown: Duration::from_secs(600),
+        }
+    }
+}
+
+// Milliseconds since the Unix epoch, the time unit of the saved state
+pub(crate) fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as i64)
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+// Rejected proofs of one subject within the window and its current block
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct RejectionWindow {
+    // Times of the rejections in the window, oldest first
+    rejections: VecDeque<i64>,
+    // End of the last block, 0 if the subject was never blocked
+    blocked_until: i64,
+    // Blocks in a row, each one within the cooldown of the one before
+    strikes: u32,
+}
+
+impl RejectionWindow {
+    // Count a rejected proof and return the decision for the following requests
+    pub(crate) fn reject(&mut self, policy: &RateLimitPolicy, now: i64) -> Decision {
+        self.expire(policy, now);
+        if now < self.blocked_until {
+            return self.decision(now);
+        }
+
+        self.rejections.push_back(now);
+        if self.rejections.len() as u64 > policy.max_rejections as u64 {
+            // A block within the cooldown of the last one lasts longer
+            if now - self.blocked_until > policy.cooldown.as_millis() as i64 {
+                self.strikes = 0;
+            }
+            let factor = 1u32.checked_shl(self.strikes).unwrap_or(u32::MAX);
+            let duration = policy.block_duration.saturating_mul(factor).min(policy.max_block_duration);
+            self.blocked_until = now.saturating_add(duration.as_millis() as i64);
+            self.strikes = self.strikes.saturating_add(1);
+            self.rejections.clear();
+        }
+        self.decision(now)
+    }
+
+    pub(crate) fn decision(&self, now: i64) -> Decision {
+        if now < self.blocked_until {
+            Decision::Block(from_millis(self.blocked_until))
+        } else {
+            Decision::Allow
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = RejectionWindow::default();
+    }
+
+    // Drop the rejections that left the window
+    fn expire(&mut self, policy: &RateLimitPolicy, now: i64) {
+        let start = now - policy.rejection_window.as_millis() as i64;
+        while self.rejections.front().is_some_and(|time| *time <= start) {
+            self.rejections.pop_front();
+        }
+    }
+
+    // Nothing about the subject has to be remembered any more
+    fn is_idle(&self, policy: &RateLimitPolicy, now: i64) -> bool {
+        let cooled_down = now - self.blocked_until > policy.cooldown.as_millis() as i64;
+        self.rejections.is_empty() && cooled_down
+    }
+}
+
+// Requests a subject may still make
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: i64,
+}
+
+impl TokenBucket {
+    fn full(policy: &RateLimitPolicy, now: i64) -> TokenBucket {
+        TokenBucket { tokens: policy.burst as f64, updated: now }
+    }
+
+    // Tokens added per second. A rate that is not positive, or not a number, adds none
+    fn rate(policy: &RateLimitPolicy) -> f64 {
+        if policy.requests_per_second > 0.0 { policy.requests_per_second } else { 0.0 }
+    }
+
+    fn refill(&mut self, policy: &RateLimitPolicy, now: i64) {
+        let elapsed = (now - self.updated).max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * TokenBucket::rate(policy)).min(policy.burst as f64);
+        self.updated = now;
+    }
+
+    // Use up a token, or return the time until the next one
+    fn take(&mut self, policy: &RateLimitPolicy, now: i64) -> Result<(), Duration> {
+        self.refill(policy, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        // Without a rate, or with one so low that the wait does not fit in a Duration, the next token never comes
+        Err(Duration::try_from_secs_f64((1.0 - self.tokens) / TokenBucket::rate(policy)).unwrap_or(Duration::MAX))
+    }
+
+    fn is_full(&mut self, policy: &RateLimitPolicy, now: i64) -> bool {
+        self.refill(policy, now);
+        self.tokens >= policy.burst as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SubjectState {
+    bucket: TokenBucket,
+    window: RejectionWindow,
+}
+
+// Subjects below this number are never pruned
+const PRUNE_MIN_SUBJECTS: usize = 1024;
+
+// Rate limiter of a server, kept in memory
+#[derive(Debug)]
+pub struct RateLimiter {
+    policy: RateLimitPolicy,
+    subjects: Mutex<Subjects>,
+}
+
+#[derive(Debug)]
+struct Subjects {
+    states: HashMap<Subject, SubjectState>,
+    // Number of subjects at which idle ones are removed next
+    prune_at: usize,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy) -> RateLimiter {
+        RateLimiter {
+            policy,
+            subjects: Mutex::new(Subjects { states: HashMap::new(), prune_at: PRUNE_MIN_SUBJECTS }),
+        }
+    }
+
+    pub fn policy(&self) -> &RateLimitPolicy {
+        &self.policy
+    }
+
+    // Decide if a request of the subject is handled. An allowed request uses up a token of the subject
+    pub fn check(&self, subject: &Subject) -> Decision {
+        self.check_at(subject, SystemTime::now())
+    }
+
+    pub fn check_at(&self, subject: &Subject, now: SystemTime) -> Decision {
+        let now = to_millis(now);
+        self.with_state(subject, now, |policy, state| {
+            let decision = state.window.decision(now);
+            if decision != Decision::Allow {
+                return decision;
+            }
+            match state.bucket.take(policy, now) {
+                Ok(()) => Decision::Allow,
+                Err(retry_after) => Decision::Throttle(retry_after),
+            }
+        })
+    }
+
+    // Count a rejected proof of the subject, returns the decision for its following requests
+    pub fn record_rejection(&self, subject: &Subject) -> Decision {
+        self.record_rejection_at(subject, SystemTime::now())
+    }
+
+    pub fn record_rejection_at(&self, subject: &Subject, now: SystemTime) -> Decision {
+        let now = to_millis(now);
+        self.with_state(subject, now, |policy, state| state.window.reject(policy, now))
+    }
+
+    // Forget the rejections and the block of the subject
+    pub fn unblock(&self, subject: &Subject) {
+        let mut subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(state) = subjects.states.get_mut(subject) {
+            state.window.reset();
+        }
+    }
+
+    fn with_state<T>(&self, subject: &Subject, now: i64, f: impl FnOnce(&RateLimitPolicy, &mut SubjectState) -> T) -> T {
+        let mut subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Remove idle subjects so the map does not grow with every address that ever connected
+        if subjects.states.len() >= subjects.prune_at {
+            let policy = &self.policy;
+            subjects.states.retain(|_, state| !(state.window.is_idle(policy, now) && state.bucket.is_full(policy, now)));
+            subjects.prune_at = (2 * subjects.states.len()).max(PRUNE_MIN_SUBJECTS);
+        }
+
+        let state = subjects.states.entry(subject.clone()).or_insert_with(|| SubjectState {
+            bucket: TokenBucket::full(&self.policy, now),
+            window: RejectionWindow::default(),
+        });
+        f(&self.policy, state)
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new(RateLimitPolicy::default())
+    }
+}
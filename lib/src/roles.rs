@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::audit::{self, AuditEvent};
+use crate::config;
+use crate::file_lock::{self, FileLock};
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+
+// Roles and device groups of the access control.
+//
+// A role is a named set of permissions, each an action of a resource. Devices are members of groups and
+// roles are assigned to groups, so a device has the permissions of all roles of all its groups, besides
+// the devices allowed directly on an action of a resource. A permission only grants an action that the
+// resource has, check_access of access_control resolves both. The action of a permission can be a
+// pattern like the action names of access_control, and a permission on a resource also applies to its
+// children.
+//
+// All roles and groups are saved in "access_control/roles.json" of the state directory.
+
+// Action of a resource
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Permission {
+    pub resource: u32,
+    pub action: Vec<u8>,
+}
+
+impl Permission {
+    pub fn new(resource: u32, action: &[u8]) -> Permission {
+        Permission { resource, action: action.to_vec() }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Group {
+    pub(crate) devices: Vec<DeviceId>,
+    pub(crate) roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Roles {
+    pub(crate) roles: BTreeMap<String, Vec<Permission>>,
+    pub(crate) groups: BTreeMap<String, Group>,
+}
+
+// Change of the roles or groups, recorded in the audit log. Action names are shown as text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum RoleChange {
+    AddRole { role: String },
+    RemoveRole { role: String },
+    AddPermission { role: String, resource: u32, action: String },
+    RemovePermission { role: String, resource: u32, action: String },
+    AddGroup { group: String },
+    RemoveGroup { group: String },
+    AddMember { group: String, device: DeviceId },
+    RemoveMember { group: String, device: DeviceId },
+    AssignRole { group: String, role: String },
+    UnassignRole { group: String, role: String },
+}
+
+fn get_roles_file_path() -> Result<PathBuf, NizkError> {
+    config::state_path("access_control/roles.json")
+}
+
+// Read all roles and groups, there are none before the first one is added
+pub(crate) fn load() -> Result<Roles, NizkError> {
+    let file_path = get_roles_file_path()?;
+    match fs::read(&file_path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Roles::default()),
+        Err(e) => Err(NizkError::Io(e)),
+    }
+}
+
+// Change the roles and groups while holding the lock of their file, then record the change
+fn modify(change: RoleChange, f: impl FnOnce(&mut Roles) -> Result<(), NizkError>) -> Result<(), NizkError> {
+    let file_path = get_roles_file_path()?;
+    if let Some(parent) = Path::new(&file_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _lock = FileLock::acquire(&file_path)?;
+    let mut roles = load()?;
+    f(&mut roles)?;
+    file_lock::write_atomic(&file_path, serde_json::to_string(&roles)?.as_bytes())?;
+    audit::record(AuditEvent::RolesChanged { change });
+    Ok(())
+}
+
+// Replace all roles and groups, for a policy import that records its own audit event
+pub(crate) fn replace(roles: &Roles) -> Result<(), NizkError> {
+    let file_path = get_roles_file_path()?;
+    if let Some(parent) = Path::new(&file_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _lock = FileLock::acquire(&file_path)?;
+    file_lock::write_atomic(&file_path, serde_json::to_string(roles)?.as_bytes())?;
+    Ok(())
+}
+
+impl Roles {
+    fn role_mut(&mut self, role: &str) -> Result<&mut Vec<Permission>, NizkError> {
+        self.roles.get_mut(role).ok_or_else(|| NizkError::RoleNotFound(role.to_string()))
+    }
+
+    fn group_mut(&mut self, group: &str) -> Result<&mut Group, NizkError> {
+        self.groups.get_mut(group).ok_or_else(|| NizkError::GroupNotFound(group.to_string()))
+    }
+
+    // Groups of the device with the roles assigned to them, in the order of their names
+    pub(crate) fn roles_of<'a>(&'a self, device: &'a DeviceId) -> impl Iterator<Item = (&'a str, &'a str, &'a [Permission])> + 'a {
+        self.groups.iter()
+            .filter(move |(_, group)| group.devices.contains(device))
+            .flat_map(move |(group_name, group)| {
+                group.roles.iter().filter_map(move |role| {
+                    self.roles.get(role).map(|permissions| (group_name.as_str(), role.as_str(), permissions.as_slice()))
+                })
+            })
+    }
+}
+
+// Create a role with the given permissions
+pub fn add_role(role: &str, permissions: Vec<Permission>) -> Result<(), NizkError> {
+    modify(RoleChange::AddRole { role: role.to_string() }, |roles| {
+        if roles.roles.contains_key(role) {
+            return Err(NizkError::RoleAlreadyExists(role.to_string()));
+        }
+        let mut permissions = permissions;
+        permissions.sort();
+        permissions.dedup();
+        roles.roles.insert(role.to_string(), permissions);
+        Ok(())
+    })
+}
+
+// Delete a role, it is also removed from all groups
+pub fn remove_role(role: &str) -> Result<(), NizkError> {
+    modify(RoleChange::RemoveRole { role: role.to_string() }, |roles| {
+        if roles.roles.remove(role).is_none() {
+            return Err(NizkError::RoleNotFound(role.to_string()));
+        }
+        for group in roles.groups.values_mut() {
+            group.roles.retain(|name| name != role);
+        }
+        Ok(())
+    })
+}
+
+pub fn add_permission_to_role(role: &str, permission: Permission) -> Result<(), NizkError> {
+    let change = RoleChange::AddPermission {
+        role: role.to_string(),
+        resource: permission.resource,
+        action: audit::action_name(&permission.action),
+    };
+    modify(change, |roles| {
+        let permissions = roles.role_mut(role)?;
+        if !permissions.contains(&permission) {
+            permissions.push(permission);
+            permissions.sort();
+        }
+        Ok(())
+    })
+}
+
+pub fn remove_permission_from_role(role: &str, permission: &Permission) -> Result<(), NizkError> {
+    let change = RoleChange::RemovePermission {
+        role: role.to_string(),
+        resource: permission.resource,
+        action: audit::action_name(&permission.action),
+    };
+    modify(change, |roles| {
+        let permissions = roles.role_mut(role)?;
+        match permissions.iter().position(|existing| existing == permission) {
+            Some(index) => {
+                permissions.remove(index);
+                Ok(())
+            },
+            None => Err(NizkError::ActionNotFound),
+        }
+    })
+}
+
+// Create an empty group
+pub fn add_group(group: &str) -> Result<(), NizkError> {
+    modify(RoleChange::AddGroup { group: group.to_string() }, |roles| {
+        if roles.groups.contains_key(group) {
+            return Err(NizkError::GroupAlreadyExists(group.to_string()));
+        }
+        roles.groups.insert(group.to_string(), Group::default());
+        Ok(())
+    })
+}
+
+pub fn remove_group(group: &str) -> Result<(), NizkError> {
+    modify(RoleChange::RemoveGroup { group: group.to_string() }, |roles| {
+        roles.groups.remove(group)
+            .map(|_| ())
+            .ok_or_else(|| NizkError::GroupNotFound(group.to_string()))
+    })
+}
+
+pub fn add_device_to_group(group: &str, deviceID: &DeviceId) -> Result<(), NizkError> {
+    modify(RoleChange::AddMember { group: group.to_string(), device: deviceID.clone() }, |roles| {
+        let group = roles.group_mut(group)?;
+        if !group.devices.contains(deviceID) {
+            group.devices.push(deviceID.clone());
+        }
+        Ok(())
+    })
+}
+
+pub fn remove_device_from_group(group: &str, deviceID: &DeviceId) -> Result<(), NizkError> {
+    modify(RoleChange::RemoveMember { group: group.to_string(), device: deviceID.clone() }, |roles| {
+        let group = roles.group_mut(group)?;
+        match group.devices.iter().position(|device| device == deviceID) {
+            Some(index) => {
+                group.devices.remove(index);
+                Ok(())
+            },
+            None => Err(NizkError::DeviceNotFound(deviceID.clone())),
+        }
+    })
+}
+
+// Give all devices of the group the permissions of the role
+pub fn assign_role_to_group(group: &str, role: &str) -> Result<(), NizkError> {
+    modify(RoleChange::AssignRole { group: group.to_string(), role: role.to_string() }, |roles| {
+        if !roles.roles.contains_key(role) {
+            return Err(NizkError::RoleNotFound(role.to_string()));
+        }
+        let group = roles.group_mut(group)?;
+        if !group.roles.iter().any(|name| name == role) {
+            group.roles.push(role.to_string());
+        }
+        Ok(())
+    })
+}
+
+pub fn unassign_role_from_group(group: &str, role: &str) -> Result<(), NizkError> {
+    modify(RoleChange::UnassignRole { group: group.to_string(), role: role.to_string() }, |roles| {
+        let group = roles.group_mut(group)?;
+        match group.roles.iter().position(|name| name == role) {
+            Some(index) => {
+                group.roles.remove(index);
+                Ok(())
+            },
+            None => Err(NizkError::RoleNotFound(role.to_string())),
+        }
+    })
+}
+
+// Groups the device is a member of
+pub fn groups_of_device(deviceID: &DeviceId) -> Result<Vec<String>, NizkError> {
+    let roles = load()?;
+    Ok(roles.groups.iter()
+        .filter(|(_, group)| group.devices.contains(deviceID))
+        .map(|(name, _)| name.clone())
+        .collect())
+}
@@ -6,7 +6,37 @@ extern crate curve25519_dalek;
 use curve25519_dalek::scalar::Scalar;
 use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
 use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
-
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
+use subtle::ConstantTimeEq;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+
+// Domain separation label and version of the challenge transcript
+const TRANSCRIPT_LABEL: &[u8] = b"schnorr-nizk/challenge";
+const TRANSCRIPT_VERSION: u8 = 2;
+
+// Domain separation label of the Fiat-Shamir challenge transcript, used without shared secret key
+const FS_TRANSCRIPT_LABEL: &[u8] = b"schnorr-nizk/fs-challenge";
+
+// KMAC customization strings, one for each use of a shared key
+pub const KMAC_NIZK_PROOF: &[u8] = b"schnorr-nizk/proof";
+pub const KMAC_MUT_AUTH_PROOF: &[u8] = b"schnorr-nizk/mut-auth-proof";
+pub const KMAC_SESSION_EXTRACT: &[u8] = b"schnorr-nizk/session-extract";
+pub const KMAC_SESSION_EXPAND: &[u8] = b"schnorr-nizk/session-expand";
+pub const KMAC_SESSION_EXPORTER: &[u8] = b"schnorr-nizk/session-exporter";
+pub const KMAC_KEY_CONFIRMATION: &[u8] = b"schnorr-nizk/key-confirmation";
+pub const KMAC_CHANNEL_REKEY: &[u8] = b"schnorr-nizk/channel-rekey";
+pub const KMAC_KEY_UPDATE: &[u8] = b"schnorr-nizk/key-update";
+pub const KMAC_AUDIT_LOG: &[u8] = b"schnorr-nizk/audit-log";
+
+// Values the challenge of a NIZK proof is bound to, besides the commitment
+pub struct Transcript<'a> {
+    pub customization: &'a [u8],
+    pub sender_ID: &'a DeviceId,
+    pub recipient_ID: &'a DeviceId,
+    pub shared_counter: [u8; 4],
+    pub message: Option<&'a [u8]>,
+}
 
 // Generate a random 32-byte value
 pub fn generate_random_32bytes() -> [u8; 32] {
@@ -26,7 +56,7 @@ pub fn key_gen() -> ([u8; 32], [u8; 32]) {
     // let private_key = Scalar::from_bytes_mod_order(<[u8; 32]>::try_from(random_bytes).unwrap());
 
     // Calculate public key using ED25519_BASEPOINT_POINT
-    let public_key = (private_key * &ED25519_BASEPOINT_POINT).compress().to_bytes();
+    let public_key = (private_key * ED25519_BASEPOINT_POINT).compress().to_bytes();
 
     // Return Public and Private Key pair
     (public_key, private_key.to_bytes())
@@ -48,15 +78,8 @@ pub fn sha3_256(arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>, arg4: Opt
 
     // Check the optional args
     let args = [arg2, arg3, arg4];
-    for arg in args.iter() {
-        match arg {
-            Some(x) => {
-                sha3_instance.update(x);
-            },
-            None => {
-
-            }
-        }
+    for arg in args.iter().flatten() {
+        sha3_instance.update(arg);
     }
 
     // Generate Hash
@@ -67,25 +90,18 @@ pub fn sha3_256(arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>, arg4: Opt
     digest
 }
 
-// Generate a Kmac Tag
-pub fn kmac_256(key: [u8; 32], arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>) -> [u8; 32] {
+// Generate a Kmac Tag, the customization string separates the different uses of the same key
+pub fn kmac_256(key: [u8; 32], customization: &[u8], arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>) -> [u8; 32] {
     // Define a kmac instance
-    let mut kmac_instance = Kmac::v256(&key, b"");
+    let mut kmac_instance = Kmac::v256(&key, customization);
 
     // Include main arg into data
     kmac_instance.update(arg1);
 
     // Check the optional args
     let args = [arg2, arg3];
-    for arg in args.iter() {
-        match arg {
-            Some(x) => {
-                kmac_instance.update(x);
-            },
-            None => {
-
-            }
-        }
+    for arg in args.iter().flatten() {
+        kmac_instance.update(arg);
     }
 
     // Generate Tag
@@ -99,23 +115,41 @@ pub fn kmac_256(key: [u8; 32], arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[
 // Calculate the response
 pub fn generate_proof_response(random_secret: Scalar, private_key: Scalar, challenge: Scalar) -> [u8; 32] {
     // Compute the response
-    (random_secret + private_key * &challenge).to_bytes()
+    (random_secret + private_key * challenge).to_bytes()
+}
+
+// Calculate the challenge of a NIZK proof. The transcript is
+// label | version | sender ID | recipient ID | commitment | counter | message
+fn nizk_challenge(shared_secret_key: [u8; 32], commitment: [u8; 32], transcript: &Transcript) -> [u8; 32] {
+    // Header of the transcript with the identities of both devices. The wire form of the IDs is self-delimiting
+    let mut header = TRANSCRIPT_LABEL.to_vec();
+    header.push(TRANSCRIPT_VERSION);
+    transcript.sender_ID.encode_into(&mut header);
+    transcript.recipient_ID.encode_into(&mut header);
+
+    // Commitment and counter have a fixed size, the message is always the last value
+    let mut values = Vec::with_capacity(36);
+    values.extend_from_slice(&commitment);
+    values.extend_from_slice(&transcript.shared_counter);
+
+    kmac_256(shared_secret_key,
+             transcript.customization,
+             &header,
+             Some(&values),
+             transcript.message)
 }
 
 // Generate a proof that the device knows the private key, using Non-Interactive Zero-Knowledge
-pub fn nizk_proof(private_key: [u8; 32], shared_secret_key: [u8; 32], shared_counter: [u8; 4], message: Option<&[u8]>) -> (Scalar, [u8; 32], [u8; 32], [u8; 32]) {
+pub fn nizk_proof(private_key: [u8; 32], shared_secret_key: [u8; 32], transcript: &Transcript) -> (Scalar, [u8; 32], [u8; 32], [u8; 32]) {
     // Turn private key into Scalar
     let private_key_sc = Scalar::from_bytes_mod_order(private_key);
 
     // The prover generates a random number k and the commitment
     let r = generate_random_scalar();
-    let commitment = (r * &ED25519_BASEPOINT_POINT).compress().to_bytes();
+    let commitment = (r * ED25519_BASEPOINT_POINT).compress().to_bytes();
 
-    // Generate challenge using KMAC function with a random value
-    let challenge = kmac_256(shared_secret_key,
-                             &commitment,
-                             Some(&shared_counter),
-                             message);
+    // Generate challenge using KMAC function over the transcript
+    let challenge = nizk_challenge(shared_secret_key, commitment, transcript);
 
     // Convert challenge into a Scalar
     let c = Scalar::from_bytes_mod_order(challenge);
@@ -127,68 +161,134 @@ pub fn nizk_proof(private_key: [u8; 32], shared_secret_key: [u8; 32], shared_cou
     (r, commitment, challenge, response)
 }
 
+// Calculate the Fiat-Shamir challenge. The transcript is
+// label | version | public key | commitment | context length | context | message
+fn fs_challenge(public_key: [u8; 32], commitment: [u8; 32], message: &[u8], context: &[u8]) -> [u8; 32] {
+    let mut header = Vec::with_capacity(FS_TRANSCRIPT_LABEL.len() + 69);
+    header.extend_from_slice(FS_TRANSCRIPT_LABEL);
+    header.push(TRANSCRIPT_VERSION);
+    header.extend_from_slice(&public_key);
+    header.extend_from_slice(&commitment);
+    header.extend_from_slice(&(context.len() as u32).to_be_bytes());
+
+    sha3_256(&header, Some(context), Some(message), None)
+}
+
+// Generate a proof with a Fiat-Shamir challenge, which only needs the public key to be verified
+pub fn fs_proof(private_key: [u8; 32], message: &[u8], context: &[u8]) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    // Turn private key into Scalar and calculate the public key
+    let private_key_sc = Scalar::from_bytes_mod_order(private_key);
+    let public_key = (private_key_sc * ED25519_BASEPOINT_POINT).compress().to_bytes();
+
+    // The prover generates a random number k and the commitment
+    let r = generate_random_scalar();
+    let commitment = (r * ED25519_BASEPOINT_POINT).compress().to_bytes();
+
+    // Generate challenge by hashing the transcript and compute the proof
+    let challenge = fs_challenge(public_key, commitment, message, context);
+    let response = generate_proof_response(r, private_key_sc, Scalar::from_bytes_mod_order(challenge));
+
+    (commitment, challenge, response)
+}
+
+// Verify a proof with a Fiat-Shamir challenge
+pub fn verify_fs_proof(public_key: [u8; 32], message: &[u8], context: &[u8], proof: ([u8; 32], [u8; 32], [u8; 32])) -> Result<bool, NizkError> {
+    let (commitment, challenge, response) = proof;
+
+    // Challenge has to be the hash of the transcript
+    if challenge != fs_challenge(public_key, commitment, message, context) {
+        return Ok(false);
+    }
+
+    // Verify the Schnorr proof with the challenge
+    verify_int_proof(public_key, (commitment, Scalar::from_bytes_mod_order(challenge), response))
+}
+
 // Turn bytes value into Edward points.
-// ToDo: If this created an error, that means bytes or not valid on the elliptic curves. Reject request ...
-pub fn bytes_to_edwards(bytes: &[u8; 32]) -> EdwardsPoint {
+// Returns an error if the bytes are not a valid point on the elliptic curve, so the request can be rejected
+pub fn bytes_to_edwards(bytes: &[u8; 32]) -> Result<EdwardsPoint, NizkError> {
     let compressed = CompressedEdwardsY(*bytes);
-    compressed.decompress().unwrap()
+    compressed.decompress().ok_or(NizkError::InvalidCurvePoint)
 }
 
 // Verify if the challenge is generated correctly using the MAC Tag
-fn verify_challenge(shared_secret: [u8; 32], shared_counter: [u8; 4], commitment: [u8; 32], challenge: [u8; 32], message: Option<&[u8]>) -> bool {
-    // Generate expected challenge using KMAC function with a random value
-    let expected_challenge = kmac_256(shared_secret,
-                                      &commitment,
-                                      Some(&shared_counter),
-                                      message);
+pub fn verify_challenge(shared_secret: [u8; 32], commitment: [u8; 32], challenge: [u8; 32], transcript: &Transcript) -> bool {
+    // Generate expected challenge using KMAC function over the transcript
+    let expected_challenge = nizk_challenge(shared_secret, commitment, transcript);
 
-    return challenge == expected_challenge;
+    // Compared in constant time, so the time does not tell how many bytes of a guessed MAC Tag are right
+    challenge.ct_eq(&expected_challenge).into()
 }
 
 // Verify the proof
-pub fn verify_nizk_proof(public_key: [u8; 32], shared_secret: [u8; 32], shared_counter: [u8; 4],
-                         message: Option<&[u8]>, proof: ([u8; 32], [u8; 32], [u8; 32])) -> (bool, bool) {
+pub fn verify_nizk_proof(public_key: [u8; 32], shared_secret: [u8; 32], transcript: &Transcript,
+                         proof: ([u8; 32], [u8; 32], [u8; 32])) -> Result<(bool, bool), NizkError> {
 
     // Convert compressed public key into an Edwards point
-    let public_key_ed = bytes_to_edwards(&public_key);
+    let public_key_ed = bytes_to_edwards(&public_key)?;
 
     // Get the commitment and the challenge response
     let (commitment, challenge, response) = proof;
 
     // Verify Challenge generation
-    let challenge_accepted = verify_challenge(shared_secret, shared_counter, commitment, challenge, message);
+    let challenge_accepted = verify_challenge(shared_secret, commitment, challenge, transcript);
 
     // Convert values for schnorr verification
-    let commitment_ed = bytes_to_edwards(&commitment);
+    let commitment_ed = bytes_to_edwards(&commitment)?;
     let challenge_sc = Scalar::from_bytes_mod_order(challenge);
     let response_sc = Scalar::from_bytes_mod_order(response);
 
     // Compute the rhs and the lhs of the expected result
-    let lhs = response_sc * &ED25519_BASEPOINT_POINT;
+    let lhs = response_sc * ED25519_BASEPOINT_POINT;
     let rhs = commitment_ed + challenge_sc * public_key_ed;
 
     // Compare the received commitment and the expected result
-    return (lhs == rhs, challenge_accepted);
+    Ok((lhs == rhs, challenge_accepted))
 }
 
 // Verify the proof
-pub fn verify_int_proof(public_key: [u8; 32], proof: ([u8; 32], Scalar, [u8; 32])) -> bool {
+pub fn verify_int_proof(public_key: [u8; 32], proof: ([u8; 32], Scalar, [u8; 32])) -> Result<bool, NizkError> {
 
     // Convert compressed public key into an Edwards point
-    let public_key_ed = bytes_to_edwards(&public_key);
+    let public_key_ed = bytes_to_edwards(&public_key)?;
 
     // Get the commitment and the challenge response
     let (commitment, challenge_sc, response) = proof;
 
     // Convert values for schnorr verification
-    let commitment_ed = bytes_to_edwards(&commitment);
+    let commitment_ed = bytes_to_edwards(&commitment)?;
     let response_sc = Scalar::from_bytes_mod_order(response);
 
     // Compute the rhs and the lhs of the expected result
-    let lhs = response_sc * &ED25519_BASEPOINT_POINT;
+    let lhs = response_sc * ED25519_BASEPOINT_POINT;
     let rhs = commitment_ed + challenge_sc * public_key_ed;
 
     // Compare the received commitment and the expected result
-    return lhs == rhs;
+    Ok(lhs == rhs)
 }
 
+// Verify many Schnorr proofs (public key, commitment, challenge, response) at once.
+// Checks that the random linear combination of all equations s*B - R - c*P is the identity,
+// so it only tells if all proofs are valid. Points have to be torsion free
+pub fn verify_schnorr_batch(proofs: &[(EdwardsPoint, EdwardsPoint, Scalar, Scalar)]) -> bool {
+    let mut basepoint_scalar = Scalar::zero();
+    let mut scalars = Vec::with_capacity(2 * proofs.len() + 1);
+    let mut points = Vec::with_capacity(2 * proofs.len() + 1);
+
+    for (public_key, commitment, challenge, response) in proofs {
+        // Random 128-bit weight, so invalid proofs can not cancel each other out
+        let mut weight_bytes = [0u8; 32];
+        thread_rng().fill_bytes(&mut weight_bytes[..16]);
+        let weight = Scalar::from_bytes_mod_order(weight_bytes);
+
+        basepoint_scalar += weight * response;
+        scalars.push(-weight);
+        points.push(*commitment);
+        scalars.push(-(weight * challenge));
+        points.push(*public_key);
+    }
+    scalars.push(basepoint_scalar);
+    points.push(ED25519_BASEPOINT_POINT);
+
+    EdwardsPoint::vartime_multiscalar_mul(scalars, points).is_identity()
+}
@@ -2,7 +2,16 @@ extern crate linux_keyutils;
 use linux_keyutils::{Key, KeyRing, KeyError, KeyRingIdentifier};
 use linux_keyutils::{KeyPermissionsBuilder, Permission};
 use rand::RngCore;
-use hex;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key as AeadKey, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use crate::file_lock::{self, FileLock};
 
 
 #[derive(Debug)]
@@ -14,6 +23,12 @@ pub enum SecretKeyErrors {
     UnableToChangeKeyValue(KeyError),
     UnableToGetKeyFromOS(KeyError),
     UnableToDeleteKeyFromOS(KeyError),
+    UnableToListKeys(KeyError),
+    KeyNotFound(String),
+    UnableToAccessKeyFile(io::Error),
+    InvalidKeyFile,
+    WrongPassword,
+    KeyStoreLockPoisoned,
 }
 
 // Struct that has secret key info
@@ -33,8 +48,7 @@ impl MyKey {
             Ok(ring) => {
                 // Save key description as Vec<u8> and use description as it's encoded value
                 let key_description = key_description_str.as_bytes().to_vec();
-                let key_description_copy = key_description_str.as_bytes().to_vec();
-                let key_description_encoded = hex::encode(key_description_copy);
+                let key_description_encoded = hex::encode(&key_description);
 
                 // Generate an instance of MyKey struct
                 let mut my_key = MyKey {
@@ -47,14 +61,17 @@ impl MyKey {
                 match ring.search(&key_description_encoded) {
                     // Retrieve stored key if found
                     Ok(secret_key) => {
-                        my_key.key = secret_key.read_to_vec().unwrap();
+                        my_key.key = secret_key.read_to_vec().map_err(SecretKeyErrors::UnableToGetKeyFromOS)?;
                     },
-                    Err(e) => {
+                    Err(_) => {
                         // Assign provided key to MyKey
-                        if key != None {
-                            my_key.key = key.unwrap();
-                        }else {
-                            return Err(SecretKeyErrors::KeyNotProvided);
+                        match key {
+                            Some(k) => {
+                                my_key.key = k;
+                            },
+                            None => {
+                                return Err(SecretKeyErrors::KeyNotProvided);
+                            }
                         }
 
                         // Store key in Keyring
@@ -114,7 +131,7 @@ impl MyKey {
     // Change key of the function. This requires the MyKey instance to be declared as mutable
     pub fn update_key_in_ring(&mut self, new_key: Vec<u8>) -> Result<(), SecretKeyErrors> {
         // Read key instance from keyring
-        let key = self.retrieve_key_from_ring().unwrap();
+        let key = self.retrieve_key_from_ring()?;
 
         // Update key in keyring
         if let Err(e) = key.update(&new_key){
@@ -129,15 +146,15 @@ impl MyKey {
 
     // Retrieve a secret key from Stored value in the os
     fn retrieve_key_from_ring(&self) -> Result<Key, SecretKeyErrors> {
-        let description = unsafe {&*self.get_key_description()};
+        let description = self.get_key_description();
 
-        match &self.ring.search(&description){
+        match self.ring.search(&description){
             Ok(secret) => {
-                Ok(*secret)
+                Ok(secret)
             },
             Err(e) => {
                 println!("Couldn't Read key from ring: {:?}\n", e);
-                return Err(SecretKeyErrors::UnableToGetKeyFromOS(*e));
+                Err(SecretKeyErrors::UnableToGetKeyFromOS(e))
             }
         }
     }
@@ -145,12 +162,368 @@ impl MyKey {
     // Delete a secret key
     pub fn delete_key_from_ring(&self) -> Result<(), SecretKeyErrors> {
         // Read key instance from keyring
-        let key = self.retrieve_key_from_ring().unwrap();
+        let key = self.retrieve_key_from_ring()?;
 
         if let Err(e) = key.invalidate() {
-            return  Err(SecretKeyErrors::UnableToDeleteKeyFromOS(e));
+            Err(SecretKeyErrors::UnableToDeleteKeyFromOS(e))
         } else {
             Ok(())
         }
     }
 }
+
+
+// Storage backend for all keys used by the protocol, like "PrivateKey:<id>", "PublicKey:<id>",
+// "SharedState:<a>:<b>".
+// All keys are addressed by their description.
+pub trait KeyStore: Send + Sync {
+    // Read the key with the given description
+    fn get(&self, description: &str) -> Result<Vec<u8>, SecretKeyErrors>;
+
+    // Save a key, replacing the old value if a key with the same description already exists
+    fn put(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors>;
+
+    // Change the value of an existing key
+    fn update(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors>;
+
+    // Delete an existing key
+    fn delete(&self, description: &str) -> Result<(), SecretKeyErrors>;
+
+    // Return the descriptions of all stored keys starting with the given prefix
+    fn list(&self, prefix: &str) -> Result<Vec<String>, SecretKeyErrors>;
+}
+
+// Key store using the Linux user keyring. Keys are lost when the device reboots
+pub struct KeyUtilsStore {
+    ring: KeyRing,
+}
+
+impl KeyUtilsStore {
+    // Create a key store using the keyring of the current user
+    pub fn new() -> Result<KeyUtilsStore, SecretKeyErrors> {
+        let ring = KeyRing::from_special_id(KeyRingIdentifier::User, false)
+            .map_err(SecretKeyErrors::KeyRingNotFound)?;
+        Ok(KeyUtilsStore { ring })
+    }
+
+    // Descriptions are saved hex encoded in the keyring, same as in MyKey
+    fn search(&self, description: &str) -> Result<Key, SecretKeyErrors> {
+        self.ring.search(&hex::encode(description))
+            .map_err(|_| SecretKeyErrors::KeyNotFound(description.to_string()))
+    }
+}
+
+impl KeyStore for KeyUtilsStore {
+    fn get(&self, description: &str) -> Result<Vec<u8>, SecretKeyErrors> {
+        let key = self.search(description)?;
+        key.read_to_vec().map_err(SecretKeyErrors::UnableToGetKeyFromOS)
+    }
+
+    fn put(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors> {
+        // Adding a key with an existing description replaces the value of the old key
+        let ring_key = self.ring.add_key(&hex::encode(description), key)
+            .map_err(SecretKeyErrors::UnableToStoreKeyInOS)?;
+
+        // Define Key Permissions
+        let perms = KeyPermissionsBuilder::builder()
+            .posessor(Permission::ALL)
+            .user(Permission::ALL)
+            .group(Permission::VIEW)
+            .build();
+        ring_key.set_perms(perms).map_err(SecretKeyErrors::UnableToChangeKeyPermissions)
+    }
+
+    fn update(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors> {
+        let ring_key = self.search(description)?;
+        ring_key.update(&key).map_err(SecretKeyErrors::UnableToChangeKeyValue)
+    }
+
+    fn delete(&self, description: &str) -> Result<(), SecretKeyErrors> {
+        let ring_key = self.search(description)?;
+        ring_key.invalidate().map_err(SecretKeyErrors::UnableToDeleteKeyFromOS)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, SecretKeyErrors> {
+        let links = self.ring.get_links(4096).map_err(SecretKeyErrors::UnableToListKeys)?;
+
+        // Decode the hex encoded descriptions and ignore entries not created by this crate
+        let mut descriptions = Vec::new();
+        for link in links.iter() {
+            if let Some(key) = link.as_key() {
+                let metadata = key.metadata().map_err(SecretKeyErrors::UnableToListKeys)?;
+                let decoded = hex::decode(metadata.get_description()).ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok());
+                if let Some(description) = decoded {
+                    if description.starts_with(prefix) {
+                        descriptions.push(description);
+                    }
+                }
+            }
+        }
+        descriptions.sort();
+        Ok(descriptions)
+    }
+}
+
+// Key store that keeps all keys in memory. Useful for tests and short living processes
+#[derive(Default)]
+pub struct MemoryStore {
+    keys: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn get(&self, description: &str) -> Result<Vec<u8>, SecretKeyErrors> {
+        let keys = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        keys.get(description).cloned().ok_or_else(|| SecretKeyErrors::KeyNotFound(description.to_string()))
+    }
+
+    fn put(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors> {
+        let mut keys = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        keys.insert(description.to_string(), key.to_vec());
+        Ok(())
+    }
+
+    fn update(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors> {
+        let mut keys = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        match keys.get_mut(description) {
+            Some(old_key) => {
+                *old_key = key.to_vec();
+                Ok(())
+            },
+            None => Err(SecretKeyErrors::KeyNotFound(description.to_string())),
+        }
+    }
+
+    fn delete(&self, description: &str) -> Result<(), SecretKeyErrors> {
+        let mut keys = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        keys.remove(description)
+            .map(|_| ())
+            .ok_or_else(|| SecretKeyErrors::KeyNotFound(description.to_string()))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, SecretKeyErrors> {
+        let keys = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        let mut descriptions: Vec<String> = keys.keys()
+            .filter(|description| description.starts_with(prefix))
+            .cloned()
+            .collect();
+        descriptions.sort();
+        Ok(descriptions)
+    }
+}
+
+// Header of the encrypted key file, followed by the salt, the nonce and the encrypted keys
+const KEY_FILE_MAGIC: &[u8; 8] = b"NIZKKS01";
+const KEY_FILE_SALT_SIZE: usize = 16;
+const KEY_FILE_NONCE_SIZE: usize = 12;
+const KEY_FILE_HEADER_SIZE: usize = KEY_FILE_MAGIC.len() + KEY_FILE_SALT_SIZE + KEY_FILE_NONCE_SIZE;
+
+// Key store that persists all keys in a file encrypted with a password, so keys survive reboots.
+// The file key is derived from the password using Argon2 and keys are encrypted with ChaCha20Poly1305.
+// Several processes can use the same file: changes are made under the file lock on the newest content,
+// and the keys are read again when another process replaced the file.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    salt: [u8; KEY_FILE_SALT_SIZE],
+    file_key: [u8; 32],
+    keys: Mutex<LoadedKeys>,
+}
+
+// Keys of the file with the version of the file they were read from
+struct LoadedKeys {
+    version: FileVersion,
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+// Inode and modification time. Every save renames a new file into place, so both change
+type FileVersion = (u64, i64, i64);
+
+fn file_version(path: &Path) -> Result<FileVersion, SecretKeyErrors> {
+    let metadata = fs::metadata(path).map_err(SecretKeyErrors::UnableToAccessKeyFile)?;
+    Ok((metadata.ino(), metadata.mtime(), metadata.mtime_nsec()))
+}
+
+impl EncryptedFileStore {
+    // Open the key file at the given path, or create an empty one if it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P, password: &[u8]) -> Result<EncryptedFileStore, SecretKeyErrors> {
+        let path = path.as_ref().to_path_buf();
+        let _lock = FileLock::acquire(&path).map_err(SecretKeyErrors::UnableToAccessKeyFile)?;
+
+        // Create a new empty store
+        if !path.exists() {
+            let mut salt = [0u8; KEY_FILE_SALT_SIZE];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let file_key = derive_file_key(password, &salt)?;
+            let store = EncryptedFileStore::with_keys(path, salt, file_key, BTreeMap::new())?;
+            store.save(&BTreeMap::new())?;
+            return Ok(store);
+        }
+
+        // Derive the file key with the salt of the file and decrypt the keys
+        let content = read_key_file(&path)?;
+        let salt = key_file_salt(&content)?;
+        let file_key = derive_file_key(password, &salt)?;
+        let keys = decrypt_key_file(&content, &file_key)?;
+        EncryptedFileStore::with_keys(path, salt, file_key, keys)
+    }
+
+    fn with_keys(path: PathBuf, salt: [u8; KEY_FILE_SALT_SIZE], file_key: [u8; 32], keys: BTreeMap<String, Vec<u8>>) -> Result<EncryptedFileStore, SecretKeyErrors> {
+        let version = if path.exists() { file_version(&path)? } else { (0, 0, 0) };
+        Ok(EncryptedFileStore {
+            path,
+            salt,
+            file_key,
+            keys: Mutex::new(LoadedKeys { version, keys }),
+        })
+    }
+
+    // Read the keys again if another process replaced the file
+    fn refresh(&self, loaded: &mut LoadedKeys) -> Result<(), SecretKeyErrors> {
+        let version = file_version(&self.path)?;
+        if version == loaded.version {
+            return Ok(());
+        }
+
+        let content = read_key_file(&self.path)?;
+        if key_file_salt(&content)? != self.salt {
+            return Err(SecretKeyErrors::InvalidKeyFile);
+        }
+        loaded.keys = decrypt_key_file(&content, &self.file_key)?;
+        loaded.version = version;
+        Ok(())
+    }
+
+    // Change the keys under the file lock, starting from the newest content of the file.
+    // The change is only kept in memory if it was saved to disk
+    fn modify<F>(&self, change: F) -> Result<(), SecretKeyErrors>
+        where F: FnOnce(&mut BTreeMap<String, Vec<u8>>) -> Result<(), SecretKeyErrors> {
+        let mut loaded = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        let _lock = FileLock::acquire(&self.path).map_err(SecretKeyErrors::UnableToAccessKeyFile)?;
+        self.refresh(&mut loaded)?;
+
+        let mut new_keys = loaded.keys.clone();
+        change(&mut new_keys)?;
+        self.save(&new_keys)?;
+        loaded.version = file_version(&self.path)?;
+        loaded.keys = new_keys;
+        Ok(())
+    }
+
+    // Encrypt all keys and replace the key file. The new content is written into a temporary file
+    // first and renamed afterwards, so the key file is never left half written.
+    fn save(&self, keys: &BTreeMap<String, Vec<u8>>) -> Result<(), SecretKeyErrors> {
+        let encoded: BTreeMap<&String, String> = keys.iter()
+            .map(|(description, key)| (description, hex::encode(key)))
+            .collect();
+        let plaintext = serde_json::to_vec(&encoded).map_err(|_| SecretKeyErrors::InvalidKeyFile)?;
+
+        // Build the header with a fresh nonce
+        let mut nonce = [0u8; KEY_FILE_NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut content = Vec::with_capacity(KEY_FILE_HEADER_SIZE + plaintext.len() + 16);
+        content.extend_from_slice(KEY_FILE_MAGIC);
+        content.extend_from_slice(&self.salt);
+        content.extend_from_slice(&nonce);
+
+        // Encrypt keys
+        let cipher = ChaCha20Poly1305::new(&AeadKey::from(self.file_key));
+        let ciphertext = cipher.encrypt(&Nonce::from(nonce), Payload { msg: &plaintext, aad: &content })
+            .map_err(|_| SecretKeyErrors::InvalidKeyFile)?;
+        content.extend_from_slice(&ciphertext);
+
+        // Write into a temporary file that only the user can read, and rename it. The caller holds the file lock
+        file_lock::write_atomic(&self.path, &content).map_err(SecretKeyErrors::UnableToAccessKeyFile)
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<Vec<u8>, SecretKeyErrors> {
+    let mut content = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut content))
+        .map_err(SecretKeyErrors::UnableToAccessKeyFile)?;
+    Ok(content)
+}
+
+// Check the header and return the salt
+fn key_file_salt(content: &[u8]) -> Result<[u8; KEY_FILE_SALT_SIZE], SecretKeyErrors> {
+    if content.len() < KEY_FILE_HEADER_SIZE || &content[..KEY_FILE_MAGIC.len()] != KEY_FILE_MAGIC {
+        return Err(SecretKeyErrors::InvalidKeyFile);
+    }
+    Ok(content[KEY_FILE_MAGIC.len()..][..KEY_FILE_SALT_SIZE].try_into().expect("salt has a fixed size"))
+}
+
+// Decrypt the keys. The header is authenticated as additional data
+fn decrypt_key_file(content: &[u8], file_key: &[u8; 32]) -> Result<BTreeMap<String, Vec<u8>>, SecretKeyErrors> {
+    key_file_salt(content)?;
+    let (header, ciphertext) = content.split_at(KEY_FILE_HEADER_SIZE);
+    let nonce: [u8; KEY_FILE_NONCE_SIZE] = header[KEY_FILE_MAGIC.len() + KEY_FILE_SALT_SIZE..].try_into().expect("nonce has a fixed size");
+    let cipher = ChaCha20Poly1305::new(&AeadKey::from(*file_key));
+    let plaintext = cipher.decrypt(&Nonce::from(nonce), Payload { msg: ciphertext, aad: header })
+        .map_err(|_| SecretKeyErrors::WrongPassword)?;
+
+    // Keys are saved as a json map of description to hex encoded key
+    let encoded: BTreeMap<String, String> = serde_json::from_slice(&plaintext)
+        .map_err(|_| SecretKeyErrors::InvalidKeyFile)?;
+    let mut keys = BTreeMap::new();
+    for (description, key) in encoded {
+        let key = hex::decode(key).map_err(|_| SecretKeyErrors::InvalidKeyFile)?;
+        keys.insert(description, key);
+    }
+    Ok(keys)
+}
+
+// Derive the key used to encrypt the key file from the password
+fn derive_file_key(password: &[u8], salt: &[u8]) -> Result<[u8; 32], SecretKeyErrors> {
+    let mut file_key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password, salt, &mut file_key)
+        .map_err(|_| SecretKeyErrors::InvalidKeyFile)?;
+    Ok(file_key)
+}
+
+impl KeyStore for EncryptedFileStore {
+    fn get(&self, description: &str) -> Result<Vec<u8>, SecretKeyErrors> {
+        let mut loaded = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        self.refresh(&mut loaded)?;
+        loaded.keys.get(description).cloned().ok_or_else(|| SecretKeyErrors::KeyNotFound(description.to_string()))
+    }
+
+    fn put(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors> {
+        self.modify(|keys| {
+            keys.insert(description.to_string(), key.to_vec());
+            Ok(())
+        })
+    }
+
+    fn update(&self, description: &str, key: &[u8]) -> Result<(), SecretKeyErrors> {
+        self.modify(|keys| match keys.get_mut(description) {
+            Some(old_key) => {
+                *old_key = key.to_vec();
+                Ok(())
+            },
+            None => Err(SecretKeyErrors::KeyNotFound(description.to_string())),
+        })
+    }
+
+    fn delete(&self, description: &str) -> Result<(), SecretKeyErrors> {
+        self.modify(|keys| match keys.remove(description) {
+            Some(_) => Ok(()),
+            None => Err(SecretKeyErrors::KeyNotFound(description.to_string())),
+        })
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, SecretKeyErrors> {
+        let mut loaded = self.keys.lock().map_err(|_| SecretKeyErrors::KeyStoreLockPoisoned)?;
+        self.refresh(&mut loaded)?;
+        Ok(loaded.keys.keys()
+            .filter(|description| description.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
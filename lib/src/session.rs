@@ -0,0 +1,156 @@
+use std::fmt;
+use subtle::ConstantTimeEq;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::schnorr_identification::{self, KMAC_KEY_CONFIRMATION, KMAC_SESSION_EXPAND, KMAC_SESSION_EXTRACT, KMAC_SESSION_EXPORTER};
+
+// Key schedule of a NIZK mutual authentication.
+//
+// Extract: prk = KMAC(ecdh point, transcript) with the transcript
+//   initiator commitment | responder commitment | initiator ID | responder ID | shared counter
+// Expand: every key is KMAC(prk, label), with a different label for each key.
+//
+// Optional key confirmation: after the two proofs, each device sends
+//   tag = KMAC(confirmation key, role label, transcript)
+// The initiator sends its tag first, the responder checks it and answers with its own tag.
+// A device is established once it has checked the tag of the other device.
+
+// Labels of the derived keys
+const LABEL_INITIATOR_TO_RESPONDER: &[u8] = b"initiator to responder";
+const LABEL_RESPONDER_TO_INITIATOR: &[u8] = b"responder to initiator";
+const LABEL_CONFIRMATION: &[u8] = b"key confirmation";
+const LABEL_EXPORTER: &[u8] = b"exporter";
+
+// Labels of the confirmation tags
+const LABEL_INITIATOR_CONFIRMATION: &[u8] = b"initiator confirmation";
+const LABEL_RESPONDER_CONFIRMATION: &[u8] = b"responder confirmation";
+
+// Values of the mutual authentication both devices agree on
+pub(crate) struct SessionTranscript {
+    pub initiator_ID: DeviceId,
+    pub responder_ID: DeviceId,
+    pub initiator_commitment: [u8; 32],
+    pub responder_commitment: [u8; 32],
+    pub shared_counter: u32,
+}
+
+impl SessionTranscript {
+    fn encode(&self) -> Vec<u8> {
+        let mut values = Vec::with_capacity(76);
+        values.extend_from_slice(&self.initiator_commitment);
+        values.extend_from_slice(&self.responder_commitment);
+        self.initiator_ID.encode_into(&mut values);
+        self.responder_ID.encode_into(&mut values);
+        values.extend_from_slice(&self.shared_counter.to_be_bytes());
+        values
+    }
+}
+
+// Keys of a session between an initiator and a responder
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    // Encryption key for messages of the initiator
+    pub initiator_to_responder: [u8; 32],
+    // Encryption key for messages of the responder
+    pub responder_to_initiator: [u8; 32],
+    // Key to confirm that both devices derived the same keys
+    pub confirmation: [u8; 32],
+    // Secret for deriving further keys of the application, see export
+    pub exporter: [u8; 32],
+}
+
+impl SessionKeys {
+    // Derive all session keys from the ECDH point and the transcript
+    pub(crate) fn derive(ecdh_point: [u8; 32], transcript: &SessionTranscript) -> SessionKeys {
+        let values = transcript.encode();
+
+        // Extract a pseudorandom key and expand it into the session keys
+        let prk = schnorr_identification::kmac_256(ecdh_point, KMAC_SESSION_EXTRACT, &values, None, None);
+        let expand = |label: &[u8]| schnorr_identification::kmac_256(prk, KMAC_SESSION_EXPAND, label, None, None);
+
+        SessionKeys {
+            initiator_to_responder: expand(LABEL_INITIATOR_TO_RESPONDER),
+            responder_to_initiator: expand(LABEL_RESPONDER_TO_INITIATOR),
+            confirmation: expand(LABEL_CONFIRMATION),
+            exporter: expand(LABEL_EXPORTER),
+        }
+    }
+
+    // Derive a key for the application from the exporter secret. Different labels give independent keys
+    pub fn export(&self, label: &[u8], context: &[u8]) -> [u8; 32] {
+        let mut prefixed_label = (label.len() as u32).to_be_bytes().to_vec();
+        prefixed_label.extend_from_slice(label);
+        schnorr_identification::kmac_256(self.exporter, KMAC_SESSION_EXPORTER, &prefixed_label, Some(context), None)
+    }
+}
+
+// Keys are not printed
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKeys { .. }")
+    }
+}
+
+// State of the key confirmation round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    // Keys are derived, the tag of the other device was not checked yet
+    AwaitingConfirmation,
+    // Other device derived the same keys
+    Established,
+}
+
+// Key confirmation round of a NIZK mutual authentication
+#[derive(Debug)]
+pub struct KeyConfirmation {
+    keys: SessionKeys,
+    transcript: Vec<u8>,
+    initiator: bool,
+    other_ID: DeviceId,
+    state: SessionState,
+}
+
+impl KeyConfirmation {
+    pub(crate) fn new(keys: SessionKeys, transcript: &SessionTranscript, initiator: bool) -> KeyConfirmation {
+        let other_ID = if initiator { &transcript.responder_ID } else { &transcript.initiator_ID };
+        KeyConfirmation {
+            keys,
+            transcript: transcript.encode(),
+            initiator,
+            other_ID: other_ID.clone(),
+            state: SessionState::AwaitingConfirmation,
+        }
+    }
+
+    fn calculate_tag(&self, initiator: bool) -> [u8; 32] {
+        let label = if initiator { LABEL_INITIATOR_CONFIRMATION } else { LABEL_RESPONDER_CONFIRMATION };
+        schnorr_identification::kmac_256(self.keys.confirmation, KMAC_KEY_CONFIRMATION, label, Some(&self.transcript), None)
+    }
+
+    // Tag to send to the other device
+    pub fn tag(&self) -> [u8; 32] {
+        self.calculate_tag(self.initiator)
+    }
+
+    // Check the tag of the other device. A wrong tag means that the devices derived different keys
+    pub fn verify(&mut self, tag: [u8; 32]) -> Result<(), NizkError> {
+        // Compared in constant time, so the time does not tell how many bytes of a guessed tag are right
+        if !bool::from(tag.ct_eq(&self.calculate_tag(!self.initiator))) {
+            return Err(NizkError::KeyConfirmationFailed(self.other_ID.clone()));
+        }
+        self.state = SessionState::Established;
+        Ok(())
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    // Return the session keys, only after the tag of the other device was accepted
+    pub fn session_keys(self) -> Result<SessionKeys, NizkError> {
+        match self.state {
+            SessionState::Established => Ok(self.keys),
+            SessionState::AwaitingConfirmation => Err(NizkError::WrongState("key confirmation is not finished")),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::file_lock::FileLock;
+use crate::schnorr_identification;
+use crate::secret_management::{KeyStore, SecretKeyErrors};
+
+// Shared secret key and shared counter of a device pair, saved as one record in the key store.
+//
+// The record is saved with a single put, so key and counter can not diverge on a crash.
+// Before a new record is saved the old one is copied to "SharedState:<a>:<b>:prev". If the current
+// record is not valid (a write of the storage backend was torn), the previous record is used instead.
+//
+// Record layout: version (1 byte) | epoch (4 bytes) | counter (4 bytes) | key (32 bytes) | checksum (8 bytes)
+//
+// Proofs that ratchet the shared values hold the lock of the device pair from loading the record until the
+// new record is saved, so two threads or processes never accept a proof with the same counter.
+
+// Version of the record layout
+const RECORD_VERSION: u8 = 1;
+
+// Size of a record in bytes
+const RECORD_SIZE: usize = 1 + 4 + 4 + 32 + 8;
+
+// Key and counter of a device pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedState {
+    // Incremented each time a new shared key is agreed on, by pairing or by a resync
+    pub epoch: u32,
+    pub counter: u32,
+    pub key: [u8; 32],
+}
+
+// What the recovery found for a device pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    // Current record is valid
+    Intact,
+    // Current record was broken and restored from the previous record
+    Repaired,
+    // Separate legacy key and counter were moved into a record
+    Migrated,
+}
+
+fn get_description(my_ID: &DeviceId, other_ID: &DeviceId) -> String {
+    format!("SharedState:{}:{}", my_ID, other_ID)
+}
+
+fn get_prev_description(my_ID: &DeviceId, other_ID: &DeviceId) -> String {
+    format!("SharedState:{}:{}:prev", my_ID, other_ID)
+}
+
+impl SharedState {
+    // Encode the record with its checksum
+    fn encode(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(RECORD_SIZE);
+        record.push(RECORD_VERSION);
+        record.extend_from_slice(&self.epoch.to_be_bytes());
+        record.extend_from_slice(&self.counter.to_be_bytes());
+        record.extend_from_slice(&self.key);
+        let checksum = schnorr_identification::sha3_256(&record, None, None, None);
+        record.extend_from_slice(&checksum[..8]);
+        record
+    }
+
+    // Decode a record, returns None if it is half-written or corrupted
+    fn decode(record: &[u8]) -> Option<SharedState> {
+        if record.len() != RECORD_SIZE || record[0] != RECORD_VERSION {
+            return None;
+        }
+
+        // Compare the checksum
+        let (content, checksum) = record.split_at(RECORD_SIZE - 8);
+        let expected = schnorr_identification::sha3_256(content, None, None, None);
+        if checksum != &expected[..8] {
+            return None;
+        }
+
+        Some(SharedState {
+            epoch: u32::from_be_bytes(content[1..5].try_into().ok()?),
+            counter: u32::from_be_bytes(content[5..9].try_into().ok()?),
+            key: content[9..41].try_into().ok()?,
+        })
+    }
+}
+
+// Read a record, Ok(None) if it does not exist
+fn read_record(store: &dyn KeyStore, description: &str) -> Result<Option<Vec<u8>>, NizkError> {
+    match store.get(description) {
+        Ok(record) => Ok(Some(record)),
+        Err(SecretKeyErrors::KeyNotFound(_)) => Ok(None),
+        Err(e) => Err(NizkError::KeyRing(e)),
+    }
+}
+
+// Lock the shared state with another device. The key store has no locks, so the lock is a file in the state directory
+pub(crate) fn lock(my_ID: &DeviceId, other_ID: &DeviceId) -> Result<FileLock, NizkError> {
+    let path = config::state_path(&format!("shared_state_{}_{}", my_ID.file_name(), other_ID.file_name()))?;
+    Ok(FileLock::acquire(&path)?)
+}
+
+// Load the shared state with another device, repairing or migrating it if necessary
+pub fn load(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId) -> Result<SharedState, NizkError> {
+    let (state, _) = load_and_recover(store, my_ID, other_ID)?;
+    Ok(state)
+}
+
+// Save a new shared state. The current record is kept as previous record first
+pub fn save(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId, state: &SharedState) -> Result<(), NizkError> {
+    let description = get_description(my_ID, other_ID);
+    if let Some(record) = read_record(store, &description)? {
+        if SharedState::decode(&record).is_some() {
+            store.put(&get_prev_description(my_ID, other_ID), &record)?;
+        }
+    }
+
+    store.put(&description, &state.encode())?;
+    Ok(())
+}
+
+// Save a newly agreed shared key, starting a new epoch with counter 1
+pub fn establish(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId, key: [u8; 32]) -> Result<SharedState, NizkError> {
+    // Continue with the epoch of the old state. A state that can not be read is an error, it has to be revoked first
+    let epoch = match load_and_recover(store, my_ID, other_ID) {
+        Ok((old_state, _)) => old_state.epoch.wrapping_add(1),
+        Err(NizkError::KeyRing(SecretKeyErrors::KeyNotFound(_))) => 1,
+        Err(e) => return Err(e),
+    };
+
+    let state = SharedState {
+        epoch,
+        counter: 1,
+        key,
+    };
+    save(store, my_ID, other_ID, &state)?;
+    Ok(state)
+}
+
+// Delete the shared state with another device, including the previous record and legacy keys.
+// A new shared key has to be agreed on before proofs with the device are accepted again
+pub fn revoke(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId) -> Result<(), NizkError> {
+    let descriptions = [
+        get_description(my_ID, other_ID),
+        get_prev_description(my_ID, other_ID),
+        format!("SharedSecretKey:{}:{}", my_ID, other_ID),
+        format!("SharedCounter:{}:{}", my_ID, other_ID),
+    ];
+    for description in descriptions {
+        match store.delete(&description) {
+            Ok(()) | Err(SecretKeyErrors::KeyNotFound(_)) => (),
+            Err(e) => return Err(NizkError::KeyRing(e)),
+        }
+    }
+    Ok(())
+}
+
+// Check the shared states with all other devices, should be called when the device starts.
+// Broken records are restored and legacy keys are migrated
+pub fn recover(store: &dyn KeyStore, my_ID: &DeviceId) -> Result<Vec<(DeviceId, Recovery)>, NizkError> {
+    // Collect the IDs of all devices with a record or legacy keys
+    let mut other_IDs: Vec<DeviceId> = Vec::new();
+    for prefix in ["SharedState", "SharedSecretKey"] {
+        let prefix = format!("{}:{}:", prefix, my_ID);
+        for description in store.list(&prefix)? {
+            // The ID of the other device may contain ":" itself, only the suffix of the previous record is removed
+            let rest = &description[prefix.len()..];
+            let id = rest.strip_suffix(":prev").unwrap_or(rest).parse::<DeviceId>().ok();
+            if let Some(id) = id {
+                if !other_IDs.contains(&id) {
+                    other_IDs.push(id);
+                }
+            }
+        }
+    }
+
+    // Recover each of them
+    let mut result = Vec::new();
+    for other_ID in other_IDs {
+        let (_, recovery) = load_and_recover(store, my_ID, &other_ID)?;
+        result.push((other_ID, recovery));
+    }
+    Ok(result)
+}
+
+fn load_and_recover(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId) -> Result<(SharedState, Recovery), NizkError> {
+    let description = get_description(my_ID, other_ID);
+
+    // Current record is valid
+    let current = read_record(store, &description)?;
+    if let Some(state) = current.as_deref().and_then(SharedState::decode) {
+        return Ok((state, Recovery::Intact));
+    }
+
+    // Current record is broken, restore the previous one
+    if current.is_some() {
+        let previous = read_record(store, &get_prev_description(my_ID, other_ID))?;
+        return match previous.as_deref().and_then(SharedState::decode) {
+            Some(state) => {
+                store.put(&description, &state.encode())?;
+                Ok((state, Recovery::Repaired))
+            },
+            None => Err(NizkError::CorruptedSharedState(other_ID.clone())),
+        };
+    }
+
+    // No record yet, migrate separate key and counter of older versions
+    let state = migrate_legacy(store, my_ID, other_ID)?;
+    Ok((state, Recovery::Migrated))
+}
+
+// Move "SharedSecretKey:<a>:<b>" and "SharedCounter:<a>:<b>" into a record
+fn migrate_legacy(store: &dyn KeyStore, my_ID: &DeviceId, other_ID: &DeviceId) -> Result<SharedState, NizkError> {
+    let key_description = format!("SharedSecretKey:{}:{}", my_ID, other_ID);
+    let counter_description = format!("SharedCounter:{}:{}", my_ID, other_ID);
+
+    // Fetch legacy key and counter
+    let key = crate::get_32byte_key(store, key_description.clone())?;
+    let counter = store.get(&counter_description)?;
+    let counter: [u8; 4] = counter.as_slice().try_into()
+        .map_err(|_| NizkError::InvalidKeyLength { description: counter_description.clone(), expected: 4, found: counter.len() })?;
+
+    // Save the record before deleting the old keys
+    let state = SharedState {
+        epoch: 0,
+        counter: u32::from_be_bytes(counter),
+        key,
+    };
+    store.put(&get_description(my_ID, other_ID), &state.encode())?;
+    store.delete(&key_description)?;
+    store.delete(&counter_description)?;
+    Ok(state)
+}
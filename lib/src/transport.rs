@@ -0,0 +1,297 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::{self, JoinSet};
+use tokio::time;
+use crate::device_id::DeviceId;
+use crate::engine::{self, Engine, Event};
+use crate::error::NizkError;
+use crate::rate_limit::{Decision, RateLimiter, Subject};
+use crate::secret_management::KeyStore;
+use crate::session::SessionKeys;
+use crate::wire::{Header, Message, PREFIX_SIZE};
+use crate::NizkMode;
+
+// Async transport over Tokio, available with the "tokio" feature.
+//
+// NizkClient runs the initiator side of the protocols and NizkServer answers them, both over any
+// AsyncRead + AsyncWrite stream, e.g. a TcpStream or an in-memory duplex. The sequencing is done by
+// the protocol engine, this module only moves the messages. Every protocol run has a timeout, so a
+// silent device can not hold a connection. The engine and the key store block on file locks, Argon2 and
+// fsync, so they run on the blocking thread pool of Tokio.
+
+// Default time for a whole protocol run
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Pause after a failed accept before the next one
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Read one message from an async stream
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message, NizkError> {
+    let mut bytes = vec![0u8; PREFIX_SIZE];
+    reader.read_exact(&mut bytes).await?;
+    let remaining = Header::remaining_length(bytes[..].try_into().expect("prefix has a fixed size"))?;
+
+    bytes.resize(PREFIX_SIZE + remaining, 0);
+    reader.read_exact(&mut bytes[PREFIX_SIZE..]).await?;
+    Message::decode(&bytes)
+}
+
+// Write the encoded message into an async stream
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<(), NizkError> {
+    writer.write_all(&message.encode()?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// Run blocking work on the blocking thread pool. A panic of the work is resumed on the task
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Result<T, NizkError> {
+    match task::spawn_blocking(work).await {
+        Ok(result) => Ok(result),
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(NizkError::Io(io::Error::other(e))),
+    }
+}
+
+// Give a received message to the engine
+async fn handle_message(mut engine: Engine, message: Message) -> Result<(Engine, Vec<Event>), NizkError> {
+    blocking(move || {
+        let events = engine.handle_message(message);
+        (engine, events)
+    }).await
+}
+
+// Limit a protocol run to the given time
+async fn with_timeout<T>(timeout: Duration, future: impl Future<Output = Result<T, NizkError>>) -> Result<T, NizkError> {
+    time::timeout(timeout, future).await.map_err(|_| NizkError::Timeout)?
+}
+
+// Send the messages of the engine and give it the received ones until the protocol is finished.
+// Returns the Established or Verified event. peer_ID is set once the engine knows the other device
+async fn run_engine<S: AsyncRead + AsyncWrite + Unpin>(mut engine: Engine, stream: &mut S, mut events: Vec<Event>, peer_ID: &mut Option<DeviceId>) -> Result<Event, NizkError> {
+    loop {
+        for event in events {
+            match event {
+                Event::NeedSend(message) => write_message(stream, &message).await?,
+                Event::Rejected { reason } => return Err(reason),
+                finished => return Ok(finished),
+            }
+        }
+
+        let message = read_message(stream).await?;
+        (engine, events) = handle_message(engine, message).await?;
+        *peer_ID = engine.peer_ID().cloned();
+    }
+}
+
+// Initiator of the protocols
+pub struct NizkClient {
+    store: Arc<dyn KeyStore>,
+    my_ID: DeviceId,
+    timeout: Duration,
+}
+
+impl NizkClient {
+    pub fn new(store: Arc<dyn KeyStore>, my_ID: DeviceId) -> NizkClient {
+        NizkClient {
+            store,
+            my_ID,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    // Change the time a protocol run may take
+    pub fn with_timeout(mut self, timeout: Duration) -> NizkClient {
+        self.timeout = timeout;
+        self
+    }
+
+    // Run the interactive key agreement, or a resync. The shared secret key is saved in the key store
+    pub async fn key_agreement<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S, peer_ID: &DeviceId, resync: bool) -> Result<(), NizkError> {
+        let (store, my_ID, peer) = (self.store.clone(), self.my_ID.clone(), peer_ID.clone());
+        let (engine, events) = blocking(move || Engine::key_agreement(store, my_ID, peer, resync)).await??;
+        with_timeout(self.timeout, run_engine(engine, stream, events, &mut Some(peer_ID.clone()))).await?;
+        Ok(())
+    }
+
+    // Run the NIZK mutual authentication with key confirmation and return the session keys
+    pub async fn mut_auth<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S, peer_ID: &DeviceId) -> Result<SessionKeys, NizkError> {
+        let (store, my_ID, peer) = (self.store.clone(), self.my_ID.clone(), peer_ID.clone());
+        let (engine, events) = blocking(move || Engine::mut_auth(store, my_ID, peer, true)).await??;
+        match with_timeout(self.timeout, run_engine(engine, stream, events, &mut Some(peer_ID.clone()))).await? {
+            Event::Established { session_key: Some(session_key), .. } => Ok(session_key),
+            _ => Err(NizkError::WrongState("mutual authentication finished without session keys")),
+        }
+    }
+
+    // Send an application message with a one-shot NIZK proof
+    pub async fn send_message<S: AsyncWrite + Unpin>(&self, stream: &mut S, mode: NizkMode<'_>, peer_ID: &DeviceId, message: &[u8]) -> Result<(), NizkError> {
+        // The mode borrows its context, the blocking work needs its own copy
+        let (store, my_ID, peer, message) = (self.store.clone(), self.my_ID.clone(), peer_ID.clone(), message.to_vec());
+        let (update_keys, context) = match mode {
+            NizkMode::SharedKey { update_keys } => (update_keys, None),
+            NizkMode::PublicKey { context } => (false, Some(context.to_vec())),
+        };
+        let message = blocking(move || {
+            let mode = match &context {
+                Some(context) => NizkMode::PublicKey { context },
+                None => NizkMode::SharedKey { update_keys },
+            };
+            engine::nizk_message(&*store, mode, &my_ID, &peer, &message)
+        }).await??;
+        with_timeout(self.timeout, write_message(stream, &message)).await
+    }
+}
+
+// Callbacks of the server, called from the task of the connection
+pub trait MessageHandler: Send + Sync + 'static {
+    // Proof of an application message was accepted
+    fn on_message(&self, peer_ID: DeviceId, message: Vec<u8>);
+
+    // Key agreement or mutual authentication finished. Session keys are only set for the mutual authentication
+    fn on_established(&self, _peer_ID: DeviceId, _session_key: Option<SessionKeys>) {}
+
+    // Protocol was aborted. The device is unknown if its first message was invalid
+    fn on_rejected(&self, _peer_ID: Option<&DeviceId>, _reason: &NizkError) {}
+
+    // Accepting a connection failed, the server goes on after a short pause
+    fn on_accept_error(&self, _error: &io::Error) {}
+}
+
+// Responder of the protocols, passing authenticated messages to the handler
+pub struct NizkServer<H: MessageHandler> {
+    store: Arc<dyn KeyStore>,
+    my_ID: DeviceId,
+    timeout: Duration,
+    public_context: Option<Vec<u8>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    handler: H,
+}
+
+impl<H: MessageHandler> NizkServer<H> {
+    pub fn new(store: Arc<dyn KeyStore>, my_ID: DeviceId, handler: H) -> NizkServer<H> {
+        NizkServer {
+            store,
+            my_ID,
+            timeout: DEFAULT_TIMEOUT,
+            public_context: None,
+            rate_limiter: None,
+            handler,
+        }
+    }
+
+    // Change the time a protocol run may take
+    pub fn with_timeout(mut self, timeout: Duration) -> NizkServer<H> {
+        self.timeout = timeout;
+        self
+    }
+
+    // Accept messages with proofs in public key mode and the given context
+    pub fn with_public_context(mut self, context: &[u8]) -> NizkServer<H> {
+        self.public_context = Some(context.to_vec());
+        self
+    }
+
+    // Refuse throttled and blocked addresses and devices, and count their rejected proofs
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> NizkServer<H> {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    // Refuse the request if the rate limiter does not allow it
+    fn check_rate(&self, subject: &Subject) -> Result<(), NizkError> {
+        match self.rate_limiter.as_ref().map(|rate_limiter| rate_limiter.check(subject)) {
+            Some(Decision::Allow) | None => Ok(()),
+            Some(decision) => Err(NizkError::RateLimited(decision)),
+        }
+    }
+
+    // Answer one protocol run on the stream. The stream can be used for the application afterwards
+    pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<(), NizkError> {
+        self.handle(stream, None).await
+    }
+
+    async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S, address: Option<IpAddr>) -> Result<(), NizkError> {
+        let mut engine = Engine::responder(self.store.clone(), self.my_ID.clone()).with_key_confirmation(true);
+        if let Some(context) = &self.public_context {
+            engine = engine.with_public_context(context);
+        }
+
+        let mut peer_ID = None;
+        let result = with_timeout(self.timeout, async {
+            let message = read_message(stream).await?;
+
+            // A device that is throttled or blocked is refused before its proof is checked
+            self.check_rate(&Subject::Device(message.sender_ID.clone()))?;
+            let (engine, events) = handle_message(engine, message).await?;
+            peer_ID = engine.peer_ID().cloned();
+            run_engine(engine, stream, events, &mut peer_ID).await
+        }).await;
+
+        match result {
+            Ok(Event::Verified { peer_ID, message }) => self.handler.on_message(peer_ID, message),
+            Ok(Event::Established { peer_ID, session_key }) => self.handler.on_established(peer_ID, session_key),
+            Ok(Event::NeedSend(_) | Event::Rejected { .. }) => unreachable!("run_engine only returns finished protocols"),
+            Err(e) => {
+                // Rejected proofs count for the device and the address, too many of them block both
+                let failed_proof = matches!(e, NizkError::ProofRejected | NizkError::ReplayedCommitment
+                    | NizkError::Desynchronized(_) | NizkError::KeyConfirmationFailed(_));
+                if let (Some(rate_limiter), true) = (&self.rate_limiter, failed_proof) {
+                    let subjects = peer_ID.clone().map(Subject::Device).into_iter().chain(address.map(Subject::Address));
+                    for subject in subjects {
+                        rate_limiter.record_rejection(&subject);
+                    }
+                }
+                self.handler.on_rejected(peer_ID.as_ref(), &e);
+                return Err(e);
+            },
+        }
+        Ok(())
+    }
+
+    // Accept connections until shutdown is completed, then wait for the running connections.
+    // Each connection runs one protocol in its own task
+    pub async fn serve<F: Future<Output = ()>>(self: Arc<Self>, listener: TcpListener, shutdown: F) -> Result<(), NizkError> {
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = listener.accept() => {
+                    // A failed accept, e.g. with too many open files, does not end the server
+                    let (mut stream, address) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            self.handler.on_accept_error(&e);
+                            time::sleep(ACCEPT_RETRY_DELAY).await;
+                            continue;
+                        },
+                    };
+
+                    // A throttled or blocked address is disconnected before anything is read
+                    if let Err(e) = self.check_rate(&Subject::Address(address.ip())) {
+                        self.handler.on_rejected(None, &e);
+                        continue;
+                    }
+                    let server = self.clone();
+                    connections.spawn(async move {
+                        // Errors are reported to the handler
+                        let _ = server.handle(&mut stream, Some(address.ip())).await;
+                    });
+                },
+                // Clean up finished connections
+                Some(_) = connections.join_next(), if !connections.is_empty() => {},
+            }
+        }
+
+        // Running connections end at the latest after the timeout
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use std::io::{Read, Write};
+use curve25519_dalek::scalar::Scalar;
+use crate::device_id::DeviceId;
+use crate::error::NizkError;
+use crate::int_mut_auth::{ChallengeAndResponse, Commitment, CommitmentAndChallenge, Response};
+use crate::{schnorr_identification, NizkProof};
+
+// Binary encoding of all protocol messages.
+//
+// Every message starts with a header, followed by the body:
+//   version (1 byte) | message type (1 byte) | IDs length (1 byte) | body length (2 bytes) | sender ID | recipient ID
+// The first five bytes have a fixed size, so a reader knows how many bytes follow. The device IDs use the
+// tagged wire form of DeviceId. All integers are big endian. Curve points, challenges and responses are fixed 32-byte fields,
+// the message of a NIZK proof is prefixed with its length (2 bytes).
+
+// Current version of the wire format
+pub const WIRE_VERSION: u8 = 2;
+
+// Size of the fixed start of the header in bytes
+pub const PREFIX_SIZE: usize = 5;
+
+// Biggest possible body of a message
+pub const MAX_BODY_SIZE: usize = u16::MAX as usize;
+
+// Biggest message that can be included in a NIZK proof
+pub const MAX_NIZK_MESSAGE_SIZE: usize = MAX_BODY_SIZE - 3 * 32 - 2;
+
+// Message types
+const TYPE_INT_COMMITMENT: u8 = 0x01;
+const TYPE_INT_COMMITMENT_AND_CHALLENGE: u8 = 0x02;
+const TYPE_INT_CHALLENGE_AND_RESPONSE: u8 = 0x03;
+const TYPE_INT_RESPONSE: u8 = 0x04;
+const TYPE_RESYNC_REQUEST: u8 = 0x05;
+const TYPE_NIZK_PROOF: u8 = 0x10;
+const TYPE_PUBLIC_NIZK_PROOF: u8 = 0x11;
+const TYPE_NIZK_MUT_AUTH_PROOF: u8 = 0x20;
+const TYPE_KEY_CONFIRMATION: u8 = 0x21;
+const TYPE_PUBLIC_KEY: u8 = 0x30;
+
+// Content of a protocol message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    // Interactive mutual authentication
+    IntCommitment(Commitment),
+    IntCommitmentAndChallenge(CommitmentAndChallenge),
+    IntChallengeAndResponse(ChallengeAndResponse),
+    IntResponse(Response),
+    // First message of a resync, the rest of the handshake uses the interactive messages
+    ResyncRequest(Commitment),
+    // NIZK proof of a message
+    NizkProof { proof: NizkProof, message: Vec<u8> },
+    // NIZK proof of a message in public key mode, the context is known by the verifier
+    PublicNizkProof { proof: NizkProof, message: Vec<u8> },
+    // NIZK mutual authentication for session keys
+    NizkMutAuthProof(NizkProof),
+    // Key confirmation tag after the NIZK mutual authentication
+    KeyConfirmation([u8; 32]),
+    // Public key of the sender, used when pairing devices
+    PublicKey([u8; 32]),
+}
+
+// Header fields of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub message_type: u8,
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
+    pub body_length: u16,
+}
+
+// A protocol message with the IDs of both devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
+    pub body: Body,
+}
+
+impl Body {
+    fn message_type(&self) -> u8 {
+        match self {
+            Body::IntCommitment(_) => TYPE_INT_COMMITMENT,
+            Body::IntCommitmentAndChallenge(_) => TYPE_INT_COMMITMENT_AND_CHALLENGE,
+            Body::IntChallengeAndResponse(_) => TYPE_INT_CHALLENGE_AND_RESPONSE,
+            Body::IntResponse(_) => TYPE_INT_RESPONSE,
+            Body::ResyncRequest(_) => TYPE_RESYNC_REQUEST,
+            Body::NizkProof { .. } => TYPE_NIZK_PROOF,
+            Body::PublicNizkProof { .. } => TYPE_PUBLIC_NIZK_PROOF,
+            Body::NizkMutAuthProof(_) => TYPE_NIZK_MUT_AUTH_PROOF,
+            Body::KeyConfirmation(_) => TYPE_KEY_CONFIRMATION,
+            Body::PublicKey(_) => TYPE_PUBLIC_KEY,
+        }
+    }
+
+    // Write all fields of the body
+    fn encode_into(&self, buffer: &mut Vec<u8>) -> Result<(), NizkError> {
+        match self {
+            Body::IntCommitment(m) | Body::ResyncRequest(m) => {
+                buffer.extend_from_slice(&m.commitment);
+            },
+            Body::IntCommitmentAndChallenge(m) => {
+                buffer.extend_from_slice(&m.commitment);
+                buffer.extend_from_slice(&m.challenge);
+            },
+            Body::IntChallengeAndResponse(m) => {
+                buffer.extend_from_slice(&m.challenge);
+                buffer.extend_from_slice(&m.response);
+            },
+            Body::IntResponse(m) => {
+                buffer.extend_from_slice(&m.response);
+            },
+            Body::NizkProof { proof, message } | Body::PublicNizkProof { proof, message } => {
+                if message.len() > MAX_NIZK_MESSAGE_SIZE {
+                    return Err(NizkError::InvalidMessage("NIZK message is too long"));
+                }
+                encode_proof(buffer, proof);
+                buffer.extend_from_slice(&(message.len() as u16).to_be_bytes());
+                buffer.extend_from_slice(message);
+            },
+            Body::NizkMutAuthProof(proof) => {
+                encode_proof(buffer, proof);
+            },
+            Body::KeyConfirmation(tag) => {
+                buffer.extend_from_slice(tag);
+            },
+            Body::PublicKey(key) => {
+                buffer.extend_from_slice(key);
+            },
+        }
+        Ok(())
+    }
+
+    // Read and validate all fields of a body with the given type
+    fn decode(message_type: u8, body: &[u8]) -> Result<Body, NizkError> {
+        let mut reader = FieldReader { bytes: body };
+        let decoded = match message_type {
+            TYPE_INT_COMMITMENT => {
+                Body::IntCommitment(Commitment {
+                    commitment: reader.point()?,
+                })
+            },
+            TYPE_INT_COMMITMENT_AND_CHALLENGE => {
+                Body::IntCommitmentAndChallenge(CommitmentAndChallenge {
+                    commitment: reader.point()?,
+                    challenge: reader.bytes32()?,
+                })
+            },
+            TYPE_INT_CHALLENGE_AND_RESPONSE => {
+                Body::IntChallengeAndResponse(ChallengeAndResponse {
+                    challenge: reader.bytes32()?,
+                    response: reader.scalar()?,
+                })
+            },
+            TYPE_INT_RESPONSE => {
+                Body::IntResponse(Response {
+                    response: reader.scalar()?,
+                })
+            },
+            TYPE_RESYNC_REQUEST => {
+                Body::ResyncRequest(Commitment {
+                    commitment: reader.point()?,
+                })
+            },
+            TYPE_NIZK_PROOF => {
+                let proof = reader.proof()?;
+                let length = reader.u16()? as usize;
+                let message = reader.take(length)?.to_vec();
+                Body::NizkProof { proof, message }
+            },
+            TYPE_PUBLIC_NIZK_PROOF => {
+                let proof = reader.proof()?;
+                let length = reader.u16()? as usize;
+                let message = reader.take(length)?.to_vec();
+                Body::PublicNizkProof { proof, message }
+            },
+            TYPE_NIZK_MUT_AUTH_PROOF => {
+                Body::NizkMutAuthProof(reader.proof()?)
+            },
+            TYPE_KEY_CONFIRMATION => {
+                Body::KeyConfirmation(reader.bytes32()?)
+            },
+            TYPE_PUBLIC_KEY => {
+                Body::PublicKey(reader.point()?)
+            },
+            _ => {
+                return Err(NizkError::InvalidMessage("unknown message type"));
+            }
+        };
+
+        // Reject trailing bytes
+        if !reader.bytes.is_empty() {
+            return Err(NizkError::InvalidMessage("unexpected bytes after the message body"));
+        }
+        Ok(decoded)
+    }
+}
+
+impl Header {
+    // Validate the fixed start of a header and return the number of bytes that follow it
+    pub fn remaining_length(prefix: &[u8; PREFIX_SIZE]) -> Result<usize, NizkError> {
+        if prefix[0] != WIRE_VERSION {
+            return Err(NizkError::UnsupportedVersion(prefix[0]));
+        }
+        Ok(prefix[2] as usize + u16::from_be_bytes([prefix[3], prefix[4]]) as usize)
+    }
+
+    // Read and validate a message header, returns the header and the bytes after it
+    pub fn decode(bytes: &[u8]) -> Result<(Header, &[u8]), NizkError> {
+        if bytes.len() < PREFIX_SIZE {
+            return Err(NizkError::InvalidMessage("message is shorter than the header"));
+        }
+        let (prefix, rest) = bytes.split_at(PREFIX_SIZE);
+        Header::remaining_length(prefix.try_into().expect("prefix has a fixed size"))?;
+
+        // Both IDs have to fill exactly the announced length
+        let ids_length = prefix[2] as usize;
+        if rest.len() < ids_length {
+            return Err(NizkError::InvalidMessage("message is shorter than the header"));
+        }
+        let (ids, rest) = rest.split_at(ids_length);
+        let (sender_ID, used) = DeviceId::decode(ids)?;
+        let (recipient_ID, used_recipient) = DeviceId::decode(&ids[used..])?;
+        if used + used_recipient != ids_length {
+            return Err(NizkError::InvalidMessage("IDs length does not match the header"));
+        }
+
+        let header = Header {
+            version: prefix[0],
+            message_type: prefix[1],
+            sender_ID,
+            recipient_ID,
+            body_length: u16::from_be_bytes([prefix[3], prefix[4]]),
+        };
+        Ok((header, rest))
+    }
+}
+
+impl Message {
+    pub fn new(sender_ID: DeviceId, recipient_ID: DeviceId, body: Body) -> Message {
+        Message {
+            sender_ID,
+            recipient_ID,
+            body,
+        }
+    }
+
+    // Encode the message with its header
+    pub fn encode(&self) -> Result<Vec<u8>, NizkError> {
+        // Encode body first to know its length
+        let mut body = Vec::new();
+        self.body.encode_into(&mut body)?;
+        if body.len() > MAX_BODY_SIZE {
+            return Err(NizkError::InvalidMessage("message body is too long"));
+        }
+
+        // IDs are at most 66 bytes each, so their length always fits into one byte
+        let mut ids = Vec::new();
+        self.sender_ID.encode_into(&mut ids);
+        self.recipient_ID.encode_into(&mut ids);
+
+        // Write header followed by the body
+        let mut buffer = Vec::with_capacity(PREFIX_SIZE + ids.len() + body.len());
+        buffer.push(WIRE_VERSION);
+        buffer.push(self.body.message_type());
+        buffer.push(ids.len() as u8);
+        buffer.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&ids);
+        buffer.extend_from_slice(&body);
+        Ok(buffer)
+    }
+
+    // Decode a complete message. The buffer has to contain exactly one message
+    pub fn decode(bytes: &[u8]) -> Result<Message, NizkError> {
+        let (header, body) = Header::decode(bytes)?;
+
+        // Check that the body has exactly the announced length
+        if body.len() != header.body_length as usize {
+            return Err(NizkError::InvalidMessage("body length does not match the header"));
+        }
+
+        Ok(Message {
+            sender_ID: header.sender_ID,
+            recipient_ID: header.recipient_ID,
+            body: Body::decode(header.message_type, body)?,
+        })
+    }
+
+    // Read one message from a stream
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Message, NizkError> {
+        let mut bytes = vec![0u8; PREFIX_SIZE];
+        reader.read_exact(&mut bytes)?;
+        let remaining = Header::remaining_length(bytes[..].try_into().expect("prefix has a fixed size"))?;
+
+        bytes.resize(PREFIX_SIZE + remaining, 0);
+        reader.read_exact(&mut bytes[PREFIX_SIZE..])?;
+        Message::decode(&bytes)
+    }
+
+    // Write the encoded message into a stream
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), NizkError> {
+        writer.write_all(&self.encode()?)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Collects received bytes until they form complete messages, for transports without message boundaries
+#[derive(Debug, Default)]
+pub struct MessageBuffer {
+    bytes: Vec<u8>,
+}
+
+impl MessageBuffer {
+    pub fn new() -> MessageBuffer {
+        MessageBuffer::default()
+    }
+
+    // Add received bytes
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Take the next complete message out of the buffer, Ok(None) if more bytes are needed
+    pub fn next_message(&mut self) -> Result<Option<Message>, NizkError> {
+        if self.bytes.len() < PREFIX_SIZE {
+            return Ok(None);
+        }
+        let remaining = Header::remaining_length(self.bytes[..PREFIX_SIZE].try_into().expect("prefix has a fixed size"))?;
+        let length = PREFIX_SIZE + remaining;
+        if self.bytes.len() < length {
+            return Ok(None);
+        }
+
+        let message = Message::decode(&self.bytes[..length]);
+        self.bytes.drain(..length);
+        message.map(Some)
+    }
+}
+
+fn encode_proof(buffer: &mut Vec<u8>, proof: &NizkProof) {
+    let (commitment, challenge, response) = proof;
+    buffer.extend_from_slice(commitment);
+    buffer.extend_from_slice(challenge);
+    buffer.extend_from_slice(response);
+}
+
+// Helper for reading fields of a body one after another
+struct FieldReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], NizkError> {
+        if self.bytes.len() < length {
+            return Err(NizkError::InvalidMessage("message body is too short"));
+        }
+        let (field, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    fn u16(&mut self) -> Result<u16, NizkError> {
+        let field = self.take(2)?;
+        Ok(u16::from_be_bytes([field[0], field[1]]))
+    }
+
+    fn bytes32(&mut self) -> Result<[u8; 32], NizkError> {
+        let field = self.take(32)?;
+        Ok(field.try_into().expect("field has a fixed size"))
+    }
+
+    // Commitments and public keys have to be valid points on the curve
+    fn point(&mut self) -> Result<[u8; 32], NizkError> {
+        let field = self.bytes32()?;
+        schnorr_identification::bytes_to_edwards(&field)?;
+        Ok(field)
+    }
+
+    // Responses are always encoded as reduced scalars
+    fn scalar(&mut self) -> Result<[u8; 32], NizkError> {
+        let field = self.bytes32()?;
+        if Scalar::from_canonical_bytes(field).is_none() {
+            return Err(NizkError::InvalidMessage("response is not a canonical scalar"));
+        }
+        Ok(field)
+    }
+
+    fn proof(&mut self) -> Result<NizkProof, NizkError> {
+        Ok((self.point()?, self.bytes32()?, self.scalar()?))
+    }
+}
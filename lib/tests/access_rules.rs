@@ -0,0 +1,179 @@
+// Conflicting rules of the access control: denies against allows, wildcard actions against exact ones
+// and rules of parent resources against rules of their children.
+
+mod common;
+
+use schnorr_nizk::access_control::{self, AccessExplanation, Deny, Grant};
+use schnorr_nizk::conditions::{self, Conditions, GrantSubject};
+use schnorr_nizk::roles::{self, Permission};
+use schnorr_nizk::{DeviceId, NizkError};
+
+const DEVICE: DeviceId = DeviceId::numeric(1);
+const OTHER: DeviceId = DeviceId::numeric(2);
+
+fn allowed(resource: u32, action: &[u8], device: &DeviceId) -> bool {
+    access_control::check_access(resource, action.to_vec(), device).unwrap()
+}
+
+#[test]
+fn action_patterns() {
+    let _state = common::state("patterns");
+    access_control::add_resource(1, Some(vec![b"GET:/sensors/*".to_vec(), b"SET:/config".to_vec()])).unwrap();
+    access_control::add_device_to_all_actions(1, &DEVICE).unwrap();
+
+    assert!(allowed(1, b"GET:/sensors/temperature", &DEVICE));
+    assert!(allowed(1, b"GET:/sensors/", &DEVICE));
+    assert!(allowed(1, b"SET:/config", &DEVICE));
+    assert!(!allowed(1, b"SET:/config/network", &DEVICE));
+    assert!(!allowed(1, b"GET:/actuators/valve", &DEVICE));
+    assert_eq!(access_control::explain_access(1, b"GET:/actuators/valve", &DEVICE).unwrap(), AccessExplanation::ActionNotFound);
+
+    // "*" matches every action
+    access_control::add_action_to_resource(1, b"*".to_vec()).unwrap();
+    access_control::add_device_to_resource_action(1, b"*".to_vec(), &OTHER).unwrap();
+    assert!(allowed(1, b"DEL:/anything", &OTHER));
+    assert_eq!(access_control::explain_access(1, b"DEL:/anything", &DEVICE).unwrap(), AccessExplanation::NoGrant);
+
+    // Every matching action grants the device
+    let explanation = access_control::explain_access(1, b"GET:/sensors/humidity", &DEVICE).unwrap();
+    assert_eq!(explanation, AccessExplanation::Granted(vec![Grant::Direct { permission: Permission::new(1, b"GET:/sensors/*") }]));
+    access_control::add_device_to_resource_action(1, b"*".to_vec(), &DEVICE).unwrap();
+    let AccessExplanation::Granted(grants) = access_control::explain_access(1, b"GET:/sensors/humidity", &DEVICE).unwrap() else {
+        panic!("access not granted");
+    };
+    assert_eq!(grants.len(), 2);
+}
+
+#[test]
+fn deny_overrides_allow() {
+    let _state = common::state("deny");
+    access_control::add_resource(1, Some(vec![b"GET:/sensors/*".to_vec(), b"GET:/sensors/secret".to_vec()])).unwrap();
+    access_control::add_device_to_all_actions(1, &DEVICE).unwrap();
+
+    // A deny on the exact action overrides the allow of the pattern
+    access_control::deny_device_on_resource_action(1, b"GET:/sensors/secret".to_vec(), &DEVICE).unwrap();
+    assert!(allowed(1, b"GET:/sensors/temperature", &DEVICE));
+    assert!(!allowed(1, b"GET:/sensors/secret", &DEVICE));
+    assert_eq!(
+        access_control::explain_access(1, b"GET:/sensors/secret", &DEVICE).unwrap(),
+        AccessExplanation::Denied(vec![Deny::Device { permission: Permission::new(1, b"GET:/sensors/secret") }]),
+    );
+
+    // A deny on the pattern overrides the allow of the exact action
+    access_control::remove_device_deny_from_resource_action(1, b"GET:/sensors/secret".to_vec(), &DEVICE).unwrap();
+    assert!(allowed(1, b"GET:/sensors/secret", &DEVICE));
+    access_control::deny_device_on_resource_action(1, b"GET:/sensors/*".to_vec(), &DEVICE).unwrap();
+    assert!(!allowed(1, b"GET:/sensors/secret", &DEVICE));
+    assert!(!allowed(1, b"GET:/sensors/temperature", &DEVICE));
+
+    // A deny of the device on the same action as its allow
+    access_control::add_resource(2, Some(vec![b"SET".to_vec()])).unwrap();
+    access_control::add_device_to_resource_action(2, b"SET".to_vec(), &DEVICE).unwrap();
+    access_control::deny_device_on_resource_action(2, b"SET".to_vec(), &DEVICE).unwrap();
+    assert!(!allowed(2, b"SET", &DEVICE));
+
+    // Denied actions are not effective permissions
+    let permissions = access_control::effective_permissions(&DEVICE).unwrap();
+    assert!(permissions.is_empty(), "{:?}", permissions);
+
+    assert!(matches!(
+        access_control::remove_device_deny_from_resource_action(2, b"SET".to_vec(), &OTHER),
+        Err(NizkError::DeviceNotFound(_)),
+    ));
+    assert!(matches!(access_control::deny_device_on_resource_action(2, b"DEL".to_vec(), &DEVICE), Err(NizkError::ActionNotFound)));
+}
+
+#[test]
+fn group_deny_overrides_role() {
+    let _state = common::state("groups");
+    access_control::add_resource(1, Some(vec![b"GET".to_vec(), b"SET".to_vec()])).unwrap();
+    roles::add_role("operator", vec![Permission::new(1, b"*")]).unwrap();
+    roles::add_group("sensors").unwrap();
+    roles::add_device_to_group("sensors", &DEVICE).unwrap();
+    roles::assign_role_to_group("sensors", "operator").unwrap();
+    assert!(allowed(1, b"GET", &DEVICE));
+    assert!(allowed(1, b"SET", &DEVICE));
+
+    // Sensors may read, but never write, even with a direct allow
+    access_control::add_device_to_resource_action(1, b"SET".to_vec(), &DEVICE).unwrap();
+    access_control::deny_group_on_resource_action(1, b"SET".to_vec(), "sensors").unwrap();
+    assert!(allowed(1, b"GET", &DEVICE));
+    assert_eq!(
+        access_control::explain_access(1, b"SET", &DEVICE).unwrap(),
+        AccessExplanation::Denied(vec![Deny::Group { group: String::from("sensors"), permission: Permission::new(1, b"SET") }]),
+    );
+
+    // The deny ends with the membership
+    roles::remove_device_from_group("sensors", &DEVICE).unwrap();
+    assert!(allowed(1, b"SET", &DEVICE));
+    access_control::remove_group_deny_from_resource_action(1, b"SET".to_vec(), "sensors").unwrap();
+    assert!(matches!(
+        access_control::remove_group_deny_from_resource_action(1, b"SET".to_vec(), "sensors"),
+        Err(NizkError::GroupNotFound(_)),
+    ));
+}
+
+#[test]
+fn parent_rules_apply_to_children() {
+    let _state = common::state("hierarchy");
+    access_control::add_resource(1, Some(vec![b"GET:*".to_vec(), b"SET".to_vec()])).unwrap();
+    access_control::add_resource(2, Some(vec![b"GET:/status".to_vec()])).unwrap();
+    access_control::add_resource(3, Some(vec![b"SET".to_vec()])).unwrap();
+    access_control::set_resource_parent(2, Some(1)).unwrap();
+    access_control::set_resource_parent(3, Some(2)).unwrap();
+    assert_eq!(access_control::get_resource_parent(3).unwrap(), Some(2));
+
+    // An allow on the parent is inherited by the children and grandchildren
+    access_control::add_device_to_resource_action(1, b"GET:*".to_vec(), &DEVICE).unwrap();
+    assert!(allowed(2, b"GET:/status", &DEVICE));
+    assert!(allowed(3, b"GET:/status", &DEVICE));
+    assert!(allowed(3, b"GET:/other", &DEVICE));
+    assert!(!allowed(1, b"GET:/status", &OTHER));
+
+    // The effective permissions of each resource include the actions of all its parents
+    let permissions: Vec<_> = access_control::effective_permissions(&DEVICE).unwrap().into_iter()
+        .map(|effective| (effective.permission, effective.grants))
+        .collect();
+    let grants = vec![(Grant::Direct { permission: Permission::new(1, b"GET:*") }, None)];
+    let expected: Vec<_> = [(1, &b"GET:*"[..]), (2, b"GET:*"), (2, b"GET:/status"), (3, b"GET:*"), (3, b"GET:/status")].into_iter()
+        .map(|(resource, action)| (Permission::new(resource, action), grants.clone()))
+        .collect();
+    assert_eq!(permissions, expected);
+
+    // An allow on a child is not inherited by the parent
+    access_control::add_device_to_resource_action(3, b"SET".to_vec(), &OTHER).unwrap();
+    assert!(allowed(3, b"SET", &OTHER));
+    assert!(!allowed(1, b"SET", &OTHER));
+
+    // A deny on the parent overrides an allow on the child
+    access_control::deny_device_on_resource_action(1, b"SET".to_vec(), &OTHER).unwrap();
+    assert!(!allowed(3, b"SET", &OTHER));
+
+    // A deny on the child overrides an allow on the parent, the parent and siblings stay allowed
+    access_control::deny_device_on_resource_action(2, b"GET:/status".to_vec(), &DEVICE).unwrap();
+    assert!(!allowed(2, b"GET:/status", &DEVICE));
+    assert!(!allowed(3, b"GET:/status", &DEVICE));
+    assert!(allowed(3, b"GET:/other", &DEVICE));
+    assert!(allowed(1, b"GET:/status", &DEVICE));
+
+    // Role permissions on the parent are inherited as well
+    roles::add_role("reader", vec![Permission::new(1, b"GET:*")]).unwrap();
+    roles::add_group("readers").unwrap();
+    roles::add_device_to_group("readers", &OTHER).unwrap();
+    roles::assign_role_to_group("readers", "reader").unwrap();
+    assert!(allowed(3, b"GET:/status", &OTHER));
+
+    // Conditions of the grant on the parent also limit it on the children
+    conditions::set_conditions(GrantSubject::Role(String::from("reader")), Permission::new(1, b"GET:*"), Conditions::default().with_max_uses(1)).unwrap();
+    assert!(allowed(3, b"GET:/status", &OTHER));
+    assert!(!allowed(2, b"GET:/status", &OTHER));
+    assert!(access_control::explain_access(2, b"GET:/status", &OTHER).unwrap().is_expired());
+
+    // Cycles are refused, a removed parent ends the chain
+    assert!(matches!(access_control::set_resource_parent(1, Some(3)), Err(NizkError::InvalidResourceParent(3))));
+    assert!(matches!(access_control::set_resource_parent(1, Some(1)), Err(NizkError::InvalidResourceParent(1))));
+    assert!(matches!(access_control::set_resource_parent(1, Some(9)), Err(NizkError::ResourceNotFound(9))));
+    access_control::remove_resource(1).unwrap();
+    assert!(!allowed(3, b"GET:/other", &DEVICE));
+    assert!(allowed(3, b"SET", &OTHER));
+}
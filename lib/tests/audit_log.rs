@@ -0,0 +1,150 @@
+// Verification of the audit log after its file or head were changed, and changes that are saved while
+// the log can not be written.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use common::State;
+use schnorr_nizk::access_control;
+use schnorr_nizk::audit::{self, AuditEvent, AuditLog};
+use schnorr_nizk::conditions::{self, Conditions, GrantSubject};
+use schnorr_nizk::roles::Permission;
+use schnorr_nizk::{gen_nizk_proof, gen_random_key_pair, shared_state, verify_nizk_proof};
+use schnorr_nizk::{DeviceId, KeyStore, MemoryStore, NizkError};
+
+const KEY: [u8; 32] = [7; 32];
+
+fn event(counter: u32) -> AuditEvent {
+    AuditEvent::KeyRatcheted { device: DeviceId::numeric(1), peer: DeviceId::numeric(2), counter, steps: 1 }
+}
+
+// Log with three entries, returns the path of the log file
+fn write_log(state: &State) -> (AuditLog, PathBuf) {
+    let log = AuditLog::new("audit.log").with_mac_key(KEY);
+    for counter in 1..=3 {
+        log.append(&event(counter)).unwrap();
+    }
+    assert_eq!(log.verify().unwrap().entries, 3);
+    (log, state.dir.join("audit.log"))
+}
+
+fn head(path: &Path) -> PathBuf {
+    path.with_file_name("audit.log.head")
+}
+
+fn tampered(result: Result<audit::AuditSummary, NizkError>) -> Option<(u64, &'static str)> {
+    match result {
+        Err(NizkError::AuditLogTampered { entry, reason }) => Some((entry, reason)),
+        _ => None,
+    }
+}
+
+#[test]
+fn intact_log() {
+    let state = common::state("intact");
+    let (log, path) = write_log(&state);
+    let summary = audit::verify_file(&path, Some(&KEY)).unwrap();
+    assert_eq!(summary, log.verify().unwrap());
+    assert_eq!(audit::verify_file(&path, None).unwrap().entries, 3);
+
+    // An interrupted append left bytes after the head, the next append cuts them off
+    let mut content = fs::read(&path).unwrap();
+    content.extend_from_slice(b"{\"seq\":4");
+    fs::write(&path, &content).unwrap();
+    log.append(&event(4)).unwrap();
+    assert_eq!(log.verify().unwrap().entries, 4);
+}
+
+#[test]
+fn edited_entry() {
+    let state = common::state("edited");
+    let (_, path) = write_log(&state);
+    let content = fs::read_to_string(&path).unwrap();
+    let edited = content.replacen("\"counter\":2", "\"counter\":9", 1);
+    assert_ne!(edited, content);
+    fs::write(&path, edited).unwrap();
+    assert_eq!(tampered(audit::verify_file(&path, Some(&KEY))), Some((2, "entry was changed")));
+    assert_eq!(tampered(audit::verify_file(&path, None)), Some((2, "entry was changed")));
+}
+
+#[test]
+fn removed_last_entry() {
+    let state = common::state("removed-entry");
+    let (log, path) = write_log(&state);
+    let content = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    fs::write(&path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+    assert_eq!(tampered(audit::verify_file(&path, Some(&KEY))), Some((2, "log does not end with the entry of its head")));
+
+    // Nothing is appended to the shortened log
+    assert!(matches!(log.append(&event(4)), Err(NizkError::AuditLogTampered { .. })));
+}
+
+#[test]
+fn removed_head() {
+    let state = common::state("removed-head");
+    let (log, path) = write_log(&state);
+    fs::remove_file(head(&path)).unwrap();
+    assert_eq!(tampered(audit::verify_file(&path, Some(&KEY))), Some((3, "head of the log is missing")));
+
+    // The entries are kept, not overwritten by a new log
+    let content = fs::read(&path).unwrap();
+    assert!(matches!(log.append(&event(4)), Err(NizkError::AuditLogTampered { reason: "head of the log is missing", .. })));
+    assert_eq!(fs::read(&path).unwrap(), content);
+    assert!(!head(&path).exists());
+}
+
+#[test]
+fn invalid_mac() {
+    let state = common::state("mac");
+    let (_, path) = write_log(&state);
+    assert_eq!(tampered(audit::verify_file(&path, Some(&[8; 32]))), Some((1, "MAC tag is not valid")));
+
+    // Entries without a MAC tag are rejected when a key is given
+    let unauthenticated = AuditLog::new("plain.log");
+    unauthenticated.append(&event(1)).unwrap();
+    assert_eq!(tampered(audit::verify_file(&state.dir.join("plain.log"), Some(&KEY))), Some((1, "MAC tag is not valid")));
+
+    // A head with a changed MAC tag
+    let head_path = head(&path);
+    let head = fs::read_to_string(&head_path).unwrap();
+    let position = head.find("\"mac\":\"").unwrap() + 7;
+    let mut changed = head.into_bytes();
+    changed[position] = if changed[position] == b'0' { b'1' } else { b'0' };
+    fs::write(&head_path, changed).unwrap();
+    assert_eq!(tampered(audit::verify_file(&path, Some(&KEY))), Some((3, "MAC tag of the head is not valid")));
+}
+
+#[test]
+fn unwritable_log_does_not_fail_changes() {
+    let state = common::state("unwritable");
+
+    // A directory in place of the log file, every append fails
+    fs::create_dir_all(state.dir.join("audit.log")).unwrap();
+    audit::install(AuditLog::new("audit.log"));
+    assert!(AuditLog::new("audit.log").append(&event(1)).is_err());
+
+    // The changes are saved and reported as done
+    let (device, verifier_id) = (DeviceId::numeric(1), DeviceId::numeric(2));
+    access_control::add_resource(1, Some(vec![b"GET".to_vec()])).unwrap();
+    access_control::add_device_to_all_actions(1, &device).unwrap();
+    conditions::set_conditions(GrantSubject::Device(device.clone()), Permission::new(1, b"GET"), Conditions::default().with_max_uses(1)).unwrap();
+    let first = access_control::check_access(1, b"GET".to_vec(), &device);
+    let second = access_control::check_access(1, b"GET".to_vec(), &device);
+
+    let (sender, verifier) = (MemoryStore::new(), MemoryStore::new());
+    let (public_key, private_key) = gen_random_key_pair();
+    sender.put(&format!("PrivateKey:{}", device), &private_key).unwrap();
+    verifier.put(&format!("PublicKey:{}", device), &public_key).unwrap();
+    shared_state::establish(&sender, &device, &verifier_id, [1; 32]).unwrap();
+    shared_state::establish(&verifier, &verifier_id, &device, [1; 32]).unwrap();
+    let proof = gen_nizk_proof(&sender, &device, &verifier_id, String::from("open"), true).unwrap();
+    let verified = verify_nizk_proof(&verifier, &verifier_id, &device, String::from("open"), proof, true);
+    audit::uninstall();
+
+    // The only use of the grant was counted and the shared key was ratcheted
+    assert_eq!((first.unwrap(), second.unwrap()), (true, false));
+    assert!(verified.unwrap());
+    assert_eq!(shared_state::load(&verifier, &verifier_id, &device).unwrap().counter, 2);
+}
@@ -0,0 +1,159 @@
+// Batch verification gives the same result for each proof as the verification of the proof alone.
+
+mod common;
+
+use common::State;
+use schnorr_nizk::rate_limit::RateLimitPolicy;
+use schnorr_nizk::shared_state::{self, SharedState};
+use schnorr_nizk::{gen_nizk_proof, gen_random_key_pair, verify_nizk_proof, verify_nizk_proof_batch};
+use schnorr_nizk::{BatchItem, DeviceId, KeyStore, MemoryStore, NizkConfig, NizkError};
+
+const VERIFIER: DeviceId = DeviceId::numeric(1);
+const SENDERS: [DeviceId; 2] = [DeviceId::numeric(2), DeviceId::numeric(3)];
+
+// Rejected proofs do not block the senders
+fn state(name: &str) -> State {
+    let rate_limit = RateLimitPolicy { max_rejections: u32::MAX, ..Default::default() };
+    common::state_with(name, NizkConfig { rate_limit, ..Default::default() })
+}
+
+// Stores of the senders, and two stores of the verifier with the same keys: one for the batch and one
+// for the proofs alone
+fn devices() -> (Vec<MemoryStore>, MemoryStore, MemoryStore) {
+    let (batch, single) = (MemoryStore::new(), MemoryStore::new());
+    let senders = SENDERS.iter().map(|sender| {
+        let store = MemoryStore::new();
+        let (public_key, private_key) = gen_random_key_pair();
+        store.put(&format!("PrivateKey:{}", sender), &private_key).unwrap();
+        shared_state::establish(&store, sender, &VERIFIER, [4; 32]).unwrap();
+        for verifier in [&batch, &single] {
+            verifier.put(&format!("PublicKey:{}", sender), &public_key).unwrap();
+            shared_state::establish(verifier, &VERIFIER, sender, [4; 32]).unwrap();
+        }
+        store
+    }).collect();
+    (senders, batch, single)
+}
+
+fn item(store: &MemoryStore, sender: &DeviceId, message: &str) -> BatchItem {
+    let proof = gen_nizk_proof(store, sender, &VERIFIER, message.to_string(), true).unwrap();
+    BatchItem { sender_ID: sender.clone(), message: message.as_bytes().to_vec(), proof }
+}
+
+// Errors are compared by their description
+fn outcome(result: Result<bool, NizkError>) -> Result<bool, String> {
+    result.map_err(|e| format!("{:?}", e))
+}
+
+fn verify_alone(store: &MemoryStore, items: &[BatchItem], update_keys: bool) -> Vec<Result<bool, String>> {
+    items.iter()
+        .map(|item| {
+            let message = String::from_utf8(item.message.clone()).unwrap();
+            outcome(verify_nizk_proof(store, &VERIFIER, &item.sender_ID, message, item.proof, update_keys))
+        })
+        .collect()
+}
+
+fn verify_batch(store: &MemoryStore, items: &[BatchItem], update_keys: bool) -> Vec<Result<bool, String>> {
+    verify_nizk_proof_batch(store, &VERIFIER, items, update_keys).into_iter().map(outcome).collect()
+}
+
+fn saved(store: &MemoryStore) -> Vec<SharedState> {
+    SENDERS.iter().map(|sender| shared_state::load(store, &VERIFIER, sender).unwrap()).collect()
+}
+
+// Valid proofs of both senders, some at later counters, and invalid ones of every kind
+fn mixed_items(senders: &[MemoryStore]) -> Vec<BatchItem> {
+    let (first, second) = (&senders[0], &senders[1]);
+    let mut items = vec![item(first, &SENDERS[0], "a"), item(second, &SENDERS[1], "b")];
+
+    // Changed response, the Schnorr proof is invalid
+    let mut invalid = item(first, &SENDERS[0], "c");
+    invalid.proof.2[0] ^= 1;
+    items.push(invalid);
+
+    // Changed message, the MAC tag is not found
+    let mut changed = item(second, &SENDERS[1], "d");
+    changed.message = b"e".to_vec();
+    items.push(changed);
+
+    // Lost proofs before a valid one, and a proof of an unknown sender
+    item(first, &SENDERS[0], "lost");
+    items.push(item(first, &SENDERS[0], "f"));
+    items.push(BatchItem { sender_ID: DeviceId::numeric(9), ..item(second, &SENDERS[1], "g") });
+    items.push(item(second, &SENDERS[1], "h"));
+
+    // The same proof twice
+    items.push(items[0].clone());
+    items
+}
+
+#[test]
+fn mixed_batch_without_update() {
+    let _state = state("mixed");
+    let (senders, batch, single) = devices();
+    let items = mixed_items(&senders);
+
+    let results = verify_batch(&batch, &items, false);
+    assert_eq!(results, verify_alone(&single, &items, false));
+    assert_eq!(results[0], Ok(true));
+    assert_eq!(results[2], Ok(false));
+    assert!(matches!(&results[3], Err(e) if e.starts_with("Desynchronized")));
+
+    // Nothing was ratcheted
+    assert_eq!(saved(&batch), saved(&single));
+    assert!(saved(&batch).iter().all(|state| state.counter == 1));
+}
+
+#[test]
+fn mixed_batch_with_update() {
+    let _state = state("mixed-update");
+    let (senders, batch, single) = devices();
+    let items = mixed_items(&senders);
+
+    let results = verify_batch(&batch, &items, true);
+    assert_eq!(results, verify_alone(&single, &items, true));
+    assert_eq!(saved(&batch), saved(&single));
+
+    // The same proof is not accepted again
+    assert!(results[0] == Ok(true) && results[7].is_err());
+
+    // Both senders continue after the batch
+    let items: Vec<BatchItem> = SENDERS.iter().zip(&senders).map(|(sender, store)| item(store, sender, "next")).collect();
+    assert_eq!(verify_batch(&batch, &items, true), vec![Ok(true), Ok(true)]);
+}
+
+#[test]
+fn empty_and_single_batches() {
+    let _state = state("sizes");
+    let (senders, batch, single) = devices();
+    assert!(verify_nizk_proof_batch(&batch, &VERIFIER, &[], true).is_empty());
+    assert_eq!(saved(&batch), saved(&single));
+
+    let items = vec![item(&senders[0], &SENDERS[0], "a")];
+    assert_eq!(verify_batch(&batch, &items, false), vec![Ok(true)]);
+    assert_eq!(verify_batch(&batch, &items, true), verify_alone(&single, &items, true));
+    assert_eq!(saved(&batch), saved(&single));
+    assert_eq!(saved(&batch)[0].counter, 2);
+}
+
+#[test]
+fn last_counter() {
+    let _state = state("last-counter");
+    let (senders, batch, single) = devices();
+    let last = SharedState { epoch: 1, counter: u32::MAX, key: [6; 32] };
+    shared_state::save(&senders[0], &SENDERS[0], &VERIFIER, &last).unwrap();
+    for verifier in [&batch, &single] {
+        shared_state::save(verifier, &VERIFIER, &SENDERS[0], &last).unwrap();
+    }
+
+    // The proof with the last counter is found, only saving a later counter fails
+    let proof = gen_nizk_proof(&senders[0], &SENDERS[0], &VERIFIER, String::from("a"), false).unwrap();
+    let items = vec![BatchItem { sender_ID: SENDERS[0].clone(), message: b"a".to_vec(), proof }];
+    assert_eq!(verify_batch(&batch, &items, false), vec![Ok(true)]);
+    assert_eq!(verify_alone(&single, &items, false), vec![Ok(true)]);
+
+    let results = verify_batch(&batch, &items, true);
+    assert_eq!(results, verify_alone(&single, &items, true));
+    assert_eq!(results, vec![Err(format!("{:?}", NizkError::CounterExhausted))]);
+}
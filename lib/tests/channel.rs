@@ -0,0 +1,137 @@
+// Records of the secure channel: order, tampering and key renewal.
+//
+// The records of one direction are written into a buffer, changed, and read back by the other device.
+
+use std::io::Cursor;
+use schnorr_nizk::channel::MAX_RECORD_SIZE;
+use schnorr_nizk::{NizkError, SecureChannel, SessionKeys};
+
+// Version, epoch, sequence number and length
+const HEADER_SIZE: usize = 15;
+
+fn keys() -> SessionKeys {
+    SessionKeys {
+        initiator_to_responder: [1; 32],
+        responder_to_initiator: [2; 32],
+        confirmation: [3; 32],
+        exporter: [4; 32],
+    }
+}
+
+// Records the initiator sends for the messages
+fn send(messages: &[&[u8]], rekey_interval: u64) -> Vec<Vec<u8>> {
+    let mut channel = SecureChannel::new(Cursor::new(Vec::new()), &keys(), true).with_rekey_interval(rekey_interval);
+    for message in messages {
+        channel.send(message).unwrap();
+    }
+
+    // Split the stream at the lengths of the headers
+    let bytes = channel.into_inner().into_inner();
+    let mut records = Vec::new();
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        let length = HEADER_SIZE + u16::from_be_bytes([rest[13], rest[14]]) as usize;
+        records.push(rest[..length].to_vec());
+        rest = &rest[length..];
+    }
+    records
+}
+
+// Channel of the responder that reads the records
+fn responder(records: &[Vec<u8>], rekey_interval: u64) -> SecureChannel<Cursor<Vec<u8>>> {
+    SecureChannel::new(Cursor::new(records.concat()), &keys(), false).with_rekey_interval(rekey_interval)
+}
+
+fn epoch(record: &[u8]) -> u32 {
+    u32::from_be_bytes(record[1..5].try_into().unwrap())
+}
+
+#[test]
+fn records_in_order() {
+    let messages: [&[u8]; 3] = [b"first", b"", &[7; MAX_RECORD_SIZE]];
+    let records = send(&messages, 100);
+    let mut channel = responder(&records, 100);
+    for message in messages {
+        assert_eq!(channel.receive().unwrap(), message);
+    }
+    assert!(matches!(channel.receive(), Err(NizkError::Io(_))));
+
+    // The responder sends with its own key
+    let mut channel = SecureChannel::new(Cursor::new(Vec::new()), &keys(), false);
+    channel.send(b"answer").unwrap();
+    let bytes = channel.into_inner().into_inner();
+    let mut initiator = SecureChannel::new(Cursor::new(bytes.clone()), &keys(), true);
+    assert_eq!(initiator.receive().unwrap(), b"answer");
+    let mut responder = SecureChannel::new(Cursor::new(bytes), &keys(), false);
+    assert!(matches!(responder.receive(), Err(NizkError::RecordRejected)));
+
+    let mut channel = SecureChannel::new(Cursor::new(Vec::new()), &keys(), true);
+    assert!(matches!(channel.send(&[0; MAX_RECORD_SIZE + 1]), Err(NizkError::InvalidMessage(_))));
+}
+
+#[test]
+fn replayed_reordered_and_lost_records() {
+    let records = send(&[b"zero", b"one", b"two"], 100);
+
+    // Replay
+    let mut channel = responder(&[records[0].clone(), records[0].clone()], 100);
+    assert_eq!(channel.receive().unwrap(), b"zero");
+    assert!(matches!(channel.receive(), Err(NizkError::RecordOutOfOrder { expected: 1, found: 0 })));
+
+    // Reorder
+    let mut channel = responder(&[records[1].clone(), records[0].clone()], 100);
+    assert!(matches!(channel.receive(), Err(NizkError::RecordOutOfOrder { expected: 0, found: 1 })));
+
+    // Loss
+    let mut channel = responder(&[records[0].clone(), records[2].clone()], 100);
+    assert_eq!(channel.receive().unwrap(), b"zero");
+    assert!(matches!(channel.receive(), Err(NizkError::RecordOutOfOrder { expected: 1, found: 2 })));
+
+    // A record with a changed sequence number does not decrypt
+    let mut record = records[1].clone();
+    record[12] = 0;
+    let mut channel = responder(&[record], 100);
+    assert!(matches!(channel.receive(), Err(NizkError::RecordRejected)));
+}
+
+#[test]
+fn tampered_records() {
+    let record = send(&[b"open valve"], 100).remove(0);
+
+    // Every byte of the ciphertext and the tag is authenticated
+    for index in HEADER_SIZE..record.len() {
+        let mut changed = record.clone();
+        changed[index] ^= 1;
+        let mut channel = responder(&[changed], 100);
+        assert!(matches!(channel.receive(), Err(NizkError::RecordRejected)), "byte {}", index);
+    }
+
+    let mut changed = record.clone();
+    changed[0] = 2;
+    assert!(matches!(responder(&[changed], 100).receive(), Err(NizkError::UnsupportedVersion(2))));
+
+    // A record with another key
+    let mut channel = SecureChannel::new(Cursor::new(record), &SessionKeys { initiator_to_responder: [9; 32], ..keys() }, false);
+    assert!(matches!(channel.receive(), Err(NizkError::RecordRejected)));
+}
+
+#[test]
+fn key_renewal() {
+    let messages: Vec<Vec<u8>> = (0..7u8).map(|i| vec![i; 10]).collect();
+    let messages: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+    let records = send(&messages, 3);
+    let epochs: Vec<u32> = records.iter().map(|record| epoch(record)).collect();
+    assert_eq!(epochs, [0, 0, 0, 1, 1, 1, 2]);
+
+    let mut channel = responder(&records, 3);
+    for message in &messages {
+        assert_eq!(channel.receive().unwrap(), *message);
+    }
+
+    // A device with another interval does not have the renewed key
+    let mut channel = responder(&records, 4);
+    for message in &messages[..3] {
+        assert_eq!(channel.receive().unwrap(), *message);
+    }
+    assert!(matches!(channel.receive(), Err(NizkError::RecordRejected)));
+}
@@ -0,0 +1,179 @@
+// Log of the used commitments: migration of the text files of older versions, expiry of old epochs,
+// compaction and logs with a half-written record at the end.
+// A log is read from the disk again by copying it into another state directory.
+
+mod common;
+
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use common::State;
+use schnorr_nizk::commitment_store::{check_and_insert, compact, start_epoch};
+use schnorr_nizk::{config, DeviceId, NizkConfig, NizkError};
+
+const SENDER: DeviceId = DeviceId::numeric(7);
+const LOG_FILE: &str = "commitments_7.log";
+const LEGACY_FILE: &str = "mut_comm_7.txt";
+
+// Magic of the log and the sizes of its records
+const MAGIC: &[u8; 8] = b"NIZKCL01";
+const COMMITMENT_RECORD: u64 = 33;
+const EPOCH_RECORD: u64 = 5;
+
+fn state(name: &str, commitment_epochs: Option<u32>) -> State {
+    common::state_with(name, NizkConfig { commitment_epochs, ..Default::default() })
+}
+
+// Switch to another state directory, with the files that are already in it. The test already holds the state
+fn state_dir(name: &str, commitment_epochs: Option<u32>) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nizk-{}-{}-{}", env!("CARGO_CRATE_NAME"), name, std::process::id()));
+    config::install(NizkConfig { state_dir: dir.clone(), commitment_epochs, ..Default::default() }).unwrap();
+    dir
+}
+
+fn commitment(i: u32) -> [u8; 32] {
+    let mut commitment = [0u8; 32];
+    commitment[..4].copy_from_slice(&i.to_be_bytes());
+    commitment
+}
+
+fn log_length(dir: &Path) -> u64 {
+    fs::metadata(dir.join(LOG_FILE)).unwrap().len()
+}
+
+// Copy the log into a new state directory, so it is read from the disk
+fn reload(state: &State, name: &str, commitment_epochs: Option<u32>) -> PathBuf {
+    let bytes = fs::read(state.dir.join(LOG_FILE)).unwrap();
+    let dir = env::temp_dir().join(format!("nizk-{}-{}-{}", env!("CARGO_CRATE_NAME"), name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    write_file(&dir, LOG_FILE, &bytes);
+    state_dir(name, commitment_epochs)
+}
+
+fn write_file(dir: &Path, name: &str, bytes: &[u8]) {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir).unwrap();
+    fs::write(dir.join(name), bytes).unwrap();
+}
+
+#[test]
+fn migration_of_text_file() {
+    let state = state("migration", None);
+    let lines = format!("{}\n  {}  \nnot a commitment\n{}\n", hex::encode(commitment(1)), hex::encode(commitment(2)), hex::encode([1u8; 16]));
+    write_file(&state.dir, LEGACY_FILE, lines.as_bytes());
+
+    // The commitments of the text file are used, the lines that are no commitment are left out
+    assert!(!check_and_insert(&SENDER, commitment(1)).unwrap());
+    assert!(!check_and_insert(&SENDER, commitment(2)).unwrap());
+    assert!(check_and_insert(&SENDER, commitment(3)).unwrap());
+    assert!(!state.dir.join(LEGACY_FILE).exists());
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + 3 * COMMITMENT_RECORD);
+
+    let dir = reload(&state, "migration-reload", None);
+    for i in 1..=3 {
+        assert!(!check_and_insert(&SENDER, commitment(i)).unwrap());
+    }
+    assert!(check_and_insert(&SENDER, commitment(4)).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn expiry_of_old_epochs() {
+    let state = state("expiry", Some(2));
+    assert!(check_and_insert(&SENDER, commitment(0)).unwrap());
+    start_epoch(&SENDER, 1).unwrap();
+    assert!(check_and_insert(&SENDER, commitment(1)).unwrap());
+
+    // Commitments are kept for two epochs. An epoch that is not newer, as after a revoke, still starts the next one
+    start_epoch(&SENDER, 1).unwrap();
+    assert!(!check_and_insert(&SENDER, commitment(1)).unwrap());
+    assert!(check_and_insert(&SENDER, commitment(0)).unwrap());
+
+    // Each commitment keeps its epoch when the log is read again
+    let dir = reload(&state, "expiry-reload", Some(2));
+    start_epoch(&SENDER, 3).unwrap();
+    assert!(check_and_insert(&SENDER, commitment(1)).unwrap());
+    assert!(!check_and_insert(&SENDER, commitment(0)).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn compaction() {
+    let state = state("compaction", Some(1));
+    const COUNT: u32 = 1100;
+    for i in 0..COUNT {
+        assert!(check_and_insert(&SENDER, commitment(i)).unwrap());
+    }
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + COUNT as u64 * COMMITMENT_RECORD);
+
+    // A compaction without dead records writes the same commitments again
+    compact(&SENDER).unwrap();
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + COUNT as u64 * COMMITMENT_RECORD);
+    assert!(!check_and_insert(&SENDER, commitment(0)).unwrap());
+
+    // All commitments expire with the next epoch and only the epoch is left in the log
+    start_epoch(&SENDER, 1).unwrap();
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + EPOCH_RECORD);
+    assert!(check_and_insert(&SENDER, commitment(0)).unwrap());
+
+    let dir = reload(&state, "compaction-reload", Some(1));
+    assert!(!check_and_insert(&SENDER, commitment(0)).unwrap());
+    assert!(check_and_insert(&SENDER, commitment(1)).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn truncated_tail() {
+    let state = state("truncated", None);
+
+    // A log that ends in the middle of a commitment record
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(0x01);
+    bytes.extend_from_slice(&commitment(1));
+    bytes.push(0x01);
+    bytes.extend_from_slice(&commitment(2)[..10]);
+    write_file(&state.dir, LOG_FILE, &bytes);
+
+    assert!(!check_and_insert(&SENDER, commitment(1)).unwrap());
+    assert!(check_and_insert(&SENDER, commitment(2)).unwrap());
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + 2 * COMMITMENT_RECORD);
+
+    // A half-written record appended by another process is cut off as well
+    let mut file = OpenOptions::new().append(true).open(state.dir.join(LOG_FILE)).unwrap();
+    file.write_all(&[0x02, 0, 0]).unwrap();
+    assert!(check_and_insert(&SENDER, commitment(3)).unwrap());
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + 3 * COMMITMENT_RECORD);
+
+    let dir = reload(&state, "truncated-reload", None);
+    for i in 1..=3 {
+        assert!(!check_and_insert(&SENDER, commitment(i)).unwrap());
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn empty_log_file() {
+    let state = state("empty", None);
+
+    // A crash while the log was created can leave an empty file, it is created again
+    write_file(&state.dir, LOG_FILE, b"");
+    assert!(check_and_insert(&SENDER, commitment(1)).unwrap());
+    assert_eq!(log_length(&state.dir), MAGIC.len() as u64 + COMMITMENT_RECORD);
+
+    let dir = reload(&state, "empty-reload", None);
+    assert!(!check_and_insert(&SENDER, commitment(1)).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn invalid_logs() {
+    let _state = state("invalid", None);
+    for bytes in [&b"NIZKCL99"[..], &b"NIZK"[..], &[MAGIC.as_slice(), &[0x03; 33]].concat()] {
+        let dir = state_dir(&format!("invalid-{}", bytes.len()), None);
+        write_file(&dir, LOG_FILE, bytes);
+        let result = check_and_insert(&SENDER, commitment(1));
+        assert!(matches!(result, Err(NizkError::Io(e)) if e.kind() == ErrorKind::InvalidData));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+// Fixture shared by the integration tests.
+//
+// The configuration is global to the process, so the tests take turns, each with a new state directory
+// that is removed at its end.
+
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use schnorr_nizk::{config, NizkConfig};
+
+static STATE: Mutex<()> = Mutex::new(());
+
+// State directory of one test, removed at its end
+pub struct State {
+    pub dir: PathBuf,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Install the default configuration with a new state directory
+pub fn state(name: &str) -> State {
+    state_with(name, NizkConfig::default())
+}
+
+// Install the given configuration with a new state directory
+pub fn state_with(name: &str, config: NizkConfig) -> State {
+    let guard = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    let dir = env::temp_dir().join(format!("nizk-{}-{}-{}", env!("CARGO_CRATE_NAME"), name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    config::install(NizkConfig { state_dir: dir.clone(), ..config }).unwrap();
+    State { dir, _guard: guard }
+}
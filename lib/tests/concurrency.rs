@@ -0,0 +1,203 @@
+// Stress test of the persistent state: used commitments, intrusion data, access control data, the
+// encrypted key file and the shared values of a device pair are changed at the same time by many threads
+// and by several processes.
+//
+// The other processes run this test binary again with NIZK_STRESS_DIR set, which only runs the worker.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use schnorr_nizk::{access_control, config, file_management, shared_state, DeviceId, EncryptedFileStore, KeyStore, MemoryStore, NizkConfig, NizkError, RateLimitPolicy};
+
+const THREADS: usize = 8;
+const PROCESSES: usize = 3;
+const COMMITMENTS: u8 = 100;
+const DEVICES_PER_WORKER: u32 = 10;
+const REJECTIONS_PER_WORKER: usize = 10;
+const KEYS_PER_WORKER: usize = 5;
+const PROOFS: u32 = 10;
+
+const SENDER: DeviceId = DeviceId::numeric(42);
+// Verifier of the NIZK proofs and the device that sends them
+const VERIFIER: DeviceId = DeviceId::numeric(1);
+const PROVER: DeviceId = DeviceId::numeric(43);
+const RESOURCE: u32 = 7;
+const ACTION: &[u8] = b"GET";
+const PASSWORD: &[u8] = b"stress";
+
+fn commitment(index: u8) -> [u8; 32] {
+    let mut commitment = [0u8; 32];
+    commitment[0] = index;
+    commitment
+}
+
+// Counters of the proofs a worker accepted, each worker sends its own proofs for the counters 1 to PROOFS
+fn verify_proofs(store: &EncryptedFileStore) -> Vec<u32> {
+    let prover = MemoryStore::new();
+    prover.put(&format!("PrivateKey:{}", PROVER), &store.get(&format!("PrivateKey:{}", PROVER)).unwrap()).unwrap();
+    let shared = shared_state::load(store, &PROVER, &VERIFIER).unwrap();
+    shared_state::save(&prover, &PROVER, &VERIFIER, &shared).unwrap();
+
+    let mut accepted = Vec::new();
+    for counter in 1..=PROOFS {
+        let proof = schnorr_nizk::gen_nizk_proof(&prover, &PROVER, &VERIFIER, String::from("open"), true).unwrap();
+        match schnorr_nizk::verify_nizk_proof(store, &VERIFIER, &PROVER, String::from("open"), proof, true) {
+            Ok(true) => accepted.push(counter),
+            // Another worker already accepted a proof with this or a later counter
+            Err(NizkError::Desynchronized(_)) => {},
+            result => panic!("proof {} not verified: {:?}", counter, result),
+        }
+    }
+    accepted
+}
+
+// One worker of a thread or process. Returns the number of commitments it was the first to use
+fn work(worker: usize, store: &EncryptedFileStore) -> usize {
+    // All workers try all commitments, each starting at another one
+    let mut accepted = 0;
+    for i in 0..COMMITMENTS {
+        let index = ((i as usize + worker * 7) % COMMITMENTS as usize) as u8;
+        if file_management::check_commitment(&SENDER, commitment(index)).unwrap() {
+            accepted += 1;
+        }
+    }
+
+    for i in 0..DEVICES_PER_WORKER {
+        let device = DeviceId::numeric(worker as u32 * 1000 + i);
+        access_control::add_device_to_resource_action(RESOURCE, ACTION.to_vec(), &device).unwrap();
+    }
+
+    for _ in 0..REJECTIONS_PER_WORKER {
+        file_management::manage_intrusion(&SENDER, true, false).unwrap();
+    }
+
+    for i in 0..KEYS_PER_WORKER {
+        store.put(&format!("Key:{}:{}", worker, i), &[worker as u8; 32]).unwrap();
+    }
+    accepted
+}
+
+// Rejections never block the sender, so every rejection is counted in the window
+fn install_config(dir: &Path) {
+    let rate_limit = RateLimitPolicy {
+        max_rejections: u32::MAX,
+        rejection_window: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    config::install(NizkConfig { state_dir: dir.to_path_buf(), rate_limit, ..Default::default() }).unwrap();
+}
+
+fn key_file(dir: &Path) -> PathBuf {
+    dir.join("keys.bin")
+}
+
+// Runs only inside the processes started by concurrent_state_access
+#[test]
+fn stress_worker() {
+    let Some(dir) = env::var_os("NIZK_STRESS_DIR").map(PathBuf::from) else {
+        return;
+    };
+    let worker: usize = env::var("NIZK_STRESS_WORKER").unwrap().parse().unwrap();
+    install_config(&dir);
+
+    let store = EncryptedFileStore::open(key_file(&dir), PASSWORD).unwrap();
+    println!("accepted {}", work(worker, &store));
+    println!("proofs {:?}", verify_proofs(&store));
+}
+
+#[test]
+fn concurrent_state_access() {
+    if env::var_os("NIZK_STRESS_DIR").is_some() {
+        return;
+    }
+    let dir = env::temp_dir().join(format!("nizk-stress-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    install_config(&dir);
+    access_control::add_resource(RESOURCE, Some(vec![ACTION.to_vec()])).unwrap();
+    let store = Arc::new(EncryptedFileStore::open(key_file(&dir), PASSWORD).unwrap());
+
+    // Both sides of the pair are in the same key store, all workers verify as VERIFIER
+    let (public_key, private_key) = schnorr_nizk::gen_random_key_pair();
+    store.put(&format!("PrivateKey:{}", PROVER), &private_key).unwrap();
+    store.put(&format!("PublicKey:{}", PROVER), &public_key).unwrap();
+    shared_state::establish(&*store, &PROVER, &VERIFIER, [5; 32]).unwrap();
+    shared_state::establish(&*store, &VERIFIER, &PROVER, [5; 32]).unwrap();
+
+    // Start the other processes, they use worker numbers after the threads
+    let processes: Vec<_> = (0..PROCESSES)
+        .map(|process| {
+            Command::new(env::current_exe().unwrap())
+                .args(["stress_worker", "--exact", "--nocapture", "--test-threads=1"])
+                .env("NIZK_STRESS_DIR", &dir)
+                .env("NIZK_STRESS_WORKER", (THREADS + process).to_string())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|worker| {
+            let store = store.clone();
+            thread::spawn(move || (work(worker, &store), verify_proofs(&store)))
+        })
+        .collect();
+
+    let mut accepted = 0;
+    let mut proofs: Vec<u32> = Vec::new();
+    for thread in threads {
+        let (commitments, counters) = thread.join().unwrap();
+        accepted += commitments;
+        proofs.extend(counters);
+    }
+    for process in processes {
+        let output = process.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let line = stdout.lines().find_map(|line| line.strip_prefix("accepted ")).expect("worker did not finish");
+        accepted += line.parse::<usize>().unwrap();
+        let line = stdout.lines().find_map(|line| line.strip_prefix("proofs ")).expect("worker did not finish");
+        let counters = line.trim_matches(['[', ']']).split(", ").filter(|counter| !counter.is_empty());
+        proofs.extend(counters.map(|counter| counter.parse::<u32>().unwrap()));
+    }
+    let workers = THREADS + PROCESSES;
+
+    // No counter of the shared values was accepted twice, and the verifier is past the last one
+    let proof_count = proofs.len();
+    proofs.sort();
+    proofs.dedup();
+    assert_eq!(proofs.len(), proof_count, "a counter was accepted twice");
+    assert_eq!(proofs.last(), Some(&PROOFS));
+    assert_eq!(shared_state::load(&*store, &VERIFIER, &PROVER).unwrap().counter, PROOFS + 1);
+
+    // Every commitment was accepted exactly once
+    assert_eq!(accepted, COMMITMENTS as usize);
+    for index in 0..COMMITMENTS {
+        assert!(!file_management::check_commitment(&SENDER, commitment(index)).unwrap());
+    }
+
+    // No device was lost
+    for worker in 0..workers {
+        for i in 0..DEVICES_PER_WORKER {
+            let device = DeviceId::numeric(worker as u32 * 1000 + i);
+            assert!(access_control::check_access(RESOURCE, ACTION.to_vec(), &device).unwrap());
+        }
+    }
+
+    // Every rejection was counted
+    let intrusion: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("intrusion_data_42.json")).unwrap()).unwrap();
+    assert_eq!(intrusion["asym_counter"], workers * REJECTIONS_PER_WORKER);
+    assert_eq!(intrusion["window"]["rejections"].as_array().unwrap().len(), workers * REJECTIONS_PER_WORKER);
+
+    // No key was lost, also not for a store opened later
+    let reopened = EncryptedFileStore::open(key_file(&dir), PASSWORD).unwrap();
+    for keys in [&*store, &reopened] {
+        assert_eq!(keys.list("Key:").unwrap().len(), workers * KEYS_PER_WORKER);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,163 @@
+// Conditions of grants: evaluation of each condition type, the checks of set_conditions and the uses of
+// grants to roles.
+
+mod common;
+
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use schnorr_nizk::access_control;
+use schnorr_nizk::conditions::{self, AccessContext, ConditionFailure, Conditions, GrantSubject, Network, TimeWindow};
+use schnorr_nizk::roles::{self, Permission};
+use schnorr_nizk::{DeviceId, NizkError};
+
+// Time in minutes after the Unix epoch, a Thursday at midnight UTC
+fn at_minute(minute: u64) -> AccessContext {
+    AccessContext::default().with_time(UNIX_EPOCH + Duration::from_secs(minute * 60))
+}
+
+fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+}
+
+#[test]
+fn validity_period() {
+    let from = UNIX_EPOCH + Duration::from_secs(100);
+    let until = UNIX_EPOCH + Duration::from_secs(200);
+    let conditions = Conditions::default().with_validity(Some(from), Some(until));
+    let at = |seconds| AccessContext::default().with_time(UNIX_EPOCH + Duration::from_secs(seconds));
+
+    assert_eq!(conditions.evaluate(0, &at(99)), Err(ConditionFailure::NotYetValid { valid_from: 100_000 }));
+    assert_eq!(conditions.evaluate(0, &at(100)), Ok(()));
+    assert_eq!(conditions.evaluate(0, &at(199)), Ok(()));
+    let expired = conditions.evaluate(0, &at(200)).unwrap_err();
+    assert_eq!(expired, ConditionFailure::Expired { valid_until: 200_000 });
+    assert!(expired.is_expired());
+}
+
+#[test]
+fn time_windows() {
+    // 08:00 to 17:00 on Thursdays, and 22:00 to 02:00 on every day
+    let office = TimeWindow { weekdays: vec![3], ..TimeWindow::new(8 * 60, 17 * 60) };
+    let conditions = Conditions::default().with_time_window(office).with_time_window(TimeWindow::new(22 * 60, 2 * 60));
+
+    assert_eq!(conditions.evaluate(0, &at_minute(9 * 60)), Ok(()));
+    assert_eq!(conditions.evaluate(0, &at_minute(17 * 60)), Err(ConditionFailure::OutsideTimeWindow));
+    assert_eq!(conditions.evaluate(0, &at_minute(23 * 60)), Ok(()));
+    assert_eq!(conditions.evaluate(0, &at_minute(25 * 60)), Ok(()));
+    // Friday
+    assert_eq!(conditions.evaluate(0, &at_minute(24 * 60 + 9 * 60)), Err(ConditionFailure::OutsideTimeWindow));
+
+    // The window over midnight belongs to the day it started on
+    let thursday_night = Conditions::default().with_time_window(TimeWindow { weekdays: vec![3], ..TimeWindow::new(22 * 60, 2 * 60) });
+    assert_eq!(thursday_night.evaluate(0, &at_minute(25 * 60)), Ok(()));
+    assert_eq!(thursday_night.evaluate(0, &at_minute(60)), Err(ConditionFailure::OutsideTimeWindow));
+
+    // The offset moves the window to local time
+    let local = Conditions::default().with_time_window(TimeWindow { utc_offset: 120, ..TimeWindow::new(8 * 60, 9 * 60) });
+    assert_eq!(local.evaluate(0, &at_minute(6 * 60)), Ok(()));
+    assert_eq!(local.evaluate(0, &at_minute(8 * 60)), Err(ConditionFailure::OutsideTimeWindow));
+}
+
+#[test]
+fn request_conditions() {
+    let conditions = Conditions::default().with_max_uses(2).with_max_message_size(10);
+    let context = AccessContext::default().with_message_size(10);
+    assert_eq!(conditions.evaluate(1, &context), Ok(()));
+    assert_eq!(conditions.evaluate(2, &context), Err(ConditionFailure::UsesExhausted { max_uses: 2 }));
+    assert_eq!(conditions.evaluate(0, &context.clone().with_message_size(11)), Err(ConditionFailure::MessageTooLarge { size: 11, max_size: 10 }));
+    assert_eq!(conditions.evaluate(0, &AccessContext::default()), Err(ConditionFailure::MissingAttribute(String::from("message_size"))));
+
+    let conditions = Conditions::default()
+        .with_source_network("10.0.0.0/8".parse().unwrap())
+        .with_source_network("fd00::/16".parse().unwrap());
+    assert_eq!(conditions.evaluate(0, &AccessContext::default().with_source(ip("10.1.2.3"))), Ok(()));
+    assert_eq!(conditions.evaluate(0, &AccessContext::default().with_source(ip("fd00::1"))), Ok(()));
+    assert_eq!(conditions.evaluate(0, &AccessContext::default().with_source(ip("11.0.0.1"))), Err(ConditionFailure::SourceNotAllowed(ip("11.0.0.1"))));
+    assert_eq!(conditions.evaluate(0, &AccessContext::default()), Err(ConditionFailure::MissingAttribute(String::from("source"))));
+
+    let conditions = Conditions::default().with_attribute("site", "plant-1");
+    assert_eq!(conditions.evaluate(0, &AccessContext::default().with_attribute("site", "plant-1")), Ok(()));
+    assert_eq!(conditions.evaluate(0, &AccessContext::default().with_attribute("site", "plant-2")), Err(ConditionFailure::AttributeMismatch(String::from("site"))));
+    assert_eq!(conditions.evaluate(0, &AccessContext::default()), Err(ConditionFailure::MissingAttribute(String::from("site"))));
+}
+
+#[test]
+fn networks() {
+    let network: Network = "192.168.1.0/24".parse().unwrap();
+    assert_eq!((network.address(), network.prefix()), (ip("192.168.1.0"), 24));
+    assert!(network.contains(&ip("192.168.1.200")));
+    assert!(!network.contains(&ip("192.168.2.1")));
+    assert!(!network.contains(&ip("::1")));
+    assert_eq!(network.to_string(), "192.168.1.0/24");
+
+    // Prefixes of zero and of the full address
+    assert!(Network::new(ip("0.0.0.0"), 0).unwrap().contains(&ip("8.8.8.8")));
+    assert!(Network::new(ip("::"), 0).unwrap().contains(&ip("2001:db8::1")));
+    let host: Network = "10.0.0.1".parse().unwrap();
+    assert_eq!(host.prefix(), 32);
+    assert!(host.contains(&ip("10.0.0.1")) && !host.contains(&ip("10.0.0.2")));
+    assert_eq!("fe80::1".parse::<Network>().unwrap().prefix(), 128);
+
+    // Prefixes longer than the address are refused
+    assert!(matches!(Network::new(ip("10.0.0.0"), 33), Err(NizkError::InvalidCondition(_))));
+    assert!(matches!(Network::new(ip("::"), 129), Err(NizkError::InvalidCondition(_))));
+    assert!(Network::new(ip("::"), 128).is_ok());
+    for text in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "host/8"] {
+        assert!(matches!(text.parse::<Network>(), Err(NizkError::InvalidCondition(_))), "{}", text);
+    }
+    assert!(serde_json::from_str::<Network>("\"10.0.0.0/40\"").is_err());
+    assert_eq!(serde_json::from_str::<Network>("\"10.0.0.0/8\"").unwrap(), "10.0.0.0/8".parse().unwrap());
+}
+
+#[test]
+fn invalid_conditions_are_refused() {
+    let _state = common::state("invalid");
+    let set = |conditions: Conditions| {
+        conditions::set_conditions(GrantSubject::Device(DeviceId::numeric(1)), Permission::new(1, b"GET"), conditions)
+    };
+    let invalid = [
+        Conditions::default().with_time_window(TimeWindow::new(0, 1441)),
+        Conditions::default().with_time_window(TimeWindow::new(1441, 10)),
+        Conditions::default().with_time_window(TimeWindow::new(60, 60)),
+        Conditions::default().with_time_window(TimeWindow { weekdays: vec![0, 7], ..TimeWindow::new(0, 60) }),
+        Conditions::default().with_validity(Some(UNIX_EPOCH + Duration::from_secs(10)), Some(UNIX_EPOCH + Duration::from_secs(10))),
+        Conditions::default().with_validity(Some(SystemTime::now()), Some(UNIX_EPOCH)),
+    ];
+    for conditions in invalid {
+        assert!(matches!(set(conditions.clone()), Err(NizkError::InvalidCondition(_))), "{:?}", conditions);
+    }
+    assert_eq!(conditions::get_conditions(&GrantSubject::Device(DeviceId::numeric(1)), &Permission::new(1, b"GET")).unwrap(), None);
+
+    // A window up to the end of the day and the whole week are valid
+    let valid = Conditions::default()
+        .with_time_window(TimeWindow { weekdays: vec![0, 6], ..TimeWindow::new(1380, 1440) })
+        .with_validity(Some(UNIX_EPOCH), None);
+    set(valid.clone()).unwrap();
+    assert_eq!(conditions::get_conditions(&GrantSubject::Device(DeviceId::numeric(1)), &Permission::new(1, b"GET")).unwrap(), Some(valid));
+}
+
+#[test]
+fn role_uses_are_shared() {
+    let _state = common::state("role-uses");
+    let (first, second) = (DeviceId::numeric(1), DeviceId::numeric(2));
+    access_control::add_resource(1, Some(vec![b"GET".to_vec()])).unwrap();
+    roles::add_role("reader", vec![Permission::new(1, b"GET")]).unwrap();
+    roles::add_group("readers").unwrap();
+    roles::assign_role_to_group("readers", "reader").unwrap();
+    roles::add_device_to_group("readers", &first).unwrap();
+    roles::add_device_to_group("readers", &second).unwrap();
+    conditions::set_conditions(GrantSubject::Role(String::from("reader")), Permission::new(1, b"GET"), Conditions::default().with_max_uses(2)).unwrap();
+
+    // Two uses of the role in total, not per device
+    assert!(access_control::check_access(1, b"GET".to_vec(), &first).unwrap());
+    assert!(access_control::check_access(1, b"GET".to_vec(), &second).unwrap());
+    assert!(!access_control::check_access(1, b"GET".to_vec(), &first).unwrap());
+    assert!(!access_control::check_access(1, b"GET".to_vec(), &second).unwrap());
+
+    // A direct grant with its own limit is counted apart from the role
+    access_control::add_device_to_resource_action(1, b"GET".to_vec(), &second).unwrap();
+    conditions::set_conditions(GrantSubject::Device(second.clone()), Permission::new(1, b"GET"), Conditions::default().with_max_uses(1)).unwrap();
+    assert!(access_control::check_access(1, b"GET".to_vec(), &second).unwrap());
+    assert!(!access_control::check_access(1, b"GET".to_vec(), &second).unwrap());
+    assert!(!access_control::check_access(1, b"GET".to_vec(), &first).unwrap());
+}
@@ -0,0 +1,47 @@
+// Text, JSON and wire forms of device IDs.
+
+use schnorr_nizk::{DeviceId, NizkError};
+
+fn ids() -> Vec<DeviceId> {
+    vec![
+        DeviceId::numeric(42),
+        DeviceId::uuid([0xab; 16]),
+        DeviceId::name("plant-1/sensor.temperature_2").unwrap(),
+    ]
+}
+
+#[test]
+fn device_id_text() {
+    for id in ids() {
+        assert_eq!(id.to_string().parse::<DeviceId>().unwrap(), id);
+    }
+    assert_eq!(DeviceId::numeric(42).to_string(), "42");
+    assert_eq!(DeviceId::uuid([0xab; 16]).to_string(), "uuid:abababab-abab-abab-abab-abababababab");
+    assert_eq!("name:gateway".parse::<DeviceId>().unwrap(), DeviceId::name("gateway").unwrap());
+    assert_eq!(DeviceId::from(7).as_numeric(), Some(7));
+    assert_eq!(DeviceId::name("gateway").unwrap().as_numeric(), None);
+
+    for text in ["", "-1", "4294967296", "uuid:abcd", "uuid:zz", "name:", "name:a//b", "name:a b", "gateway"] {
+        assert!(matches!(text.parse::<DeviceId>(), Err(NizkError::InvalidDeviceId(_))), "{:?}", text);
+    }
+    assert!(DeviceId::name(&"a".repeat(64)).is_ok());
+    assert!(matches!(DeviceId::name(&"a".repeat(65)), Err(NizkError::InvalidDeviceId(_))));
+}
+
+#[test]
+fn device_id_json() {
+    for id in ids() {
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(serde_json::from_str::<DeviceId>(&json).unwrap(), id);
+    }
+    // Numeric IDs stay JSON numbers, like the u32 IDs of older versions
+    assert_eq!(serde_json::to_string(&DeviceId::numeric(42)).unwrap(), "42");
+}
+
+#[test]
+fn device_id_bytes() {
+    assert_eq!(DeviceId::numeric(42).to_bytes(), vec![0x01, 0, 0, 0, 42]);
+    let uuid = DeviceId::uuid([0xab; 16]).to_bytes();
+    assert_eq!((uuid[0], uuid.len()), (0x02, 17));
+    assert_eq!(DeviceId::name("gw").unwrap().to_bytes(), vec![0x03, 2, b'g', b'w']);
+}
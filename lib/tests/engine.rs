@@ -0,0 +1,168 @@
+// Two engines connected directly in memory, without any transport. The messages are passed as bytes,
+// split into chunks, to check that the sequencing does not depend on how the bytes arrive.
+
+mod common;
+
+use std::sync::Arc;
+use schnorr_nizk::engine::nizk_message;
+use schnorr_nizk::wire::Message;
+use schnorr_nizk::{gen_random_key_pair, shared_state};
+use schnorr_nizk::{DeviceId, Engine, Event, KeyStore, MemoryStore, NizkError, NizkMode, SessionKeys};
+
+const INITIATOR: DeviceId = DeviceId::numeric(1);
+const RESPONDER: DeviceId = DeviceId::numeric(2);
+
+// Two devices that know each other's public keys, but share no key yet
+fn devices() -> (Arc<MemoryStore>, Arc<MemoryStore>) {
+    let (initiator, responder) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    for (store, id, other) in [(&initiator, &INITIATOR, &responder), (&responder, &RESPONDER, &initiator)] {
+        let (public_key, private_key) = gen_random_key_pair();
+        store.put(&format!("PrivateKey:{}", id), &private_key).unwrap();
+        other.put(&format!("PublicKey:{}", id), &public_key).unwrap();
+    }
+    (initiator, responder)
+}
+
+// Give the messages to the engine in chunks of the given size. Returns the messages the engine wants
+// to send, the other events are added to results
+fn deliver(engine: &mut Engine, messages: Vec<Message>, chunk: usize, results: &mut Vec<Event>) -> Vec<Message> {
+    let bytes: Vec<u8> = messages.iter().flat_map(|message| message.encode().unwrap()).collect();
+    let mut replies = Vec::new();
+    for part in bytes.chunks(chunk) {
+        for event in engine.handle_bytes(part) {
+            match event {
+                Event::NeedSend(message) => replies.push(message),
+                event => results.push(event),
+            }
+        }
+    }
+    replies
+}
+
+// Pass the messages between the engines until none is left, and return the results of both engines
+fn run(initiator: &mut Engine, events: Vec<Event>, responder: &mut Engine, chunk: usize) -> (Vec<Event>, Vec<Event>) {
+    let (mut initiator_results, mut responder_results) = (Vec::new(), Vec::new());
+    let mut to_responder: Vec<Message> = events.into_iter().map(|event| match event {
+        Event::NeedSend(message) => message,
+        event => panic!("unexpected event {:?}", event),
+    }).collect();
+
+    while !to_responder.is_empty() {
+        let to_initiator = deliver(responder, to_responder, chunk, &mut responder_results);
+        to_responder = deliver(initiator, to_initiator, chunk, &mut initiator_results);
+    }
+    (initiator_results, responder_results)
+}
+
+fn established(events: &[Event], peer: &DeviceId) -> Option<SessionKeys> {
+    match events {
+        [Event::Established { peer_ID, session_key }] if peer_ID == peer => session_key.clone(),
+        events => panic!("not established with {}: {:?}", peer, events),
+    }
+}
+
+fn rejected(events: &[Event]) -> &NizkError {
+    match events {
+        [Event::Rejected { reason }] => reason,
+        events => panic!("not rejected: {:?}", events),
+    }
+}
+
+#[test]
+fn handshakes() {
+    let _state = common::state("handshakes");
+    let (initiator_store, responder_store) = devices();
+
+    // Key agreement saves the same shared state on both devices
+    let (mut initiator, events) = Engine::key_agreement(initiator_store.clone(), INITIATOR, RESPONDER, false).unwrap();
+    let mut responder = Engine::responder(responder_store.clone(), RESPONDER);
+    assert_eq!(responder.peer_ID(), None);
+    let (initiator_results, responder_results) = run(&mut initiator, events, &mut responder, 1);
+    assert!(established(&initiator_results, &RESPONDER).is_none());
+    assert!(established(&responder_results, &INITIATOR).is_none());
+    assert!(initiator.is_finished() && responder.is_finished());
+    assert_eq!(responder.peer_ID(), Some(&INITIATOR));
+    let initiator_state = shared_state::load(&*initiator_store, &INITIATOR, &RESPONDER).unwrap();
+    assert_eq!(initiator_state, shared_state::load(&*responder_store, &RESPONDER, &INITIATOR).unwrap());
+
+    // Mutual authentication with and without key confirmation gives the same keys on both devices
+    for (key_confirmation, chunk) in [(true, 7), (false, 1000)] {
+        let (mut initiator, events) = Engine::mut_auth(initiator_store.clone(), INITIATOR, RESPONDER, key_confirmation).unwrap();
+        let mut responder = Engine::responder(responder_store.clone(), RESPONDER).with_key_confirmation(key_confirmation);
+        let (initiator_results, responder_results) = run(&mut initiator, events, &mut responder, chunk);
+        let initiator_keys = established(&initiator_results, &RESPONDER).unwrap();
+        let responder_keys = established(&responder_results, &INITIATOR).unwrap();
+        assert!(initiator_keys == responder_keys, "key confirmation {}", key_confirmation);
+    }
+
+    // A resync gives a new shared state with the next epoch
+    let (mut initiator, events) = Engine::key_agreement(initiator_store.clone(), INITIATOR, RESPONDER, true).unwrap();
+    let mut responder = Engine::responder(responder_store.clone(), RESPONDER);
+    let (initiator_results, responder_results) = run(&mut initiator, events, &mut responder, 3);
+    established(&initiator_results, &RESPONDER);
+    established(&responder_results, &INITIATOR);
+    let resynced = shared_state::load(&*initiator_store, &INITIATOR, &RESPONDER).unwrap();
+    assert_eq!(resynced.epoch, initiator_state.epoch + 1);
+    assert_eq!(resynced, shared_state::load(&*responder_store, &RESPONDER, &INITIATOR).unwrap());
+}
+
+#[test]
+fn one_shot_messages() {
+    let _state = common::state("one-shot");
+    let (initiator_store, responder_store) = devices();
+    shared_state::establish(&*initiator_store, &INITIATOR, &RESPONDER, [1; 32]).unwrap();
+    shared_state::establish(&*responder_store, &RESPONDER, &INITIATOR, [1; 32]).unwrap();
+
+    let modes = [NizkMode::SharedKey { update_keys: true }, NizkMode::PublicKey { context: b"engine test" }];
+    let mut messages = Vec::new();
+    for mode in modes {
+        let message = nizk_message(&*initiator_store, mode, &INITIATOR, &RESPONDER, b"open valve").unwrap();
+        let mut responder = Engine::responder(responder_store.clone(), RESPONDER).with_public_context(b"engine test");
+        let mut results = Vec::new();
+        assert!(deliver(&mut responder, vec![message.clone()], 5, &mut results).is_empty());
+        match results.as_slice() {
+            [Event::Verified { peer_ID, message }] => assert_eq!((peer_ID, message.as_slice()), (&INITIATOR, &b"open valve"[..])),
+            events => panic!("not verified: {:?}", events),
+        }
+        messages.push(message);
+    }
+
+    // The shared key was ratcheted, so the same proof is not accepted again
+    let mut responder = Engine::responder(responder_store.clone(), RESPONDER);
+    rejected(&responder.handle_message(messages.remove(0)));
+
+    // Without a context proofs in public key mode are refused
+    let message = nizk_message(&*initiator_store, modes[1], &INITIATOR, &RESPONDER, b"open valve").unwrap();
+    let mut responder = Engine::responder(responder_store, RESPONDER);
+    assert!(matches!(rejected(&responder.handle_message(message)), NizkError::WrongState(_)));
+}
+
+#[test]
+fn unexpected_messages() {
+    let _state = common::state("unexpected");
+    let (initiator_store, responder_store) = devices();
+    let (_, events) = Engine::key_agreement(initiator_store.clone(), INITIATOR, RESPONDER, false).unwrap();
+    let Some(Event::NeedSend(commitment)) = events.into_iter().next() else { panic!("no commitment") };
+
+    // A message for another device
+    let mut other = Engine::responder(responder_store.clone(), DeviceId::numeric(3));
+    assert!(matches!(rejected(&other.handle_message(commitment.clone())), NizkError::InvalidMessage(_)));
+    assert!(other.is_finished());
+
+    // A message from another device than the peer
+    let mut responder = Engine::responder(responder_store.clone(), RESPONDER);
+    let Some(Event::NeedSend(answer)) = responder.handle_message(commitment.clone()).pop() else { panic!("no answer") };
+    let from_other = Message { sender_ID: DeviceId::numeric(3), ..commitment.clone() };
+    assert!(matches!(rejected(&responder.handle_message(from_other)), NizkError::InvalidMessage(_)));
+
+    // A message for another step, and any message after the end
+    let (mut initiator, _) = Engine::key_agreement(initiator_store, INITIATOR, RESPONDER, false).unwrap();
+    let from_responder = Message { sender_ID: RESPONDER, recipient_ID: INITIATOR, ..commitment.clone() };
+    assert!(matches!(rejected(&initiator.handle_message(from_responder)), NizkError::WrongState(_)));
+    assert!(matches!(rejected(&initiator.handle_message(answer)), NizkError::WrongState(_)));
+
+    // Invalid bytes
+    let mut responder = Engine::responder(responder_store, RESPONDER);
+    assert!(matches!(rejected(&responder.handle_bytes(&[0xff; 64])), NizkError::UnsupportedVersion(0xff)));
+    assert!(responder.handle_bytes(&commitment.encode().unwrap()).is_empty());
+}
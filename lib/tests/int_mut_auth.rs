@@ -0,0 +1,135 @@
+// Interactive mutual authentication between an initiator and a responder, step by step.
+
+mod common;
+
+use std::sync::Arc;
+use schnorr_nizk::commitment_store;
+use schnorr_nizk::int_mut_auth::{Initiator, Responder, Response};
+use schnorr_nizk::shared_state::{self, SharedState};
+use schnorr_nizk::{gen_random_key_pair, DeviceId, KeyStore, MemoryStore, NizkConfig, NizkError};
+
+const INITIATOR: DeviceId = DeviceId::numeric(1);
+const RESPONDER: DeviceId = DeviceId::numeric(2);
+
+// Two devices that know each other's public keys
+fn devices() -> (Arc<MemoryStore>, Arc<MemoryStore>) {
+    let (initiator, responder) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    for (store, id, other) in [(&initiator, &INITIATOR, &responder), (&responder, &RESPONDER, &initiator)] {
+        let (public_key, private_key) = gen_random_key_pair();
+        store.put(&format!("PrivateKey:{}", id), &private_key).unwrap();
+        other.put(&format!("PublicKey:{}", id), &public_key).unwrap();
+    }
+    (initiator, responder)
+}
+
+// Agree on a new shared key
+fn agree(initiator_store: &Arc<MemoryStore>, responder_store: &Arc<MemoryStore>) {
+    let (initiator, commitment) = Initiator::start(initiator_store.clone(), INITIATOR, RESPONDER);
+    let (responder, commitment_and_challenge) = Responder::start(responder_store.clone(), RESPONDER, INITIATOR, commitment).unwrap();
+    let (initiator, challenge_and_response) = initiator.receive_commitment(commitment_and_challenge).unwrap();
+    let (_, response) = responder.receive_challenge_response(challenge_and_response).unwrap();
+    initiator.receive_response(response).unwrap();
+}
+
+#[test]
+fn round_trip() {
+    let _state = common::state("round-trip");
+    let (initiator_store, responder_store) = devices();
+
+    let (initiator, commitment) = Initiator::start(initiator_store.clone(), INITIATOR, RESPONDER);
+    let (responder, commitment_and_challenge) = Responder::start(responder_store.clone(), RESPONDER, INITIATOR, commitment).unwrap();
+    let (initiator, challenge_and_response) = initiator.receive_commitment(commitment_and_challenge).unwrap();
+    let (responder_verified, response) = responder.receive_challenge_response(challenge_and_response).unwrap();
+    let initiator_verified = initiator.receive_response(response).unwrap();
+    assert_eq!((initiator_verified.sender_ID, initiator_verified.recipient_ID), (INITIATOR, RESPONDER));
+    assert_eq!((responder_verified.sender_ID, responder_verified.recipient_ID), (RESPONDER, INITIATOR));
+
+    // Both devices saved the same shared key with a new counter
+    let state = shared_state::load(&*initiator_store, &INITIATOR, &RESPONDER).unwrap();
+    assert_eq!(state, shared_state::load(&*responder_store, &RESPONDER, &INITIATOR).unwrap());
+    assert_eq!((state.epoch, state.counter), (1, 1));
+}
+
+#[test]
+fn wrong_proofs_are_rejected() {
+    let _state = common::state("rejected");
+    let (initiator_store, responder_store) = devices();
+
+    // The responder knows another public key for the initiator
+    let (public_key, _) = gen_random_key_pair();
+    let impostor_store = Arc::new(MemoryStore::new());
+    impostor_store.put(&format!("PublicKey:{}", INITIATOR), &public_key).unwrap();
+    impostor_store.put(&format!("PrivateKey:{}", RESPONDER), &responder_store.get(&format!("PrivateKey:{}", RESPONDER)).unwrap()).unwrap();
+    let (initiator, commitment) = Initiator::start(initiator_store.clone(), INITIATOR, RESPONDER);
+    let (responder, commitment_and_challenge) = Responder::start(impostor_store.clone(), RESPONDER, INITIATOR, commitment).unwrap();
+    let (_, challenge_and_response) = initiator.receive_commitment(commitment_and_challenge).unwrap();
+    assert!(matches!(responder.receive_challenge_response(challenge_and_response), Err(NizkError::ProofRejected)));
+    assert!(shared_state::load(&*impostor_store, &RESPONDER, &INITIATOR).is_err());
+
+    // A changed response of the initiator
+    let (initiator, commitment) = Initiator::start(initiator_store.clone(), INITIATOR, RESPONDER);
+    let (responder, commitment_and_challenge) = Responder::start(responder_store.clone(), RESPONDER, INITIATOR, commitment).unwrap();
+    let (initiator, challenge_and_response) = initiator.receive_commitment(commitment_and_challenge).unwrap();
+    let mut changed = challenge_and_response;
+    changed.response[0] ^= 1;
+    assert!(matches!(responder.receive_challenge_response(changed), Err(NizkError::ProofRejected)));
+
+    // A changed response of the responder
+    let mut response = [0u8; 32];
+    response[0] = 1;
+    assert!(matches!(initiator.receive_response(Response { response }), Err(NizkError::ProofRejected)));
+
+    // A commitment that was already used
+    let (responder, _) = Responder::start(responder_store.clone(), RESPONDER, INITIATOR, commitment).unwrap();
+    assert!(matches!(responder.receive_challenge_response(challenge_and_response), Err(NizkError::ReplayedCommitment)));
+
+    // Bytes that are not a point on the curve
+    let mut commitment = commitment;
+    commitment.commitment = [0; 32];
+    commitment.commitment[0] = 2;
+    assert!(matches!(Responder::start(responder_store, RESPONDER, INITIATOR, commitment), Err(NizkError::InvalidCurvePoint)));
+}
+
+#[test]
+fn resync_round_trip() {
+    let _state = common::state("resync");
+    let (initiator_store, responder_store) = devices();
+
+    // A device that was never paired has no public key to resync with
+    assert!(Initiator::resync(initiator_store.clone(), INITIATOR, DeviceId::numeric(3)).is_err());
+
+    // The shared values diverged after the pairing
+    shared_state::save(&*initiator_store, &INITIATOR, &RESPONDER, &SharedState { epoch: 1, counter: 9, key: [1; 32] }).unwrap();
+    shared_state::save(&*responder_store, &RESPONDER, &INITIATOR, &SharedState { epoch: 1, counter: 4, key: [2; 32] }).unwrap();
+
+    let (initiator, commitment) = Initiator::resync(initiator_store.clone(), INITIATOR, RESPONDER).unwrap();
+    let (responder, commitment_and_challenge) = Responder::start(responder_store.clone(), RESPONDER, INITIATOR, commitment).unwrap();
+    let (initiator, challenge_and_response) = initiator.receive_commitment(commitment_and_challenge).unwrap();
+    let (_, response) = responder.receive_challenge_response(challenge_and_response).unwrap();
+    initiator.receive_response(response).unwrap();
+
+    // Both devices start a new epoch with the same new key
+    let state = shared_state::load(&*initiator_store, &INITIATOR, &RESPONDER).unwrap();
+    assert_eq!(state, shared_state::load(&*responder_store, &RESPONDER, &INITIATOR).unwrap());
+    assert_eq!((state.epoch, state.counter), (2, 1));
+    assert_ne!(state.key, [1; 32]);
+}
+
+#[test]
+fn commitments_expire_after_revoke() {
+    let _state = common::state_with("revoke-expiry", NizkConfig { commitment_epochs: Some(2), ..Default::default() });
+    let (initiator_store, responder_store) = devices();
+    agree(&initiator_store, &responder_store);
+    assert!(commitment_store::check_and_insert(&INITIATOR, [7; 32]).unwrap());
+
+    // The key is revoked and agreed on again, its epochs start at 1 again
+    shared_state::revoke(&*initiator_store, &INITIATOR, &RESPONDER).unwrap();
+    shared_state::revoke(&*responder_store, &RESPONDER, &INITIATOR).unwrap();
+    agree(&initiator_store, &responder_store);
+    assert_eq!(shared_state::load(&*responder_store, &RESPONDER, &INITIATOR).unwrap().epoch, 1);
+    assert!(!commitment_store::check_and_insert(&INITIATOR, [7; 32]).unwrap());
+
+    // The used commitment still expires two agreements after it was used
+    agree(&initiator_store, &responder_store);
+    assert!(commitment_store::check_and_insert(&INITIATOR, [7; 32]).unwrap());
+}
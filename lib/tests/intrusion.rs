@@ -0,0 +1,145 @@
+// Detection of compromised keys: repeated proofs of a peer that fail the same way are counted until the
+// handlers act on the key that is compromised.
+
+mod common;
+
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use common::State;
+use schnorr_nizk::audit::{self, AuditLog};
+use schnorr_nizk::intrusion::{self, CompromisedKey, IntrusionEvent, IntrusionHandler, IntrusionKind, QuarantinePublicKey};
+use schnorr_nizk::rate_limit::RateLimitPolicy;
+use schnorr_nizk::{gen_nizk_proof, gen_random_key_pair, shared_state, verify_nizk_proof};
+use schnorr_nizk::{DeviceId, KeyStore, MemoryStore, NizkConfig, NizkError};
+
+const SENDER: DeviceId = DeviceId::numeric(1);
+const VERIFIER: DeviceId = DeviceId::numeric(2);
+const THRESHOLD: u8 = 3;
+
+// State of one test, the installed handlers are removed at its end
+struct Handlers {
+    state: State,
+}
+
+impl Drop for Handlers {
+    fn drop(&mut self) {
+        intrusion::clear_handlers();
+    }
+}
+
+fn state(name: &str) -> Handlers {
+    // Blocking the sender would hide the counting
+    let rate_limit = RateLimitPolicy { max_rejections: u32::MAX, ..Default::default() };
+    let state = common::state_with(name, NizkConfig { key_guess_threshold: THRESHOLD, rate_limit, ..Default::default() });
+    intrusion::clear_handlers();
+    Handlers { state }
+}
+
+// Keeps every event it is called with
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<IntrusionEvent>>,
+}
+
+impl IntrusionHandler for Recorder {
+    fn on_event(&self, _store: &dyn KeyStore, event: &IntrusionEvent) -> Result<(), NizkError> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+// Sender and verifier paired with the same shared key, and an attacker that has the private key of the
+// sender but not the shared key
+fn devices() -> (MemoryStore, MemoryStore, MemoryStore) {
+    let (sender, verifier, attacker) = (MemoryStore::new(), MemoryStore::new(), MemoryStore::new());
+    let (public_key, private_key) = gen_random_key_pair();
+    sender.put(&format!("PrivateKey:{}", SENDER), &private_key).unwrap();
+    attacker.put(&format!("PrivateKey:{}", SENDER), &private_key).unwrap();
+    verifier.put(&format!("PublicKey:{}", SENDER), &public_key).unwrap();
+    shared_state::establish(&sender, &SENDER, &VERIFIER, [1; 32]).unwrap();
+    shared_state::establish(&verifier, &VERIFIER, &SENDER, [1; 32]).unwrap();
+    shared_state::establish(&attacker, &SENDER, &VERIFIER, [2; 32]).unwrap();
+    (sender, verifier, attacker)
+}
+
+#[test]
+fn stolen_private_key_is_quarantined() {
+    let _state = state("quarantine");
+    let recorder = Arc::new(Recorder::default());
+    intrusion::add_handler(recorder.clone());
+    intrusion::add_handler(Arc::new(QuarantinePublicKey));
+    let (sender, verifier, attacker) = devices();
+
+    // Every proof of the attacker has a valid Schnorr part but a MAC tag that is never found
+    for attempt in 1..=THRESHOLD + 1 {
+        let proof = gen_nizk_proof(&attacker, &SENDER, &VERIFIER, String::from("open"), true).unwrap();
+        let result = verify_nizk_proof(&verifier, &VERIFIER, &SENDER, String::from("open"), proof, true);
+        assert!(matches!(result, Err(NizkError::Desynchronized(_))), "attempt {}: {:?}", attempt, result);
+    }
+
+    let events = recorder.events.lock().unwrap().clone();
+    assert_eq!(events.len(), usize::from(THRESHOLD) + 1);
+    assert!(events.iter().all(|event| event.kind == IntrusionKind::MacOnlyFailure && event.peer == SENDER));
+    assert_eq!(events.iter().map(|event| event.asym_counter).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+    assert!(events[..events.len() - 1].iter().all(|event| event.compromised.is_none()));
+    assert_eq!(events.last().unwrap().compromised, Some(CompromisedKey::Asymmetric));
+
+    // The public key is quarantined, so even proofs of the honest sender are refused
+    assert!(verifier.get(&format!("PublicKey:{}", SENDER)).is_err());
+    assert!(verifier.get(&format!("Quarantine:PublicKey:{}", SENDER)).is_ok());
+    let proof = gen_nizk_proof(&sender, &SENDER, &VERIFIER, String::from("open"), true).unwrap();
+    assert!(verify_nizk_proof(&verifier, &VERIFIER, &SENDER, String::from("open"), proof, true).is_err());
+    assert_eq!(schnorr_nizk::check_intrusion(&SENDER).unwrap(), (true, false, false));
+
+    // Released, the honest sender is accepted again
+    intrusion::release_public_key(&verifier, &SENDER).unwrap();
+    let proof = gen_nizk_proof(&sender, &SENDER, &VERIFIER, String::from("open"), true).unwrap();
+    assert!(verify_nizk_proof(&verifier, &VERIFIER, &SENDER, String::from("open"), proof, true).unwrap());
+}
+
+#[test]
+fn below_threshold_nothing_is_compromised() {
+    let _state = state("threshold");
+    intrusion::add_handler(Arc::new(QuarantinePublicKey));
+    let (sender, verifier, attacker) = devices();
+
+    for _ in 0..THRESHOLD {
+        let proof = gen_nizk_proof(&attacker, &SENDER, &VERIFIER, String::from("open"), true).unwrap();
+        assert!(verify_nizk_proof(&verifier, &VERIFIER, &SENDER, String::from("open"), proof, true).is_err());
+    }
+    assert_eq!(schnorr_nizk::check_intrusion(&SENDER).unwrap(), (false, false, false));
+
+    // The honest sender is still accepted
+    let proof = gen_nizk_proof(&sender, &SENDER, &VERIFIER, String::from("open"), true).unwrap();
+    assert!(verify_nizk_proof(&verifier, &VERIFIER, &SENDER, String::from("open"), proof, true).unwrap());
+}
+
+// Fails for every event
+struct Failing;
+
+impl IntrusionHandler for Failing {
+    fn on_event(&self, _store: &dyn KeyStore, _event: &IntrusionEvent) -> Result<(), NizkError> {
+        Err(NizkError::Io(io::Error::other("handler failed")))
+    }
+}
+
+#[test]
+fn failed_handler_is_returned_and_audited() {
+    let state = state("failed-handler");
+    audit::install(AuditLog::new("audit.log"));
+    let recorder = Arc::new(Recorder::default());
+    intrusion::add_handler(Arc::new(Failing));
+    intrusion::add_handler(recorder.clone());
+    let (_, verifier, attacker) = devices();
+
+    // The failure reaches the verifier, the handlers after the failing one are still called
+    let proof = gen_nizk_proof(&attacker, &SENDER, &VERIFIER, String::from("open"), true).unwrap();
+    let result = verify_nizk_proof(&verifier, &VERIFIER, &SENDER, String::from("open"), proof, true);
+    audit::uninstall();
+    assert!(matches!(&result, Err(NizkError::Io(e)) if e.to_string() == "handler failed"), "{:?}", result);
+    assert_eq!(recorder.events.lock().unwrap().len(), 1);
+
+    let log = fs::read_to_string(state.state.dir.join("audit.log")).unwrap();
+    assert!(log.lines().any(|line| line.contains(r#""type":"intrusion_handler_failed","kind":"mac_only_failure""#)), "{}", log);
+}
@@ -0,0 +1,66 @@
+// Round trips of the key store backends that need no kernel keyring: the in-memory store and the
+// password-encrypted key file.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use schnorr_nizk::{EncryptedFileStore, KeyStore, MemoryStore, SecretKeyErrors};
+
+// Empty directory of one test, the key file is created in it
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nizk-keys-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// put, get, update, list and delete of one store
+fn round_trip(store: &dyn KeyStore) {
+    assert!(matches!(store.get("SecretKey:1"), Err(SecretKeyErrors::KeyNotFound(_))));
+    assert!(matches!(store.update("SecretKey:1", &[1; 32]), Err(SecretKeyErrors::KeyNotFound(_))));
+
+    store.put("SecretKey:1", &[1; 32]).unwrap();
+    store.put("SecretKey:2", &[2; 32]).unwrap();
+    store.put("SharedState:1:2", &[3; 16]).unwrap();
+    assert_eq!(store.get("SecretKey:1").unwrap(), vec![1; 32]);
+
+    store.update("SecretKey:1", &[4; 32]).unwrap();
+    assert_eq!(store.get("SecretKey:1").unwrap(), vec![4; 32]);
+    assert_eq!(store.list("SecretKey:").unwrap(), vec!["SecretKey:1", "SecretKey:2"]);
+
+    store.delete("SecretKey:1").unwrap();
+    assert!(matches!(store.get("SecretKey:1"), Err(SecretKeyErrors::KeyNotFound(_))));
+    assert!(matches!(store.delete("SecretKey:1"), Err(SecretKeyErrors::KeyNotFound(_))));
+    assert_eq!(store.list("").unwrap(), vec!["SecretKey:2", "SharedState:1:2"]);
+}
+
+#[test]
+fn memory_store() {
+    round_trip(&MemoryStore::new());
+}
+
+#[test]
+fn encrypted_file_store() {
+    let dir = temp_dir("round-trip");
+    let path = dir.join("keys.bin");
+    round_trip(&EncryptedFileStore::open(&path, b"password").unwrap());
+
+    // The keys are in the file, not only in the opened store
+    let reopened = EncryptedFileStore::open(&path, b"password").unwrap();
+    assert_eq!(reopened.get("SecretKey:2").unwrap(), vec![2; 32]);
+    assert_eq!(reopened.list("").unwrap().len(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn encrypted_file_store_wrong_password() {
+    let dir = temp_dir("password");
+    let path = dir.join("keys.bin");
+    EncryptedFileStore::open(&path, b"password").unwrap().put("SecretKey:1", &[1; 32]).unwrap();
+    assert!(matches!(EncryptedFileStore::open(&path, b"wrong"), Err(SecretKeyErrors::WrongPassword)));
+
+    // A file that is not a key file is refused
+    fs::write(&path, b"not a key file").unwrap();
+    assert!(matches!(EncryptedFileStore::open(&path, b"password"), Err(SecretKeyErrors::InvalidKeyFile)));
+    fs::remove_dir_all(&dir).unwrap();
+}
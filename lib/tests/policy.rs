@@ -0,0 +1,179 @@
+// Export and import of the access control as a policy document in JSON and TOML.
+
+mod common;
+
+use common::State;
+use schnorr_nizk::access_control;
+use schnorr_nizk::conditions::{self, Conditions, GrantSubject, TimeWindow};
+use schnorr_nizk::policy::{self, Policy, PolicyChange, PolicyFormat};
+use schnorr_nizk::roles::{self, Permission};
+use schnorr_nizk::{config, DeviceId, NizkConfig, NizkError};
+
+const DEVICE: DeviceId = DeviceId::numeric(1);
+const OTHER: DeviceId = DeviceId::numeric(2);
+// Action that is not UTF-8, and a text action that looks like a hex name
+const BINARY: &[u8] = &[0xff, 0x00, 0x17];
+const HEX_TEXT: &[u8] = b"hex:616263";
+
+// Use a new empty state directory below the one of the test
+fn switch(state: &State, name: &str) {
+    config::install(NizkConfig { state_dir: state.dir.join(name), ..Default::default() }).unwrap();
+}
+
+// Access control with every kind of rule
+fn build_access_control() {
+    access_control::add_resource(1, Some(vec![b"GET:/sensors/*".to_vec(), BINARY.to_vec(), HEX_TEXT.to_vec()])).unwrap();
+    access_control::add_resource(2, Some(vec![b"SET".to_vec()])).unwrap();
+    access_control::set_resource_parent(2, Some(1)).unwrap();
+    access_control::add_device_to_resource_action(1, BINARY.to_vec(), &DEVICE).unwrap();
+    access_control::add_device_to_resource_action(1, HEX_TEXT.to_vec(), &DEVICE).unwrap();
+    access_control::deny_device_on_resource_action(1, b"GET:/sensors/*".to_vec(), &OTHER).unwrap();
+    access_control::deny_group_on_resource_action(2, b"SET".to_vec(), "readers").unwrap();
+
+    roles::add_role("reader", vec![Permission::new(1, b"GET:/sensors/*"), Permission::new(1, BINARY)]).unwrap();
+    roles::add_group("readers").unwrap();
+    roles::add_device_to_group("readers", &OTHER).unwrap();
+    roles::assign_role_to_group("readers", "reader").unwrap();
+
+    conditions::set_conditions(GrantSubject::Role(String::from("reader")), Permission::new(1, BINARY), Conditions::default().with_max_uses(3)).unwrap();
+    let office = Conditions::default().with_time_window(TimeWindow { weekdays: vec![0, 1, 2, 3, 4], ..TimeWindow::new(480, 1020) });
+    conditions::set_conditions(GrantSubject::Device(DEVICE), Permission::new(1, HEX_TEXT), office).unwrap();
+}
+
+fn round_trip(format: PolicyFormat) {
+    let state = common::state(if format == PolicyFormat::Json { "json" } else { "toml" });
+    build_access_control();
+    let exported = access_control::export_policy().unwrap();
+    let text = exported.encode(format).unwrap();
+    assert_eq!(Policy::decode(&text, format).unwrap(), exported);
+
+    // Actions that are not text, or look like hex, are written in hex
+    let names: Vec<&str> = exported.resources[0].actions.iter().map(|action| action.name.as_str()).collect();
+    assert_eq!(names, vec!["GET:/sensors/*", "hex:ff0017", "hex:6865783a363136323633"]);
+
+    // Imported into an empty access control, the same policy is exported again
+    switch(&state, "target");
+    let changes = access_control::import_policy(&Policy::decode(&text, format).unwrap(), false).unwrap();
+    assert!(changes.contains(&PolicyChange::AddAction { resource: 1, action: String::from("hex:ff0017") }));
+    assert_eq!(access_control::export_policy().unwrap(), exported);
+    assert!(access_control::import_policy(&exported, false).unwrap().is_empty());
+
+    assert_eq!(access_control::list_actions(1).unwrap(), vec![b"GET:/sensors/*".to_vec(), BINARY.to_vec(), HEX_TEXT.to_vec()]);
+    assert!(access_control::check_access(1, BINARY.to_vec(), &DEVICE).unwrap());
+    assert!(access_control::check_access(1, BINARY.to_vec(), &OTHER).unwrap());
+    assert!(!access_control::check_access(1, b"GET:/sensors/a".to_vec(), &OTHER).unwrap());
+    assert!(!access_control::check_access(2, b"SET".to_vec(), &OTHER).unwrap());
+    assert_eq!(conditions::get_conditions(&GrantSubject::Role(String::from("reader")), &Permission::new(1, BINARY)).unwrap(),
+               Some(Conditions::default().with_max_uses(3)));
+}
+
+#[test]
+fn json_round_trip() {
+    round_trip(PolicyFormat::Json);
+}
+
+#[test]
+fn toml_round_trip() {
+    round_trip(PolicyFormat::Toml);
+}
+
+#[test]
+fn action_names() {
+    for action in [b"GET".as_slice(), b"", BINARY, HEX_TEXT, b"hex:", "caf\u{e9}".as_bytes()] {
+        assert_eq!(policy::action_bytes(&policy::action_name(action)).unwrap(), action);
+    }
+    assert_eq!(policy::action_name(b"GET"), "GET");
+    assert!(matches!(policy::action_bytes("hex:xyz"), Err(NizkError::InvalidPolicy(_))));
+}
+
+#[test]
+fn dry_run_diff() {
+    let _state = common::state("dry-run");
+    build_access_control();
+    let current = access_control::export_policy().unwrap();
+
+    let mut target = current.clone();
+    target.resources.retain(|resource| resource.id != 2);
+    target.resources[0].actions[0].allow.push(DEVICE);
+    target.resources[0].actions.remove(2);
+    target.roles.insert(String::from("writer"), Vec::new());
+    target.groups.get_mut("readers").unwrap().devices.clear();
+    target.conditions.retain(|condition| condition.subject != GrantSubject::Device(DEVICE));
+    target.conditions[0].conditions.max_uses = Some(5);
+
+    let expected = vec![
+        PolicyChange::RemoveResource(2),
+        PolicyChange::RemoveAction { resource: 1, action: String::from("hex:6865783a363136323633") },
+        PolicyChange::ChangeAction { resource: 1, action: String::from("GET:/sensors/*") },
+        PolicyChange::AddRole(String::from("writer")),
+        PolicyChange::ChangeGroup(String::from("readers")),
+        PolicyChange::ClearConditions { subject: GrantSubject::Device(DEVICE), resource: 1, action: String::from("hex:6865783a363136323633") },
+        PolicyChange::SetConditions { subject: GrantSubject::Role(String::from("reader")), resource: 1, action: String::from("hex:ff0017") },
+    ];
+    assert_eq!(current.diff(&target), expected);
+
+    // A dry run changes nothing
+    assert_eq!(access_control::import_policy(&target, true).unwrap(), expected);
+    assert_eq!(access_control::export_policy().unwrap(), current);
+
+    assert_eq!(access_control::import_policy(&target, false).unwrap(), expected);
+    assert_eq!(access_control::export_policy().unwrap(), target);
+    assert!(matches!(access_control::get_resource_parent(2), Err(NizkError::ResourceNotFound(2))));
+    assert_eq!(expected[2].to_string(), "change devices of action GET:/sensors/* of resource 1");
+}
+
+#[test]
+fn invalid_policies_are_refused() {
+    let _state = common::state("invalid");
+    build_access_control();
+    let current = access_control::export_policy().unwrap();
+
+    let mut invalid = Vec::new();
+    let mut policy = current.clone();
+    policy.version = 2;
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.resources.push(policy.resources[0].clone());
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.resources[0].actions[2].name = String::from("GET:/sensors/*");
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.resources[0].actions[0].name = String::from("hex:");
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.resources[0].actions[0].name = String::from("hex:zz");
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.resources[0].parent = Some(2);
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.resources[1].parent = Some(9);
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.groups.get_mut("readers").unwrap().roles.push(String::from("missing"));
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.roles.get_mut("reader").unwrap()[0].action = String::from("hex:1");
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.conditions[0].resource = 9;
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.conditions[0].conditions.time_windows.push(TimeWindow::new(60, 60));
+    invalid.push(policy);
+    let mut policy = current.clone();
+    policy.conditions.push(policy.conditions[0].clone());
+    invalid.push(policy);
+
+    for policy in invalid {
+        assert!(matches!(policy.validate(), Err(NizkError::InvalidPolicy(_))), "{:?}", policy);
+        assert!(matches!(access_control::import_policy(&policy, false), Err(NizkError::InvalidPolicy(_))));
+    }
+    assert_eq!(access_control::export_policy().unwrap(), current);
+
+    // Documents that are not a policy
+    assert!(matches!(Policy::from_toml("version = \"one\""), Err(NizkError::InvalidPolicy(_))));
+    assert!(Policy::from_json("{\"resources\": []}").is_err());
+    assert!(matches!(PolicyFormat::of_path(std::path::Path::new("policy.yaml")), Err(NizkError::InvalidPolicy(_))));
+}
@@ -0,0 +1,66 @@
+// Proofs in public key mode, which only need the public key of the sender, and in shared key mode.
+
+mod common;
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_COMPRESSED;
+use schnorr_nizk::{gen_proof, gen_random_key_pair, shared_state, verify_proof};
+use schnorr_nizk::{DeviceId, KeyStore, MemoryStore, NizkMode};
+
+const SENDER: DeviceId = DeviceId::numeric(1);
+const VERIFIER: DeviceId = DeviceId::numeric(2);
+const PUBLIC: NizkMode = NizkMode::PublicKey { context: b"announcement" };
+const SHARED: NizkMode = NizkMode::SharedKey { update_keys: true };
+
+// The verifier knows the public key of the sender, both have the same shared key
+fn devices() -> (MemoryStore, MemoryStore) {
+    let (sender, verifier) = (MemoryStore::new(), MemoryStore::new());
+    let (public_key, private_key) = gen_random_key_pair();
+    sender.put(&format!("PrivateKey:{}", SENDER), &private_key).unwrap();
+    verifier.put(&format!("PublicKey:{}", SENDER), &public_key).unwrap();
+    shared_state::establish(&sender, &SENDER, &VERIFIER, [1; 32]).unwrap();
+    shared_state::establish(&verifier, &VERIFIER, &SENDER, [1; 32]).unwrap();
+    (sender, verifier)
+}
+
+#[test]
+fn public_key_proof() {
+    let (sender, verifier) = devices();
+    let proof = gen_proof(&sender, PUBLIC, &SENDER, &VERIFIER, b"firmware 1.2").unwrap();
+    assert!(verify_proof(&verifier, PUBLIC, &VERIFIER, &SENDER, b"firmware 1.2", proof).unwrap());
+
+    // The recipient ID is not part of the proof, any device with the public key can verify it
+    assert!(verify_proof(&verifier, PUBLIC, &DeviceId::numeric(3), &SENDER, b"firmware 1.2", proof).unwrap());
+
+    // Other message or context
+    assert!(!verify_proof(&verifier, PUBLIC, &VERIFIER, &SENDER, b"firmware 1.3", proof).unwrap());
+    let other_context = NizkMode::PublicKey { context: b"status" };
+    assert!(!verify_proof(&verifier, other_context, &VERIFIER, &SENDER, b"firmware 1.2", proof).unwrap());
+}
+
+#[test]
+fn tampered_public_key_proof() {
+    let (sender, verifier) = devices();
+    let (commitment, challenge, response) = gen_proof(&sender, PUBLIC, &SENDER, &VERIFIER, b"firmware 1.2").unwrap();
+
+    let mut tampered_response = response;
+    tampered_response[0] ^= 1;
+    let tampered_commitment = ED25519_BASEPOINT_COMPRESSED.0;
+    for proof in [(commitment, challenge, tampered_response), (tampered_commitment, challenge, response)] {
+        assert!(!matches!(verify_proof(&verifier, PUBLIC, &VERIFIER, &SENDER, b"firmware 1.2", proof), Ok(true)));
+    }
+}
+
+#[test]
+fn shared_key_proof_is_not_replayed() {
+    let _state = common::state("replay");
+    let (sender, verifier) = devices();
+    let proof = gen_proof(&sender, SHARED, &SENDER, &VERIFIER, b"open").unwrap();
+    assert!(verify_proof(&verifier, SHARED, &VERIFIER, &SENDER, b"open", proof).unwrap());
+
+    // The shared key was ratcheted, so the same proof is not accepted again
+    assert!(!matches!(verify_proof(&verifier, SHARED, &VERIFIER, &SENDER, b"open", proof), Ok(true)));
+
+    // A proof in public key mode is no proof in shared key mode
+    let public_proof = gen_proof(&sender, PUBLIC, &SENDER, &VERIFIER, b"open").unwrap();
+    assert!(!matches!(verify_proof(&verifier, SHARED, &VERIFIER, &SENDER, b"open", public_proof), Ok(true)));
+}
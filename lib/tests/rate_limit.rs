@@ -0,0 +1,99 @@
+// Token buckets and rejection windows of the rate limiter, driven with explicit times.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use schnorr_nizk::{Decision, DeviceId, RateLimitPolicy, RateLimiter, Subject};
+
+const START: u64 = 1_000_000;
+
+fn at(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(START + millis)
+}
+
+fn device() -> Subject {
+    Subject::Device(DeviceId::numeric(1))
+}
+
+#[test]
+fn token_bucket() {
+    let limiter = RateLimiter::new(RateLimitPolicy { requests_per_second: 2.0, burst: 3, ..Default::default() });
+    for _ in 0..3 {
+        assert_eq!(limiter.check_at(&device(), at(0)), Decision::Allow);
+    }
+    assert_eq!(limiter.check_at(&device(), at(0)), Decision::Throttle(Duration::from_millis(500)));
+    assert_eq!(limiter.check_at(&device(), at(250)), Decision::Throttle(Duration::from_millis(250)));
+    assert_eq!(limiter.check_at(&device(), at(500)), Decision::Allow);
+
+    // Other subjects have their own bucket
+    let address = Subject::Address("10.0.0.1".parse().unwrap());
+    assert_eq!(limiter.check_at(&address, at(500)), Decision::Allow);
+
+    // The bucket does not fill up beyond the burst
+    for _ in 0..3 {
+        assert_eq!(limiter.check_at(&device(), at(60_000)), Decision::Allow);
+    }
+    assert!(matches!(limiter.check_at(&device(), at(60_000)), Decision::Throttle(_)));
+}
+
+#[test]
+fn rates_without_next_token() {
+    for requests_per_second in [0.0, -1.0, 1e-300, f64::MIN_POSITIVE, f64::NAN] {
+        let limiter = RateLimiter::new(RateLimitPolicy { requests_per_second, burst: 1, ..Default::default() });
+        assert_eq!(limiter.check_at(&device(), at(0)), Decision::Allow);
+        assert_eq!(limiter.check_at(&device(), at(1)), Decision::Throttle(Duration::MAX), "{}", requests_per_second);
+    }
+
+    // Without a burst no request is allowed
+    let limiter = RateLimiter::new(RateLimitPolicy { burst: 0, ..Default::default() });
+    assert!(matches!(limiter.check_at(&device(), at(0)), Decision::Throttle(_)));
+
+    let limiter = RateLimiter::new(RateLimitPolicy { requests_per_second: f64::INFINITY, burst: 1, ..Default::default() });
+    for millis in 0..3 {
+        assert_eq!(limiter.check_at(&device(), at(millis)), Decision::Allow);
+    }
+}
+
+#[test]
+fn rejection_window() {
+    let policy = RateLimitPolicy {
+        max_rejections: 2,
+        rejection_window: Duration::from_secs(10),
+        block_duration: Duration::from_secs(10),
+        max_block_duration: Duration::from_secs(25),
+        cooldown: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let limiter = RateLimiter::new(policy);
+
+    // Rejections that left the window are not counted
+    assert_eq!(limiter.record_rejection_at(&device(), at(0)), Decision::Allow);
+    assert_eq!(limiter.record_rejection_at(&device(), at(2_000)), Decision::Allow);
+    assert_eq!(limiter.record_rejection_at(&device(), at(10_500)), Decision::Allow);
+
+    // The third rejection within the window blocks the subject
+    assert_eq!(limiter.record_rejection_at(&device(), at(11_000)), Decision::Block(at(21_000)));
+    assert_eq!(limiter.check_at(&device(), at(20_999)), Decision::Block(at(21_000)));
+    assert_eq!(limiter.record_rejection_at(&device(), at(15_000)), Decision::Block(at(21_000)));
+    assert_eq!(limiter.check_at(&device(), at(21_000)), Decision::Allow);
+
+    // A block within the cooldown lasts twice as long, up to the maximum
+    for millis in [22_000, 22_001, 22_002] {
+        limiter.record_rejection_at(&device(), at(millis));
+    }
+    assert_eq!(limiter.check_at(&device(), at(22_003)), Decision::Block(at(42_002)));
+    for millis in [43_000, 43_001, 43_002] {
+        limiter.record_rejection_at(&device(), at(millis));
+    }
+    assert_eq!(limiter.check_at(&device(), at(43_003)), Decision::Block(at(68_002)));
+
+    // After the cooldown the next block is a first block again
+    for millis in [200_000, 200_001, 200_002] {
+        limiter.record_rejection_at(&device(), at(millis));
+    }
+    assert_eq!(limiter.check_at(&device(), at(200_003)), Decision::Block(at(210_002)));
+
+    // Unblocking forgets the block and the rejections
+    limiter.unblock(&device());
+    assert_eq!(limiter.check_at(&device(), at(200_004)), Decision::Allow);
+    assert_eq!(limiter.record_rejection_at(&device(), at(200_005)), Decision::Allow);
+    assert_eq!(limiter.check_at(&Subject::Device(DeviceId::numeric(2)), at(200_005)), Decision::Allow);
+}
@@ -0,0 +1,119 @@
+// Roles and groups of the access control, and the access they give to the devices of the groups.
+
+mod common;
+
+use schnorr_nizk::access_control::{self, AccessExplanation, EffectivePermission, Grant};
+use schnorr_nizk::roles::{self, Permission};
+use schnorr_nizk::{DeviceId, NizkError};
+
+const DEVICE: DeviceId = DeviceId::numeric(1);
+const OTHER: DeviceId = DeviceId::numeric(2);
+
+fn role_grant(group: &str, role: &str, resource: u32, action: &[u8]) -> Grant {
+    Grant::Role { group: group.to_string(), role: role.to_string(), permission: Permission::new(resource, action) }
+}
+
+#[test]
+fn roles_and_groups() {
+    let _state = common::state("changes");
+    let read = Permission::new(1, b"READ");
+
+    roles::add_role("reader", vec![read.clone(), read.clone()]).unwrap();
+    assert!(matches!(roles::add_role("reader", vec![]), Err(NizkError::RoleAlreadyExists(name)) if name == "reader"));
+    assert!(matches!(roles::remove_role("writer"), Err(NizkError::RoleNotFound(name)) if name == "writer"));
+
+    // Permissions of roles
+    roles::add_permission_to_role("reader", read.clone()).unwrap();
+    assert!(matches!(roles::add_permission_to_role("writer", read.clone()), Err(NizkError::RoleNotFound(_))));
+    roles::remove_permission_from_role("reader", &read).unwrap();
+    assert!(matches!(roles::remove_permission_from_role("reader", &read), Err(NizkError::ActionNotFound)));
+    assert!(matches!(roles::remove_permission_from_role("writer", &read), Err(NizkError::RoleNotFound(_))));
+
+    // Groups
+    roles::add_group("operators").unwrap();
+    assert!(matches!(roles::add_group("operators"), Err(NizkError::GroupAlreadyExists(name)) if name == "operators"));
+    assert!(matches!(roles::remove_group("admins"), Err(NizkError::GroupNotFound(name)) if name == "admins"));
+
+    // Members of groups
+    roles::add_device_to_group("operators", &DEVICE).unwrap();
+    roles::add_device_to_group("operators", &DEVICE).unwrap();
+    assert!(matches!(roles::add_device_to_group("admins", &DEVICE), Err(NizkError::GroupNotFound(_))));
+    assert!(matches!(roles::remove_device_from_group("operators", &OTHER), Err(NizkError::DeviceNotFound(id)) if id == OTHER));
+    assert!(matches!(roles::remove_device_from_group("admins", &DEVICE), Err(NizkError::GroupNotFound(_))));
+    assert_eq!(roles::groups_of_device(&DEVICE).unwrap(), vec!["operators".to_string()]);
+    assert!(roles::groups_of_device(&OTHER).unwrap().is_empty());
+
+    // Roles of groups
+    roles::assign_role_to_group("operators", "reader").unwrap();
+    assert!(matches!(roles::assign_role_to_group("operators", "writer"), Err(NizkError::RoleNotFound(_))));
+    assert!(matches!(roles::assign_role_to_group("admins", "reader"), Err(NizkError::GroupNotFound(_))));
+    roles::unassign_role_from_group("operators", "reader").unwrap();
+    assert!(matches!(roles::unassign_role_from_group("operators", "reader"), Err(NizkError::RoleNotFound(_))));
+
+    roles::remove_device_from_group("operators", &DEVICE).unwrap();
+    assert!(roles::groups_of_device(&DEVICE).unwrap().is_empty());
+    roles::remove_group("operators").unwrap();
+    roles::remove_role("reader").unwrap();
+}
+
+#[test]
+fn removed_role_leaves_its_groups() {
+    let _state = common::state("removed");
+    access_control::add_resource(1, Some(vec![b"READ".to_vec()])).unwrap();
+    roles::add_role("reader", vec![Permission::new(1, b"READ")]).unwrap();
+    for group in ["operators", "admins"] {
+        roles::add_group(group).unwrap();
+        roles::add_device_to_group(group, &DEVICE).unwrap();
+        roles::assign_role_to_group(group, "reader").unwrap();
+    }
+    let explanation = access_control::explain_access(1, b"READ", &DEVICE).unwrap();
+    assert_eq!(explanation, AccessExplanation::Granted(vec![role_grant("admins", "reader", 1, b"READ"), role_grant("operators", "reader", 1, b"READ")]));
+
+    roles::remove_role("reader").unwrap();
+    assert_eq!(access_control::explain_access(1, b"READ", &DEVICE).unwrap(), AccessExplanation::NoGrant);
+    for group in ["operators", "admins"] {
+        assert!(matches!(roles::unassign_role_from_group(group, "reader"), Err(NizkError::RoleNotFound(_))));
+    }
+
+    // A new role with the same name is not assigned to the groups of the old one
+    roles::add_role("reader", vec![Permission::new(1, b"READ")]).unwrap();
+    assert_eq!(access_control::explain_access(1, b"READ", &DEVICE).unwrap(), AccessExplanation::NoGrant);
+    assert!(!access_control::check_access(1, b"READ".to_vec(), &DEVICE).unwrap());
+}
+
+#[test]
+fn access_through_roles() {
+    let _state = common::state("access");
+    access_control::add_resource(1, Some(vec![b"READ".to_vec(), b"WRITE".to_vec()])).unwrap();
+    access_control::add_resource(2, Some(vec![b"READ".to_vec()])).unwrap();
+    roles::add_role("reader", vec![Permission::new(1, b"READ"), Permission::new(3, b"READ")]).unwrap();
+    roles::add_group("operators").unwrap();
+    roles::add_device_to_group("operators", &DEVICE).unwrap();
+    roles::assign_role_to_group("operators", "reader").unwrap();
+
+    // The role only grants the permissions it has, on actions the resource has
+    let explanation = access_control::explain_access(1, b"READ", &DEVICE).unwrap();
+    assert_eq!(explanation, AccessExplanation::Granted(vec![role_grant("operators", "reader", 1, b"READ")]));
+    assert!(access_control::check_access(1, b"READ".to_vec(), &DEVICE).unwrap());
+    assert_eq!(access_control::explain_access(1, b"WRITE", &DEVICE).unwrap(), AccessExplanation::NoGrant);
+    assert_eq!(access_control::explain_access(2, b"READ", &DEVICE).unwrap(), AccessExplanation::NoGrant);
+    assert_eq!(access_control::explain_access(1, b"READ", &OTHER).unwrap(), AccessExplanation::NoGrant);
+    let read = EffectivePermission { permission: Permission::new(1, b"READ"), grants: vec![(role_grant("operators", "reader", 1, b"READ"), None)] };
+    assert_eq!(access_control::effective_permissions(&DEVICE).unwrap(), vec![read.clone()]);
+    assert!(access_control::effective_permissions(&OTHER).unwrap().is_empty());
+
+    // A direct grant and a role grant of the same action are both listed
+    access_control::add_device_to_resource_action(1, b"WRITE".to_vec(), &DEVICE).unwrap();
+    roles::add_permission_to_role("reader", Permission::new(1, b"WRITE")).unwrap();
+    let write = EffectivePermission {
+        permission: Permission::new(1, b"WRITE"),
+        grants: vec![(Grant::Direct { permission: Permission::new(1, b"WRITE") }, None), (role_grant("operators", "reader", 1, b"WRITE"), None)],
+    };
+    assert_eq!(access_control::effective_permissions(&DEVICE).unwrap(), vec![read, write.clone()]);
+
+    // A device that leaves the group loses the permissions of its roles
+    roles::remove_device_from_group("operators", &DEVICE).unwrap();
+    assert_eq!(access_control::explain_access(1, b"READ", &DEVICE).unwrap(), AccessExplanation::NoGrant);
+    let direct = EffectivePermission { grants: write.grants[..1].to_vec(), ..write };
+    assert_eq!(access_control::effective_permissions(&DEVICE).unwrap(), vec![direct]);
+}
@@ -0,0 +1,120 @@
+// Session keys of a NIZK mutual authentication and the key confirmation round.
+
+mod common;
+
+use std::sync::Arc;
+use schnorr_nizk::{gen_random_key_pair, shared_state, NIZKMutAuth};
+use schnorr_nizk::{DeviceId, KeyConfirmation, KeyStore, MemoryStore, NizkError, SessionKeys, SessionState};
+
+const INITIATOR: DeviceId = DeviceId::numeric(1);
+const RESPONDER: DeviceId = DeviceId::numeric(2);
+
+// Two devices with each other's public keys and the same shared key
+fn paired() -> (Arc<MemoryStore>, Arc<MemoryStore>) {
+    let (initiator, responder) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    for (store, id, other) in [(&initiator, &INITIATOR, &responder), (&responder, &RESPONDER, &initiator)] {
+        let (public_key, private_key) = gen_random_key_pair();
+        store.put(&format!("PrivateKey:{}", id), &private_key).unwrap();
+        other.put(&format!("PublicKey:{}", id), &public_key).unwrap();
+    }
+    shared_state::establish(&*initiator, &INITIATOR, &RESPONDER, [1; 32]).unwrap();
+    shared_state::establish(&*responder, &RESPONDER, &INITIATOR, [1; 32]).unwrap();
+    (initiator, responder)
+}
+
+// Run the mutual authentication up to the accepted proofs of both devices
+fn proofs(initiator: &Arc<MemoryStore>, responder: &Arc<MemoryStore>) -> (NIZKMutAuth, NIZKMutAuth) {
+    let (mut initiator_auth, initiator_proof) = NIZKMutAuth::new(initiator.clone(), INITIATOR, RESPONDER, None).unwrap();
+    let (mut responder_auth, responder_proof) = NIZKMutAuth::new(responder.clone(), RESPONDER, INITIATOR, Some(initiator_proof)).unwrap();
+    assert!(responder_auth.verify_proof().unwrap());
+    initiator_auth.add_recipient_values(responder_proof);
+    assert!(initiator_auth.verify_proof().unwrap());
+    (initiator_auth, responder_auth)
+}
+
+// Derive the session keys on both devices without confirmation
+fn session_keys(initiator: &Arc<MemoryStore>, responder: &Arc<MemoryStore>) -> (SessionKeys, SessionKeys) {
+    let (initiator_auth, responder_auth) = proofs(initiator, responder);
+    (initiator_auth.calculate_session_keys().unwrap(), responder_auth.calculate_session_keys().unwrap())
+}
+
+// Run the mutual authentication and start the key confirmation round on both devices
+fn mut_auth(initiator: &Arc<MemoryStore>, responder: &Arc<MemoryStore>) -> (KeyConfirmation, KeyConfirmation) {
+    let (initiator_auth, responder_auth) = proofs(initiator, responder);
+    (initiator_auth.confirm_session_keys().unwrap(), responder_auth.confirm_session_keys().unwrap())
+}
+
+#[test]
+fn both_devices_derive_the_same_keys() {
+    let _state = common::state("same-keys");
+    let (initiator, responder) = paired();
+    let (initiator_keys, responder_keys) = session_keys(&initiator, &responder);
+    assert!(initiator_keys == responder_keys);
+    assert_eq!(initiator_keys.export(b"label", b"context"), responder_keys.export(b"label", b"context"));
+
+    // The next authentication uses new commitments and the ratcheted shared key
+    let (next_keys, _) = session_keys(&initiator, &responder);
+    assert_ne!(next_keys.initiator_to_responder, initiator_keys.initiator_to_responder);
+}
+
+#[test]
+fn keys_differ_by_direction_and_label() {
+    let _state = common::state("labels");
+    let (initiator, responder) = paired();
+    let (keys, _) = session_keys(&initiator, &responder);
+
+    let derived = [keys.initiator_to_responder, keys.responder_to_initiator, keys.confirmation, keys.exporter];
+    for (i, first) in derived.iter().enumerate() {
+        for second in &derived[i + 1..] {
+            assert_ne!(first, second);
+        }
+    }
+
+    // Exported keys depend on label and context, and the label length is part of the input
+    let exported = keys.export(b"label", b"context");
+    assert_ne!(exported, keys.export(b"other", b"context"));
+    assert_ne!(exported, keys.export(b"label", b"other"));
+    assert_ne!(keys.export(b"ab", b"c"), keys.export(b"a", b"bc"));
+}
+
+#[test]
+fn confirmed_session_keys() {
+    let _state = common::state("confirmed");
+    let (initiator, responder) = paired();
+    let (mut initiator_confirmation, mut responder_confirmation) = mut_auth(&initiator, &responder);
+    assert_eq!(initiator_confirmation.state(), SessionState::AwaitingConfirmation);
+
+    // The initiator sends its tag first
+    responder_confirmation.verify(initiator_confirmation.tag()).unwrap();
+    initiator_confirmation.verify(responder_confirmation.tag()).unwrap();
+    assert_eq!(initiator_confirmation.state(), SessionState::Established);
+    assert_eq!(responder_confirmation.state(), SessionState::Established);
+
+    let initiator_keys = initiator_confirmation.session_keys().unwrap();
+    let responder_keys = responder_confirmation.session_keys().unwrap();
+    assert!(initiator_keys == responder_keys);
+    assert_eq!(initiator_keys.export(b"label", b"context"), responder_keys.export(b"label", b"context"));
+}
+
+#[test]
+fn mismatched_session_keys() {
+    let _state = common::state("mismatched");
+    let (initiator, responder) = paired();
+    let (mut first_initiator, _) = mut_auth(&initiator, &responder);
+    let (_, mut second_responder) = mut_auth(&initiator, &responder);
+
+    // Tags of another session were calculated with other keys
+    let result = second_responder.verify(first_initiator.tag());
+    assert!(matches!(result, Err(NizkError::KeyConfirmationFailed(id)) if id == INITIATOR));
+    let result = first_initiator.verify(second_responder.tag());
+    assert!(matches!(result, Err(NizkError::KeyConfirmationFailed(id)) if id == RESPONDER));
+
+    // A device does not accept its own tag back
+    let tag = first_initiator.tag();
+    assert!(matches!(first_initiator.verify(tag), Err(NizkError::KeyConfirmationFailed(_))));
+
+    // The keys stay unavailable
+    assert_eq!(first_initiator.state(), SessionState::AwaitingConfirmation);
+    assert!(matches!(first_initiator.session_keys(), Err(NizkError::WrongState(_))));
+    assert!(matches!(second_responder.session_keys(), Err(NizkError::WrongState(_))));
+}
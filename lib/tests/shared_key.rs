@@ -0,0 +1,113 @@
+// Proofs with the shared key of two paired devices when proofs get lost: the verifier catches up within
+// the lookahead window, beyond it the devices are desynchronized until a resync.
+
+mod common;
+
+use std::sync::Arc;
+use common::State;
+use schnorr_nizk::int_mut_auth::{Initiator, Responder};
+use schnorr_nizk::rate_limit::RateLimitPolicy;
+use schnorr_nizk::{gen_nizk_proof, gen_random_key_pair, shared_state, verify_nizk_proof};
+use schnorr_nizk::{DeviceId, KeyStore, MemoryStore, NizkConfig, NizkError};
+
+const SENDER: DeviceId = DeviceId::numeric(1);
+const VERIFIER: DeviceId = DeviceId::numeric(2);
+const WINDOW: u32 = 4;
+const MAX_REJECTIONS: u32 = 3;
+
+fn state(name: &str) -> State {
+    let rate_limit = RateLimitPolicy { max_rejections: MAX_REJECTIONS, ..Default::default() };
+    common::state_with(name, NizkConfig { lookahead_window: WINDOW, rate_limit, ..Default::default() })
+}
+
+// Two devices with each other's public keys and the same shared key
+fn paired() -> (Arc<MemoryStore>, Arc<MemoryStore>) {
+    let (sender, verifier) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    for (store, id, other) in [(&sender, &SENDER, &verifier), (&verifier, &VERIFIER, &sender)] {
+        let (public_key, private_key) = gen_random_key_pair();
+        store.put(&format!("PrivateKey:{}", id), &private_key).unwrap();
+        other.put(&format!("PublicKey:{}", id), &public_key).unwrap();
+    }
+    shared_state::establish(&*sender, &SENDER, &VERIFIER, [1; 32]).unwrap();
+    shared_state::establish(&*verifier, &VERIFIER, &SENDER, [1; 32]).unwrap();
+    (sender, verifier)
+}
+
+// Proofs that the sender generated but the verifier never received
+fn lose(sender: &MemoryStore, count: u32) {
+    for _ in 0..count {
+        gen_nizk_proof(sender, &SENDER, &VERIFIER, String::from("lost"), true).unwrap();
+    }
+}
+
+fn send(sender: &MemoryStore, verifier: &MemoryStore) -> Result<bool, NizkError> {
+    let proof = gen_nizk_proof(sender, &SENDER, &VERIFIER, String::from("open"), true)?;
+    verify_nizk_proof(verifier, &VERIFIER, &SENDER, String::from("open"), proof, true)
+}
+
+#[test]
+fn lost_proofs_within_window() {
+    let _state = state("within");
+    let (sender, verifier) = paired();
+
+    for lost in 0..=WINDOW {
+        lose(&sender, lost);
+        assert!(send(&sender, &verifier).unwrap(), "{} lost proofs", lost);
+
+        // The verifier caught up, both devices use the same counter again
+        let sender_state = shared_state::load(&*sender, &SENDER, &VERIFIER).unwrap();
+        let verifier_state = shared_state::load(&*verifier, &VERIFIER, &SENDER).unwrap();
+        assert_eq!(sender_state, verifier_state);
+    }
+    assert!(send(&sender, &verifier).unwrap());
+}
+
+#[test]
+fn lost_proofs_beyond_window() {
+    let _state = state("beyond");
+    let (sender, verifier) = paired();
+    let before = shared_state::load(&*verifier, &VERIFIER, &SENDER).unwrap();
+
+    lose(&sender, WINDOW + 1);
+    assert!(matches!(send(&sender, &verifier), Err(NizkError::Desynchronized(id)) if id == SENDER));
+    assert!(matches!(send(&sender, &verifier), Err(NizkError::Desynchronized(_))));
+
+    // The verifier keeps its shared values
+    assert_eq!(shared_state::load(&*verifier, &VERIFIER, &SENDER).unwrap(), before);
+
+    // Every desynchronized proof counts as a rejection, a sender that keeps sending them is blocked
+    assert!(schnorr_nizk::check_sender(&SENDER).unwrap().is_allowed());
+    for _ in 2..=MAX_REJECTIONS {
+        assert!(matches!(send(&sender, &verifier), Err(NizkError::Desynchronized(_))));
+    }
+    assert!(!schnorr_nizk::check_sender(&SENDER).unwrap().is_allowed());
+}
+
+#[test]
+fn resync_restores_shared_key() {
+    let _state = state("resync");
+    let (sender, verifier) = paired();
+    lose(&sender, WINDOW + 1);
+    assert!(matches!(send(&sender, &verifier), Err(NizkError::Desynchronized(_))));
+    let epoch = shared_state::load(&*verifier, &VERIFIER, &SENDER).unwrap().epoch;
+
+    // The verifier starts the resync with the handshake of the pairing
+    let (initiator, commitment) = Initiator::resync(verifier.clone(), VERIFIER, SENDER).unwrap();
+    let (responder, commitment_and_challenge) = Responder::start(sender.clone(), SENDER, VERIFIER, commitment).unwrap();
+    let (initiator, challenge_and_response) = initiator.receive_commitment(commitment_and_challenge).unwrap();
+    let (_, response) = responder.receive_challenge_response(challenge_and_response).unwrap();
+    initiator.receive_response(response).unwrap();
+
+    // Both devices start a new epoch with the same key and counter
+    let sender_state = shared_state::load(&*sender, &SENDER, &VERIFIER).unwrap();
+    let verifier_state = shared_state::load(&*verifier, &VERIFIER, &SENDER).unwrap();
+    assert_eq!(sender_state.key, verifier_state.key);
+    assert_eq!((sender_state.counter, verifier_state.counter), (1, 1));
+    assert_eq!(verifier_state.epoch, epoch + 1);
+    assert_ne!(verifier_state.key, [1; 32]);
+    assert!(send(&sender, &verifier).unwrap());
+    assert!(send(&sender, &verifier).unwrap());
+
+    // A device whose public key is unknown can not be resynced
+    assert!(Initiator::resync(verifier, VERIFIER, DeviceId::numeric(3)).is_err());
+}
@@ -0,0 +1,131 @@
+// Recovery of the shared state records: a broken record is restored from the previous record, legacy
+// keys are migrated, and a pair without any readable record is reported as corrupted.
+
+use schnorr_nizk::shared_state::{self, Recovery, SharedState};
+use schnorr_nizk::{DeviceId, KeyStore, MemoryStore, NizkError};
+
+const ME: DeviceId = DeviceId::numeric(1);
+
+fn others() -> Vec<DeviceId> {
+    vec![DeviceId::numeric(2), DeviceId::uuid([0xcd; 16]), DeviceId::name("plant-1/valve").unwrap()]
+}
+
+fn description(other: &DeviceId) -> String {
+    format!("SharedState:{}:{}", ME, other)
+}
+
+// Two saved states, so the first one is the previous record of the second one
+fn saved(store: &MemoryStore, other: &DeviceId) -> (SharedState, SharedState) {
+    let first = shared_state::establish(store, &ME, other, [1; 32]).unwrap();
+    let second = SharedState { counter: 2, key: [2; 32], ..first };
+    shared_state::save(store, &ME, other, &second).unwrap();
+    (first, second)
+}
+
+// Records with a cut off end, a flipped bit and an unknown version
+fn broken(record: &[u8]) -> Vec<Vec<u8>> {
+    let mut flipped = record.to_vec();
+    flipped[10] ^= 1;
+    let mut version = record.to_vec();
+    version[0] = 9;
+    vec![record[..record.len() - 1].to_vec(), flipped, version, Vec::new()]
+}
+
+#[test]
+fn broken_record_is_restored_from_previous() {
+    for other in others() {
+        let store = MemoryStore::new();
+        let (first, second) = saved(&store, &other);
+        assert_eq!(shared_state::recover(&store, &ME).unwrap(), vec![(other.clone(), Recovery::Intact)]);
+        assert_eq!(shared_state::load(&store, &ME, &other).unwrap(), second);
+
+        let record = store.get(&description(&other)).unwrap();
+        for broken_record in broken(&record) {
+            store.put(&description(&other), &broken_record).unwrap();
+            assert_eq!(shared_state::recover(&store, &ME).unwrap(), vec![(other.clone(), Recovery::Repaired)]);
+
+            // The previous record is saved as current record again
+            assert_eq!(shared_state::recover(&store, &ME).unwrap(), vec![(other.clone(), Recovery::Intact)]);
+            assert_eq!(shared_state::load(&store, &ME, &other).unwrap(), first);
+            store.put(&description(&other), &record).unwrap();
+        }
+
+        // A broken record is not kept as previous record when the next one is saved
+        store.put(&description(&other), &broken(&record)[1]).unwrap();
+        shared_state::save(&store, &ME, &other, &SharedState { counter: 3, ..second }).unwrap();
+        store.put(&description(&other), &broken(&record)[1]).unwrap();
+        assert_eq!(shared_state::load(&store, &ME, &other).unwrap(), first);
+    }
+}
+
+#[test]
+fn corrupted_without_previous_record() {
+    for other in others() {
+        // Only one record was ever saved, so there is no previous record
+        let store = MemoryStore::new();
+        shared_state::establish(&store, &ME, &other, [1; 32]).unwrap();
+        let record = store.get(&description(&other)).unwrap();
+        store.put(&description(&other), &broken(&record)[0]).unwrap();
+        let result = shared_state::recover(&store, &ME);
+        assert!(matches!(result, Err(NizkError::CorruptedSharedState(id)) if id == other));
+        assert!(matches!(shared_state::load(&store, &ME, &other), Err(NizkError::CorruptedSharedState(_))));
+
+        // Both records are broken
+        let store = MemoryStore::new();
+        saved(&store, &other);
+        for description in [description(&other), format!("{}:prev", description(&other))] {
+            let record = store.get(&description).unwrap();
+            store.put(&description, &broken(&record)[1]).unwrap();
+        }
+        assert!(matches!(shared_state::load(&store, &ME, &other), Err(NizkError::CorruptedSharedState(id)) if id == other));
+
+        // A new key agreement does not replace the state, only after a revoke it starts again with the first epoch
+        let result = shared_state::establish(&store, &ME, &other, [3; 32]);
+        assert!(matches!(result, Err(NizkError::CorruptedSharedState(id)) if id == other));
+        shared_state::revoke(&store, &ME, &other).unwrap();
+        let state = shared_state::establish(&store, &ME, &other, [3; 32]).unwrap();
+        assert_eq!((state.epoch, state.counter), (1, 1));
+        assert_eq!(shared_state::load(&store, &ME, &other).unwrap(), state);
+    }
+}
+
+#[test]
+fn legacy_keys_are_migrated() {
+    let store = MemoryStore::new();
+    let others = others();
+    for other in &others {
+        store.put(&format!("SharedSecretKey:{}:{}", ME, other), &[4; 32]).unwrap();
+        store.put(&format!("SharedCounter:{}:{}", ME, other), &7u32.to_be_bytes()).unwrap();
+    }
+
+    let mut recovered = shared_state::recover(&store, &ME).unwrap();
+    recovered.sort_by_key(|(id, _)| id.to_string());
+    let mut expected: Vec<_> = others.iter().map(|other| (other.clone(), Recovery::Migrated)).collect();
+    expected.sort_by_key(|(id, _)| id.to_string());
+    assert_eq!(recovered, expected);
+
+    for other in &others {
+        assert_eq!(shared_state::load(&store, &ME, other).unwrap(), SharedState { epoch: 0, counter: 7, key: [4; 32] });
+        assert!(store.get(&format!("SharedSecretKey:{}:{}", ME, other)).is_err());
+        assert!(store.get(&format!("SharedCounter:{}:{}", ME, other)).is_err());
+    }
+
+    // A legacy counter with the wrong size is not migrated
+    let store = MemoryStore::new();
+    store.put(&format!("SharedSecretKey:{}:{}", ME, others[0]), &[4; 32]).unwrap();
+    store.put(&format!("SharedCounter:{}:{}", ME, others[0]), &[7; 3]).unwrap();
+    assert!(matches!(shared_state::recover(&store, &ME), Err(NizkError::InvalidKeyLength { expected: 4, found: 3, .. })));
+}
+
+#[test]
+fn revoked_state_is_gone() {
+    let store = MemoryStore::new();
+    let other = DeviceId::numeric(2);
+    saved(&store, &other);
+    shared_state::revoke(&store, &ME, &other).unwrap();
+    assert_eq!(shared_state::recover(&store, &ME).unwrap(), vec![]);
+    assert!(matches!(shared_state::load(&store, &ME, &other), Err(NizkError::KeyRing(_))));
+
+    // Revoking twice is no error
+    shared_state::revoke(&store, &ME, &other).unwrap();
+}
@@ -0,0 +1,63 @@
+// Checks of the state directory before any state is written into it.
+
+use std::env;
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use schnorr_nizk::{NizkConfig, NizkError};
+
+// Empty directory of one test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nizk-state-dir-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn unsafe_reason(state_dir: PathBuf) -> Option<&'static str> {
+    let config = NizkConfig { state_dir, ..Default::default() };
+    match config.prepare_state_dir() {
+        Err(NizkError::UnsafeStateDir { reason, .. }) => Some(reason),
+        _ => None,
+    }
+}
+
+#[test]
+fn missing_dir_is_created_private() {
+    let dir = temp_dir("created");
+    let state_dir = dir.join("a/b");
+    NizkConfig { state_dir: state_dir.clone(), ..Default::default() }.prepare_state_dir().unwrap();
+    assert_eq!(fs::metadata(&state_dir).unwrap().permissions().mode() & 0o777, 0o700);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unsafe_dirs_are_refused() {
+    let dir = temp_dir("unsafe");
+
+    let writable = dir.join("writable");
+    fs::create_dir(&writable).unwrap();
+    fs::set_permissions(&writable, Permissions::from_mode(0o777)).unwrap();
+    assert_eq!(unsafe_reason(writable.clone()), Some("state directory is writable by other users"));
+    fs::set_permissions(&writable, Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(unsafe_reason(writable.clone()), None);
+
+    let link = dir.join("link");
+    unix_fs::symlink(&writable, &link).unwrap();
+    assert_eq!(unsafe_reason(link), Some("state directory is a symbolic link"));
+
+    let file = dir.join("file");
+    fs::write(&file, b"").unwrap();
+    assert_eq!(unsafe_reason(file), Some("state directory is not a directory"));
+
+    // Only root can give the directory to another user
+    if fs::metadata(&dir).unwrap().uid() == 0 {
+        let foreign = dir.join("foreign");
+        fs::create_dir(&foreign).unwrap();
+        unix_fs::chown(&foreign, Some(65534), None).unwrap();
+        assert_eq!(unsafe_reason(foreign.clone()), Some("owned by another user"));
+        unix_fs::chown(&foreign, Some(0), None).unwrap();
+        assert_eq!(unsafe_reason(foreign), None);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,48 @@
+// The challenge of a NIZK proof is bound to the label, the device IDs and the message.
+
+mod common;
+
+use std::sync::Arc;
+use schnorr_nizk::{gen_nizk_proof, gen_random_key_pair, shared_state, verify_nizk_proof, DeviceId, KeyStore, MemoryStore, NIZKMutAuth};
+
+const SENDER: DeviceId = DeviceId::numeric(1);
+const RECIPIENT: DeviceId = DeviceId::numeric(2);
+const OTHER_RECIPIENT: DeviceId = DeviceId::numeric(3);
+
+// The sender shares the same key and counter with both recipients, so only the IDs in the transcript differ
+fn devices() -> (Arc<MemoryStore>, MemoryStore) {
+    let (sender, recipients) = (Arc::new(MemoryStore::new()), MemoryStore::new());
+    let (public_key, private_key) = gen_random_key_pair();
+    let shared_key = [5u8; 32];
+    sender.put(&format!("PrivateKey:{}", SENDER), &private_key).unwrap();
+    recipients.put(&format!("PublicKey:{}", SENDER), &public_key).unwrap();
+    for recipient in [RECIPIENT, OTHER_RECIPIENT] {
+        shared_state::establish(&*sender, &SENDER, &recipient, shared_key).unwrap();
+        shared_state::establish(&recipients, &recipient, &SENDER, shared_key).unwrap();
+    }
+    (sender, recipients)
+}
+
+#[test]
+fn proof_is_bound_to_ids_and_message() {
+    let _state = common::state("ids-and-message");
+    let (sender, recipients) = devices();
+    let proof = gen_nizk_proof(&*sender, &SENDER, &RECIPIENT, "open valve".to_string(), false).unwrap();
+
+    assert!(!matches!(verify_nizk_proof(&recipients, &OTHER_RECIPIENT, &SENDER, "open valve".to_string(), proof, false), Ok(true)));
+    assert!(!matches!(verify_nizk_proof(&recipients, &RECIPIENT, &SENDER, "close valve".to_string(), proof, false), Ok(true)));
+    assert!(verify_nizk_proof(&recipients, &RECIPIENT, &SENDER, "open valve".to_string(), proof, false).unwrap());
+}
+
+#[test]
+fn proof_is_bound_to_label() {
+    let _state = common::state("label");
+    let (sender, recipients) = devices();
+
+    // A mutual authentication proof has no message, like a NIZK proof of an empty message
+    let (_, mut_auth_proof) = NIZKMutAuth::new(sender.clone(), SENDER, RECIPIENT, None).unwrap();
+    assert!(!matches!(verify_nizk_proof(&recipients, &RECIPIENT, &SENDER, String::new(), mut_auth_proof, false), Ok(true)));
+
+    let proof = gen_nizk_proof(&*sender, &SENDER, &RECIPIENT, String::new(), false).unwrap();
+    assert!(verify_nizk_proof(&recipients, &RECIPIENT, &SENDER, String::new(), proof, false).unwrap());
+}
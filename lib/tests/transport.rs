@@ -0,0 +1,146 @@
+// Client and server of the async transport connected by an in-memory duplex stream.
+
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::duplex;
+use schnorr_nizk::transport::{MessageHandler, NizkClient, NizkServer};
+use schnorr_nizk::{gen_random_key_pair, Decision, DeviceId, KeyStore, MemoryStore, NizkError, NizkMode, RateLimitPolicy, RateLimiter, SessionKeys};
+
+const CLIENT: DeviceId = DeviceId::numeric(1);
+const SERVER: DeviceId = DeviceId::numeric(2);
+
+// Everything the server passed to the handler
+#[derive(Default)]
+struct Received {
+    messages: Mutex<Vec<(DeviceId, Vec<u8>)>>,
+    established: Mutex<Vec<(DeviceId, Option<SessionKeys>)>>,
+    rejected: Mutex<Vec<(Option<DeviceId>, String)>>,
+}
+
+#[derive(Clone, Default)]
+struct Recorder(Arc<Received>);
+
+impl MessageHandler for Recorder {
+    fn on_message(&self, peer: DeviceId, message: Vec<u8>) {
+        self.0.messages.lock().unwrap().push((peer, message));
+    }
+
+    fn on_established(&self, peer: DeviceId, session_key: Option<SessionKeys>) {
+        self.0.established.lock().unwrap().push((peer, session_key));
+    }
+
+    fn on_rejected(&self, peer: Option<&DeviceId>, reason: &NizkError) {
+        self.0.rejected.lock().unwrap().push((peer.cloned(), reason.to_string()));
+    }
+}
+
+// Client and server that know each other's public keys, but share no key yet
+fn devices() -> (NizkClient, NizkServer<Recorder>, Recorder) {
+    let (client_store, server_store) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    for (store, id, other) in [(&client_store, &CLIENT, &server_store), (&server_store, &SERVER, &client_store)] {
+        let (public_key, private_key) = gen_random_key_pair();
+        store.put(&format!("PrivateKey:{}", id), &private_key).unwrap();
+        other.put(&format!("PublicKey:{}", id), &public_key).unwrap();
+    }
+    let recorder = Recorder::default();
+    let server = NizkServer::new(server_store, SERVER, recorder.clone()).with_public_context(b"transport test");
+    (NizkClient::new(client_store, CLIENT), server, recorder)
+}
+
+#[tokio::test]
+async fn duplex_round_trip() {
+    let _state = common::state("round-trip");
+    let (client, server, recorder) = devices();
+    let server_id = SERVER;
+
+    // Key agreement
+    let (mut client_stream, mut server_stream) = duplex(1024);
+    let (client_result, server_result) = tokio::join!(
+        client.key_agreement(&mut client_stream, &server_id, false),
+        server.handle_connection(&mut server_stream),
+    );
+    client_result.unwrap();
+    server_result.unwrap();
+    assert_eq!(recorder.0.established.lock().unwrap().as_slice(), &[(CLIENT, None)]);
+
+    // Mutual authentication, both sides derive the same session keys
+    let (mut client_stream, mut server_stream) = duplex(1024);
+    let (client_result, server_result) = tokio::join!(
+        client.mut_auth(&mut client_stream, &server_id),
+        server.handle_connection(&mut server_stream),
+    );
+    server_result.unwrap();
+    let session_key = client_result.unwrap();
+    assert_eq!(recorder.0.established.lock().unwrap()[1], (CLIENT, Some(session_key)));
+
+    // Application messages in both proof modes
+    for mode in [NizkMode::SharedKey { update_keys: true }, NizkMode::PublicKey { context: b"transport test" }] {
+        let (mut client_stream, mut server_stream) = duplex(1024);
+        let (client_result, server_result) = tokio::join!(
+            client.send_message(&mut client_stream, mode, &server_id, b"open valve"),
+            server.handle_connection(&mut server_stream),
+        );
+        client_result.unwrap();
+        server_result.unwrap();
+    }
+    let messages = recorder.0.messages.lock().unwrap().clone();
+    assert_eq!(messages, vec![(CLIENT, b"open valve".to_vec()), (CLIENT, b"open valve".to_vec())]);
+    assert!(recorder.0.rejected.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn silent_peer_times_out() {
+    let _state = common::state("timeout");
+    let (client, server, recorder) = devices();
+    let client = client.with_timeout(Duration::from_millis(100));
+    let server = server.with_timeout(Duration::from_millis(100));
+
+    // The server never answers the client
+    let (mut client_stream, _server_stream) = duplex(1024);
+    assert!(matches!(client.key_agreement(&mut client_stream, &SERVER, false).await, Err(NizkError::Timeout)));
+
+    // The client never sends anything to the server
+    let (_client_stream, mut server_stream) = duplex(1024);
+    assert!(matches!(server.handle_connection(&mut server_stream).await, Err(NizkError::Timeout)));
+    assert_eq!(recorder.0.rejected.lock().unwrap().as_slice(), &[(None, NizkError::Timeout.to_string())]);
+
+    // A stream that is closed is an error, not a timeout
+    let (client_stream, mut server_stream) = duplex(1024);
+    drop(client_stream);
+    assert!(matches!(server.handle_connection(&mut server_stream).await, Err(NizkError::Io(_))));
+}
+
+#[tokio::test]
+async fn rejected_proofs_block_the_device() {
+    let _state = common::state("rate-limit");
+    let (client, server, recorder) = devices();
+    let policy = RateLimitPolicy { max_rejections: 1, ..RateLimitPolicy::default() };
+    let server = server.with_rate_limiter(Arc::new(RateLimiter::new(policy)));
+    let server_id = SERVER;
+
+    // Proofs for another context are rejected, the second rejection blocks the client
+    for _ in 0..2 {
+        let (mut client_stream, mut server_stream) = duplex(1024);
+        let mode = NizkMode::PublicKey { context: b"other context" };
+        let (_, server_result) = tokio::join!(
+            client.send_message(&mut client_stream, mode, &server_id, b"open valve"),
+            server.handle_connection(&mut server_stream),
+        );
+        assert!(matches!(server_result, Err(NizkError::ProofRejected)));
+    }
+
+    // A valid proof of the blocked client is refused before it is checked
+    let (mut client_stream, mut server_stream) = duplex(1024);
+    let mode = NizkMode::PublicKey { context: b"transport test" };
+    let (_, server_result) = tokio::join!(
+        client.send_message(&mut client_stream, mode, &server_id, b"open valve"),
+        server.handle_connection(&mut server_stream),
+    );
+    assert!(matches!(server_result, Err(NizkError::RateLimited(Decision::Block(_)))));
+    assert!(recorder.0.messages.lock().unwrap().is_empty());
+    assert_eq!(recorder.0.rejected.lock().unwrap().len(), 3);
+}
@@ -0,0 +1,187 @@
+// Encoding and decoding of protocol messages and device IDs, including malformed input.
+
+use std::io::Cursor;
+use curve25519_dalek::constants::ED25519_BASEPOINT_COMPRESSED;
+use schnorr_nizk::int_mut_auth::{ChallengeAndResponse, Commitment};
+use schnorr_nizk::wire::{Body, Header, Message, MessageBuffer, PREFIX_SIZE, WIRE_VERSION};
+use schnorr_nizk::{DeviceId, NizkError};
+
+// A valid point and a canonical scalar
+const POINT: [u8; 32] = ED25519_BASEPOINT_COMPRESSED.0;
+const SCALAR: [u8; 32] = [7; 32];
+
+fn ids() -> Vec<DeviceId> {
+    vec![
+        DeviceId::numeric(42),
+        DeviceId::uuid([0xab; 16]),
+        DeviceId::name("plant-1/sensor.temperature_2").unwrap(),
+    ]
+}
+
+fn proof_message(sender: DeviceId, recipient: DeviceId) -> Message {
+    let body = Body::NizkProof { proof: (POINT, [9; 32], SCALAR), message: b"open valve".to_vec() };
+    Message::new(sender, recipient, body)
+}
+
+fn is_invalid_message(result: Result<Message, NizkError>) -> bool {
+    matches!(result, Err(NizkError::InvalidMessage(_)))
+}
+
+#[test]
+fn message_round_trip() {
+    for sender in ids() {
+        for recipient in ids() {
+            let message = proof_message(sender.clone(), recipient);
+            let bytes = message.encode().unwrap();
+            assert_eq!(bytes[0], WIRE_VERSION);
+            assert_eq!(Message::decode(&bytes).unwrap(), message);
+            assert_eq!(Message::read_from(&mut Cursor::new(&bytes)).unwrap(), message);
+
+            let (header, body) = Header::decode(&bytes).unwrap();
+            assert_eq!(header.sender_ID, message.sender_ID);
+            assert_eq!(header.body_length as usize, body.len());
+        }
+    }
+
+    let bodies = vec![
+        Body::IntCommitment(Commitment { commitment: POINT }),
+        Body::IntChallengeAndResponse(ChallengeAndResponse { challenge: [1; 32], response: SCALAR }),
+        Body::NizkMutAuthProof((POINT, [3; 32], SCALAR)),
+        Body::KeyConfirmation([3; 32]),
+        Body::PublicKey(POINT),
+    ];
+    for body in bodies {
+        let message = Message::new(DeviceId::numeric(1), DeviceId::numeric(2), body);
+        assert_eq!(Message::decode(&message.encode().unwrap()).unwrap(), message);
+    }
+}
+
+#[test]
+fn stream_of_messages() {
+    let first = proof_message(DeviceId::numeric(1), DeviceId::name("gateway").unwrap());
+    let second = Message::new(DeviceId::name("gateway").unwrap(), DeviceId::numeric(1), Body::PublicKey(POINT));
+    let mut stream = Vec::new();
+    first.write_to(&mut stream).unwrap();
+    second.write_to(&mut stream).unwrap();
+
+    let mut reader = Cursor::new(stream);
+    assert_eq!(Message::read_from(&mut reader).unwrap(), first);
+    assert_eq!(Message::read_from(&mut reader).unwrap(), second);
+    assert!(matches!(Message::read_from(&mut reader), Err(NizkError::Io(_))));
+}
+
+#[test]
+fn message_buffer_splits_stream() {
+    let first = proof_message(DeviceId::numeric(1), DeviceId::numeric(2));
+    let second = Message::new(DeviceId::numeric(2), DeviceId::numeric(1), Body::KeyConfirmation([5; 32]));
+    let mut stream = first.encode().unwrap();
+    stream.extend(second.encode().unwrap());
+
+    // Bytes arrive one by one
+    let mut buffer = MessageBuffer::new();
+    let mut received = Vec::new();
+    for byte in stream {
+        buffer.push(&[byte]);
+        if let Some(message) = buffer.next_message().unwrap() {
+            received.push(message);
+        }
+    }
+    assert_eq!(received, vec![first, second]);
+    assert!(buffer.next_message().unwrap().is_none());
+}
+
+#[test]
+fn truncated_and_extended_messages() {
+    let bytes = proof_message(DeviceId::name("gateway").unwrap(), DeviceId::numeric(2)).encode().unwrap();
+    for length in 0..bytes.len() {
+        assert!(is_invalid_message(Message::decode(&bytes[..length])), "length {}", length);
+    }
+    assert!(matches!(Message::read_from(&mut Cursor::new(&bytes[..bytes.len() - 1])), Err(NizkError::Io(_))));
+
+    let mut extended = bytes.clone();
+    extended.push(0);
+    assert!(is_invalid_message(Message::decode(&extended)));
+
+    // Body length that claims more bytes than the NIZK message has
+    let mut short_message = bytes.clone();
+    let length_position = bytes.len() - b"open valve".len() - 2;
+    short_message[length_position + 1] += 1;
+    assert!(is_invalid_message(Message::decode(&short_message)));
+}
+
+#[test]
+fn wrong_version() {
+    let mut bytes = proof_message(DeviceId::numeric(1), DeviceId::numeric(2)).encode().unwrap();
+    bytes[0] = WIRE_VERSION + 1;
+    assert!(matches!(Message::decode(&bytes), Err(NizkError::UnsupportedVersion(version)) if version == WIRE_VERSION + 1));
+    assert!(matches!(Message::read_from(&mut Cursor::new(&bytes)), Err(NizkError::UnsupportedVersion(_))));
+
+    let mut buffer = MessageBuffer::new();
+    buffer.push(&bytes);
+    assert!(matches!(buffer.next_message(), Err(NizkError::UnsupportedVersion(_))));
+}
+
+#[test]
+fn invalid_fields() {
+    let ids_length = DeviceId::numeric(1).to_bytes().len() + DeviceId::numeric(2).to_bytes().len();
+    let body_start = PREFIX_SIZE + ids_length;
+
+    // Commitment that is not a point on the curve
+    let mut not_a_point = [0u8; 32];
+    not_a_point[0] = 2;
+    let mut bytes = Message::new(DeviceId::numeric(1), DeviceId::numeric(2), Body::PublicKey(POINT)).encode().unwrap();
+    bytes[body_start..].copy_from_slice(&not_a_point);
+    assert!(matches!(Message::decode(&bytes), Err(NizkError::InvalidCurvePoint)));
+
+    // Response that is not a reduced scalar
+    let message = Message::new(DeviceId::numeric(1), DeviceId::numeric(2), Body::IntChallengeAndResponse(ChallengeAndResponse { challenge: [1; 32], response: SCALAR }));
+    let mut bytes = message.encode().unwrap();
+    bytes[body_start + 32..].copy_from_slice(&[0xff; 32]);
+    assert!(is_invalid_message(Message::decode(&bytes)));
+
+    // Unknown message type
+    let mut bytes = message.encode().unwrap();
+    bytes[1] = 0x7f;
+    assert!(is_invalid_message(Message::decode(&bytes)));
+}
+
+#[test]
+fn invalid_device_ids() {
+    let message = Message::new(DeviceId::numeric(1), DeviceId::numeric(2), Body::KeyConfirmation([0; 32]));
+    let body = &message.encode().unwrap()[PREFIX_SIZE + 10..];
+
+    // Builds a message with the given IDs field
+    let with_ids = |ids: &[u8]| {
+        let mut bytes = vec![WIRE_VERSION, 0x21, ids.len() as u8];
+        bytes.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(ids);
+        bytes.extend_from_slice(body);
+        bytes
+    };
+    let recipient = DeviceId::numeric(2).to_bytes();
+
+    // Name that is longer than allowed
+    let long_name = "a".repeat(65);
+    let mut ids = vec![0x03, long_name.len() as u8];
+    ids.extend_from_slice(long_name.as_bytes());
+    ids.extend_from_slice(&recipient);
+    assert!(matches!(Message::decode(&with_ids(&ids)), Err(NizkError::InvalidDeviceId(_))));
+
+    // Name that is not UTF-8
+    let mut ids = vec![0x03, 2, 0xff, 0xfe];
+    ids.extend_from_slice(&recipient);
+    assert!(matches!(Message::decode(&with_ids(&ids)), Err(NizkError::InvalidDeviceId(_))));
+
+    // Unknown ID type, and a name longer than the IDs field
+    let mut ids = vec![0x09, 0, 0, 0, 1];
+    ids.extend_from_slice(&recipient);
+    assert!(is_invalid_message(Message::decode(&with_ids(&ids))));
+    assert!(is_invalid_message(Message::decode(&with_ids(&[0x03, 40, b'a']))));
+
+    // IDs field with bytes after both IDs
+    let mut ids = DeviceId::numeric(1).to_bytes();
+    ids.extend_from_slice(&recipient);
+    ids.push(0);
+    assert!(is_invalid_message(Message::decode(&with_ids(&ids))));
+    assert_eq!(Message::decode(&with_ids(&ids[..ids.len() - 1])).unwrap(), message);
+}